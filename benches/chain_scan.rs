@@ -0,0 +1,60 @@
+//! Full scans of a deep layer stack.
+//!
+//! Compares a scan through `Layer::triples`, which merges the whole
+//! stack in a single pass, with a scan through the subject lookups,
+//! which check every parent for every triple.
+use criterion::{criterion_group, criterion_main, Criterion};
+use terminus_store::layer::{Layer, StringTriple};
+use terminus_store::open_sync_memory_store;
+use terminus_store::store::sync::SyncStoreLayer;
+
+const DEPTH: usize = 20;
+const BASE_TRIPLES: usize = 10_000;
+const CHANGES_PER_LAYER: usize = 200;
+
+fn triple(i: usize) -> StringTriple {
+    StringTriple::new_value(&format!("node{}", i), "value", &i.to_string())
+}
+
+fn deep_stack() -> SyncStoreLayer {
+    let store = open_sync_memory_store();
+    let builder = store.create_base_layer().unwrap();
+    for i in 0..BASE_TRIPLES {
+        builder.add_string_triple(&triple(i)).unwrap();
+    }
+    let mut layer = builder.commit().unwrap();
+
+    for depth in 1..DEPTH {
+        let builder = layer.open_write().unwrap();
+        // remove some of the base triples and add some new ones,
+        // spread out over all subjects so that every subject is
+        // changed in many layers
+        let stride = BASE_TRIPLES / CHANGES_PER_LAYER;
+        for i in 0..CHANGES_PER_LAYER {
+            builder
+                .remove_string_triple(&triple(i * stride + depth))
+                .unwrap();
+            builder
+                .add_string_triple(&triple(i * stride + BASE_TRIPLES + depth))
+                .unwrap();
+        }
+        layer = builder.commit().unwrap();
+    }
+
+    layer
+}
+
+fn chain_scan(c: &mut Criterion) {
+    let layer = deep_stack();
+    assert_eq!(DEPTH, layer.chain_depth());
+
+    let mut group = c.benchmark_group("scan 20 deep");
+    group.bench_function("triples", |b| b.iter(|| layer.triples().count()));
+    group.bench_function("subject lookups", |b| {
+        b.iter(|| layer.subjects().flat_map(|s| s.triples()).count())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, chain_scan);
+criterion_main!(benches);
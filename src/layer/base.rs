@@ -12,6 +12,7 @@ use crate::structure::*;
 
 use std::collections::BTreeSet;
 use std::io;
+use std::sync::Arc;
 
 /// A base layer.
 ///
@@ -29,6 +30,11 @@ pub struct BaseLayer<M: 'static + AsRef<[u8]> + Clone + Send + Sync> {
     o_ps_adjacency_list: AdjacencyList<M>,
 
     predicate_wavelet_tree: WaveletTree<M>,
+
+    bloom_filter: Option<BloomFilter<M>>,
+    collation: Arc<dyn Collation>,
+
+    statistics: LayerStatisticsCache,
 }
 
 impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> BaseLayer<M> {
@@ -36,9 +42,16 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> BaseLayer<M> {
         name: [u32; 5],
         files: &BaseLayerFiles<F>,
     ) -> impl Future<Item = Self, Error = std::io::Error> {
-        files.map_all().map(move |maps| Self::load(name, maps))
+        files.map_all().and_then(move |maps| {
+            let collation =
+                collation_from_stored_name(maps.collation_map.as_ref().map(|m| m.as_ref()))?;
+            Ok(Self::load(name, maps).with_collation(collation))
+        })
     }
 
+    /// Load a base layer from its maps, searching its dictionaries in bytewise order.
+    ///
+    /// `load_from_files` uses the collation stored with the layer instead.
     pub fn load(name: [u32; 5], maps: BaseLayerMaps<M>) -> BaseLayer<M> {
         let node_dictionary = PfcDict::parse(
             maps.node_dictionary_maps.blocks_map,
@@ -85,6 +98,13 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> BaseLayer<M> {
             predicate_wavelet_tree_width,
         );
 
+        let bloom_filter = maps
+            .bloom_filter_map
+            .filter(|map| !map.as_ref().is_empty())
+            // a filter that can't be parsed is ignored, so lookups go
+            // straight to the adjacency lists.
+            .and_then(|map| BloomFilter::parse(map).ok());
+
         BaseLayer {
             name,
             node_dictionary,
@@ -97,6 +117,32 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> BaseLayer<M> {
             o_ps_adjacency_list,
 
             predicate_wavelet_tree,
+
+            bloom_filter,
+            collation: Arc::new(BytewiseCollation),
+
+            statistics: LayerStatisticsCache::default(),
+        }
+    }
+
+    fn with_collation(mut self, collation: Arc<dyn Collation>) -> Self {
+        self.node_dictionary = self.node_dictionary.with_collation(collation.clone());
+        self.predicate_dictionary = self.predicate_dictionary.with_collation(collation.clone());
+        self.value_dictionary = self.value_dictionary.with_collation(collation.clone());
+        self.collation = collation;
+        self
+    }
+
+    /// Borrow the adjacency lists of this layer.
+    ///
+    /// See `LayerAdjacencyLists` for how they are laid out.
+    pub fn adjacency_lists(&self) -> LayerAdjacencyLists<'_, M> {
+        LayerAdjacencyLists {
+            subjects: None,
+            objects: None,
+            s_p: &self.s_p_adjacency_list,
+            sp_o: &self.sp_o_adjacency_list,
+            o_ps: &self.o_ps_adjacency_list,
         }
     }
 }
@@ -110,6 +156,52 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for BaseLayer<M> {
         None
     }
 
+    fn layer_type(&self) -> LayerType {
+        LayerType::Base
+    }
+
+    fn may_contain_triple(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        self.bloom_filter
+            .as_ref()
+            .map(|filter| filter.may_contain(subject, predicate, object))
+            .unwrap_or(true)
+    }
+
+    fn collation(&self) -> Arc<dyn Collation> {
+        self.collation.clone()
+    }
+
+    fn statistics(&self) -> Arc<LayerStatistics> {
+        self.statistics.get_or_compute(|| self.compute_statistics())
+    }
+
+    fn scan_hint(&self, hint: ScanHint) {
+        hint.advise_adjacency_list(&self.s_p_adjacency_list);
+        hint.advise_adjacency_list(&self.sp_o_adjacency_list);
+        hint.advise_adjacency_list(&self.o_ps_adjacency_list);
+    }
+
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        // Nodes that are never a subject get a stub entry with
+        // predicate 0 in the s_p adjacency list, and a matching stub
+        // entry with object 0 in the sp_o adjacency list.
+        let stubs = self
+            .predicate_wavelet_tree
+            .lookup(0)
+            .map_or(0, |lookup| lookup.len());
+        LayerTripleCounts {
+            subjects: self.s_p_adjacency_list.left_count() - stubs,
+            subject_predicate_pairs: self.s_p_adjacency_list.right_count() - stubs,
+            triples: self.sp_o_adjacency_list.right_count() - stubs,
+        }
+    }
+
+    fn predicate_addition_count(&self, predicate: u64) -> usize {
+        self.predicate_wavelet_tree
+            .lookup(predicate)
+            .map_or(0, |lookup| lookup.len())
+    }
+
     fn subject_additions(&self) -> Box<dyn Iterator<Item = Box<dyn LayerSubjectLookup>>> {
         Box::new(BaseLayerSubjectIterator {
             pos: 0,
@@ -126,6 +218,13 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for BaseLayer<M> {
         self.node_dictionary.len()
     }
 
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a> {
+        Box::new(self.node_dictionary.strings_with_prefix(prefix))
+    }
+
     fn node_dict_get(&self, id: usize) -> Option<String> {
         self.node_dictionary.get(id)
     }
@@ -138,6 +237,24 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for BaseLayer<M> {
         self.value_dictionary.get(id)
     }
 
+    fn node_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.node_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "node", id))
+    }
+
+    fn predicate_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.predicate_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "predicate", id))
+    }
+
+    fn value_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.value_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "value", id))
+    }
+
     fn predicate_dict_len(&self) -> usize {
         self.predicate_dictionary.len()
     }
@@ -233,6 +350,35 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for BaseLayer<M> {
         None
     }
 
+    fn triples_for_subjects(&self, subjects: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        let subject_count = self.s_p_adjacency_list.left_count() as u64;
+        let mut subjects: Vec<u64> = subjects
+            .iter()
+            .cloned()
+            .filter(|&subject| subject != 0 && subject <= subject_count)
+            .collect();
+        subjects.sort();
+        subjects.dedup();
+
+        // walk the adjacency lists directly, in subject order
+        let s_p_adjacency_list = self.s_p_adjacency_list.clone();
+        let sp_o_adjacency_list = self.sp_o_adjacency_list.clone();
+        Box::new(subjects.into_iter().flat_map(move |subject| {
+            let predicates = s_p_adjacency_list.get(subject);
+            let sp_offset = s_p_adjacency_list.offset_for(subject);
+            let sp_o_adjacency_list = sp_o_adjacency_list.clone();
+            (0..predicates.len()).flat_map(move |pos| {
+                let predicate = predicates.entry(pos);
+                let objects = sp_o_adjacency_list.get(sp_offset + pos as u64 + 1);
+                (0..objects.len())
+                    .map(move |i| objects.entry(i))
+                    // a 0 object marks a stub entry without triples
+                    .filter(|&object| object != 0)
+                    .map(move |object| IdTriple::new(subject, predicate, object))
+            })
+        }))
+    }
+
     fn object_additions(&self) -> Box<dyn Iterator<Item = Box<dyn LayerObjectLookup>>> {
         // todo: there might be a more efficient method than doing
         // this lookup over and over, due to sequentiality of the
@@ -423,6 +569,14 @@ impl<M: 'static + AsRef<[u8]> + Clone> LayerSubjectPredicateLookup
         // todo: use monotoniclogarray here to find object quicker
         self.objects.iter().find(|&o| o == object).is_some()
     }
+
+    fn object_count(&self) -> usize {
+        // a 0 object marks a stub entry without triples
+        match self.objects.len() {
+            1 if self.objects.entry(0) == 0 => 0,
+            len => len,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -1037,7 +1191,7 @@ pub mod tests {
     use crate::storage::memory::*;
 
     pub fn base_layer_files() -> BaseLayerFiles<MemoryBackedStore> {
-        let files: Vec<_> = (0..21).map(|_| MemoryBackedStore::new()).collect();
+        let files: Vec<_> = (0..23).map(|_| MemoryBackedStore::new()).collect();
         BaseLayerFiles {
             node_dictionary_files: DictionaryFiles {
                 blocks_file: files[0].clone(),
@@ -1080,6 +1234,8 @@ pub mod tests {
                 blocks_file: files[19].clone(),
                 sblocks_file: files[20].clone(),
             },
+            bloom_filter_file: files[21].clone(),
+            collation_file: files[22].clone(),
         }
     }
 
@@ -1413,4 +1569,97 @@ pub mod tests {
             triples
         );
     }
+
+    #[test]
+    fn corrupt_dictionary_entry_is_reported() {
+        use crate::layer::{LayerBuilder, SimpleLayerBuilder};
+
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+
+        let blocks = files
+            .node_dictionary_files
+            .blocks_file
+            .map()
+            .wait()
+            .unwrap();
+        let pos = blocks.as_ref().iter().position(|&b| b == b'p').unwrap();
+        std::io::Write::write_all(
+            &mut files.node_dictionary_files.blocks_file.open_write_from(pos),
+            &[0xff],
+        )
+        .unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            Some("cow".to_string()),
+            layer.id_subject_checked(1).unwrap()
+        );
+        assert_eq!(
+            Some(ObjectType::Value("oink".to_string())),
+            layer.id_object_checked(3).unwrap()
+        );
+        assert_eq!(
+            Some("says".to_string()),
+            layer.id_predicate_checked(2).unwrap()
+        );
+
+        let error = layer.id_subject_checked(2).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("node dictionary entry 1"));
+        assert!(error.to_string().contains("0000000100000002"));
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            layer.id_object_checked(2).unwrap_err().kind()
+        );
+
+        // exports and diffs report the corrupt entry instead of panicking
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            crate::layer::export_ntriples(&layer, &mut Vec::new())
+                .unwrap_err()
+                .kind()
+        );
+        let empty = empty_base_layer();
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            crate::layer::LayerDiff::between(&empty, &layer)
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn corrupt_bloom_filter_falls_back_to_lookup() {
+        use crate::layer::{LayerBuilder, SimpleLayerBuilder};
+
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.set_bloom_filter(0.01);
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.commit().wait().unwrap();
+
+        // a bit count of zero makes the filter unparseable
+        let size = files.bloom_filter_file.size().unwrap();
+        assert!(size > 16);
+        std::io::Write::write_all(
+            &mut files.bloom_filter_file.open_write_from(size - 16),
+            &[0; 8],
+        )
+        .unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        assert!(layer.bloom_filter.is_none());
+        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "pig")));
+        assert!(!layer.string_triple_exists(&StringTriple::new_node("pig", "likes", "cow")));
+    }
 }
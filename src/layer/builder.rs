@@ -8,22 +8,69 @@
 //! The layer builder implemented here will instead take triples in
 //! any format (numerical, string, or a mixture), store them in
 //! memory, then does the required sorting and id conversion on
-//! commit.
+//! commit. Base layers that are too large to build in memory can
+//! spill their triples to disk instead, see
+//! `LayerBuilder::set_spill_threshold`.
 use super::base::*;
 use super::child::*;
 use super::layer::*;
+use super::squash::{build_sorted_base_layer, build_spilled_base_layer, SpilledTriples};
 use crate::storage::*;
+use crate::structure::{
+    check_false_positive_rate, is_bytewise, BloomFilterBuilder, BytewiseCollation, Collation,
+};
+use futures::future;
 use futures::prelude::*;
 use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// A layer builder trait with no generic typing.
 ///
 /// Lack of generic types allows layer builders with different storage
 /// backends to be handled by trait objects of this type.
+///
+/// # Id stability
+///
+/// A child layer only adds dictionary entries for strings that do
+/// not occur in any of its ancestors. A subject, predicate or object
+/// string that is already known to an ancestor keeps the id it has in
+/// that ancestor, so an id obtained from the parent refers to the
+/// same string in the child and in every layer built on top of it.
+///
+/// Strings that are new to a layer get ids following all ids of the
+/// parent. These ids are only assigned on commit, and may differ
+/// between two children of the same parent. Ids are never stable
+/// across layers that do not share ancestry, such as two independently
+/// built base layers.
+///
+/// The `subject_id`, `predicate_id`, `object_node_id` and
+/// `object_value_id` methods of the builder return the id a string
+/// will have in the committed layer if that id is already known,
+/// which is the case exactly when the string occurs in the parent.
 pub trait LayerBuilder: Send + Sync {
     /// Returns the name of the layer being built
     fn name(&self) -> [u32; 5];
+    /// Returns the parent of the layer being built, or None if this is a base layer
+    fn parent(&self) -> Option<&dyn Layer>;
+    /// The id the given subject has in the parent, or None if it is not known there
+    fn subject_id(&self, subject: &str) -> Option<u64> {
+        self.parent().and_then(|p| p.subject_id(subject))
+    }
+    /// The id the given predicate has in the parent, or None if it is not known there
+    fn predicate_id(&self, predicate: &str) -> Option<u64> {
+        self.parent().and_then(|p| p.predicate_id(predicate))
+    }
+    /// The id the given node object has in the parent, or None if it is not known there
+    fn object_node_id(&self, object: &str) -> Option<u64> {
+        self.parent().and_then(|p| p.object_node_id(object))
+    }
+    /// The id the given value object has in the parent, or None if it is not known there
+    fn object_value_id(&self, object: &str) -> Option<u64> {
+        self.parent().and_then(|p| p.object_value_id(object))
+    }
     /// Add a string triple
     fn add_string_triple(&mut self, triple: &StringTriple);
     /// Add an id triple
@@ -32,10 +79,200 @@ pub trait LayerBuilder: Send + Sync {
     fn remove_string_triple(&mut self, triple: &StringTriple) -> bool;
     /// Remove an id triple
     fn remove_id_triple(&mut self, triple: IdTriple) -> bool;
+    /// Remove every triple in the parent that has the given subject
+    ///
+    /// Returns the number of triples that were removed.
+    fn remove_subject(&mut self, subject: &str) -> usize {
+        let triples: BTreeSet<IdTriple> = self
+            .parent()
+            .and_then(|p| p.subject_id(subject).and_then(|id| p.lookup_subject(id)))
+            .map(|lookup| lookup.triples().collect())
+            .unwrap_or_default();
+
+        triples
+            .into_iter()
+            .filter(|triple| self.remove_id_triple(*triple))
+            .count()
+    }
+    /// Remove every triple in the parent that has the given node as either subject or object
+    ///
+    /// Returns the number of triples that were removed.
+    fn remove_subject_fully(&mut self, subject: &str) -> usize {
+        let mut triples: BTreeSet<IdTriple> = BTreeSet::new();
+        if let Some(parent) = self.parent() {
+            if let Some(lookup) = parent
+                .subject_id(subject)
+                .and_then(|id| parent.lookup_subject(id))
+            {
+                triples.extend(lookup.triples());
+            }
+            if let Some(lookup) = parent
+                .object_node_id(subject)
+                .and_then(|id| parent.lookup_object(id))
+            {
+                triples.extend(lookup.triples());
+            }
+        }
+
+        triples
+            .into_iter()
+            .filter(|triple| self.remove_id_triple(*triple))
+            .count()
+    }
+    /// Build a bloom filter over the added triples on commit, with the given false positive rate
+    ///
+    /// If the rate is not between 0 and 1, the commit fails with an
+    /// `InvalidInput` error.
+    fn set_bloom_filter(&mut self, false_positive_rate: f64);
+    /// Drop added string triples that already exist in the parent, rather than storing them again
+    ///
+    /// This is off by default. Turning it on makes adding a triple to
+    /// a child layer a bit slower, but keeps redundant additions out
+    /// of the layer. Adding back a triple that was removed earlier in
+    /// the same builder cancels the removal.
+    fn set_skip_existing(&mut self, skip_existing: bool);
+    /// Sort the dictionaries of the new layer with the given collation
+    ///
+    /// The collation is recorded in the layer, and has to be
+    /// registered with `register_collation` in every process that
+    /// loads the layer. It is fixed once the layer is built, and
+    /// only applies to this layer. Child layers built through a
+    /// `SimpleLayerBuilder` take over the collation of their parent.
+    fn set_collation(&mut self, collation: Arc<dyn Collation>);
+    /// Spill added triples to temporary files in the given directory once `threshold` of them are held in memory
+    ///
+    /// By default, all added triples are kept in memory until commit.
+    /// With a threshold, a base layer builder writes its triples out
+    /// as a sorted run whenever the threshold is reached, and merges
+    /// the runs into the sorted id triples of the layer on commit.
+    /// This bounds the memory used for building very large base
+    /// layers, at the cost of a slower commit. The temporary files
+    /// are removed once the builder is committed or dropped.
+    ///
+    /// Only base layers with the default bytewise collation are
+    /// spilled. Child layer builders keep all triples in memory.
+    fn set_spill_threshold(&mut self, threshold: usize, temp_dir: PathBuf);
     /// Commit the layer to storage
     fn commit(self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send>;
     /// Commit a boxed layer to storage
     fn commit_boxed(self: Box<Self>) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send>;
+    /// Commit a boxed layer to storage, reporting progress to the given callback
+    fn commit_boxed_with_progress(
+        self: Box<Self>,
+        progress: CommitProgressCallback,
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send>;
+}
+
+/// A phase of committing a layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitPhase {
+    /// Writing the dictionaries of new nodes, predicates and values
+    WritingDictionaries,
+    /// Converting the added triples to ids and sorting them
+    Sorting,
+    /// Writing the adjacency lists of added and removed triples
+    WritingTriples,
+    /// Building the indexes over the triples
+    Finalizing,
+    /// The layer has been written
+    Done,
+}
+
+/// The progress of a layer commit, as reported to a progress callback
+///
+/// Progress is reported at the start of every phase, and then again
+/// every time another percent of the items of the phase, like
+/// strings or triples, has been processed. Phases that aren't
+/// counted, like finalizing, have a total of 0. The percentage is a
+/// rough estimate based on how long each phase usually takes, and
+/// never goes down during a commit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitProgress {
+    pub phase: CommitPhase,
+    pub percent: u8,
+    /// The number of items of this phase that have been processed
+    pub done: u64,
+    /// The number of items this phase processes
+    pub total: u64,
+}
+
+/// A callback that is invoked as a layer commit makes progress
+pub type CommitProgressCallback = Arc<dyn Fn(CommitProgress) + Send + Sync>;
+
+/// Count-based progress through one phase of a commit
+///
+/// The phase covers the given range of percentages. Clones share
+/// their count, so that several iterators can make progress through
+/// the same phase.
+#[derive(Clone)]
+pub(crate) struct PhaseProgress {
+    callback: CommitProgressCallback,
+    phase: CommitPhase,
+    percentages: (u8, u8),
+    total: u64,
+    step: u64,
+    done: Arc<AtomicU64>,
+}
+
+impl PhaseProgress {
+    /// Start a phase of `total` items, reporting that none of them are done yet.
+    pub(crate) fn start(
+        callback: &CommitProgressCallback,
+        phase: CommitPhase,
+        percentages: (u8, u8),
+        total: u64,
+    ) -> PhaseProgress {
+        let progress = PhaseProgress {
+            callback: callback.clone(),
+            phase,
+            percentages,
+            total,
+            step: std::cmp::max(total / 100, 1),
+            done: Arc::new(AtomicU64::new(0)),
+        };
+        progress.report(0);
+
+        progress
+    }
+
+    /// Report that the commit is done.
+    pub(crate) fn finish(callback: &CommitProgressCallback) {
+        callback(CommitProgress {
+            phase: CommitPhase::Done,
+            percent: 100,
+            done: 0,
+            total: 0,
+        });
+    }
+
+    fn report(&self, done: u64) {
+        let (start, end) = self.percentages;
+        let percent = ((end - start) as u64 * done.min(self.total))
+            .checked_div(self.total)
+            .map_or(start, |p| start + p as u8);
+
+        (self.callback)(CommitProgress {
+            phase: self.phase,
+            percent,
+            done,
+            total: self.total,
+        });
+    }
+
+    /// Mark another `count` items as done, reporting progress if that completes another step.
+    pub(crate) fn advance(&self, count: u64) {
+        let before = self.done.fetch_add(count, Ordering::Relaxed);
+        let done = before + count;
+        if before / self.step != done / self.step || (done >= self.total && before < self.total) {
+            self.report(done);
+        }
+    }
+
+    /// Mark every item of the given iterator as done once it has been produced.
+    pub(crate) fn track<I: Iterator>(&self, iter: I) -> impl Iterator<Item = I::Item> {
+        let progress = self.clone();
+        iter.inspect(move |_| progress.advance(1))
+    }
 }
 
 /// A layer builder
@@ -49,6 +286,12 @@ pub struct SimpleLayerBuilder<F: 'static + FileLoad + FileStore + Clone> {
     files: LayerFiles<F>,
     additions: BTreeSet<PartiallyResolvedTriple>,
     removals: BTreeSet<IdTriple>, // always resolved!
+    bloom_filter_rate: Option<f64>,
+    skip_existing: bool,
+    collation: Arc<dyn Collation>,
+    spill_threshold: Option<(usize, PathBuf)>,
+    spilled: Option<SpilledTriples>,
+    spill_error: Option<Arc<io::Error>>,
 }
 
 impl<F: 'static + FileLoad + FileStore + Clone> SimpleLayerBuilder<F> {
@@ -60,17 +303,59 @@ impl<F: 'static + FileLoad + FileStore + Clone> SimpleLayerBuilder<F> {
             files: LayerFiles::Base(files),
             additions: BTreeSet::new(),
             removals: BTreeSet::new(),
+            bloom_filter_rate: None,
+            skip_existing: false,
+            collation: Arc::new(BytewiseCollation),
+            spill_threshold: None,
+            spilled: None,
+            spill_error: None,
         }
     }
 
     /// Construct a layer builder for a child layer
+    ///
+    /// The dictionaries of the child layer are sorted with the
+    /// collation of the parent, unless `set_collation` is used.
     pub fn from_parent(name: [u32; 5], parent: Arc<dyn Layer>, files: ChildLayerFiles<F>) -> Self {
         Self {
             name,
+            collation: parent.collation(),
             parent: Some(parent),
             files: LayerFiles::Child(files),
             additions: BTreeSet::new(),
             removals: BTreeSet::new(),
+            bloom_filter_rate: None,
+            skip_existing: false,
+            spill_threshold: None,
+            spilled: None,
+            spill_error: None,
+        }
+    }
+
+    /// Spill the added triples to disk if there are too many of them to keep in memory.
+    ///
+    /// An error is kept until commit, as adding a triple cannot fail.
+    fn spill_if_needed(&mut self) {
+        let dir = match &self.spill_threshold {
+            Some((threshold, dir))
+                if self.additions.len() >= *threshold
+                    && self.parent.is_none()
+                    && self.spill_error.is_none()
+                    && is_bytewise(&*self.collation) =>
+            {
+                dir
+            }
+            _ => return,
+        };
+
+        let spilled = self
+            .spilled
+            .get_or_insert_with(|| SpilledTriples::new(dir.clone()));
+        // all triples of a base layer are unresolved, and they come
+        // out of the set in the same order as string triples do
+        let additions = std::mem::take(&mut self.additions);
+        if let Err(e) = spilled.spill(additions.into_iter().map(unresolved_to_string_triple)) {
+            self.spill_error = Some(Arc::new(e));
         }
     }
 
@@ -102,10 +387,19 @@ impl<F: 'static + FileLoad + FileStore + Clone> SimpleLayerBuilder<F> {
             }
         }
 
+        let collate = |strings: BTreeSet<String>| -> Vec<String> {
+            let mut strings: Vec<_> = strings.into_iter().collect();
+            if !is_bytewise(&*self.collation) {
+                strings.sort_by(|a, b| self.collation.compare(a, b));
+            }
+
+            strings
+        };
+
         (
-            node_builder.into_iter().collect(),
-            predicate_builder.into_iter().collect(),
-            value_builder.into_iter().collect(),
+            collate(node_builder),
+            collate(predicate_builder),
+            collate(value_builder),
         )
     }
 }
@@ -115,16 +409,33 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
         self.name
     }
 
+    fn parent(&self) -> Option<&dyn Layer> {
+        self.parent.as_deref()
+    }
+
     fn add_string_triple(&mut self, triple: &StringTriple) {
-        if self.parent.is_some() {
-            self.additions.insert(
-                self.parent
-                    .as_ref()
-                    .unwrap()
-                    .string_triple_to_partially_resolved(triple),
-            );
+        if let Some(parent) = self.parent.as_ref() {
+            let triple = parent.string_triple_to_partially_resolved(triple);
+            if self.skip_existing
+                && triple.subject.is_resolved()
+                && triple.predicate.is_resolved()
+                && triple.object.is_resolved()
+            {
+                let id_triple = IdTriple::new(
+                    triple.subject.clone().unwrap_resolved(),
+                    triple.predicate.clone().unwrap_resolved(),
+                    triple.object.clone().unwrap_resolved(),
+                );
+                if parent.id_triple_exists(id_triple) {
+                    self.removals.remove(&id_triple);
+                    return;
+                }
+            }
+
+            self.additions.insert(triple);
         } else {
             self.additions.insert(triple.to_unresolved());
+            self.spill_if_needed();
         }
     }
 
@@ -172,11 +483,86 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
         }
     }
 
+    fn set_bloom_filter(&mut self, false_positive_rate: f64) {
+        self.bloom_filter_rate = Some(false_positive_rate);
+    }
+
+    fn set_skip_existing(&mut self, skip_existing: bool) {
+        self.skip_existing = skip_existing;
+    }
+
+    fn set_collation(&mut self, collation: Arc<dyn Collation>) {
+        self.collation = collation;
+    }
+
+    fn set_spill_threshold(&mut self, threshold: usize, temp_dir: PathBuf) {
+        self.spill_threshold = Some((threshold, temp_dir));
+    }
+
     fn commit(self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        self.commit_with_progress(Arc::new(|_| {}))
+    }
+
+    fn commit_boxed(self: Box<Self>) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let builder = *self;
+        builder.commit()
+    }
+
+    fn commit_boxed_with_progress(
+        self: Box<Self>,
+        progress: CommitProgressCallback,
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let builder = *self;
+        builder.commit_with_progress(progress)
+    }
+}
+
+impl<F: 'static + FileLoad + FileStore + Clone> SimpleLayerBuilder<F> {
+    /// Commit the layer to storage, reporting progress to the given callback
+    pub fn commit_with_progress(
+        mut self,
+        progress: CommitProgressCallback,
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        if let Some(e) = self.spill_error {
+            return Box::new(future::err(io::Error::new(e.kind(), e.to_string())));
+        }
+        if let Some(spilled) = self.spilled.take() {
+            if !is_bytewise(&*self.collation) {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "triples that were spilled to disk can only be committed bytewise",
+                )));
+            }
+
+            let remaining = self
+                .additions
+                .into_iter()
+                .map(unresolved_to_string_triple)
+                .collect();
+            return Box::new(
+                build_spilled_base_layer(
+                    self.files.into_base(),
+                    spilled,
+                    remaining,
+                    self.bloom_filter_rate,
+                    progress.clone(),
+                )
+                .map(move |_| PhaseProgress::finish(&progress)),
+            );
+        }
+
         let (unresolved_nodes, unresolved_predicates, unresolved_values) =
             self.unresolved_strings();
+        let dictionaries = PhaseProgress::start(
+            &progress,
+            CommitPhase::WritingDictionaries,
+            (0, 30),
+            (unresolved_nodes.len() + unresolved_predicates.len() + unresolved_values.len()) as u64,
+        );
         let additions = self.additions;
         let removals = self.removals;
+        let bloom_filter_rate = self.bloom_filter_rate;
+        let collation = self.collation;
         // store a copy. The original will be used to build the dictionaries.
         // The copy will be used later on to map unresolved strings to their id's before inserting
         let unresolved_nodes2 = unresolved_nodes.clone();
@@ -186,19 +572,29 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
             Some(parent) => {
                 let files = self.files.into_child();
                 let builder = ChildLayerFileBuilder::from_files(parent.clone(), &files);
+                let bloom_filter_file = files.bloom_filter_file;
+                let write_collation = write_collation(&files.collation_file, &*collation);
 
                 Box::new(
                     builder
-                        .add_nodes(unresolved_nodes)
-                        .and_then(|(nodes, b)| {
-                            b.add_predicates(unresolved_predicates)
-                                .and_then(|(predicates, b)| {
-                                    b.add_values(unresolved_values).and_then(|(values, b)| {
-                                        b.into_phase2().map(move |b| (b, nodes, predicates, values))
-                                    })
+                        .add_nodes(dictionaries.track(unresolved_nodes.into_iter()))
+                        .and_then(move |(nodes, b)| {
+                            b.add_predicates(dictionaries.track(unresolved_predicates.into_iter()))
+                                .and_then(move |(predicates, b)| {
+                                    b.add_values(dictionaries.track(unresolved_values.into_iter()))
+                                        .and_then(|(values, b)| {
+                                            b.into_phase2()
+                                                .map(move |b| (b, nodes, predicates, values))
+                                        })
                                 })
                         })
                         .and_then(move |(builder, node_ids, predicate_ids, value_ids)| {
+                            let sorting = PhaseProgress::start(
+                                &progress,
+                                CommitPhase::Sorting,
+                                (30, 40),
+                                additions.len() as u64,
+                            );
                             let counts = parent.all_counts();
                             let parent_node_offset =
                                 counts.node_count as u64 + counts.value_count as u64;
@@ -219,8 +615,8 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
                                     .insert(value, id + parent_node_offset + node_map.len() as u64);
                             }
 
-                            let mut add_triples: Vec<_> = additions
-                                .into_iter()
+                            let mut add_triples: Vec<_> = sorting
+                                .track(additions.into_iter())
                                 .map(|t| {
                                     t.resolve_with(&node_map, &predicate_map, &value_map)
                                         .expect("triple should have been resolvable")
@@ -228,31 +624,68 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
                                 .collect();
                             add_triples.sort();
                             let remove_triples: Vec<_> = removals.into_iter().collect(); // comes out of a btreeset, so sorted
+                            let write_bloom_filter = write_bloom_filter(
+                                &bloom_filter_file,
+                                &add_triples,
+                                bloom_filter_rate,
+                            );
 
+                            let triples = PhaseProgress::start(
+                                &progress,
+                                CommitPhase::WritingTriples,
+                                (40, 70),
+                                (add_triples.len() + remove_triples.len()) as u64,
+                            );
                             builder
-                                .add_id_triples(add_triples)
-                                .and_then(move |b| b.remove_id_triples(remove_triples))
-                                .and_then(|b| b.finalize())
+                                .add_id_triples(triples.track(add_triples.into_iter()))
+                                .and_then(move |b| {
+                                    b.remove_id_triples(triples.track(remove_triples.into_iter()))
+                                })
+                                .and_then({
+                                    let progress = progress.clone();
+                                    move |b| {
+                                        PhaseProgress::start(
+                                            &progress,
+                                            CommitPhase::Finalizing,
+                                            (70, 100),
+                                            0,
+                                        );
+                                        b.finalize()
+                                    }
+                                })
+                                .and_then(|_| write_bloom_filter)
+                                .and_then(|_| write_collation)
+                                .map(move |_| PhaseProgress::finish(&progress))
                         }),
                 )
             }
             None => {
                 let files = self.files.into_base();
                 let builder = BaseLayerFileBuilder::from_files(&files);
+                let bloom_filter_file = files.bloom_filter_file;
+                let write_collation = write_collation(&files.collation_file, &*collation);
 
                 // TODO - this is exactly the same as above. We should generalize builder and run it once on the generalized instead.
                 Box::new(
                     builder
-                        .add_nodes(unresolved_nodes)
-                        .and_then(|(nodes, b)| {
-                            b.add_predicates(unresolved_predicates)
-                                .and_then(|(predicates, b)| {
-                                    b.add_values(unresolved_values).and_then(|(values, b)| {
-                                        b.into_phase2().map(move |b| (b, nodes, predicates, values))
-                                    })
+                        .add_nodes(dictionaries.track(unresolved_nodes.into_iter()))
+                        .and_then(move |(nodes, b)| {
+                            b.add_predicates(dictionaries.track(unresolved_predicates.into_iter()))
+                                .and_then(move |(predicates, b)| {
+                                    b.add_values(dictionaries.track(unresolved_values.into_iter()))
+                                        .and_then(|(values, b)| {
+                                            b.into_phase2()
+                                                .map(move |b| (b, nodes, predicates, values))
+                                        })
                                 })
                         })
                         .and_then(move |(builder, node_ids, predicate_ids, value_ids)| {
+                            let sorting = PhaseProgress::start(
+                                &progress,
+                                CommitPhase::Sorting,
+                                (30, 40),
+                                additions.len() as u64,
+                            );
                             let mut node_map = HashMap::new();
                             for (node, id) in unresolved_nodes2.into_iter().zip(node_ids) {
                                 node_map.insert(node, id);
@@ -268,35 +701,127 @@ impl<F: 'static + FileLoad + FileStore + Clone> LayerBuilder for SimpleLayerBuil
                                 value_map.insert(value, id + node_map.len() as u64);
                             }
 
-                            let mut triples: Vec<_> = additions
-                                .into_iter()
+                            let mut triples: Vec<_> = sorting
+                                .track(additions.into_iter())
                                 .map(|t| {
                                     t.resolve_with(&node_map, &predicate_map, &value_map)
                                         .expect("triple should have been resolvable")
                                 })
                                 .collect();
                             triples.sort();
+                            let write_bloom_filter =
+                                write_bloom_filter(&bloom_filter_file, &triples, bloom_filter_rate);
 
-                            builder.add_id_triples(triples).and_then(|b| b.finalize())
+                            let writing = PhaseProgress::start(
+                                &progress,
+                                CommitPhase::WritingTriples,
+                                (40, 70),
+                                triples.len() as u64,
+                            );
+                            builder
+                                .add_id_triples(writing.track(triples.into_iter()))
+                                .and_then({
+                                    let progress = progress.clone();
+                                    move |b| {
+                                        PhaseProgress::start(
+                                            &progress,
+                                            CommitPhase::Finalizing,
+                                            (70, 100),
+                                            0,
+                                        );
+                                        b.finalize()
+                                    }
+                                })
+                                .and_then(|_| write_bloom_filter)
+                                .and_then(|_| write_collation)
+                                .map(move |_| PhaseProgress::finish(&progress))
                         }),
                 )
             }
         }
     }
+}
 
-    fn commit_boxed(self: Box<Self>) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
-        let builder = *self;
-        builder.commit()
+fn unresolved_to_string_triple(triple: PartiallyResolvedTriple) -> StringTriple {
+    StringTriple {
+        subject: triple.subject.unwrap_unresolved(),
+        predicate: triple.predicate.unwrap_unresolved(),
+        object: triple.object.unwrap_unresolved(),
+    }
+}
+
+/// Write a bloom filter over the given triples to the given file, if a false positive rate was set.
+///
+/// Returns an `InvalidInput` error if the rate is not between 0 and 1.
+fn write_bloom_filter<F: FileStore>(
+    file: &F,
+    triples: &[IdTriple],
+    false_positive_rate: Option<f64>,
+) -> impl Future<Item = (), Error = std::io::Error> {
+    match false_positive_rate {
+        None => future::Either::A(future::ok(())),
+        Some(rate) => {
+            if let Err(e) = check_false_positive_rate(rate) {
+                return future::Either::A(future::err(e));
+            }
+
+            let mut builder = BloomFilterBuilder::new(triples.len(), rate);
+            for triple in triples {
+                builder.insert(triple.subject, triple.predicate, triple.object);
+            }
+
+            future::Either::B(builder.finalize(file.open_write()).map(|_| ()))
+        }
     }
 }
 
+/// Write the name of the given collation to the given file, unless it is the default bytewise collation.
+pub(crate) fn write_collation<F: FileStore>(
+    file: &F,
+    collation: &dyn Collation,
+) -> impl Future<Item = (), Error = std::io::Error> {
+    if is_bytewise(collation) {
+        future::Either::A(future::ok(()))
+    } else {
+        future::Either::B(
+            tokio::io::write_all(file.open_write(), collation.name().as_bytes().to_vec())
+                .and_then(|(w, _)| tokio::io::flush(w))
+                .map(|_| ()),
+        )
+    }
+}
+
+/// Build a base layer out of string triples that are already sorted.
+///
+/// Unlike `SimpleLayerBuilder`, this does not sort the triples before
+/// writing them out. The triples are expected to be in ascending
+/// order, which for a base layer is the same as the order of their
+/// ids. Consecutive duplicate triples are skipped. The triples are
+/// never all held in memory: they are spilled to a temporary file in
+/// `temp_dir`, out of which the dictionaries and the triples are
+/// written.
+///
+/// If the triples are not sorted, this results in an `InvalidInput`
+/// error and no layer is written.
+pub fn build_base_layer_from_sorted_triples<F, I>(
+    files: BaseLayerFiles<F>,
+    triples: I,
+    temp_dir: PathBuf,
+) -> impl Future<Item = (), Error = std::io::Error> + Send
+where
+    F: 'static + FileLoad + FileStore + Clone,
+    I: 'static + Iterator<Item = io::Result<StringTriple>> + Send,
+{
+    build_sorted_base_layer(files, triples, temp_dir)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::storage::memory::*;
 
     fn new_base_files() -> BaseLayerFiles<MemoryBackedStore> {
-        let files: Vec<_> = (0..21).map(|_| MemoryBackedStore::new()).collect();
+        let files: Vec<_> = (0..23).map(|_| MemoryBackedStore::new()).collect();
         BaseLayerFiles {
             node_dictionary_files: DictionaryFiles {
                 blocks_file: files[0].clone(),
@@ -339,11 +864,13 @@ mod tests {
                 blocks_file: files[19].clone(),
                 sblocks_file: files[20].clone(),
             },
+            bloom_filter_file: files[21].clone(),
+            collation_file: files[22].clone(),
         }
     }
 
     fn new_child_files() -> ChildLayerFiles<MemoryBackedStore> {
-        let files: Vec<_> = (0..40).map(|_| MemoryBackedStore::new()).collect();
+        let files: Vec<_> = (0..42).map(|_| MemoryBackedStore::new()).collect();
         ChildLayerFiles {
             node_dictionary_files: DictionaryFiles {
                 blocks_file: files[0].clone(),
@@ -421,6 +948,8 @@ mod tests {
                 blocks_file: files[38].clone(),
                 sblocks_file: files[39].clone(),
             },
+            bloom_filter_file: files[40].clone(),
+            collation_file: files[41].clone(),
         }
     }
 
@@ -448,6 +977,65 @@ mod tests {
         assert!(layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
     }
 
+    #[test]
+    fn invalid_bloom_filter_rate_fails_commit() {
+        let files = new_base_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files);
+        builder.set_bloom_filter(1.5);
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+
+        let error = builder.commit().wait().err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn spilled_base_layer_construction() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = new_base_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.set_spill_threshold(3, dir.path().to_owned());
+        builder.set_bloom_filter(0.01);
+
+        let mut expected = Vec::new();
+        for i in (0..20).rev() {
+            let triple = if i % 2 == 0 {
+                StringTriple::new_value(&format!("node{}", i % 7), "value", &i.to_string())
+            } else {
+                StringTriple::new_node(&format!("node{}", i), "likes", &format!("node{}", i / 3))
+            };
+            // duplicates end up in different runs
+            builder.add_string_triple(&triple);
+            builder.add_string_triple(&StringTriple::new_value("node3", "value", "3"));
+            expected.push(triple);
+        }
+        expected.push(StringTriple::new_value("node3", "value", "3"));
+        expected.sort();
+        expected.dedup();
+
+        // the clone keeps its own view of the spilled runs
+        let clone = builder.clone();
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        std::mem::drop(clone);
+        expected.push(StringTriple::new_value("cow", "says", "moo"));
+        expected.sort();
+
+        builder.commit().wait().unwrap();
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let triples: Vec<_> = layer
+            .triples()
+            .map(|t| layer.id_triple_to_string(&t).unwrap())
+            .collect();
+        assert_eq!(expected, triples);
+        for triple in layer.triples() {
+            assert!(layer.may_contain_triple(triple.subject, triple.predicate, triple.object));
+        }
+        assert_eq!(Some(1), layer.subject_id("cow"));
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
     #[test]
     fn simple_child_layer_construction() {
         let base_layer = example_base_layer();
@@ -477,6 +1065,82 @@ mod tests {
         );
     }
 
+    #[test]
+    fn child_builder_skips_existing_triples() {
+        let base_layer = example_base_layer();
+        let files = new_child_files();
+        let name = [0, 0, 0, 0, 0];
+        let mut builder = SimpleLayerBuilder::from_parent(name, base_layer.clone(), files.clone());
+        builder.set_skip_existing(true);
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("horse", "says", "neigh"));
+        builder.remove_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+
+        builder.commit().wait().unwrap();
+        let child_layer = ChildLayer::load_from_files(name, base_layer, &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(1, child_layer.triple_addition_counts().triples);
+        assert_eq!(0, child_layer.triple_removal_counts().triples);
+        assert!(child_layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(child_layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+        assert!(
+            child_layer.string_triple_exists(&StringTriple::new_value("horse", "says", "neigh"))
+        );
+    }
+
+    #[test]
+    fn child_builder_reuses_parent_ids() {
+        let base_layer = example_base_layer();
+        let base_builder = SimpleLayerBuilder::new([1, 2, 3, 4, 6], new_base_files());
+        assert_eq!(None, base_builder.subject_id("cow"));
+
+        let files = new_child_files();
+        let name = [0, 0, 0, 0, 0];
+        let mut builder = SimpleLayerBuilder::from_parent(name, base_layer.clone(), files.clone());
+
+        let cow = builder.subject_id("cow").unwrap();
+        let says = builder.predicate_id("says").unwrap();
+        let moo = builder.object_value_id("moo").unwrap();
+        assert_eq!(base_layer.subject_id("cow"), Some(cow));
+        assert_eq!(base_layer.predicate_id("says"), Some(says));
+        assert_eq!(base_layer.object_value_id("moo"), Some(moo));
+        assert_eq!(None, builder.subject_id("horse"));
+        assert_eq!(None, builder.predicate_id("likes"));
+        assert_eq!(None, builder.object_node_id("sheep"));
+
+        builder.add_string_triple(&StringTriple::new_value("horse", "says", "neigh"));
+        builder.add_string_triple(&StringTriple::new_node("horse", "likes", "cow"));
+        builder.commit().wait().unwrap();
+        let child_layer = Arc::new(
+            ChildLayer::load_from_files(name, base_layer.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        assert_eq!(Some(cow), child_layer.subject_id("cow"));
+        assert_eq!(Some(cow), child_layer.object_node_id("cow"));
+        assert_eq!(Some(says), child_layer.predicate_id("says"));
+        assert_eq!(Some(moo), child_layer.object_value_id("moo"));
+        assert!(
+            child_layer.subject_id("horse").unwrap() > base_layer.node_and_value_count() as u64
+        );
+
+        let grandchild_builder = SimpleLayerBuilder::from_parent(
+            [0, 0, 0, 0, 1],
+            child_layer.clone(),
+            new_child_files(),
+        );
+        assert_eq!(Some(cow), grandchild_builder.subject_id("cow"));
+        assert_eq!(
+            child_layer.subject_id("horse"),
+            grandchild_builder.subject_id("horse")
+        );
+    }
+
     #[test]
     fn multi_level_layers() {
         let base_layer = example_base_layer();
@@ -531,4 +1195,24 @@ mod tests {
         assert!(!layer4.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
         assert!(!layer4.string_triple_exists(&StringTriple::new_node("horse", "likes", "cow")));
     }
+
+    #[test]
+    fn sorted_triples_out_of_order_errors() {
+        let files = new_base_files();
+        let triples = vec![
+            StringTriple::new_value("duck", "says", "quack"),
+            StringTriple::new_value("cow", "says", "moo"),
+        ];
+
+        let error = build_base_layer_from_sorted_triples(
+            files.clone(),
+            triples.into_iter().map(Ok),
+            std::env::temp_dir(),
+        )
+        .wait()
+        .err()
+        .unwrap();
+
+        assert_eq!(std::io::ErrorKind::InvalidInput, error.kind());
+    }
 }
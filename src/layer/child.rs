@@ -41,6 +41,11 @@ pub struct ChildLayer<M: 'static + AsRef<[u8]> + Clone + Send + Sync> {
 
     pos_predicate_wavelet_tree: WaveletTree<M>,
     neg_predicate_wavelet_tree: WaveletTree<M>,
+
+    bloom_filter: Option<BloomFilter<M>>,
+    collation: Arc<dyn Collation>,
+
+    statistics: LayerStatisticsCache,
 }
 
 impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> ChildLayer<M> {
@@ -49,11 +54,16 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> ChildLayer<M> {
         parent: Arc<dyn Layer>,
         files: &ChildLayerFiles<F>,
     ) -> impl Future<Item = Self, Error = std::io::Error> {
-        files
-            .map_all()
-            .map(move |maps| Self::load(name, parent, maps))
+        files.map_all().and_then(move |maps| {
+            let collation =
+                collation_from_stored_name(maps.collation_map.as_ref().map(|m| m.as_ref()))?;
+            Ok(Self::load(name, parent, maps).with_collation(collation))
+        })
     }
 
+    /// Load a child layer from its maps, searching its dictionaries in bytewise order.
+    ///
+    /// `load_from_files` uses the collation stored with the layer instead.
     pub fn load(name: [u32; 5], parent: Arc<dyn Layer>, maps: ChildLayerMaps<M>) -> ChildLayer<M> {
         let node_dictionary = PfcDict::parse(
             maps.node_dictionary_maps.blocks_map,
@@ -137,6 +147,13 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> ChildLayer<M> {
             neg_predicate_wavelet_tree_width,
         );
 
+        let bloom_filter = maps
+            .bloom_filter_map
+            .filter(|map| !map.as_ref().is_empty())
+            // a filter that can't be parsed is ignored, so lookups go
+            // straight to the adjacency lists.
+            .and_then(|map| BloomFilter::parse(map).ok());
+
         ChildLayer {
             name,
             parent: parent,
@@ -160,6 +177,46 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> ChildLayer<M> {
 
             pos_predicate_wavelet_tree,
             neg_predicate_wavelet_tree,
+
+            bloom_filter,
+            collation: Arc::new(BytewiseCollation),
+
+            statistics: LayerStatisticsCache::default(),
+        }
+    }
+
+    fn with_collation(mut self, collation: Arc<dyn Collation>) -> Self {
+        self.node_dictionary = self.node_dictionary.with_collation(collation.clone());
+        self.predicate_dictionary = self.predicate_dictionary.with_collation(collation.clone());
+        self.value_dictionary = self.value_dictionary.with_collation(collation.clone());
+        self.collation = collation;
+        self
+    }
+
+    /// Borrow the adjacency lists of the triples this layer adds.
+    ///
+    /// See `LayerAdjacencyLists` for how they are laid out. Only this
+    /// layer is covered, not its ancestors.
+    pub fn addition_adjacency_lists(&self) -> LayerAdjacencyLists<'_, M> {
+        LayerAdjacencyLists {
+            subjects: Some(&self.pos_subjects),
+            objects: Some(&self.pos_objects),
+            s_p: &self.pos_s_p_adjacency_list,
+            sp_o: &self.pos_sp_o_adjacency_list,
+            o_ps: &self.pos_o_ps_adjacency_list,
+        }
+    }
+
+    /// Borrow the adjacency lists of the triples this layer removes.
+    ///
+    /// See `LayerAdjacencyLists` for how they are laid out.
+    pub fn removal_adjacency_lists(&self) -> LayerAdjacencyLists<'_, M> {
+        LayerAdjacencyLists {
+            subjects: Some(&self.neg_subjects),
+            objects: Some(&self.neg_objects),
+            s_p: &self.neg_s_p_adjacency_list,
+            sp_o: &self.neg_sp_o_adjacency_list,
+            o_ps: &self.neg_o_ps_adjacency_list,
         }
     }
 
@@ -209,6 +266,67 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
         Some(&*self.parent)
     }
 
+    fn layer_type(&self) -> LayerType {
+        LayerType::Child
+    }
+
+    fn may_contain_triple(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        match &self.bloom_filter {
+            None => true,
+            Some(filter) => {
+                filter.may_contain(subject, predicate, object)
+                    || self.parent.may_contain_triple(subject, predicate, object)
+            }
+        }
+    }
+
+    fn collation(&self) -> Arc<dyn Collation> {
+        self.collation.clone()
+    }
+
+    fn statistics(&self) -> Arc<LayerStatistics> {
+        self.statistics.get_or_compute(|| self.compute_statistics())
+    }
+
+    fn scan_hint(&self, hint: ScanHint) {
+        hint.advise_adjacency_list(&self.pos_s_p_adjacency_list);
+        hint.advise_adjacency_list(&self.pos_sp_o_adjacency_list);
+        hint.advise_adjacency_list(&self.pos_o_ps_adjacency_list);
+        hint.advise_adjacency_list(&self.neg_s_p_adjacency_list);
+        hint.advise_adjacency_list(&self.neg_sp_o_adjacency_list);
+        hint.advise_adjacency_list(&self.neg_o_ps_adjacency_list);
+
+        self.parent.scan_hint(hint);
+    }
+
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        LayerTripleCounts {
+            subjects: self.pos_subjects.len(),
+            subject_predicate_pairs: self.pos_s_p_adjacency_list.right_count(),
+            triples: self.pos_sp_o_adjacency_list.right_count(),
+        }
+    }
+
+    fn triple_removal_counts(&self) -> LayerTripleCounts {
+        LayerTripleCounts {
+            subjects: self.neg_subjects.len(),
+            subject_predicate_pairs: self.neg_s_p_adjacency_list.right_count(),
+            triples: self.neg_sp_o_adjacency_list.right_count(),
+        }
+    }
+
+    fn predicate_addition_count(&self, predicate: u64) -> usize {
+        self.pos_predicate_wavelet_tree
+            .lookup(predicate)
+            .map_or(0, |lookup| lookup.len())
+    }
+
+    fn predicate_removal_count(&self, predicate: u64) -> usize {
+        self.neg_predicate_wavelet_tree
+            .lookup(predicate)
+            .map_or(0, |lookup| lookup.len())
+    }
+
     fn node_dict_id(&self, subject: &str) -> Option<u64> {
         self.node_dictionary.id(subject)
     }
@@ -217,6 +335,13 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
         self.node_dictionary.len()
     }
 
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a> {
+        Box::new(self.node_dictionary.strings_with_prefix(prefix))
+    }
+
     fn node_dict_get(&self, id: usize) -> Option<String> {
         self.node_dictionary.get(id)
     }
@@ -225,6 +350,24 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
         self.value_dictionary.get(id)
     }
 
+    fn node_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.node_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "node", id))
+    }
+
+    fn predicate_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.predicate_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "predicate", id))
+    }
+
+    fn value_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.value_dictionary
+            .get_checked(id)
+            .map_err(|_| invalid_dictionary_entry_error(self.name, "value", id))
+    }
+
     fn value_dict_id(&self, value: &str) -> Option<u64> {
         self.value_dictionary.id(value)
     }
@@ -437,6 +580,11 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
         )
     }
 
+    fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
+        // going through subject lookups would check every parent for every triple
+        self.reconciled_triples()
+    }
+
     fn lookup_subject_addition(&self, subject: u64) -> Option<Box<dyn LayerSubjectLookup>> {
         if subject == 0 {
             return None;
@@ -491,6 +639,36 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
         }
     }
 
+    fn triples_for_subjects(&self, subjects: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        let mut subjects: Vec<u64> = subjects
+            .iter()
+            .cloned()
+            .filter(|&subject| subject != 0)
+            .collect();
+        subjects.sort();
+        subjects.dedup();
+
+        // the triples of the parent, with the changes of this layer on top
+        let iters = vec![
+            self.parent.triples_for_subjects(&subjects),
+            Box::new(std::iter::empty()),
+            subject_list_triples(
+                subjects.clone(),
+                self.pos_subjects.clone(),
+                self.pos_s_p_adjacency_list.clone(),
+                self.pos_sp_o_adjacency_list.clone(),
+            ),
+            subject_list_triples(
+                subjects,
+                self.neg_subjects.clone(),
+                self.neg_s_p_adjacency_list.clone(),
+                self.neg_sp_o_adjacency_list.clone(),
+            ),
+        ];
+
+        Box::new(ReconciledTripleIterator::new(iters))
+    }
+
     fn object_additions(&self) -> Box<dyn Iterator<Item = Box<dyn LayerObjectLookup>>> {
         let cloned = self.clone();
         Box::new((0..self.pos_objects.len()).map(move |mapped_object| {
@@ -554,6 +732,49 @@ impl<M: 'static + AsRef<[u8]> + Clone + Send + Sync> Layer for ChildLayer<M> {
     }
 }
 
+/// The triples for the given sorted subjects in the additions or removals of a child layer.
+///
+/// This walks the subject list once, alongside the given subjects,
+/// and stops as soon as all of them have been passed.
+fn subject_list_triples<M: 'static + AsRef<[u8]> + Clone>(
+    subjects: Vec<u64>,
+    subject_list: MonotonicLogArray<M>,
+    s_p_adjacency_list: AdjacencyList<M>,
+    sp_o_adjacency_list: AdjacencyList<M>,
+) -> Box<dyn Iterator<Item = IdTriple>> {
+    let wanted = subjects.into_iter().peekable();
+    let left_count = s_p_adjacency_list.left_count() as u64;
+    Box::new(
+        subject_list
+            .into_iter()
+            .enumerate()
+            .scan(wanted, |wanted, (c, subject)| {
+                while wanted.peek().map(|&w| w < subject).unwrap_or(false) {
+                    wanted.next();
+                }
+
+                match wanted.peek() {
+                    None => None,
+                    Some(&w) if w == subject => Some(Some((c, subject))),
+                    Some(_) => Some(None),
+                }
+            })
+            .flatten()
+            .filter(move |&(c, _)| (c as u64) < left_count)
+            .flat_map(move |(c, subject)| {
+                ChildLayerSubjectLookup {
+                    subject,
+                    adjacencies: AdjacencyStuff {
+                        predicates: s_p_adjacency_list.get((c as u64) + 1),
+                        sp_offset: s_p_adjacency_list.offset_for((c as u64) + 1),
+                        sp_o_adjacency_list: sp_o_adjacency_list.clone(),
+                    },
+                }
+                .triples()
+            }),
+    )
+}
+
 #[derive(Clone)]
 struct AdjacencyStuff<M: 'static + AsRef<[u8]> + Clone> {
     predicates: LogArraySlice<M>,
@@ -636,6 +857,10 @@ impl<M: 'static + AsRef<[u8]> + Clone> LayerSubjectPredicateLookup
     fn has_object(&self, object: u64) -> bool {
         self.objects.iter().find(|&x| x == object).is_some()
     }
+
+    fn object_count(&self) -> usize {
+        self.objects.len()
+    }
 }
 
 struct ChildLayerObjectLookup<M: 'static + AsRef<[u8]> + Clone> {
@@ -1594,7 +1819,7 @@ pub mod tests {
     use crate::layer::base::tests::*;
     use crate::storage::memory::*;
     pub fn child_layer_files() -> ChildLayerFiles<MemoryBackedStore> {
-        let files: Vec<_> = (0..40).map(|_| MemoryBackedStore::new()).collect();
+        let files: Vec<_> = (0..42).map(|_| MemoryBackedStore::new()).collect();
 
         ChildLayerFiles {
             node_dictionary_files: DictionaryFiles {
@@ -1673,9 +1898,33 @@ pub mod tests {
                 blocks_file: files[38].clone(),
                 sblocks_file: files[39].clone(),
             },
+            bloom_filter_file: files[40].clone(),
+            collation_file: files[41].clone(),
         }
     }
 
+    #[test]
+    fn child_layer_type() {
+        let base_layer = example_base_layer();
+        assert_eq!(LayerType::Base, base_layer.layer_type());
+
+        let parent = Arc::new(base_layer);
+        let child_files = child_layer_files();
+
+        let child_builder = ChildLayerFileBuilder::from_files(parent.clone(), &child_files);
+        child_builder
+            .into_phase2()
+            .and_then(|b| b.finalize())
+            .wait()
+            .unwrap();
+
+        let child_layer = ChildLayer::load_from_files([5, 4, 3, 2, 1], parent, &child_files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(LayerType::Child, child_layer.layer_type());
+    }
+
     #[test]
     fn empty_child_layer_equivalent_to_parent() {
         let base_layer = example_base_layer();
@@ -1740,6 +1989,61 @@ pub mod tests {
         assert!(!child_layer.triple_exists(2, 2, 0));
     }
 
+    fn raw_triples<M: AsRef<[u8]> + Clone>(lists: &LayerAdjacencyLists<M>) -> Vec<IdTriple> {
+        let mut triples = Vec::new();
+        for pos in 0..lists.s_p.right_count() {
+            let (mapped_subject, predicate) = lists.s_p.pair_at_pos(pos as u64);
+            if predicate == 0 {
+                continue;
+            }
+            let subject = lists
+                .subjects
+                .map(|subjects| subjects.entry(mapped_subject as usize - 1))
+                .unwrap_or(mapped_subject);
+            for object in lists.sp_o.get(pos as u64 + 1).iter() {
+                triples.push(IdTriple::new(subject, predicate, object));
+            }
+        }
+
+        triples
+    }
+
+    #[test]
+    fn child_layer_raw_adjacency_lists() {
+        let base_layer = example_base_layer();
+        let base_triples = raw_triples(&base_layer.adjacency_lists());
+        assert_eq!(base_layer.triples().collect::<Vec<_>>(), base_triples);
+
+        let parent = Arc::new(base_layer);
+        let child_files = child_layer_files();
+        let child_builder = ChildLayerFileBuilder::from_files(parent.clone(), &child_files);
+        child_builder
+            .into_phase2()
+            .and_then(|b| b.add_triple(2, 1, 2))
+            .and_then(|b| b.add_triple(3, 3, 3))
+            .and_then(|b| b.remove_triple(4, 3, 6))
+            .and_then(|b| b.finalize())
+            .wait()
+            .unwrap();
+        let child_layer = ChildLayer::load_from_files([5, 4, 3, 2, 1], parent, &child_files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            vec![IdTriple::new(2, 1, 2), IdTriple::new(3, 3, 3)],
+            raw_triples(&child_layer.addition_adjacency_lists())
+        );
+        assert_eq!(
+            vec![IdTriple::new(4, 3, 6)],
+            raw_triples(&child_layer.removal_adjacency_lists())
+        );
+
+        let lists = child_layer.addition_adjacency_lists();
+        let objects: Vec<_> = lists.objects.unwrap().iter().collect();
+        assert_eq!(vec![2, 3], objects);
+        assert_eq!(2, lists.o_ps.left_count());
+    }
+
     #[test]
     fn child_layer_can_have_deletes() {
         let base_layer = example_base_layer();
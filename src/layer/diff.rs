@@ -0,0 +1,209 @@
+//! Differences between layers, expressed as string triples.
+//!
+//! A diff can be serialized and sent elsewhere, where it can be
+//! applied on top of a copy of the layer it was computed against,
+//! without needing any of the other layers involved.
+use super::layer::*;
+use super::translate::IdTranslator;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io;
+
+/// The triples that were added and removed between two layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerDiff {
+    pub additions: Vec<StringTriple>,
+    pub removals: Vec<StringTriple>,
+}
+
+impl LayerDiff {
+    /// Compute the diff that turns the triples of `from` into the triples of `to`.
+    ///
+    /// The layers do not need to be related. Both additions and
+    /// removals come out sorted. Triples are compared by translating
+    /// their ids into the other layer with an `IdTranslator`, so only
+    /// the triples that differ are resolved to strings. A corrupt
+    /// dictionary entry results in an `InvalidData` error.
+    pub fn between(from: &dyn Layer, to: &dyn Layer) -> io::Result<LayerDiff> {
+        Ok(LayerDiff {
+            additions: missing_triples(to, from)?,
+            removals: missing_triples(from, to)?,
+        })
+    }
+
+    /// Compute the diff that turns the triples of `ancestor` into the triples of `layer`.
+    ///
+    /// Unlike `between`, this only goes through the triples added and
+    /// removed by the layers from `ancestor` up to `layer`, so it is
+    /// cheap for a few small layers on top of a large one. A triple
+    /// that is added and then removed again along the way does not
+    /// show up at all. Both additions and removals come out sorted.
+    /// This returns an `InvalidInput` error if `ancestor` is not an
+    /// ancestor of `layer`, and an `InvalidData` error on a corrupt
+    /// dictionary entry. A layer compared with itself gives an empty
+    /// diff.
+    pub fn from_ancestor(ancestor: &dyn Layer, layer: &dyn Layer) -> io::Result<LayerDiff> {
+        if ancestor.name() != layer.name() && !ancestor.is_ancestor_of(layer) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "layer is not an ancestor of this layer",
+            ));
+        }
+
+        let mut layers = Vec::new();
+        let mut current = layer;
+        while current.name() != ancestor.name() {
+            layers.push(current);
+            current = current
+                .parent()
+                .expect("ancestor should be reachable through parents");
+        }
+
+        // child layers share the ids of their ancestors, so the
+        // changes of all layers can be combined as ids
+        let mut additions = BTreeSet::new();
+        let mut removals = BTreeSet::new();
+        for l in layers.into_iter().rev() {
+            for triple in l.subject_additions().flat_map(|s| s.triples()) {
+                if !removals.remove(&triple) {
+                    additions.insert(triple);
+                }
+            }
+            for triple in l.subject_removals().flat_map(|s| s.triples()) {
+                if !additions.remove(&triple) {
+                    removals.insert(triple);
+                }
+            }
+        }
+
+        let to_strings = |triples: BTreeSet<IdTriple>| -> io::Result<Vec<StringTriple>> {
+            let mut strings = BTreeSet::new();
+            for triple in triples.iter() {
+                strings.insert(resolve_triple(layer, triple)?);
+            }
+            Ok(strings.into_iter().collect())
+        };
+
+        Ok(LayerDiff {
+            additions: to_strings(additions)?,
+            removals: to_strings(removals)?,
+        })
+    }
+
+    /// Serialize this diff as JSON.
+    ///
+    /// The result is an object with an `additions` and a `removals`
+    /// array, each containing `[subject, predicate, object_type,
+    /// object]` arrays, where object_type is either `node` or `value`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let diff = json!({
+            "additions": self.additions.iter().map(triple_to_json).collect::<Vec<_>>(),
+            "removals": self.removals.iter().map(triple_to_json).collect::<Vec<_>>(),
+        });
+
+        serde_json::to_vec(&diff).expect("diff should always serialize")
+    }
+
+    /// Parse a diff that was serialized with `serialize`.
+    pub fn deserialize(data: &[u8]) -> io::Result<LayerDiff> {
+        let diff: Value = serde_json::from_slice(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok(LayerDiff {
+            additions: triples_from_json(&diff["additions"])?,
+            removals: triples_from_json(&diff["removals"])?,
+        })
+    }
+}
+
+/// The triples of `layer` that are not in `other`, in sorted order.
+fn missing_triples(layer: &dyn Layer, other: &dyn Layer) -> io::Result<Vec<StringTriple>> {
+    let mut translator = IdTranslator::new(layer, other);
+    let mut missing = BTreeSet::new();
+    for triple in layer.triples() {
+        let exists = translator
+            .translate_triple(triple)
+            .map(|translated| other.id_triple_exists(translated))
+            .unwrap_or(false);
+        if !exists {
+            missing.insert(resolve_triple(layer, &triple)?);
+        }
+    }
+
+    Ok(missing.into_iter().collect())
+}
+
+/// Resolve a triple of the given layer to strings, failing on a corrupt or dangling id.
+fn resolve_triple(layer: &dyn Layer, triple: &IdTriple) -> io::Result<StringTriple> {
+    layer.id_triple_to_string_checked(triple)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("could not resolve triple {:?}", triple),
+        )
+    })
+}
+
+fn triple_to_json(triple: &StringTriple) -> Value {
+    let (object_type, object) = match &triple.object {
+        ObjectType::Node(node) => ("node", node),
+        ObjectType::Value(value) => ("value", value),
+    };
+
+    json!([triple.subject, triple.predicate, object_type, object])
+}
+
+fn triples_from_json(triples: &Value) -> io::Result<Vec<StringTriple>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid triple in diff");
+
+    triples
+        .as_array()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected array of triples"))?
+        .iter()
+        .map(|triple| {
+            let parts = triple
+                .as_array()
+                .filter(|parts| parts.len() == 4)
+                .ok_or_else(invalid)?;
+            let strings = parts
+                .iter()
+                .map(|part| part.as_str().ok_or_else(invalid))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            match strings[2] {
+                "node" => Ok(StringTriple::new_node(strings[0], strings[1], strings[3])),
+                "value" => Ok(StringTriple::new_value(strings[0], strings[1], strings[3])),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_and_deserialize_diff() {
+        let diff = LayerDiff {
+            additions: vec![
+                StringTriple::new_node("cow", "likes", "duck"),
+                StringTriple::new_value("duck", "says", "\"quack\"\n"),
+            ],
+            removals: vec![StringTriple::new_value("cow", "says", "moo")],
+        };
+
+        let serialized = diff.serialize();
+
+        assert_eq!(diff, LayerDiff::deserialize(&serialized).unwrap());
+    }
+
+    #[test]
+    fn deserialize_invalid_diff() {
+        assert!(LayerDiff::deserialize(b"not json").is_err());
+        assert!(LayerDiff::deserialize(br#"{"additions": []}"#).is_err());
+        assert!(
+            LayerDiff::deserialize(br#"{"additions": [["a","b","c","d"]], "removals": []}"#)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Export of layer contents to flat file formats.
+use super::layer::*;
+use std::io::{self, Write};
+
+/// Write all triples in the given layer as CSV.
+///
+/// The output starts with a header row of
+/// `subject,predicate,object,object_type`, followed by one row per
+/// triple. Triples are written grouped by object. The object_type
+/// column is either `node` or `value`.
+pub fn export_csv<W: Write>(layer: &dyn Layer, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "subject,predicate,object,object_type")?;
+
+    for triple in layer.objects().flat_map(|o| o.triples()) {
+        let triple = layer.id_triple_to_string_checked(&triple)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("could not resolve triple {:?}", triple),
+            )
+        })?;
+
+        let (object, object_type) = match &triple.object {
+            ObjectType::Node(node) => (node, "node"),
+            ObjectType::Value(value) => (value, "value"),
+        };
+
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            csv_field(&triple.subject),
+            csv_field(&triple.predicate),
+            csv_field(object),
+            object_type
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write all triples in the given layer as N-Triples.
+///
+/// Nodes and predicates are written as IRIs, and values as string
+/// literals, with their datatype or language tag if they have one.
+/// Nodes whose name is a blank node label, like `_:b0`, are written
+/// as blank nodes, which is how `parse_ntriples_line` reads them.
+/// Triples are written one at a time, in canonical (subject,
+/// predicate, object) id order.
+pub fn export_ntriples<W: Write>(layer: &dyn Layer, writer: &mut W) -> io::Result<()> {
+    export_ntriples_from(layer, None, writer)
+}
+
+/// Write the triples in the given layer as N-Triples, starting after the given triple.
+///
+/// When `after` is given, only triples that come strictly after it
+/// in canonical (subject, predicate, object) id order are
+/// written. Since the order is stable for a given layer, an
+/// interrupted export can be resumed by passing in the last triple
+/// that was fully written, without writing any triple twice. The
+/// resume triple does not have to exist in the layer.
+pub fn export_ntriples_from<W: Write>(
+    layer: &dyn Layer,
+    after: Option<IdTriple>,
+    writer: &mut W,
+) -> io::Result<()> {
+    let triples = layer
+        .reconciled_triples()
+        .skip_while(move |t| after.map(|a| *t <= a).unwrap_or(false));
+
+    for triple in triples {
+        let triple = layer.id_triple_to_string_checked(&triple)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("could not resolve triple {:?}", triple),
+            )
+        })?;
+
+        let object = match &triple.object {
+            ObjectType::Node(node) => ntriples_node(node),
+            ObjectType::Value(value) => ntriples_value(&Literal::from_value_string(value)),
+        };
+
+        writeln!(
+            writer,
+            "{} {} {} .",
+            ntriples_node(&triple.subject),
+            ntriples_iri(&triple.predicate),
+            object
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write a node as a blank node if it has a valid blank node label, and as an IRI otherwise.
+fn ntriples_node(node: &str) -> String {
+    let is_blank = node.starts_with("_:") && {
+        let label = &node[2..];
+        !label.is_empty()
+            && !label.starts_with(&['-', '.'][..])
+            && !label.ends_with('.')
+            && label
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+    };
+
+    if is_blank {
+        node.to_owned()
+    } else {
+        ntriples_iri(node)
+    }
+}
+
+/// Write an IRI in angle brackets, escaping characters that may not appear in an N-Triples IRI.
+fn ntriples_iri(iri: &str) -> String {
+    let mut result = String::with_capacity(iri.len() + 2);
+    result.push('<');
+    for c in iri.chars() {
+        match c {
+            '<' | '>' | '"' | '{' | '}' | '|' | '^' | '`' | '\\' | '\0'..=' ' => {
+                result.push_str(&format!("\\u{:04X}", c as u32))
+            }
+            _ => result.push(c),
+        }
+    }
+    result.push('>');
+
+    result
+}
+
+/// Write a literal, followed by its datatype or language tag.
+fn ntriples_value(literal: &Literal) -> String {
+    let mut result = ntriples_literal(&literal.lexical);
+    if let Some(datatype) = &literal.datatype {
+        result.push_str("^^");
+        result.push_str(&ntriples_iri(datatype));
+    } else if let Some(language) = &literal.language {
+        result.push('@');
+        result.push_str(language);
+    }
+
+    result
+}
+
+/// Write a string literal in quotes, escaping quotes, backslashes and line breaks.
+fn ntriples_literal(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            _ => result.push(c),
+        }
+    }
+    result.push('"');
+
+    result
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::base::BaseLayer;
+    use crate::layer::builder::{LayerBuilder, SimpleLayerBuilder};
+    use futures::prelude::*;
+
+    #[test]
+    fn export_layer_as_csv() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "\"quack\", quack"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_csv(&layer, &mut output).unwrap();
+
+        assert_eq!(
+            "subject,predicate,object,object_type\n\
+             cow,likes,duck,node\n\
+             duck,says,\"\"\"quack\"\", quack\",value\n\
+             cow,says,moo,value\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn export_layer_as_ntriples_and_resume() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "\"quack\"\n"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_ntriples(&layer, &mut output).unwrap();
+
+        assert_eq!(
+            "<cow> <likes> <duck> .\n\
+             <cow> <says> \"moo\" .\n\
+             <duck> <says> \"\\\"quack\\\"\\n\" .\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let checkpoint = layer
+            .string_triple_to_id(&StringTriple::new_value("cow", "says", "moo"))
+            .unwrap();
+        let mut output = Vec::new();
+        export_ntriples_from(&layer, Some(checkpoint), &mut output).unwrap();
+
+        assert_eq!(
+            "<duck> <says> \"\\\"quack\\\"\\n\" .\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let last = layer.reconciled_triples().last().unwrap();
+        let mut output = Vec::new();
+        export_ntriples_from(&layer, Some(last), &mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn export_blank_nodes_as_ntriples() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_node("_:cow", "likes", "_:b.1"));
+        builder.add_string_triple(&StringTriple::new_node("_:b.1", "likes", "_:not blank"));
+        builder.add_string_triple(&StringTriple::new_node("_:b.1", "is", "_:"));
+
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_ntriples(&layer, &mut output).unwrap();
+
+        assert_eq!(
+            "_:b.1 <is> <_:> .\n\
+             _:b.1 <likes> <_:not\\u0020blank> .\n\
+             _:cow <likes> _:b.1 .\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn export_typed_literals_as_ntriples() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        let integer = "http://www.w3.org/2001/XMLSchema#integer";
+        builder.add_string_triple(&StringTriple::new_typed_value("cow", "age", "4", integer));
+        builder.add_string_triple(&StringTriple::new_lang_value("cow", "name", "koe", "nl"));
+
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_ntriples(&layer, &mut output).unwrap();
+
+        assert_eq!(
+            "<cow> <age> \"4\"^^<http://www.w3.org/2001/XMLSchema#integer> .\n\
+             <cow> <name> \"koe\"@nl .\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn export_plain_values_that_look_like_literals() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "x"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "\"x\""));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "\"a\"@en"));
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_ntriples(&layer, &mut output).unwrap();
+
+        assert_eq!(
+            "<cow> <says> \"\\\"a\\\"@en\" .\n\
+             <cow> <says> \"\\\"x\\\"\" .\n\
+             <cow> <says> \"x\" .\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
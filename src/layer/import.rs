@@ -0,0 +1,299 @@
+//! Import of layer contents from flat file formats.
+use super::layer::*;
+use std::io;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The datatype of plain string literals.
+///
+/// In RDF 1.1, a literal with this datatype is the same as one without
+/// a datatype, so it is imported as a plain value.
+const XSD_STRING: &str = "http://www.w3.org/2001/XMLSchema#string";
+
+/// Parse a single line of N-Triples.
+///
+/// IRIs and blank nodes become nodes, and literals become values,
+/// encoded with their datatype or language tag as described in
+/// `Literal`. IRIs are stored without their angle brackets, and blank
+/// nodes keep their `_:` prefix. Escape sequences in IRIs and literals
+/// are resolved. Lines that are empty or only hold a comment result
+/// in None.
+pub fn parse_ntriples_line(line: &str) -> io::Result<Option<StringTriple>> {
+    let mut chars = line.chars().peekable();
+    skip_whitespace(&mut chars);
+    match chars.peek() {
+        None | Some('#') => return Ok(None),
+        _ => {}
+    }
+
+    let subject = parse_node(&mut chars)?;
+    skip_whitespace(&mut chars);
+    let predicate = match chars.next() {
+        Some('<') => parse_iri(&mut chars)?,
+        _ => return Err(parse_error("expected an IRI as predicate")),
+    };
+    skip_whitespace(&mut chars);
+    let object = match chars.peek() {
+        Some('"') => {
+            chars.next();
+            ObjectType::Value(parse_literal(&mut chars)?.to_value_string())
+        }
+        _ => ObjectType::Node(parse_node(&mut chars)?),
+    };
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('.') {
+        return Err(parse_error("expected '.' at the end of the triple"));
+    }
+    skip_whitespace(&mut chars);
+    match chars.peek() {
+        None | Some('#') => {}
+        _ => return Err(parse_error("unexpected characters after the triple")),
+    }
+
+    Ok(Some(StringTriple {
+        subject,
+        predicate,
+        object,
+    }))
+}
+
+fn parse_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(' ') | Some('\t') | Some('\r') | Some('\n') = chars.peek() {
+        chars.next();
+    }
+}
+
+/// Parse an IRI or a blank node label.
+fn parse_node(chars: &mut Peekable<Chars>) -> io::Result<String> {
+    match chars.next() {
+        Some('<') => parse_iri(chars),
+        Some('_') if chars.next() == Some(':') => {
+            let mut label = "_:".to_owned();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '<' || c == '"' || c == '#' {
+                    break;
+                }
+                // a label may contain dots, but not end in one, so a
+                // dot at the end is the end of the triple instead
+                if c == '.' {
+                    let mut after = chars.clone();
+                    after.next();
+                    match after.peek() {
+                        None | Some('#') => break,
+                        Some(c) if c.is_whitespace() => break,
+                        _ => {}
+                    }
+                }
+                label.push(c);
+                chars.next();
+            }
+            if label.len() == 2 {
+                return Err(parse_error("empty blank node label"));
+            }
+
+            Ok(label)
+        }
+        _ => Err(parse_error("expected an IRI or a blank node")),
+    }
+}
+
+/// Parse the rest of an IRI, after its opening angle bracket.
+fn parse_iri(chars: &mut Peekable<Chars>) -> io::Result<String> {
+    let mut iri = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(parse_error("unterminated IRI")),
+            Some('>') => return Ok(iri),
+            Some('\\') => match chars.next() {
+                Some('u') => iri.push(parse_unicode_escape(chars, 4)?),
+                Some('U') => iri.push(parse_unicode_escape(chars, 8)?),
+                _ => return Err(parse_error("invalid escape sequence in IRI")),
+            },
+            Some(c) if c <= ' ' || "<\"{}|^`".contains(c) => {
+                return Err(parse_error("invalid character in IRI"))
+            }
+            Some(c) => iri.push(c),
+        }
+    }
+}
+
+/// Parse the rest of a literal, after its opening quote, including its datatype or language tag.
+fn parse_literal(chars: &mut Peekable<Chars>) -> io::Result<Literal> {
+    let mut lexical = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(parse_error("unterminated literal")),
+            Some('"') => break,
+            Some('\\') => lexical.push(match chars.next() {
+                Some('t') => '\t',
+                Some('b') => '\u{8}',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('f') => '\u{c}',
+                Some('"') => '"',
+                Some('\'') => '\'',
+                Some('\\') => '\\',
+                Some('u') => parse_unicode_escape(chars, 4)?,
+                Some('U') => parse_unicode_escape(chars, 8)?,
+                _ => return Err(parse_error("invalid escape sequence in literal")),
+            }),
+            Some(c) => lexical.push(c),
+        }
+    }
+
+    match chars.peek() {
+        Some('^') => {
+            chars.next();
+            if chars.next() != Some('^') || chars.next() != Some('<') {
+                return Err(parse_error("expected a datatype IRI after '^^'"));
+            }
+            let datatype = parse_iri(chars)?;
+            if datatype.contains(|c: char| c.is_control()) {
+                return Err(parse_error("invalid character in datatype IRI"));
+            }
+            if datatype == XSD_STRING {
+                Ok(Literal::plain(&lexical))
+            } else {
+                Ok(Literal::typed(&lexical, &datatype))
+            }
+        }
+        Some('@') => {
+            chars.next();
+            let mut language = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '-') {
+                    break;
+                }
+                language.push(c);
+                chars.next();
+            }
+            if language.is_empty() {
+                return Err(parse_error("expected a language tag after '@'"));
+            }
+
+            Ok(Literal::with_language(&lexical, &language))
+        }
+        _ => Ok(Literal::plain(&lexical)),
+    }
+}
+
+/// Parse the hexadecimal digits of a `\u` or `\U` escape sequence.
+fn parse_unicode_escape(chars: &mut Peekable<Chars>, digits: usize) -> io::Result<char> {
+    let hex: String = chars.take(digits).collect();
+    if hex.len() != digits {
+        return Err(parse_error("truncated unicode escape sequence"));
+    }
+
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+        .ok_or_else(|| parse_error("invalid unicode escape sequence"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::base::BaseLayer;
+    use crate::layer::builder::{LayerBuilder, SimpleLayerBuilder};
+    use crate::layer::export::export_ntriples;
+    use futures::prelude::*;
+
+    #[test]
+    fn parse_ntriples_lines() {
+        assert_eq!(None, parse_ntriples_line("").unwrap());
+        assert_eq!(None, parse_ntriples_line("  # a comment").unwrap());
+        assert_eq!(
+            Some(StringTriple::new_node(
+                "http://example.com/cow",
+                "http://example.com/likes",
+                "_:duck"
+            )),
+            parse_ntriples_line(
+                "<http://example.com/cow> <http://example.com/likes> _:duck. # moo"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Some(StringTriple::new_value("_:cow", "says", "\"moo\"\n\u{e9}")),
+            parse_ntriples_line(r#"_:cow <says> "\"moo\"\n\u00E9" ."#).unwrap()
+        );
+        assert_eq!(
+            Some(StringTriple::new_typed_value(
+                "cow",
+                "age",
+                "7",
+                "http://www.w3.org/2001/XMLSchema#integer"
+            )),
+            parse_ntriples_line(r#"<cow> <age> "7"^^<http://www.w3.org/2001/XMLSchema#integer> ."#)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(StringTriple::new_value("cow", "name", "Bessie")),
+            parse_ntriples_line(
+                r#"<cow> <name> "Bessie"^^<http://www.w3.org/2001/XMLSchema#string> ."#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Some(StringTriple::new_lang_value("cow", "says", "meuh", "fr-BE")),
+            parse_ntriples_line(r#"<cow> <says> "meuh"@fr-BE ."#).unwrap()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_ntriples_lines() {
+        for line in [
+            "<cow> <says> \"moo\"",
+            "<cow> <says> \"moo .",
+            "<cow> _:says <duck> .",
+            "<cow <says> <duck> .",
+            "<cow> <says> \"moo\"@ .",
+            "<cow> <says> \"\\x\" .",
+            "<cow> <says> <duck> . <pig>",
+            "cow <says> <duck> .",
+            "<cow> <says> \"moo\"^^<a\\u0001b> .",
+        ]
+        .iter()
+        {
+            let error = parse_ntriples_line(line).err().unwrap();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn exported_ntriples_parse_back() {
+        let triples = vec![
+            StringTriple::new_node("cow", "likes", "duck"),
+            StringTriple::new_value("duck", "says", "a \"quack\"\\\n"),
+            StringTriple::new_typed_value("cow", "age", "7", "http://example.com/int"),
+            StringTriple::new_lang_value("cow", "says", "meuh", "fr"),
+        ];
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        for triple in triples.iter() {
+            builder.add_string_triple(triple);
+        }
+        builder.commit().wait().unwrap();
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut output = Vec::new();
+        export_ntriples(&layer, &mut output).unwrap();
+        let mut parsed: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter_map(|line| parse_ntriples_line(line).unwrap())
+            .collect();
+        parsed.sort();
+        let mut expected = triples;
+        expected.sort();
+
+        assert_eq!(expected, parsed);
+    }
+}
@@ -1,7 +1,27 @@
 //! Common data structures and traits for all layer types.
-use std::collections::HashMap;
+use crate::structure::{AdjacencyList, BytewiseCollation, Collation, MonotonicLogArray};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::Hash;
+use std::io;
 use std::iter::Peekable;
+use std::sync::{Arc, Mutex};
+
+/// The error returned when a dictionary entry of a layer is not valid UTF-8.
+pub(crate) fn invalid_dictionary_entry_error(
+    layer: [u32; 5],
+    dictionary: &str,
+    id: usize,
+) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} dictionary entry {} of layer {:08x}{:08x}{:08x}{:08x}{:08x} is not valid UTF-8",
+            dictionary, id, layer[0], layer[1], layer[2], layer[3], layer[4]
+        ),
+    )
+}
 
 /// A layer containing dictionary entries and triples.
 ///
@@ -15,6 +35,12 @@ pub trait Layer: Send + Sync {
     /// The parent of this layer, or None if this is a base layer.
     fn parent(&self) -> Option<&dyn Layer>;
 
+    /// The type of this layer, either base or child.
+    ///
+    /// This is answered from the layer's own data, and is therefore
+    /// cheaper than checking whether `parent()` returns something.
+    fn layer_type(&self) -> LayerType;
+
     /// The amount of nodes and values known to this layer.
     /// This also counts entries in the parent.
     fn node_and_value_count(&self) -> usize;
@@ -33,6 +59,18 @@ pub trait Layer: Send + Sync {
     fn node_dict_get(&self, id: usize) -> Option<String>;
     /// Node dict length of this specific layer
     fn node_dict_len(&self) -> usize;
+    /// The collation the dictionaries of this specific layer are sorted with
+    ///
+    /// This is fixed when the layer is built. Layers are sorted
+    /// bytewise unless their builder was given another collation.
+    fn collation(&self) -> Arc<dyn Collation> {
+        Arc::new(BytewiseCollation)
+    }
+    /// Node dict ids and entries of this specific layer that start with the given prefix, in order
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a>;
     /// Value dict id of current layer
     fn value_dict_id(&self, value: &str) -> Option<u64>;
     /// Value dict length of this specific layer
@@ -49,12 +87,108 @@ pub trait Layer: Send + Sync {
     /// The numerical id of a value object, or None if the value object cannot be found.
     fn object_value_id(&self, object: &str) -> Option<u64>;
     /// The subject corresponding to a numerical id, or None if it cannot be found.
+    ///
+    /// This panics if the dictionary entry is not valid UTF-8. Use
+    /// `id_subject_checked` when reading data that may be corrupt.
     fn id_subject(&self, id: u64) -> Option<String>;
     /// The predicate corresponding to a numerical id, or None if it cannot be found.
+    ///
+    /// This panics if the dictionary entry is not valid UTF-8. Use
+    /// `id_predicate_checked` when reading data that may be corrupt.
     fn id_predicate(&self, id: u64) -> Option<String>;
     /// The object corresponding to a numerical id, or None if it cannot be found.
+    ///
+    /// This panics if the dictionary entry is not valid UTF-8. Use
+    /// `id_object_checked` when reading data that may be corrupt.
     fn id_object(&self, id: u64) -> Option<ObjectType>;
 
+    /// Like `node_dict_get`, but reports a corrupt entry as an `InvalidData` error.
+    ///
+    /// Layers backed by dictionary files override this to validate
+    /// that the stored string is UTF-8. By default this never fails.
+    fn node_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        Ok(self.node_dict_get(id))
+    }
+
+    /// Like `predicate_dict_get`, but reports a corrupt entry as an `InvalidData` error.
+    fn predicate_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        Ok(self.predicate_dict_get(id))
+    }
+
+    /// Like `value_dict_get`, but reports a corrupt entry as an `InvalidData` error.
+    fn value_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        Ok(self.value_dict_get(id))
+    }
+
+    /// Like `id_subject`, but reports a corrupt dictionary entry as an
+    /// `InvalidData` error naming the layer and id, rather than
+    /// panicking.
+    fn id_subject_checked(&self, id: u64) -> io::Result<Option<String>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        let corrected_id = id - 1;
+        let parent_count =
+            (self.node_and_value_count() - self.node_dict_len() - self.value_dict_len()) as u64;
+        if corrected_id >= parent_count {
+            self.node_dict_get_checked((corrected_id - parent_count) as usize)
+        } else {
+            match self.parent() {
+                Some(parent) => parent.id_subject_checked(id),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Like `id_predicate`, but reports a corrupt dictionary entry as
+    /// an `InvalidData` error naming the layer and id, rather than
+    /// panicking.
+    fn id_predicate_checked(&self, id: u64) -> io::Result<Option<String>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        let corrected_id = id - 1;
+        let parent_count = (self.predicate_count() - self.predicate_dict_len()) as u64;
+        if corrected_id >= parent_count {
+            self.predicate_dict_get_checked((corrected_id - parent_count) as usize)
+        } else {
+            match self.parent() {
+                Some(parent) => parent.id_predicate_checked(id),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Like `id_object`, but reports a corrupt dictionary entry as an
+    /// `InvalidData` error naming the layer and id, rather than
+    /// panicking.
+    fn id_object_checked(&self, id: u64) -> io::Result<Option<ObjectType>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        let corrected_id = id - 1;
+        let parent_count =
+            (self.node_and_value_count() - self.node_dict_len() - self.value_dict_len()) as u64;
+        if corrected_id >= parent_count {
+            let corrected_id = corrected_id - parent_count;
+            let node_count = self.node_dict_len() as u64;
+            if corrected_id >= node_count {
+                Ok(self
+                    .value_dict_get_checked((corrected_id - node_count) as usize)?
+                    .map(ObjectType::Value))
+            } else {
+                Ok(self
+                    .node_dict_get_checked(corrected_id as usize)?
+                    .map(ObjectType::Node))
+            }
+        } else {
+            match self.parent() {
+                Some(parent) => parent.id_object_checked(id),
+                None => Ok(None),
+            }
+        }
+    }
+
     /// Returns an iterator over all triple data known to this layer.
     ///
     /// This data is returned by
@@ -337,14 +471,192 @@ pub trait Layer: Send + Sync {
     /// Return a clone of this layer in a box.
     fn clone_boxed(&self) -> Box<dyn Layer>;
 
+    /// Returns false if the given triple was definitely never added in this layer or any of its ancestors, and true if it may have been.
+    ///
+    /// Layers that were built with a bloom filter use it to answer
+    /// this without going through the indexes. For other layers,
+    /// this always returns true.
+    fn may_contain_triple(&self, _subject: u64, _predicate: u64, _object: u64) -> bool {
+        true
+    }
+
+    /// Pass on a hint on how the triples of this layer are about to be accessed.
+    ///
+    /// For example, giving `ScanHint::Sequential` before a full scan
+    /// over `triples()` lets the operating system read ahead in the
+    /// memory-mapped files of a directory layer. The hint applies to
+    /// all ancestors of this layer as well.
+    fn scan_hint(&self, _hint: ScanHint) {}
+
+    /// The sizes of the indexes of triples added by this specific layer.
+    ///
+    /// This is answered from the layer's own data structures without
+    /// traversing them. Layers that don't keep such indexes report
+    /// zero for everything.
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        LayerTripleCounts::default()
+    }
+
+    /// The sizes of the indexes of triples removed by this specific layer.
+    ///
+    /// See `triple_addition_counts`.
+    fn triple_removal_counts(&self) -> LayerTripleCounts {
+        LayerTripleCounts::default()
+    }
+
+    /// The number of triples added by this specific layer.
+    ///
+    /// See `triple_addition_counts`.
+    fn triple_addition_count(&self) -> usize {
+        self.triple_addition_counts().triples
+    }
+
+    /// The number of triples removed by this specific layer.
+    ///
+    /// See `triple_removal_counts`.
+    fn triple_removal_count(&self) -> usize {
+        self.triple_removal_counts().triples
+    }
+
+    /// The number of triples in this layer, taking all its ancestors into account.
+    ///
+    /// This adds up the triples added and subtracts the triples
+    /// removed by each layer in the stack, which only reads the sizes
+    /// of their indexes. Layers only remove triples that exist in
+    /// their parent, so this is the number of triples `triples()`
+    /// would return. For a union, triples that occur in more than one
+    /// member are counted once for every member.
+    fn triple_count(&self) -> usize {
+        let parent = self
+            .parent()
+            .map_or(0, |parent| stack_triple_counts(parent).triples);
+        (parent + self.triple_addition_count()).saturating_sub(self.triple_removal_count())
+    }
+
+    /// The number of subject-predicate pairs with the given predicate added by this specific layer.
+    fn predicate_addition_count(&self, _predicate: u64) -> usize {
+        0
+    }
+
+    /// The number of subject-predicate pairs with the given predicate removed by this specific layer.
+    fn predicate_removal_count(&self, _predicate: u64) -> usize {
+        0
+    }
+
+    /// Returns statistics about the triples in this layer, computed in a single pass.
+    ///
+    /// Unlike `statistics`, this never uses a cached result.
+    fn compute_statistics(&self) -> LayerStatistics {
+        let mut statistics = LayerStatistics::default();
+        let mut seen_objects = vec![0u64; self.node_and_value_count() / 64 + 1];
+        let mut last_subject = 0;
+        for triple in self.reconciled_triples() {
+            statistics.triples += 1;
+            if triple.subject != last_subject {
+                statistics.subjects += 1;
+                last_subject = triple.subject;
+            }
+            let (word, bit) = (triple.object as usize / 64, triple.object % 64);
+            if seen_objects[word] & (1 << bit) == 0 {
+                seen_objects[word] |= 1 << bit;
+                statistics.objects += 1;
+            }
+            *statistics
+                .predicate_triples
+                .entry(triple.predicate)
+                .or_insert(0) += 1;
+        }
+        statistics.predicates = statistics.predicate_triples.len();
+
+        statistics
+    }
+
+    /// Returns statistics about the triples in this layer, for use in a query cost model.
+    ///
+    /// All counts take the whole layer stack and its removals into
+    /// account. Base and child layers compute these once and cache
+    /// them, so this is cheap to call repeatedly. Other layers compute
+    /// them on every call.
+    fn statistics(&self) -> Arc<LayerStatistics> {
+        Arc::new(self.compute_statistics())
+    }
+
+    /// Returns a rough estimate of the number of triples matching the given pattern.
+    ///
+    /// Unlike `count_matching`, this never walks an index. It only
+    /// uses the index sizes of each layer in the stack and the number
+    /// of subject-predicate pairs per predicate, and assumes that
+    /// triples are evenly spread over subjects, predicates and
+    /// objects. The result can be far off for skewed data, and
+    /// removals are only roughly accounted for. It is meant for
+    /// ordering query steps, not for answering queries. Fully bound
+    /// patterns are checked against the bloom filter if there is one.
+    fn estimate_matching(
+        &self,
+        subject: Option<u64>,
+        predicate: Option<u64>,
+        object: Option<u64>,
+    ) -> usize {
+        if let (Some(s), Some(p), Some(o)) = (subject, predicate, object) {
+            if !self.may_contain_triple(s, p, o) {
+                return 0;
+            }
+        }
+
+        let mut counts = self.parent().map(stack_triple_counts).unwrap_or_default();
+        counts.add(&self.triple_addition_counts());
+        counts.subtract(&self.triple_removal_counts());
+        let predicate_pairs = predicate.map(|p| {
+            let parent = self
+                .parent()
+                .map_or(0, |parent| stack_predicate_count(parent, p));
+            (parent + self.predicate_addition_count(p))
+                .saturating_sub(self.predicate_removal_count(p)) as f64
+        });
+
+        let triples = counts.triples as f64;
+        let divide = |a: f64, b: f64| if b == 0.0 { 0.0 } else { a / b };
+        // the average number of objects per subject-predicate pair
+        let fan_out = divide(triples, counts.subject_predicate_pairs as f64);
+        let mut estimate = match (subject.is_some(), predicate_pairs) {
+            (false, None) => triples,
+            (true, None) => divide(triples, counts.subjects as f64),
+            (false, Some(pairs)) => pairs * fan_out,
+            (true, Some(pairs)) => divide(pairs, counts.subjects as f64) * fan_out,
+        };
+        if object.is_some() {
+            estimate = divide(estimate, self.node_and_value_count() as f64);
+        }
+
+        (estimate.ceil() as usize).min(counts.triples)
+    }
+
     /// Returns true if the given triple exists, and false otherwise.
+    ///
+    /// If the layer has bloom filters, these are consulted first to
+    /// quickly rule out triples that were never added.
     fn triple_exists(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        if !self.may_contain_triple(subject, predicate, object) {
+            return false;
+        }
+
         self.lookup_subject(subject)
             .and_then(|pairs| pairs.lookup_predicate(predicate))
             .and_then(|objects| objects.triple(object))
             .is_some()
     }
 
+    /// Returns true if the given subject-predicate pair points at the given object, and false otherwise.
+    ///
+    /// This is equivalent to `triple_exists`, but does its lookup
+    /// from the object side, which is more natural for
+    /// object-anchored queries.
+    fn object_has_subject_predicate(&self, object: u64, subject: u64, predicate: u64) -> bool {
+        self.lookup_object(object)
+            .map(|o| o.has_subject_predicate_pair(subject, predicate))
+            .unwrap_or(false)
+    }
+
     /// Returns true if the given triple exists, and false otherwise.
     fn id_triple_exists(&self, triple: IdTriple) -> bool {
         self.triple_exists(triple.subject, triple.predicate, triple.object)
@@ -372,6 +684,624 @@ pub trait Layer: Send + Sync {
         )
     }
 
+    /// Iterator over all triples known to this layer, in sorted order.
+    ///
+    /// This yields the same triples as `triples`, but is computed as
+    /// a single sorted pass over the layer stack. The additions and
+    /// removals of every layer in the stack are merged at once, and
+    /// for each triple the topmost layer that mentions it decides
+    /// whether it is part of the result. A triple that is removed in
+    /// one layer and added again in a later layer is therefore part
+    /// of the result.
+    ///
+    /// The cost is logarithmic in the depth of the stack for every
+    /// triple added or removed anywhere in it, rather than linear in
+    /// the depth for every triple returned.
+    fn reconciled_triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
+        fn additions_and_removals<L: Layer + ?Sized>(
+            layer: &L,
+        ) -> Vec<Box<dyn Iterator<Item = IdTriple>>> {
+            vec![
+                Box::new(layer.subject_additions().flat_map(|s| s.triples())),
+                Box::new(layer.subject_removals().flat_map(|s| s.triples())),
+            ]
+        }
+
+        let mut levels = vec![additions_and_removals(self)];
+        let mut parent = self.parent();
+        while let Some(layer) = parent {
+            levels.push(additions_and_removals(layer));
+            parent = layer.parent();
+        }
+
+        let iters = levels.into_iter().rev().flatten().collect();
+        Box::new(ReconciledTripleIterator::new(iters))
+    }
+
+    /// Iterator over all triples with one of the given predicates, in sorted order.
+    ///
+    /// Only the predicate index entries of the given predicates are
+    /// visited, rather than all triples of the layer. The triples of
+    /// each predicate are merged into a single sorted stream.
+    /// Duplicate predicates are ignored.
+    fn triples_with_predicates(&self, predicates: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        let mut predicates = predicates.to_vec();
+        predicates.sort_unstable();
+        predicates.dedup();
+
+        let iters: Vec<_> = predicates
+            .into_iter()
+            .filter_map(|p| self.lookup_predicate(p))
+            .map(|lookup| lookup.triples())
+            .collect();
+
+        Box::new(MergedTripleIterator::new(iters))
+    }
+
+    /// Returns the predicates that both given subjects have triples for, in ascending order.
+    ///
+    /// Predicates for which all triples of a subject were removed are
+    /// not counted. If either subject has no triples, this returns an
+    /// empty vector.
+    fn shared_predicates(&self, a: u64, b: u64) -> Vec<u64> {
+        let live_predicates = |subject| -> Vec<u64> {
+            self.lookup_subject(subject)
+                .map(|lookup| {
+                    lookup
+                        .predicates()
+                        .filter(|p| p.objects().next().is_some())
+                        .map(|p| p.predicate())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let a_predicates = live_predicates(a);
+        let b_predicates = live_predicates(b);
+
+        let mut result = Vec::new();
+        let mut a_iter = a_predicates.into_iter().peekable();
+        let mut b_iter = b_predicates.into_iter().peekable();
+        while let (Some(&a_predicate), Some(&b_predicate)) = (a_iter.peek(), b_iter.peek()) {
+            match a_predicate.cmp(&b_predicate) {
+                Ordering::Less => {
+                    a_iter.next();
+                }
+                Ordering::Greater => {
+                    b_iter.next();
+                }
+                Ordering::Equal => {
+                    result.push(a_predicate);
+                    a_iter.next();
+                    b_iter.next();
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns the distinct nodes that are one hop away from the given node, in ascending order.
+    ///
+    /// These are the node objects of triples that have `node` as
+    /// their subject, together with the subjects of triples that have
+    /// `node` as their object. Value objects are left out, so the
+    /// result can be fed straight back into this method for a
+    /// breadth-first or depth-first traversal.
+    fn neighbors(&self, node: u64) -> Vec<u64> {
+        let mut result: Vec<u64> = Vec::new();
+        if let Some(lookup) = self.lookup_subject(node) {
+            for predicate_lookup in lookup.predicates() {
+                result.extend(
+                    predicate_lookup
+                        .objects()
+                        .filter(|&o| object_is_node(self, o)),
+                );
+            }
+        }
+        if let Some(lookup) = self.lookup_object(node) {
+            result.extend(lookup.subject_predicate_pairs().map(|(s, _)| s));
+        }
+
+        result.sort_unstable();
+        result.dedup();
+
+        result
+    }
+
+    /// Returns all simple paths from one node to another of at most `max_depth` triples.
+    ///
+    /// A path is a sequence of triples where the object of each
+    /// triple is the subject of the next one. The first triple has
+    /// `from` as its subject and the last one has `to` as its object.
+    /// No node is visited twice within a path, so in particular there
+    /// are no paths from a node to itself. Paths are returned in
+    /// depth-first order, following triples in canonical order.
+    fn find_paths(&self, from: u64, to: u64, max_depth: usize) -> Vec<Vec<IdTriple>> {
+        let mut search = PathSearch {
+            to,
+            max_depth,
+            path: Vec::new(),
+            visited: HashSet::new(),
+            paths: Vec::new(),
+        };
+        search.visited.insert(from);
+        search.extend(self, from);
+
+        search.paths
+    }
+
+    /// Returns the predicate pairs `(p, q)` for which every triple `(s, p, o)` has a matching triple `(o, q, s)`.
+    ///
+    /// This is an observation about the current triples, not a
+    /// declared constraint, so a pair may stop being reported once
+    /// more triples are added. A predicate whose triples are all
+    /// mirrored by the same predicate, like a symmetric `knows`, is
+    /// reported as its own inverse. Predicates without triples are
+    /// left out. Pairs are sorted by `p` and then by `q`.
+    ///
+    /// The candidates for `q` are taken from the first triple of `p`,
+    /// and then narrowed down by every following triple, so memory
+    /// use is bounded by the number of predicates of a single node.
+    fn candidate_inverse_predicates(&self) -> Vec<(u64, u64)> {
+        let mut result = Vec::new();
+        for lookup in self.predicates() {
+            let mut triples = lookup.triples();
+            let first = match triples.next() {
+                None => continue,
+                Some(first) => first,
+            };
+
+            let mut candidates: Vec<u64> = match self.lookup_subject(first.object) {
+                // value objects never have triples of their own
+                None => continue,
+                Some(object) => object
+                    .predicates()
+                    .map(|p| p.predicate())
+                    .filter(|&q| self.triple_exists(first.object, q, first.subject))
+                    .collect(),
+            };
+            for triple in triples {
+                if candidates.is_empty() {
+                    break;
+                }
+                candidates.retain(|&q| self.triple_exists(triple.object, q, triple.subject));
+            }
+
+            candidates.sort_unstable();
+            let predicate = lookup.predicate();
+            result.extend(candidates.into_iter().map(|q| (predicate, q)));
+        }
+
+        result
+    }
+
+    /// Returns the predicates for which no subject has more than one object, in ascending order.
+    ///
+    /// These are candidates for functional properties. Predicates
+    /// without any triples are left out. Checking a predicate stops
+    /// at the first subject found with a second object.
+    fn functional_predicates(&self) -> Vec<u64> {
+        self.predicates()
+            .filter(|lookup| {
+                let mut has_triples = false;
+                for pair in lookup.subject_predicate_pairs() {
+                    let mut objects = pair.objects();
+                    if objects.next().is_some() {
+                        if objects.next().is_some() {
+                            return false;
+                        }
+                        has_triples = true;
+                    }
+                }
+
+                has_triples
+            })
+            .map(|lookup| lookup.predicate())
+            .collect()
+    }
+
+    /// Returns the distinct objects of the given predicate with the number of triples they occur in.
+    ///
+    /// The result is sorted by count in descending order, with ties
+    /// broken by ascending object id, and holds at most `limit`
+    /// entries. Only the triples of this predicate are read, through
+    /// the predicate index. An unknown predicate gives an empty
+    /// result.
+    fn predicate_object_histogram(&self, predicate: u64, limit: usize) -> Vec<(u64, usize)> {
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        if let Some(lookup) = self.lookup_predicate(predicate) {
+            for triple in lookup.triples() {
+                *counts.entry(triple.object).or_default() += 1;
+            }
+        }
+
+        let mut result: Vec<_> = counts.into_iter().collect();
+        result.sort_unstable_by_key(|&(object, count)| (Reverse(count), object));
+        result.truncate(limit);
+
+        result
+    }
+
+    /// Summarize the predicates of this layer, using `rdf:type` triples for the types of subjects.
+    ///
+    /// See `schema_with_type_predicate` for details.
+    fn schema(&self) -> SchemaSummary {
+        self.schema_with_type_predicate(RDF_TYPE)
+    }
+
+    /// Summarize the predicates of this layer, using the given predicate for the types of subjects.
+    ///
+    /// For every predicate with at least one triple, this collects
+    /// the types of its subjects and whether its objects are nodes,
+    /// values or both. Predicates are visited one at a time through
+    /// the predicate index, so memory use is bounded by the size of
+    /// the summary rather than the number of triples.
+    fn schema_with_type_predicate(&self, type_predicate: &str) -> SchemaSummary {
+        let type_id = self.predicate_id(type_predicate);
+        let mut type_names: HashMap<u64, Option<String>> = HashMap::new();
+        let mut summary = SchemaSummary::default();
+        for lookup in self.predicates() {
+            let mut schema = PredicateSchema::default();
+            let mut has_triples = false;
+            let mut last_subject = 0;
+            for triple in lookup.triples() {
+                has_triples = true;
+                if !(schema.node_objects && schema.value_objects) {
+                    if object_is_node(self, triple.object) {
+                        schema.node_objects = true;
+                    } else {
+                        schema.value_objects = true;
+                    }
+                }
+
+                // triples are sorted by subject, so this only looks up the types of each subject once
+                if triple.subject == last_subject {
+                    continue;
+                }
+                last_subject = triple.subject;
+
+                let types =
+                    type_id.and_then(|t| self.lookup_subject(triple.subject)?.lookup_predicate(t));
+                let mut typed = false;
+                for ty in types.iter().flat_map(|types| types.objects()) {
+                    let name = type_names
+                        .entry(ty)
+                        .or_insert_with(|| match self.id_object(ty) {
+                            Some(ObjectType::Node(name)) => Some(name),
+                            _ => None,
+                        });
+                    if let Some(name) = name {
+                        typed = true;
+                        if !schema.domain.contains(name) {
+                            schema.domain.insert(name.clone());
+                        }
+                    }
+                }
+                schema.untyped_subjects |= !typed;
+            }
+
+            if has_triples {
+                if let Some(name) = self.id_predicate(lookup.predicate()) {
+                    summary.predicates.insert(name, schema);
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Returns true if the given node is the object of at least one triple.
+    ///
+    /// This uses the object index, so it does not have to scan the
+    /// triples. A node that is used as a subject but is never
+    /// referenced as an object is a root of the graph.
+    fn is_referenced_as_object(&self, node: u64) -> bool {
+        self.lookup_object(node)
+            .map(|lookup| lookup.subject_predicate_pairs().next().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the given node is the subject of at least one triple.
+    ///
+    /// A node that is referenced as an object but is never used as a
+    /// subject has no description of its own, which may point to a
+    /// dangling reference.
+    fn is_used_as_subject(&self, node: u64) -> bool {
+        self.lookup_subject(node)
+            .map(|lookup| lookup.predicates().any(|p| p.objects().next().is_some()))
+            .unwrap_or(false)
+    }
+
+    /// Returns the node objects that are not the subject of any triple, in id order.
+    ///
+    /// These are the dangling references of the layer: nodes that
+    /// triples point to, but that have no description of their own.
+    /// Both the object and the subject index are walked in id order,
+    /// so this takes a single pass over each.
+    fn dangling_node_objects(&self) -> Vec<u64> {
+        let mut subjects = self
+            .subjects()
+            .filter(|s| s.predicates().any(|p| p.objects().next().is_some()))
+            .map(|s| s.subject())
+            .peekable();
+
+        let mut result = Vec::new();
+        for object in self.objects() {
+            let id = object.object();
+            if !object_is_node(self, id) || object.subject_predicate_pairs().next().is_none() {
+                continue;
+            }
+
+            while subjects.peek().map(|&s| s < id).unwrap_or(false) {
+                subjects.next();
+            }
+            if subjects.peek() != Some(&id) {
+                result.push(id);
+            }
+        }
+
+        result
+    }
+
+    /// Returns all triples with the given subject, resolved to strings.
+    ///
+    /// This returns None if the subject is not known to this layer,
+    /// and an empty vector if it is known but currently has no
+    /// triples. Triples are returned in predicate and object id order.
+    fn describe(&self, subject: &str) -> Option<Vec<StringTriple>> {
+        let subject = self.subject_id(subject)?;
+        let triples = match self.lookup_subject(subject) {
+            None => Vec::new(),
+            Some(lookup) => lookup
+                .predicates()
+                .flat_map(|p| p.triples())
+                .filter_map(|t| self.id_triple_to_string(&t))
+                .collect(),
+        };
+
+        Some(triples)
+    }
+
+    /// Describes the given subject as a nested JSON document.
+    ///
+    /// The result is an object with the subject under `@id`, and a
+    /// key for every predicate of the subject. A predicate with a
+    /// single object maps to that object, and one with several maps
+    /// to an array of them in object id order. Values become strings,
+    /// and node objects are described in the same way, up to
+    /// `max_depth` levels below the subject. Nodes beyond that depth,
+    /// and nodes that are already being described further up, which
+    /// would otherwise lead to a cycle, only get an object with their
+    /// `@id`. This returns `null` if the subject is not known to this
+    /// layer.
+    #[cfg(feature = "serde")]
+    fn describe_recursive(&self, subject: &str, max_depth: usize) -> serde_json::Value {
+        match self.subject_id(subject) {
+            None => serde_json::Value::Null,
+            Some(id) => describe_node(self, id, subject, max_depth, &mut HashSet::new()),
+        }
+    }
+
+    /// Returns the value object of the given subject and predicate, or None if there is none.
+    ///
+    /// Node objects are skipped. If the subject has more than one
+    /// value for the predicate, the one with the lowest id is
+    /// returned.
+    fn value_for(&self, subject: &str, predicate: &str) -> Option<String> {
+        let subject = self.subject_id(subject)?;
+        let predicate = self.predicate_id(predicate)?;
+        let lookup = self.lookup_subject(subject)?.lookup_predicate(predicate)?;
+
+        lookup
+            .objects()
+            .filter_map(|o| match self.id_object(o) {
+                Some(ObjectType::Value(value)) => Some(value),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Returns all objects of the given subject and predicate, resolved to strings.
+    ///
+    /// Objects are returned in order of their id. If the subject or
+    /// predicate is not known, or the pair has no objects, this
+    /// returns an empty vector.
+    fn objects_for(&self, subject: &str, predicate: &str) -> Vec<ObjectType> {
+        let lookup = self.subject_id(subject).and_then(|subject| {
+            self.predicate_id(predicate)
+                .and_then(|predicate| self.lookup_subject(subject)?.lookup_predicate(predicate))
+        });
+
+        match lookup {
+            None => Vec::new(),
+            Some(lookup) => lookup.objects().filter_map(|o| self.id_object(o)).collect(),
+        }
+    }
+
+    /// Iterator over all triples known to this layer, in (object, subject, predicate) order.
+    ///
+    /// This walks the object index, so unlike sorting the result of
+    /// `triples`, it does not need to hold all triples in memory.
+    fn triples_by_object(&self) -> Box<dyn Iterator<Item = IdTriple>> {
+        Box::new(self.objects().flat_map(|lookup| {
+            let object = lookup.object();
+            lookup
+                .subject_predicate_pairs()
+                .map(move |(subject, predicate)| IdTriple::new(subject, predicate, object))
+        }))
+    }
+
+    /// Returns all triples known to this layer as a sorted vector.
+    ///
+    /// The triples are in canonical (subject, predicate, object)
+    /// order, so the result can be binary searched. This walks the
+    /// layer stack once, after which lookups no longer have to go
+    /// through the parent layers, at the cost of holding every triple
+    /// in memory.
+    fn collect_triples(&self) -> Vec<IdTriple> {
+        self.reconciled_triples().collect()
+    }
+
+    /// Iterator over all triples with one of the given subjects, in sorted order.
+    ///
+    /// The subjects do not need to be sorted or unique. Subjects that
+    /// do not exist are ignored. This is the expand step of a graph
+    /// traversal, so layers may implement it as a single pass over
+    /// their indexes rather than doing a separate lookup per subject.
+    fn triples_for_subjects(&self, subjects: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        let mut subjects = subjects.to_vec();
+        subjects.sort();
+        subjects.dedup();
+
+        let lookups: Vec<_> = subjects
+            .into_iter()
+            .filter_map(|subject| self.lookup_subject(subject))
+            .collect();
+
+        Box::new(lookups.into_iter().flat_map(|lookup| lookup.triples()))
+    }
+
+    /// Returns up to `limit` triples that come strictly after the given triple.
+    ///
+    /// Triples are returned in canonical (subject, predicate, object)
+    /// order. If `after` is None, this starts at the first
+    /// triple. This is intended for keyset pagination, where the last
+    /// triple of a page is passed in to retrieve the next page.
+    fn triples_page(&self, after: Option<IdTriple>, limit: usize) -> Vec<IdTriple> {
+        let first_subject = after.map(|t| t.subject).unwrap_or(0);
+        let triples = self
+            .subjects()
+            .skip_while(move |s| s.subject() < first_subject)
+            .flat_map(|s| s.triples());
+
+        match after {
+            None => triples.take(limit).collect(),
+            Some(after) => triples
+                .skip_while(move |t| *t <= after)
+                .take(limit)
+                .collect(),
+        }
+    }
+
+    /// Returns up to `max_triples` live triples, resolved to strings.
+    ///
+    /// The triples are the first ones in canonical (subject,
+    /// predicate, object) order. This is meant for showing a small
+    /// sample of a layer, for example as a graph preview.
+    fn summary(&self, max_triples: usize) -> Vec<StringTriple> {
+        self.triples()
+            .take(max_triples)
+            .filter_map(|t| self.id_triple_to_string(&t))
+            .collect()
+    }
+
+    /// Returns the number of triples matching the given pattern.
+    ///
+    /// Each of subject, predicate and object can either be bound to
+    /// an id or left open with None. The most specific lookup
+    /// available for the bound parts is used, so that only the
+    /// matching part of the layer is traversed. Where a lookup knows
+    /// the length of its object list, that length is used instead of
+    /// walking the objects, and with nothing bound, this is
+    /// `triple_count`.
+    fn count_matching(
+        &self,
+        subject: Option<u64>,
+        predicate: Option<u64>,
+        object: Option<u64>,
+    ) -> usize {
+        match (subject, predicate, object) {
+            (None, None, None) => self.triple_count(),
+            _ => count_matching_with_lookups(self, subject, predicate, object),
+        }
+    }
+
+    /// Returns the number of triples that have the given subject.
+    ///
+    /// The objects are counted per predicate while walking the
+    /// adjacency lists, without collecting the triples.
+    fn subject_degree(&self, subject: u64) -> usize {
+        self.count_matching(Some(subject), None, None)
+    }
+
+    /// Returns the `n` subjects with the most triples, along with their number of triples.
+    ///
+    /// Subjects are ordered by descending degree, and by ascending id
+    /// among subjects with the same degree. Only the `n` best
+    /// subjects found so far are kept in memory while scanning.
+    fn top_subjects_by_degree(&self, n: usize) -> Vec<(u64, usize)> {
+        if n == 0 {
+            return Vec::new();
+        }
+
+        // a min-heap on (degree, reverse id), so the worst entry is on top
+        let mut heap: BinaryHeap<Reverse<(usize, Reverse<u64>)>> = BinaryHeap::new();
+        for lookup in self.subjects() {
+            let degree: usize = lookup.predicates().map(|p| p.objects().count()).sum();
+            if degree == 0 {
+                continue;
+            }
+
+            heap.push(Reverse((degree, Reverse(lookup.subject()))));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((degree, Reverse(subject)))| (subject, degree))
+            .collect()
+    }
+
+    /// Returns the ids and strings of all subjects that start with the given prefix, sorted by string.
+    ///
+    /// Dictionaries are sorted, so this only decodes the dictionary
+    /// entries sharing the prefix, rather than all of them. Results
+    /// are sorted with the collation of this layer. Nodes that
+    /// only occur as an object are left out. If a limit is given, at
+    /// most that many subjects are returned.
+    fn subjects_with_prefix(&self, prefix: &str, limit: Option<usize>) -> Vec<(u64, String)> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let offset = |parent: Option<&dyn Layer>| {
+            parent.map_or(0, |parent| parent.node_and_value_count() as u64)
+        };
+        let is_subject = |id: u64| {
+            self.lookup_subject(id)
+                .map(|s| s.triples().next().is_some())
+                .unwrap_or(false)
+        };
+
+        // every layer only knows about its own dictionary, so collect
+        // matching entries from each layer in the stack
+        let own_offset = offset(self.parent());
+        let mut result: Vec<_> = self
+            .node_dict_entries_with_prefix(prefix)
+            .map(|(id, s)| (1 + id + own_offset, s))
+            .filter(|(id, _)| is_subject(*id))
+            .take(limit)
+            .collect();
+        let mut current = self.parent();
+        while let Some(layer) = current {
+            let layer_offset = offset(layer.parent());
+            result.extend(
+                layer
+                    .node_dict_entries_with_prefix(prefix)
+                    .map(|(id, s)| (1 + id + layer_offset, s))
+                    .filter(|(id, _)| is_subject(*id))
+                    .take(limit),
+            );
+
+            current = layer.parent();
+        }
+
+        let collation = self.collation();
+        result.sort_by(|(_, s1), (_, s2)| collation.compare(s1, s2));
+        result.truncate(limit);
+
+        result
+    }
+
     /// Convert a `StringTriple` to an `IdTriple`, returning None if any of the strings in the triple could not be resolved.
     fn string_triple_to_id(&self, triple: &StringTriple) -> Option<IdTriple> {
         self.subject_id(&triple.subject).and_then(|subject| {
@@ -416,7 +1346,31 @@ pub trait Layer: Send + Sync {
         }
     }
 
+    /// Like `id_triple_to_string`, but reports a corrupt dictionary
+    /// entry as an `InvalidData` error rather than panicking.
+    fn id_triple_to_string_checked(&self, triple: &IdTriple) -> io::Result<Option<StringTriple>> {
+        let subject = match self.id_subject_checked(triple.subject)? {
+            Some(subject) => subject,
+            None => return Ok(None),
+        };
+        let predicate = match self.id_predicate_checked(triple.predicate)? {
+            Some(predicate) => predicate,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .id_object_checked(triple.object)?
+            .map(|object| StringTriple {
+                subject,
+                predicate,
+                object,
+            }))
+    }
+
     /// Convert an id triple to the corresponding string version, returning None if any of those ids could not be converted.
+    ///
+    /// This panics on a corrupt dictionary entry. See
+    /// `id_triple_to_string_checked`.
     fn id_triple_to_string(&self, triple: &IdTriple) -> Option<StringTriple> {
         self.id_subject(triple.subject).and_then(|subject| {
             self.id_predicate(triple.predicate).and_then(|predicate| {
@@ -429,28 +1383,473 @@ pub trait Layer: Send + Sync {
         })
     }
 
-    /// Returns true if the given layer is an ancestor of this layer, false otherwise.
-    fn is_ancestor_of(&self, other: &dyn Layer) -> bool {
-        match other.parent() {
-            None => false,
-            Some(parent) => parent.name() == self.name() || self.is_ancestor_of(&*parent),
+    /// The object corresponding to a numerical id, parsed as a floating point number.
+    ///
+    /// Returns None if the object cannot be found, is a node, or is a
+    /// value that does not parse as an f64. Only the lexical form of a
+    /// typed value is parsed, regardless of its datatype.
+    fn id_object_as_f64(&self, id: u64) -> Option<f64> {
+        match self.id_object(id)? {
+            ObjectType::Node(_) => None,
+            ObjectType::Value(value) => Literal::from_value_string(&value).lexical.parse().ok(),
         }
     }
-}
 
-pub struct LayerCounts {
+    /// The object corresponding to a numerical id, parsed as an integer.
+    ///
+    /// Returns None if the object cannot be found, is a node, or is a
+    /// value that does not parse as an i64. Only the lexical form of a
+    /// typed value is parsed, regardless of its datatype.
+    fn id_object_as_i64(&self, id: u64) -> Option<i64> {
+        match self.id_object(id)? {
+            ObjectType::Node(_) => None,
+            ObjectType::Value(value) => Literal::from_value_string(&value).lexical.parse().ok(),
+        }
+    }
+
+    /// Checks that the data of this layer and its ancestors is consistent.
+    ///
+    /// Every dictionary entry of every layer in the stack is decoded,
+    /// and every added or removed triple is checked to only refer to
+    /// ids that exist. This reads all of the layer data, so it can
+    /// take a while for large layers. The first problem found is
+    /// returned as an `InvalidData` error.
+    fn verify(&self) -> io::Result<()> {
+        for id in 0..self.node_dict_len() {
+            self.node_dict_get_checked(id)?;
+        }
+        for id in 0..self.predicate_dict_len() {
+            self.predicate_dict_get_checked(id)?;
+        }
+        for id in 0..self.value_dict_len() {
+            self.value_dict_get_checked(id)?;
+        }
+
+        let node_and_value_count = self.node_and_value_count() as u64;
+        let predicate_count = self.predicate_count() as u64;
+        let is_valid = |id: u64, count: u64| id != 0 && id <= count;
+        let triples = self
+            .subject_additions()
+            .chain(self.subject_removals())
+            .flat_map(|s| s.triples());
+        for triple in triples {
+            if !is_valid(triple.subject, node_and_value_count)
+                || !is_valid(triple.predicate, predicate_count)
+                || !is_valid(triple.object, node_and_value_count)
+            {
+                let name = self.name();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "triple {:?} of layer {:08x}{:08x}{:08x}{:08x}{:08x} refers to an id that does not exist",
+                        triple, name[0], name[1], name[2], name[3], name[4]
+                    ),
+                ));
+            }
+        }
+
+        match self.parent() {
+            None => Ok(()),
+            Some(parent) => parent.verify(),
+        }
+    }
+
+    /// Returns true if the given layer is an ancestor of this layer, false otherwise.
+    fn is_ancestor_of(&self, other: &dyn Layer) -> bool {
+        match other.parent() {
+            None => false,
+            Some(parent) => parent.name() == self.name() || self.is_ancestor_of(&*parent),
+        }
+    }
+
+    /// Returns the number of layers in this layer's chain, counting itself.
+    ///
+    /// A base layer has a depth of 1, and each child layer adds one to
+    /// the depth of its parent.
+    fn chain_depth(&self) -> usize {
+        let mut depth = 1;
+        let mut current = self.parent();
+        while let Some(parent) = current {
+            depth += 1;
+            current = parent.parent();
+        }
+
+        depth
+    }
+}
+
+pub struct LayerCounts {
     pub node_count: usize,
     pub predicate_count: usize,
     pub value_count: usize,
 }
 
+/// The IRI of the `rdf:type` predicate, which `Layer::schema` uses for the types of subjects.
+pub const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+
+/// A summary of the predicates used in a layer.
+///
+/// This is returned by `Layer::schema`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaSummary {
+    /// The schema of every predicate with at least one triple, by predicate name.
+    pub predicates: BTreeMap<String, PredicateSchema>,
+}
+
+/// How a single predicate is used in a layer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PredicateSchema {
+    /// The types of the subjects of this predicate.
+    pub domain: BTreeSet<String>,
+    /// Whether some subjects of this predicate have no type.
+    pub untyped_subjects: bool,
+    /// Whether some objects of this predicate are nodes.
+    pub node_objects: bool,
+    /// Whether some objects of this predicate are values.
+    pub value_objects: bool,
+}
+
+/// Statistics about the triples that are visible in a layer.
+///
+/// These are returned by `Layer::statistics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerStatistics {
+    /// The number of distinct subjects with at least one triple.
+    pub subjects: usize,
+    /// The number of distinct objects with at least one triple.
+    pub objects: usize,
+    /// The number of distinct predicates with at least one triple.
+    pub predicates: usize,
+    /// The total number of triples.
+    pub triples: usize,
+    /// The number of triples for each predicate id that has any.
+    pub predicate_triples: BTreeMap<u64, usize>,
+}
+
+/// A lazily filled, shareable slot for the statistics of a layer.
+#[derive(Clone, Default)]
+pub(crate) struct LayerStatisticsCache(Arc<Mutex<Option<Arc<LayerStatistics>>>>);
+
+impl LayerStatisticsCache {
+    /// Returns the cached statistics, computing them first if there are none yet.
+    pub(crate) fn get_or_compute<F: FnOnce() -> LayerStatistics>(
+        &self,
+        compute: F,
+    ) -> Arc<LayerStatistics> {
+        let mut statistics = self.0.lock().unwrap();
+        statistics
+            .get_or_insert_with(|| Arc::new(compute()))
+            .clone()
+    }
+}
+
+/// The sizes of the triple indexes of a single layer.
+///
+/// These are returned by `Layer::triple_addition_counts` and
+/// `Layer::triple_removal_counts`, and are used for estimates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LayerTripleCounts {
+    pub subjects: usize,
+    pub subject_predicate_pairs: usize,
+    pub triples: usize,
+}
+
+impl LayerTripleCounts {
+    fn add(&mut self, other: &LayerTripleCounts) {
+        self.subjects += other.subjects;
+        self.subject_predicate_pairs += other.subject_predicate_pairs;
+        self.triples += other.triples;
+    }
+
+    fn subtract(&mut self, other: &LayerTripleCounts) {
+        self.subjects = self.subjects.saturating_sub(other.subjects);
+        self.subject_predicate_pairs = self
+            .subject_predicate_pairs
+            .saturating_sub(other.subject_predicate_pairs);
+        self.triples = self.triples.saturating_sub(other.triples);
+    }
+}
+
+/// The state of a depth-first search for the paths in `Layer::find_paths`.
+struct PathSearch {
+    to: u64,
+    max_depth: usize,
+    path: Vec<IdTriple>,
+    visited: HashSet<u64>,
+    paths: Vec<Vec<IdTriple>>,
+}
+
+impl PathSearch {
+    /// Extend the current path, which ends in `node`, by every triple that has `node` as subject.
+    fn extend<L: Layer + ?Sized>(&mut self, layer: &L, node: u64) {
+        if self.path.len() >= self.max_depth {
+            return;
+        }
+        let lookup = match layer.lookup_subject(node) {
+            None => return,
+            Some(lookup) => lookup,
+        };
+
+        for triple in lookup.triples() {
+            if !self.visited.insert(triple.object) {
+                continue;
+            }
+
+            self.path.push(triple);
+            if triple.object == self.to {
+                self.paths.push(self.path.clone());
+            } else {
+                self.extend(layer, triple.object);
+            }
+            self.path.pop();
+            self.visited.remove(&triple.object);
+        }
+    }
+}
+
+/// Returns true if the given object id refers to a node, and false if it refers to a value.
+fn object_is_node<L: Layer + ?Sized>(layer: &L, id: u64) -> bool {
+    // Ids are assigned per layer: first the ids of the parent, then
+    // this layer's nodes, then this layer's values. Walk down the
+    // stack until we find the layer that assigned the id.
+    let mut count = layer.node_and_value_count() as u64;
+    let mut node_len = layer.node_dict_len() as u64;
+    let mut value_len = layer.value_dict_len() as u64;
+    let mut parent = layer.parent();
+    loop {
+        let parent_count = count - node_len - value_len;
+        match parent {
+            Some(p) if id <= parent_count => {
+                count = parent_count;
+                node_len = p.node_dict_len() as u64;
+                value_len = p.value_dict_len() as u64;
+                parent = p.parent();
+            }
+            _ => return id > parent_count && id - parent_count <= node_len,
+        }
+    }
+}
+
+/// Describe a node as a JSON document for `Layer::describe_recursive`.
+///
+/// `ancestors` holds the nodes that are being described further up,
+/// which are only referred to by their `@id`.
+#[cfg(feature = "serde")]
+fn describe_node<L: Layer + ?Sized>(
+    layer: &L,
+    node: u64,
+    name: &str,
+    depth: usize,
+    ancestors: &mut HashSet<u64>,
+) -> serde_json::Value {
+    let mut document = serde_json::Map::new();
+    document.insert("@id".to_owned(), name.into());
+    let lookup = match layer.lookup_subject(node) {
+        None => return document.into(),
+        Some(lookup) => lookup,
+    };
+
+    ancestors.insert(node);
+    for predicate_lookup in lookup.predicates() {
+        let predicate = match layer.id_predicate(predicate_lookup.predicate()) {
+            None => continue,
+            Some(predicate) => predicate,
+        };
+        let mut objects: Vec<serde_json::Value> = Vec::new();
+        for object in predicate_lookup.objects() {
+            let value = match layer.id_object(object) {
+                None => continue,
+                Some(ObjectType::Value(value)) => value.into(),
+                Some(ObjectType::Node(node)) => {
+                    if depth == 0 || ancestors.contains(&object) {
+                        serde_json::json!({ "@id": node })
+                    } else {
+                        describe_node(layer, object, &node, depth - 1, ancestors)
+                    }
+                }
+            };
+            objects.push(value);
+        }
+
+        let value = match objects.len() {
+            0 => continue,
+            1 => objects.pop().unwrap(),
+            _ => objects.into(),
+        };
+        document.insert(predicate, value);
+    }
+    ancestors.remove(&node);
+
+    document.into()
+}
+
+/// Sum the triple counts of the given layer and all its ancestors, taking removals into account.
+/// Count the triples matching a pattern through the lookups of a layer.
+///
+/// See `Layer::count_matching`. With nothing bound, this counts the
+/// triples one by one.
+pub(crate) fn count_matching_with_lookups<L: Layer + ?Sized>(
+    layer: &L,
+    subject: Option<u64>,
+    predicate: Option<u64>,
+    object: Option<u64>,
+) -> usize {
+    match (subject, predicate, object) {
+        (Some(s), Some(p), Some(o)) => layer.triple_exists(s, p, o) as usize,
+        (Some(s), Some(p), None) => layer
+            .lookup_subject(s)
+            .and_then(|s| s.lookup_predicate(p))
+            .map(|p| p.object_count())
+            .unwrap_or(0),
+        (Some(s), None, Some(o)) => layer
+            .lookup_subject(s)
+            .map(|s| s.predicates().filter(|p| p.has_object(o)).count())
+            .unwrap_or(0),
+        (Some(s), None, None) => layer
+            .lookup_subject(s)
+            .map(|s| s.predicates().map(|p| p.object_count()).sum())
+            .unwrap_or(0),
+        (None, Some(p), Some(o)) => layer
+            .lookup_object(o)
+            .map(|o| {
+                o.subject_predicate_pairs()
+                    .filter(|(_, predicate)| *predicate == p)
+                    .count()
+            })
+            .unwrap_or(0),
+        (None, Some(p), None) => layer
+            .lookup_predicate(p)
+            .map(|p| {
+                p.subject_predicate_pairs()
+                    .map(|sp| sp.object_count())
+                    .sum()
+            })
+            .unwrap_or(0),
+        (None, None, Some(o)) => layer
+            .lookup_object(o)
+            .map(|o| o.subject_predicate_pairs().count())
+            .unwrap_or(0),
+        (None, None, None) => layer.triples().count(),
+    }
+}
+
+pub(crate) fn stack_triple_counts(layer: &dyn Layer) -> LayerTripleCounts {
+    let mut layers = Vec::new();
+    let mut current = Some(layer);
+    while let Some(layer) = current {
+        layers.push(layer);
+        current = layer.parent();
+    }
+
+    let mut counts = LayerTripleCounts::default();
+    for layer in layers.into_iter().rev() {
+        counts.add(&layer.triple_addition_counts());
+        counts.subtract(&layer.triple_removal_counts());
+    }
+
+    counts
+}
+
+/// Sum the number of subject-predicate pairs with the given predicate in the given layer and all its ancestors.
+pub(crate) fn stack_predicate_count(layer: &dyn Layer, predicate: u64) -> usize {
+    let mut layers = Vec::new();
+    let mut current = Some(layer);
+    while let Some(layer) = current {
+        layers.push(layer);
+        current = layer.parent();
+    }
+
+    layers.into_iter().rev().fold(0, |count, layer| {
+        (count + layer.predicate_addition_count(predicate))
+            .saturating_sub(layer.predicate_removal_count(predicate))
+    })
+}
+
 /// The type of a layer - either base or child.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LayerType {
     Base,
     Child,
 }
 
+/// The adjacency lists of a layer, borrowed straight from its memory maps.
+///
+/// This gives direct access to the sorted id arrays of a layer,
+/// without going through the boxed lookup iterators. Each list
+/// consists of a bit-packed `LogArray` of right-hand ids, and a
+/// `BitIndex` marking the last entry for each left-hand side. The
+/// packed data can be read directly through `nums().data()`,
+/// `nums().width()` and `bits().bitarray().bits()`.
+///
+/// In a base layer, the lists are indexed by subject and object id
+/// directly. In a child layer, they are indexed by position in
+/// `subjects` and `objects` instead, counting from 1. In both, the
+/// entries of `o_ps` are positions of (subject, predicate) pairs in
+/// `s_p`, again counting from 1.
+///
+/// Nothing here is checked against the rest of the layer, so code
+/// using this view is responsible for following these rules.
+pub struct LayerAdjacencyLists<'a, M: AsRef<[u8]> + Clone> {
+    /// The subject ids of a child layer. None for a base layer.
+    pub subjects: Option<&'a MonotonicLogArray<M>>,
+    /// The object ids of a child layer. None for a base layer.
+    pub objects: Option<&'a MonotonicLogArray<M>>,
+    /// Subjects to their predicates.
+    pub s_p: &'a AdjacencyList<M>,
+    /// (subject, predicate) pairs to their objects.
+    pub sp_o: &'a AdjacencyList<M>,
+    /// Objects to their (subject, predicate) pairs.
+    pub o_ps: &'a AdjacencyList<M>,
+}
+
+/// A hint on how the triples of a layer are about to be accessed.
+///
+/// Hints are passed on to the operating system for layers that are
+/// memory-mapped from disk. They never change any results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanHint {
+    /// No particular access pattern. This undoes any earlier hint.
+    Normal,
+    /// Triples are about to be scanned in order, so data should be read ahead.
+    Sequential,
+}
+
+impl ScanHint {
+    /// Advise the operating system on how the given memory will be accessed.
+    ///
+    /// This only has an effect on memory that starts at a page
+    /// boundary, which is always the case for memory maps. Other
+    /// memory is left alone.
+    #[cfg(unix)]
+    pub(crate) fn advise(self, data: &[u8]) {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        let address = data.as_ptr() as usize;
+        // page sizes are powers of two
+        if data.is_empty() || page_size <= 0 || address & (page_size as usize - 1) != 0 {
+            return;
+        }
+
+        let advice = match self {
+            ScanHint::Normal => libc::MADV_NORMAL,
+            ScanHint::Sequential => libc::MADV_SEQUENTIAL,
+        };
+
+        // the advice is only a hint, so failing to give it is not an error
+        unsafe {
+            libc::madvise(address as *mut libc::c_void, data.len(), advice);
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn advise(self, _data: &[u8]) {}
+
+    /// Advise the operating system on how the given adjacency list will be accessed.
+    pub(crate) fn advise_adjacency_list<M: AsRef<[u8]> + Clone>(self, list: &AdjacencyList<M>) {
+        self.advise(list.nums().data());
+        self.advise(list.bits().bitarray().bits());
+    }
+}
+
 struct GenericSubjectIterator {
     layers: Vec<(
         Peekable<Box<dyn Iterator<Item = Box<dyn LayerSubjectLookup>>>>,
@@ -512,6 +1911,89 @@ impl Iterator for GenericSubjectIterator {
     }
 }
 
+/// Merges the sorted additions and removals of every layer in a stack into the sorted triples of the top layer.
+pub(crate) struct ReconciledTripleIterator {
+    // additions and removals of each layer, alternating, starting at the bottom of the stack
+    iters: Vec<Box<dyn Iterator<Item = IdTriple>>>,
+    // the next triple of every iterator that is not exhausted yet, along with its index
+    heap: BinaryHeap<Reverse<(IdTriple, usize)>>,
+}
+
+impl ReconciledTripleIterator {
+    pub(crate) fn new(mut iters: Vec<Box<dyn Iterator<Item = IdTriple>>>) -> Self {
+        let heap = iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, iter)| iter.next().map(|t| Reverse((t, i))))
+            .collect();
+
+        ReconciledTripleIterator { iters, heap }
+    }
+
+    fn advance(&mut self, i: usize) {
+        if let Some(next) = self.iters[i].next() {
+            self.heap.push(Reverse((next, i)));
+        }
+    }
+}
+
+impl Iterator for ReconciledTripleIterator {
+    type Item = IdTriple;
+
+    fn next(&mut self) -> Option<IdTriple> {
+        loop {
+            let Reverse((triple, mut top)) = self.heap.pop()?;
+            self.advance(top);
+            while let Some(&Reverse((next, i))) = self.heap.peek() {
+                if next != triple {
+                    break;
+                }
+
+                self.heap.pop();
+                self.advance(i);
+                top = top.max(i);
+            }
+
+            // even indexes are additions, odd indexes are removals
+            if top % 2 == 0 {
+                return Some(triple);
+            }
+        }
+    }
+}
+
+/// Merges several sorted triple iterators into a single sorted iterator.
+struct MergedTripleIterator {
+    iters: Vec<Box<dyn Iterator<Item = IdTriple>>>,
+    // the next triple of every iterator that is not exhausted yet, along with its index
+    heap: BinaryHeap<Reverse<(IdTriple, usize)>>,
+}
+
+impl MergedTripleIterator {
+    fn new(mut iters: Vec<Box<dyn Iterator<Item = IdTriple>>>) -> Self {
+        let heap = iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, iter)| iter.next().map(|t| Reverse((t, i))))
+            .collect();
+
+        MergedTripleIterator { iters, heap }
+    }
+}
+
+impl Iterator for MergedTripleIterator {
+    type Item = IdTriple;
+
+    fn next(&mut self) -> Option<IdTriple> {
+        let Reverse((triple, i)) = self.heap.pop()?;
+        if let Some(next) = self.iters[i].next() {
+            self.heap.push(Reverse((next, i)));
+        }
+
+        Some(triple)
+    }
+}
+
 /// A trait that caches a lookup in a layer by subject, but only for that layer and not its parents.
 ///
 /// This is returned by `Layer::subjects` and
@@ -550,6 +2032,14 @@ pub trait LayerSubjectPredicateLookup {
     /// Returns true if the given object exists, and false otherwise.
     fn has_object(&self, object: u64) -> bool;
 
+    /// Returns the number of objects that can be found by this lookup.
+    ///
+    /// Lookups backed by an adjacency list return its length without
+    /// walking the objects.
+    fn object_count(&self) -> usize {
+        self.objects().count()
+    }
+
     /// Returns an iterator over all triples that can be found by this lookup.
     fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
         let subject = self.subject();
@@ -755,6 +2245,11 @@ pub trait SubjectPredicateLookup {
     /// Returns true if the given object exists, and false otherwise.
     fn has_object(&self, object: u64) -> bool;
 
+    /// Returns the number of objects that can be found by this lookup.
+    fn object_count(&self) -> usize {
+        self.objects().count()
+    }
+
     /// Returns an iterator over all triples that can be found by this lookup.
     fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
         let subject = self.subject();
@@ -842,6 +2337,14 @@ impl SubjectPredicateLookup for GenericSubjectPredicateLookup {
 
         false
     }
+
+    fn object_count(&self) -> usize {
+        match &self.lookups[..] {
+            // only a single layer knows about this pair, so nothing needs to be reconciled
+            [(Some(pos), None)] => pos.object_count(),
+            _ => self.objects().count(),
+        }
+    }
 }
 
 struct GenericSubjectPredicateObjectIterator {
@@ -1156,7 +2659,7 @@ pub trait PredicateLookup {
     fn predicate(&self) -> u64;
     fn subject_predicate_pairs(&self) -> Box<dyn Iterator<Item = Box<dyn SubjectPredicateLookup>>>;
 
-    /// Returns an iterator over all triples with the object of this lookup.
+    /// Returns an iterator over all triples with the predicate of this lookup.
     fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
         Box::new(
             self.subject_predicate_pairs()
@@ -1164,6 +2667,21 @@ pub trait PredicateLookup {
                 .flatten(),
         )
     }
+
+    /// Returns an iterator over the subject and object of every triple with the predicate of this lookup.
+    ///
+    /// Pairs are returned in subject and object order.
+    fn subject_object_pairs(&self) -> Box<dyn Iterator<Item = (u64, u64)>> {
+        Box::new(self.triples().map(|t| (t.subject, t.object)))
+    }
+
+    /// Returns true if there is a triple with the given subject and object, and the predicate of this lookup.
+    fn has_subject_object_pair(&self, subject: u64, object: u64) -> bool {
+        self.subject_predicate_pairs()
+            .find(|sp| sp.subject() >= subject)
+            .map(|sp| sp.subject() == subject && sp.has_object(object))
+            .unwrap_or(false)
+    }
 }
 
 struct GenericPredicateLookup {
@@ -1256,8 +2774,60 @@ impl Iterator for GenericSubjectPredicatePairIterator {
     }
 }
 
+/// A subject found in two layers, along with its lookup in each.
+pub type SubjectJoin = (u64, Box<dyn SubjectLookup>, Box<dyn SubjectLookup>);
+
+/// Join two layers on subject id.
+///
+/// This returns every subject that both layers return from
+/// `subjects()`, in ascending order, along with the lookup for that
+/// subject in each layer. It is done in a single linear pass over both
+/// subject iterators.
+///
+/// Subject ids only mean the same thing in two layers if one of the
+/// layers is built on top of the other, or if both are built on top of
+/// a common ancestor which assigned the id. Joining unrelated layers
+/// will match subjects by id rather than by name.
+pub fn merge_join_subjects(a: &dyn Layer, b: &dyn Layer) -> Box<dyn Iterator<Item = SubjectJoin>> {
+    Box::new(MergeJoinSubjects {
+        a: a.subjects().peekable(),
+        b: b.subjects().peekable(),
+    })
+}
+
+struct MergeJoinSubjects {
+    a: Peekable<Box<dyn Iterator<Item = Box<dyn SubjectLookup>>>>,
+    b: Peekable<Box<dyn Iterator<Item = Box<dyn SubjectLookup>>>>,
+}
+
+impl Iterator for MergeJoinSubjects {
+    type Item = SubjectJoin;
+
+    fn next(&mut self) -> Option<SubjectJoin> {
+        loop {
+            let a_subject = self.a.peek()?.subject();
+            let b_subject = self.b.peek()?.subject();
+
+            match a_subject.cmp(&b_subject) {
+                Ordering::Less => {
+                    self.a.next();
+                }
+                Ordering::Greater => {
+                    self.b.next();
+                }
+                Ordering::Equal => {
+                    let a = self.a.next().unwrap();
+                    let b = self.b.next().unwrap();
+                    return Some((a_subject, a, b));
+                }
+            }
+        }
+    }
+}
+
 /// A triple, stored as numerical ids.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IdTriple {
     pub subject: u64,
     pub predicate: u64,
@@ -1286,6 +2856,7 @@ impl IdTriple {
 
 /// A triple stored as strings.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StringTriple {
     pub subject: String,
     pub predicate: String,
@@ -1315,6 +2886,34 @@ impl StringTriple {
         }
     }
 
+    /// Construct a triple with a value object that has the given datatype IRI.
+    pub fn new_typed_value(
+        subject: &str,
+        predicate: &str,
+        lexical: &str,
+        datatype: &str,
+    ) -> StringTriple {
+        StringTriple {
+            subject: subject.to_owned(),
+            predicate: predicate.to_owned(),
+            object: ObjectType::typed_value(lexical, datatype),
+        }
+    }
+
+    /// Construct a triple with a value object that has the given language tag.
+    pub fn new_lang_value(
+        subject: &str,
+        predicate: &str,
+        lexical: &str,
+        language: &str,
+    ) -> StringTriple {
+        StringTriple {
+            subject: subject.to_owned(),
+            predicate: predicate.to_owned(),
+            object: ObjectType::lang_value(lexical, language),
+        }
+    }
+
     /// Convert this triple to a `PartiallyResolvedTriple`, marking each field as unresolved.
     pub fn to_unresolved(&self) -> PartiallyResolvedTriple {
         PartiallyResolvedTriple {
@@ -1323,6 +2922,24 @@ impl StringTriple {
             object: PossiblyResolved::Unresolved(self.object.clone()),
         }
     }
+
+    /// Returns true if this triple is equal to the other triple, treating a node and a value with the same string as equal.
+    ///
+    /// The `PartialEq` implementation does distinguish between nodes
+    /// and values. This is for comparing data from sources that do
+    /// not keep track of that distinction.
+    pub fn eq_ignoring_object_type(&self, other: &StringTriple) -> bool {
+        self.without_object_type() == other.without_object_type()
+    }
+
+    /// Returns the subject, predicate and object strings of this triple, dropping whether the object is a node or a value.
+    ///
+    /// Two triples that are equal according to
+    /// `eq_ignoring_object_type` return the same tuple, which makes
+    /// this usable as a key for deduplication.
+    pub fn without_object_type(&self) -> (&str, &str, &str) {
+        (&self.subject, &self.predicate, self.object.as_str())
+    }
 }
 
 /// Either a resolved id or an unresolved inner type.
@@ -1414,16 +3031,162 @@ impl PartiallyResolvedTriple {
 /// node or a value, and will return this information in queries. It
 /// is possible to have the same string appear both as a node and a
 /// value, without this leading to conflicts.
+///
+/// With the `serde` feature enabled, an object serializes with a tag
+/// telling nodes and values apart, like `{"type":"node","value":"cow"}`.
 #[derive(Debug, Clone, PartialOrd, PartialEq, Eq, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "lowercase")
+)]
 pub enum ObjectType {
     Node(String),
     Value(String),
 }
 
+impl ObjectType {
+    /// Returns the string of this object, regardless of whether it is a node or a value.
+    ///
+    /// For a typed value or a value with a language tag, this is the
+    /// encoded value string. See `Literal`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ObjectType::Node(node) => node,
+            ObjectType::Value(value) => value,
+        }
+    }
+
+    /// Construct a value object with the given datatype IRI.
+    pub fn typed_value(lexical: &str, datatype: &str) -> ObjectType {
+        ObjectType::Value(Literal::typed(lexical, datatype).to_value_string())
+    }
+
+    /// Construct a value object with the given language tag.
+    pub fn lang_value(lexical: &str, language: &str) -> ObjectType {
+        ObjectType::Value(Literal::with_language(lexical, language).to_value_string())
+    }
+
+    /// Returns the literal of a value object, or None for a node.
+    pub fn literal(&self) -> Option<Literal> {
+        match self {
+            ObjectType::Node(_) => None,
+            ObjectType::Value(value) => Some(Literal::from_value_string(value)),
+        }
+    }
+}
+
+/// A literal value, with an optional datatype IRI or language tag.
+///
+/// Dictionaries only store strings, so the datatype or language tag
+/// of a literal is encoded into its value string. A plain literal is
+/// stored as its lexical form, just like any other value. A typed
+/// literal is stored as a U+0001 marker character, `^`, the
+/// datatype, another marker and the lexical form. A literal with a
+/// language tag is stored the same way, with `@` and the language tag
+/// instead. A plain literal that starts with a marker is stored with
+/// two more markers in front, so that it cannot be confused with an
+/// encoded literal.
+///
+/// As a result, the same lexical form with different datatypes or
+/// language tags gets different ids, and every value string decodes
+/// to exactly one literal. Values stored before literals were
+/// encoded keep their meaning, unless they start with a marker and
+/// happen to look like an encoded literal. Datatypes and language
+/// tags cannot contain the marker.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Literal {
+    pub lexical: String,
+    pub datatype: Option<String>,
+    pub language: Option<String>,
+}
+
+impl Literal {
+    /// Construct a literal without datatype or language tag.
+    pub fn plain(lexical: &str) -> Literal {
+        Literal {
+            lexical: lexical.to_owned(),
+            datatype: None,
+            language: None,
+        }
+    }
+
+    /// Construct a literal with the given datatype IRI.
+    pub fn typed(lexical: &str, datatype: &str) -> Literal {
+        Literal {
+            datatype: Some(datatype.to_owned()),
+            ..Literal::plain(lexical)
+        }
+    }
+
+    /// Construct a literal with the given language tag.
+    pub fn with_language(lexical: &str, language: &str) -> Literal {
+        Literal {
+            language: Some(language.to_owned()),
+            ..Literal::plain(lexical)
+        }
+    }
+
+    /// Encode this literal as the value string stored in a dictionary.
+    ///
+    /// If both a datatype and a language tag are set, the datatype
+    /// wins, as RDF only allows a language tag on language-tagged
+    /// strings.
+    pub fn to_value_string(&self) -> String {
+        let (tag, annotation) = match (&self.datatype, &self.language) {
+            (Some(datatype), _) => ('^', datatype.as_str()),
+            (None, Some(language)) => ('@', language.as_str()),
+            (None, None) if !self.lexical.starts_with(LITERAL_MARKER) => {
+                return self.lexical.clone()
+            }
+            (None, None) => return format!("{}{}{}", LITERAL_MARKER, LITERAL_MARKER, self.lexical),
+        };
+
+        format!(
+            "{}{}{}{}{}",
+            LITERAL_MARKER, tag, annotation, LITERAL_MARKER, self.lexical
+        )
+    }
+
+    /// Decode a value string stored in a dictionary.
+    ///
+    /// A value string that is not an encoded literal is returned as a
+    /// plain literal.
+    pub fn from_value_string(value: &str) -> Literal {
+        Self::decode(value).unwrap_or_else(|| Literal::plain(value))
+    }
+
+    fn decode(value: &str) -> Option<Literal> {
+        let rest = value.strip_prefix(LITERAL_MARKER)?;
+        if let Some(lexical) = rest.strip_prefix(LITERAL_MARKER) {
+            return Some(Literal::plain(lexical));
+        }
+
+        let mut chars = rest.chars();
+        let tag = chars.next()?;
+        let (annotation, lexical) = chars.as_str().split_once(LITERAL_MARKER)?;
+        match tag {
+            '^' if !annotation.is_empty() => Some(Literal::typed(lexical, annotation)),
+            '@' if !annotation.is_empty()
+                && annotation
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+            {
+                Some(Literal::with_language(lexical, annotation))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The character that starts the value string of an encoded literal.
+const LITERAL_MARKER: char = '\u{1}';
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::base::tests::{base_layer_files, example_base_layer};
     use crate::layer::base::BaseLayer;
     use crate::layer::builder::{LayerBuilder, SimpleLayerBuilder};
     use crate::layer::child::tests::child_layer_files;
@@ -1431,6 +3194,1555 @@ mod tests {
     use futures::prelude::*;
     use std::sync::Arc;
 
+    #[test]
+    fn encode_and_decode_literals() {
+        let literals = vec![
+            Literal::plain("moo"),
+            Literal::plain("\"quoted\" moo"),
+            Literal::plain("\u{1}starts with a marker"),
+            Literal::plain("\"a\"@en"),
+            Literal::typed("42", "xsd:integer"),
+            Literal::typed("say \"\\moo\u{1}\"", "xsd:string"),
+            Literal::with_language("koe", "nl-BE"),
+        ];
+        for literal in literals {
+            let value = literal.to_value_string();
+            assert_eq!(literal, Literal::from_value_string(&value));
+        }
+
+        // plain values keep their meaning, even if they look like literals
+        assert_eq!("moo", Literal::plain("moo").to_value_string());
+        assert_eq!("\"moo\"", Literal::plain("\"moo\"").to_value_string());
+        assert_eq!("\"a\"@en", Literal::plain("\"a\"@en").to_value_string());
+        assert_eq!(
+            "\u{1}^xsd:integer\u{1}42",
+            Literal::typed("42", "xsd:integer").to_value_string()
+        );
+        // values that do not decode are plain literals
+        for value in &[
+            "\"a\"@en",
+            "\"x\"^^xsd:integer",
+            "\u{1}",
+            "\u{1}^no lexical form",
+            "\u{1}@\u{1}moo",
+            "\u{1}@n l\u{1}moo",
+            "\u{1}?x\u{1}moo",
+        ] {
+            assert_eq!(Literal::plain(value), Literal::from_value_string(value));
+        }
+    }
+
+    #[test]
+    fn typed_values_get_their_own_ids() {
+        let integer = StringTriple::new_typed_value("x", "p", "42", "xsd:integer");
+        let string = StringTriple::new_typed_value("x", "p", "42", "xsd:string");
+        let dutch = StringTriple::new_lang_value("x", "p", "42", "nl");
+        let plain = StringTriple::new_value("x", "p", "42");
+
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        for triple in &[&integer, &string, &dutch, &plain] {
+            builder.add_string_triple(triple);
+        }
+        builder.commit().wait().unwrap();
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let mut ids: Vec<_> = [&integer, &string, &dutch, &plain]
+            .iter()
+            .map(|t| layer.string_triple_to_id(t).unwrap().object)
+            .collect();
+        let object = layer.id_object(ids[0]).unwrap();
+        assert_eq!(integer.object, object);
+        assert_eq!(Some(Literal::typed("42", "xsd:integer")), object.literal());
+        assert_eq!(Some(42), layer.id_object_as_i64(ids[0]));
+
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(4, ids.len());
+    }
+
+    #[test]
+    fn compare_triples_ignoring_object_type() {
+        let node = StringTriple::new_node("cow", "likes", "duck");
+        let value = StringTriple::new_value("cow", "likes", "duck");
+
+        assert_ne!(node, value);
+        assert!(node.eq_ignoring_object_type(&value));
+        assert!(!node.eq_ignoring_object_type(&StringTriple::new_value("cow", "likes", "pig")));
+        assert_eq!(("cow", "likes", "duck"), value.without_object_type());
+    }
+
+    #[test]
+    fn summarize_layer_as_string_triples() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+        let base = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                StringTriple::new_node("cow", "likes", "pig"),
+                StringTriple::new_value("cow", "says", "moo"),
+            ],
+            base.summary(2)
+        );
+        assert_eq!(4, base.summary(10).len());
+        assert!(base.summary(0).is_empty());
+    }
+
+    #[test]
+    fn paginate_triples() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.commit().wait().unwrap();
+
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base, &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let all: Vec<_> = child.triples().collect();
+        assert_eq!(3, all.len());
+
+        let page1 = child.triples_page(None, 2);
+        assert_eq!(&all[0..2], &page1[..]);
+
+        let page2 = child.triples_page(Some(page1[1]), 2);
+        assert_eq!(&all[2..], &page2[..]);
+
+        let page3 = child.triples_page(Some(page2[0]), 2);
+        assert!(page3.is_empty());
+    }
+
+    #[test]
+    fn object_has_subject_predicate_after_removal() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.commit().wait().unwrap();
+
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base, &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let cow = child.subject_id("cow").unwrap();
+        let pig = child.subject_id("pig").unwrap();
+        let likes = child.predicate_id("likes").unwrap();
+        let duck = child.object_node_id("duck").unwrap();
+
+        assert!(!child.object_has_subject_predicate(duck, cow, likes));
+        assert!(child.object_has_subject_predicate(duck, pig, likes));
+        assert!(!child.object_has_subject_predicate(cow, pig, likes));
+    }
+
+    #[test]
+    fn parse_objects_as_numbers() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "weight", "650.5"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "legs", "4"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "42"));
+
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let weight = layer.object_value_id("650.5").unwrap();
+        let legs = layer.object_value_id("4").unwrap();
+        let moo = layer.object_value_id("moo").unwrap();
+        let node = layer.object_node_id("42").unwrap();
+
+        assert_eq!(Some(650.5), layer.id_object_as_f64(weight));
+        assert_eq!(None, layer.id_object_as_i64(weight));
+        assert_eq!(Some(4.0), layer.id_object_as_f64(legs));
+        assert_eq!(Some(4), layer.id_object_as_i64(legs));
+        assert_eq!(None, layer.id_object_as_f64(moo));
+        assert_eq!(None, layer.id_object_as_i64(moo));
+        assert_eq!(None, layer.id_object_as_f64(node));
+        assert_eq!(None, layer.id_object_as_i64(node));
+        assert_eq!(None, layer.id_object_as_i64(1000));
+    }
+
+    #[test]
+    fn find_shared_predicates() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "eats", "grass"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "eats", "everything"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("pig", "eats", "everything"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let cow = base.subject_id("cow").unwrap();
+        let pig = base.subject_id("pig").unwrap();
+        let says = base.predicate_id("says").unwrap();
+        let eats = base.predicate_id("eats").unwrap();
+        let mut expected = vec![says, eats];
+        expected.sort();
+
+        assert_eq!(expected, base.shared_predicates(cow, pig));
+        assert_eq!(vec![says], child.shared_predicates(cow, pig));
+        assert!(base.shared_predicates(cow, 1000).is_empty());
+    }
+
+    #[test]
+    fn compute_and_cache_statistics() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("duck", "likes", "pig"));
+        builder.remove_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let likes = child.predicate_id("likes").unwrap();
+        let says = child.predicate_id("says").unwrap();
+
+        let statistics = base.statistics();
+        assert_eq!(2, statistics.subjects);
+        assert_eq!(4, statistics.objects);
+        assert_eq!(2, statistics.predicates);
+        assert_eq!(5, statistics.triples);
+        assert_eq!(Some(&3), statistics.predicate_triples.get(&likes));
+
+        let statistics = child.statistics();
+        assert_eq!(2, statistics.subjects);
+        assert_eq!(4, statistics.objects);
+        assert_eq!(2, statistics.predicates);
+        assert_eq!(4, statistics.triples);
+        assert_eq!(Some(&2), statistics.predicate_triples.get(&likes));
+        assert_eq!(Some(&2), statistics.predicate_triples.get(&says));
+        assert_eq!(*statistics, child.compute_statistics());
+        assert!(Arc::ptr_eq(&statistics, &child.statistics()));
+    }
+
+    #[test]
+    fn iterate_triples_by_object() {
+        let base = example_base_layer();
+        let files = child_layer_files();
+        let mut builder = SimpleLayerBuilder::from_parent(
+            [5, 4, 3, 2, 1],
+            Arc::new(base.clone()) as Arc<dyn Layer>,
+            files.clone(),
+        );
+        builder.remove_id_triple(IdTriple::new(2, 3, 6));
+        builder.add_string_triple(&StringTriple::new_node("goose", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("goose", "says", "honk"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], Arc::new(base), &files)
+            .wait()
+            .unwrap();
+
+        let mut expected: Vec<_> = child.triples().collect();
+        expected.sort_unstable_by_key(|t| (t.object, t.subject, t.predicate));
+        let result: Vec<_> = child.triples_by_object().collect();
+
+        assert_eq!(expected, result);
+        assert!(!result.contains(&IdTriple::new(2, 3, 6)));
+        assert!(result.contains(&IdTriple::new(3, 3, 6)));
+    }
+
+    #[test]
+    fn find_functional_predicates() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "mooo"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "name", "bessie"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "name", "wilbur"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "mooo"));
+        builder.remove_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "name", "babe"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let says = base.predicate_id("says").unwrap();
+        let name = base.predicate_id("name").unwrap();
+        let likes = base.predicate_id("likes").unwrap();
+
+        let mut expected = vec![name, likes];
+        expected.sort_unstable();
+        assert_eq!(expected, base.functional_predicates());
+        assert_eq!(vec![says], child.functional_predicates());
+    }
+
+    #[test]
+    fn find_roots_and_referenced_nodes() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("horse", "likes", "cow"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("horse", "likes", "cow"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let cow = base.subject_id("cow").unwrap();
+        let pig = base.subject_id("pig").unwrap();
+        let duck = base.object_node_id("duck").unwrap();
+        let horse = base.subject_id("horse").unwrap();
+
+        assert!(base.is_referenced_as_object(cow));
+        assert!(base.is_used_as_subject(horse));
+        assert!(!base.is_referenced_as_object(horse));
+        assert!(base.is_referenced_as_object(duck));
+        assert!(!base.is_used_as_subject(duck));
+        assert!(base.is_referenced_as_object(pig) && base.is_used_as_subject(pig));
+
+        assert!(!child.is_referenced_as_object(cow));
+        assert!(child.is_used_as_subject(cow));
+        assert!(!child.is_used_as_subject(horse));
+    }
+
+    #[test]
+    fn lookup_subject_object_pairs_by_predicate() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cat", "subclass_of", "mammal"));
+        builder.add_string_triple(&StringTriple::new_node("dog", "subclass_of", "mammal"));
+        builder.add_string_triple(&StringTriple::new_node("mammal", "subclass_of", "animal"));
+        builder.add_string_triple(&StringTriple::new_value("cat", "says", "meow"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("dog", "subclass_of", "mammal"));
+        builder.add_string_triple(&StringTriple::new_node("bird", "subclass_of", "animal"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let node = |name| child.subject_id(name).unwrap();
+        let subclass_of = child.predicate_id("subclass_of").unwrap();
+        let lookup = child.lookup_predicate(subclass_of).unwrap();
+        let mut expected = vec![
+            (node("cat"), node("mammal")),
+            (node("mammal"), node("animal")),
+            (node("bird"), node("animal")),
+        ];
+        expected.sort_unstable();
+        assert_eq!(expected, lookup.subject_object_pairs().collect::<Vec<_>>());
+
+        assert!(lookup.has_subject_object_pair(node("cat"), node("mammal")));
+        assert!(lookup.has_subject_object_pair(node("bird"), node("animal")));
+        assert!(!lookup.has_subject_object_pair(node("dog"), node("mammal")));
+        assert!(!lookup.has_subject_object_pair(node("cat"), node("animal")));
+        assert!(base
+            .lookup_predicate(subclass_of)
+            .unwrap()
+            .has_subject_object_pair(node("dog"), node("mammal")));
+    }
+
+    #[test]
+    fn find_dangling_node_objects() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "goose"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let duck = base.object_node_id("duck").unwrap();
+        assert_eq!(vec![duck], base.dangling_node_objects());
+
+        let pig = child.object_node_id("pig").unwrap();
+        let goose = child.object_node_id("goose").unwrap();
+        let mut expected = vec![pig, goose];
+        expected.sort_unstable();
+        assert_eq!(expected, child.dangling_node_objects());
+    }
+
+    #[test]
+    fn chain_depth_counts_ancestors() {
+        let base: Arc<dyn Layer> = Arc::new(example_base_layer());
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([0, 0, 0, 0, 2], base.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.commit().wait().unwrap();
+        let child: Arc<dyn Layer> = Arc::new(
+            ChildLayer::load_from_files([0, 0, 0, 0, 2], base.clone(), &files)
+                .wait()
+                .unwrap(),
+        );
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([0, 0, 0, 0, 3], child.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+        let grandchild = ChildLayer::load_from_files([0, 0, 0, 0, 3], child.clone(), &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(1, base.chain_depth());
+        assert_eq!(2, child.chain_depth());
+        assert_eq!(3, grandchild.chain_depth());
+    }
+
+    #[test]
+    fn histogram_of_predicate_objects() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("bull", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("calf", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("duckling", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "likes", "mud"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let says = base.predicate_id("says").unwrap();
+        let moo = base.object_value_id("moo").unwrap();
+        let quack = base.object_value_id("quack").unwrap();
+        let oink = base.object_value_id("oink").unwrap();
+        assert_eq!(
+            vec![(moo, 3), (quack, 2), (oink, 1)],
+            base.predicate_object_histogram(says, 10)
+        );
+        assert_eq!(
+            vec![(moo, 3), (quack, 2)],
+            base.predicate_object_histogram(says, 2)
+        );
+        assert!(base.predicate_object_histogram(says + 100, 10).is_empty());
+
+        // removals in a child layer are taken into account
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.remove_string_triple(&StringTriple::new_value("bull", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("piglet", "says", "oink"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        // oink and quack tie, so they are ordered by id
+        let mut expected = vec![(quack, 2), (oink, 2)];
+        expected.sort();
+        expected.push((moo, 1));
+        assert_eq!(expected, child.predicate_object_histogram(says, 10));
+    }
+
+    #[test]
+    fn summarize_schema() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", RDF_TYPE, "Animal"));
+        builder.add_string_triple(&StringTriple::new_node("duck", RDF_TYPE, "Animal"));
+        builder.add_string_triple(&StringTriple::new_node("duck", RDF_TYPE, "Bird"));
+        builder.add_string_triple(&StringTriple::new_node("barn", RDF_TYPE, "Building"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("rock", "says", "nothing"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "lives_in", "barn"));
+        builder.add_string_triple(&StringTriple::new_value("barn", "color", "red"));
+        builder.add_string_triple(&StringTriple::new_node("barn", "color", "red_paint"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let domain =
+            |types: &[&str]| -> BTreeSet<String> { types.iter().map(|t| t.to_string()).collect() };
+        let schema = base.schema();
+        assert_eq!(
+            vec!["color", RDF_TYPE, "lives_in", "says"],
+            schema
+                .predicates
+                .keys()
+                .map(|p| p as &str)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            PredicateSchema {
+                domain: domain(&["Animal", "Bird"]),
+                untyped_subjects: true,
+                node_objects: false,
+                value_objects: true,
+            },
+            schema.predicates["says"]
+        );
+        assert_eq!(
+            PredicateSchema {
+                domain: domain(&["Animal"]),
+                untyped_subjects: false,
+                node_objects: true,
+                value_objects: false,
+            },
+            schema.predicates["lives_in"]
+        );
+        assert_eq!(
+            PredicateSchema {
+                domain: domain(&["Building"]),
+                untyped_subjects: false,
+                node_objects: true,
+                value_objects: true,
+            },
+            schema.predicates["color"]
+        );
+
+        // a predicate whose triples were all removed is left out
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("cow", "lives_in", "barn"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "kind", "Animal"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let schema = child.schema_with_type_predicate("kind");
+        assert_eq!(
+            vec!["color", RDF_TYPE, "kind", "says"],
+            schema
+                .predicates
+                .keys()
+                .map(|p| p as &str)
+                .collect::<Vec<_>>()
+        );
+        // pig's type is a value, so it does not count as a type
+        assert_eq!(
+            PredicateSchema {
+                domain: BTreeSet::new(),
+                untyped_subjects: true,
+                node_objects: false,
+                value_objects: true,
+            },
+            schema.predicates["kind"]
+        );
+    }
+
+    #[test]
+    fn find_candidate_inverse_predicates() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("alice", "parent_of", "bob"));
+        builder.add_string_triple(&StringTriple::new_node("bob", "child_of", "alice"));
+        builder.add_string_triple(&StringTriple::new_node("carol", "parent_of", "dave"));
+        builder.add_string_triple(&StringTriple::new_node("dave", "child_of", "carol"));
+        builder.add_string_triple(&StringTriple::new_node("alice", "knows", "bob"));
+        builder.add_string_triple(&StringTriple::new_node("bob", "knows", "alice"));
+        builder.add_string_triple(&StringTriple::new_value("alice", "name", "Alice"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let parent_of = base.predicate_id("parent_of").unwrap();
+        let child_of = base.predicate_id("child_of").unwrap();
+        let knows = base.predicate_id("knows").unwrap();
+        let mut expected = vec![(parent_of, child_of), (child_of, parent_of), (knows, knows)];
+        expected.sort();
+        assert_eq!(expected, base.candidate_inverse_predicates());
+
+        // once dave knows carol without carol knowing dave, knows is
+        // no longer its own inverse. As it happens, every child now
+        // knows their parent, so knows mirrors parent_of.
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_node("dave", "knows", "carol"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let mut expected = vec![
+            (parent_of, child_of),
+            (parent_of, knows),
+            (child_of, parent_of),
+        ];
+        expected.sort();
+        assert_eq!(expected, child.candidate_inverse_predicates());
+    }
+
+    #[test]
+    fn find_paths_between_nodes() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("a", "knows", "b"));
+        builder.add_string_triple(&StringTriple::new_node("a", "knows", "c"));
+        builder.add_string_triple(&StringTriple::new_node("b", "knows", "c"));
+        builder.add_string_triple(&StringTriple::new_node("b", "knows", "a"));
+        builder.add_string_triple(&StringTriple::new_node("c", "knows", "d"));
+        builder.add_string_triple(&StringTriple::new_value("c", "name", "see"));
+        builder.commit().wait().unwrap();
+        let base = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let id = |s| base.subject_id(s).unwrap();
+        let d = base.object_node_id("d").unwrap();
+        let knows = base.predicate_id("knows").unwrap();
+        let triple = |s, o| IdTriple::new(s, knows, o);
+
+        assert_eq!(
+            vec![
+                vec![triple(id("a"), id("b")), triple(id("b"), id("c"))],
+                vec![triple(id("a"), id("c"))],
+            ],
+            base.find_paths(id("a"), id("c"), 3)
+        );
+        assert_eq!(
+            vec![vec![triple(id("a"), id("c"))]],
+            base.find_paths(id("a"), id("c"), 1)
+        );
+        assert_eq!(2, base.find_paths(id("a"), d, 3).len());
+        assert_eq!(1, base.find_paths(id("a"), d, 2).len());
+        assert!(base.find_paths(id("a"), id("a"), 5).is_empty());
+        assert!(base.find_paths(d, id("a"), 5).is_empty());
+    }
+
+    #[test]
+    fn find_neighbors() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "hates", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_node("horse", "likes", "cow"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("horse", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "sheep"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "mooo"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let cow = base.subject_id("cow").unwrap();
+        let pig = base.object_node_id("pig").unwrap();
+        let duck = base.subject_id("duck").unwrap();
+        let horse = base.subject_id("horse").unwrap();
+        let sheep = child.object_node_id("sheep").unwrap();
+
+        let mut expected = vec![pig, duck, horse];
+        expected.sort_unstable();
+        assert_eq!(expected, base.neighbors(cow));
+
+        let mut expected = vec![pig, duck, sheep];
+        expected.sort_unstable();
+        assert_eq!(expected, child.neighbors(cow));
+
+        assert_eq!(vec![cow], child.neighbors(pig));
+        assert!(child.neighbors(horse).is_empty());
+    }
+
+    #[test]
+    fn describe_subject() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "eats", "grass"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let mut description = child.describe("cow").unwrap();
+        description.sort();
+        let mut expected = vec![
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_node("cow", "likes", "pig"),
+            StringTriple::new_value("cow", "eats", "grass"),
+        ];
+        expected.sort();
+        assert_eq!(expected, description);
+
+        assert_eq!(
+            vec![StringTriple::new_value("pig", "says", "oink")],
+            base.describe("pig").unwrap()
+        );
+        assert!(child.describe("pig").unwrap().is_empty());
+        assert_eq!(None, child.describe("horse"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn describe_subject_recursively() {
+        use serde_json::json;
+
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("alice", "name", "Alice"));
+        builder.add_string_triple(&StringTriple::new_value("alice", "nick", "Al"));
+        builder.add_string_triple(&StringTriple::new_value("alice", "nick", "Ali"));
+        builder.add_string_triple(&StringTriple::new_node("alice", "knows", "bob"));
+        builder.add_string_triple(&StringTriple::new_value("bob", "name", "Bob"));
+        builder.add_string_triple(&StringTriple::new_node("bob", "knows", "alice"));
+        builder.add_string_triple(&StringTriple::new_node("bob", "address", "home"));
+        builder.add_string_triple(&StringTriple::new_value("home", "city", "Delft"));
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            json!({
+                "@id": "alice",
+                "name": "Alice",
+                "nick": ["Al", "Ali"],
+                "knows": {"@id": "bob"},
+            }),
+            layer.describe_recursive("alice", 0)
+        );
+        assert_eq!(
+            json!({
+                "@id": "alice",
+                "name": "Alice",
+                "nick": ["Al", "Ali"],
+                "knows": {
+                    "@id": "bob",
+                    "name": "Bob",
+                    "knows": {"@id": "alice"},
+                    "address": {"@id": "home", "city": "Delft"},
+                },
+            }),
+            layer.describe_recursive("alice", 5)
+        );
+        assert_eq!(
+            json!({"@id": "home", "city": "Delft"}),
+            layer.describe_recursive("home", 1)
+        );
+        assert_eq!(
+            serde_json::Value::Null,
+            layer.describe_recursive("carol", 1)
+        );
+    }
+
+    #[test]
+    fn value_for_subject_and_predicate() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "says", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+
+        let layer = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(Some("moo".to_owned()), layer.value_for("cow", "says"));
+        assert_eq!(Some("oink".to_owned()), layer.value_for("pig", "says"));
+        assert_eq!(None, layer.value_for("cow", "likes"));
+        assert_eq!(None, layer.value_for("pig", "likes"));
+        assert_eq!(None, layer.value_for("horse", "says"));
+        assert_eq!(None, layer.value_for("cow", "eats"));
+
+        assert_eq!(
+            vec![
+                ObjectType::Node("cow".to_owned()),
+                ObjectType::Value("oink".to_owned())
+            ],
+            layer.objects_for("pig", "says")
+        );
+        assert_eq!(
+            vec![ObjectType::Node("pig".to_owned())],
+            layer.objects_for("cow", "likes")
+        );
+        assert!(layer.objects_for("pig", "likes").is_empty());
+        assert!(layer.objects_for("horse", "says").is_empty());
+        assert!(layer.objects_for("cow", "eats").is_empty());
+    }
+
+    #[test]
+    fn collect_triples_into_sorted_vec() {
+        let base_layer: Arc<dyn Layer> = Arc::new(example_base_layer());
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base_layer.clone(), files.clone());
+        builder.add_id_triple(IdTriple::new(1, 2, 3));
+        builder.add_id_triple(IdTriple::new(3, 3, 4));
+        builder.remove_id_triple(IdTriple::new(2, 1, 1));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base_layer, &files)
+            .wait()
+            .unwrap();
+
+        let triples = child.collect_triples();
+
+        let mut expected: Vec<_> = child.triples().collect();
+        expected.sort();
+        assert_eq!(expected, triples);
+        assert!(triples.binary_search(&IdTriple::new(1, 2, 3)).is_ok());
+        assert!(triples.binary_search(&IdTriple::new(2, 1, 1)).is_err());
+    }
+
+    #[test]
+    fn triples_with_predicates_are_merged_in_order() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "weight", "500"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "weight", "100"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.remove_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let says = child.predicate_id("says").unwrap();
+        let likes = child.predicate_id("likes").unwrap();
+        let result: Vec<_> = child
+            .triples_with_predicates(&[says, likes, says, 1000])
+            .collect();
+
+        let mut expected: Vec<_> = child
+            .triples()
+            .filter(|t| t.predicate == says || t.predicate == likes)
+            .collect();
+        expected.sort();
+        assert_eq!(expected, result);
+        assert_eq!(5, result.len());
+        assert_eq!(0, child.triples_with_predicates(&[]).count());
+    }
+
+    #[test]
+    fn merge_join_layers_on_subject() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cat", "says", "meow"));
+        builder.add_string_triple(&StringTriple::new_value("dog", "says", "woof"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_value("camel", "says", "grunt"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "hello"));
+        builder.commit().wait().unwrap();
+
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let joined: Vec<_> = merge_join_subjects(&*base, &child).collect();
+        let subjects: Vec<_> = joined
+            .iter()
+            .map(|(subject, _, _)| base.id_subject(*subject).unwrap())
+            .collect();
+        assert_eq!(vec!["cat", "cow", "dog"], subjects);
+
+        let cow = child.subject_id("cow").unwrap();
+        let (_, base_cow, child_cow) = joined
+            .into_iter()
+            .find(|(subject, _, _)| *subject == cow)
+            .unwrap();
+        assert_eq!(1, base_cow.triples().count());
+        assert_eq!(2, child_cow.triples().count());
+
+        assert_eq!(
+            4,
+            merge_join_subjects(&child, &child)
+                .inspect(|(subject, a, b)| {
+                    assert_eq!(*subject, a.subject());
+                    assert_eq!(*subject, b.subject());
+                })
+                .count()
+        );
+    }
+
+    #[test]
+    fn find_subjects_with_prefix() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cat", "says", "meow"));
+        builder.add_string_triple(&StringTriple::new_value("dog", "says", "woof"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "has", "calf"));
+
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_value("camel", "says", "grunt"));
+        builder.remove_string_triple(&StringTriple::new_value("cat", "says", "meow"));
+        builder.commit().wait().unwrap();
+
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let names = |found: Vec<(u64, String)>| {
+            found
+                .into_iter()
+                .map(|(id, s)| {
+                    assert_eq!(Some(id), child.subject_id(&s));
+                    s
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["cat", "cow"],
+            base.subjects_with_prefix("c", None)
+                .into_iter()
+                .map(|(_, s)| s)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["camel", "cow"],
+            names(child.subjects_with_prefix("c", None))
+        );
+        assert_eq!(
+            vec!["camel"],
+            names(child.subjects_with_prefix("c", Some(1)))
+        );
+        assert_eq!(vec!["dog"], names(child.subjects_with_prefix("do", None)));
+        assert!(child.subjects_with_prefix("x", None).is_empty());
+    }
+
+    #[test]
+    fn triples_for_set_of_subjects() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "pig"));
+
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("horse", "says", "neigh"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "horse"));
+        builder.commit().wait().unwrap();
+
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 5, 5, 5, 5], child.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.remove_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+
+        let grandchild = Arc::new(
+            ChildLayer::load_from_files([5, 5, 5, 5, 5], child.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        for layer in [base, child, grandchild].iter() {
+            let cow = layer.subject_id("cow").unwrap();
+            let duck = layer.subject_id("duck").unwrap();
+            let horse = layer.subject_id("horse");
+            let mut subjects = vec![duck, cow, duck, 1000];
+            subjects.extend(horse);
+
+            let mut expected: Vec<_> = layer
+                .triples()
+                .filter(|t| subjects.contains(&t.subject))
+                .collect();
+            expected.sort();
+
+            assert_eq!(
+                expected,
+                layer.triples_for_subjects(&subjects).collect::<Vec<_>>()
+            );
+            assert_eq!(0, layer.triples_for_subjects(&[]).count());
+        }
+    }
+
+    #[test]
+    fn subject_degrees() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.remove_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "honk"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let cow = base.subject_id("cow").unwrap();
+        let pig = base.subject_id("pig").unwrap();
+        let duck = base.subject_id("duck").unwrap();
+
+        assert_eq!(3, base.subject_degree(cow));
+        assert_eq!(1, child.subject_degree(cow));
+        assert_eq!(2, child.subject_degree(duck));
+        assert_eq!(0, base.subject_degree(1000));
+
+        assert_eq!(vec![(cow, 3), (pig, 2)], base.top_subjects_by_degree(2));
+        let mut expected = vec![(pig, 2), (duck, 2)];
+        expected.sort_by_key(|&(s, _)| s);
+        expected.push((cow, 1));
+        assert_eq!(expected, child.top_subjects_by_degree(5));
+        assert!(child.top_subjects_by_degree(0).is_empty());
+    }
+
+    #[test]
+    fn count_matching_patterns() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "sniff"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "cow"));
+        builder.commit().wait().unwrap();
+
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let cow = child.subject_id("cow").unwrap();
+        let duck = child.subject_id("duck").unwrap();
+        let says = child.predicate_id("says").unwrap();
+        let likes = child.predicate_id("likes").unwrap();
+        let moo = child.object_value_id("moo").unwrap();
+        let duck_object = child.object_node_id("duck").unwrap();
+
+        assert_eq!(1, child.count_matching(Some(cow), Some(says), Some(moo)));
+        assert_eq!(0, child.count_matching(Some(duck), Some(says), Some(moo)));
+        assert_eq!(1, child.count_matching(Some(cow), Some(says), None));
+        assert_eq!(1, child.count_matching(Some(cow), None, Some(duck_object)));
+        assert_eq!(2, child.count_matching(Some(cow), None, None));
+        assert_eq!(
+            2,
+            child.count_matching(None, Some(likes), Some(duck_object))
+        );
+        assert_eq!(3, child.count_matching(None, Some(likes), None));
+        assert_eq!(2, child.count_matching(None, Some(says), None));
+        assert_eq!(2, child.count_matching(None, None, Some(duck_object)));
+        assert_eq!(5, child.count_matching(None, None, None));
+
+        // the counts taken from the indexes agree with counting the triples
+        for layer in [base, child].iter() {
+            let triples: Vec<_> = layer.triples().collect();
+            for &s in [None, Some(cow), Some(duck)].iter() {
+                for &p in [None, Some(says), Some(likes)].iter() {
+                    for &o in [None, Some(moo), Some(duck_object)].iter() {
+                        let expected = triples
+                            .iter()
+                            .filter(|t| {
+                                s.is_none_or(|s| t.subject == s)
+                                    && p.is_none_or(|p| t.predicate == p)
+                                    && o.is_none_or(|o| t.object == o)
+                            })
+                            .count();
+                        assert_eq!(expected, layer.count_matching(s, p, o));
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip_triples() {
+        let node = StringTriple::new_node("cow", "likes", "duck");
+        let value = StringTriple::new_value("cow", "likes", "duck");
+        let node_json = serde_json::to_string(&node).unwrap();
+        let value_json = serde_json::to_string(&value).unwrap();
+
+        assert!(node_json.contains(r#"{"type":"node","value":"duck"}"#));
+        assert_ne!(node_json, value_json);
+        assert_eq!(node, serde_json::from_str(&node_json).unwrap());
+        assert_eq!(value, serde_json::from_str(&value_json).unwrap());
+
+        let id_triple = IdTriple::new(1, 2, 3);
+        let json = serde_json::to_string(&id_triple).unwrap();
+        assert_eq!(id_triple, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn estimate_matching_patterns() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "eats", "grass"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "eats", "slop"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("pig", "eats", "slop"));
+        builder.commit().wait().unwrap();
+        let child = ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+            .wait()
+            .unwrap();
+
+        let cow = base.subject_id("cow").unwrap();
+        let says = base.predicate_id("says").unwrap();
+        let eats = base.predicate_id("eats").unwrap();
+        let moo = base.object_value_id("moo").unwrap();
+
+        // evenly spread data is estimated exactly
+        assert_eq!(4, base.estimate_matching(None, None, None));
+        assert_eq!(2, base.estimate_matching(Some(cow), None, None));
+        assert_eq!(2, base.estimate_matching(None, Some(says), None));
+        assert_eq!(1, base.estimate_matching(Some(cow), Some(says), None));
+        assert_eq!(1, base.estimate_matching(Some(cow), Some(says), Some(moo)));
+
+        // removals are taken into account
+        assert_eq!(3, child.estimate_matching(None, None, None));
+        assert_eq!(1, child.estimate_matching(None, Some(eats), None));
+        assert!(child.estimate_matching(None, Some(says), None) >= 1);
+        assert_eq!(0, child.estimate_matching(None, Some(1000), None));
+    }
+
+    #[test]
+    fn count_triples_through_the_stack() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        // bird is a node without triples of its own
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "bird"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.remove_string_triple(&StringTriple::new_value("cat", "says", "meow"));
+        builder.add_string_triple(&StringTriple::new_value("cow", "eats", "grass"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "eats", "slop"));
+        builder.commit().wait().unwrap();
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([6, 6, 6, 6, 6], child.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "eats", "grass"));
+        builder.commit().wait().unwrap();
+        let grandchild = ChildLayer::load_from_files([6, 6, 6, 6, 6], child.clone(), &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(3, base.triple_addition_count());
+        assert_eq!(0, base.triple_removal_count());
+        assert_eq!(3, base.triple_count());
+
+        assert_eq!(2, child.triple_addition_count());
+        assert_eq!(1, child.triple_removal_count());
+        assert_eq!(4, child.triple_count());
+
+        assert_eq!(0, grandchild.triple_addition_count());
+        assert_eq!(1, grandchild.triple_removal_count());
+        assert_eq!(3, grandchild.triple_count());
+        assert_eq!(grandchild.triples().count(), grandchild.triple_count());
+
+        let files = base_layer_files();
+        let builder = SimpleLayerBuilder::new([7, 7, 7, 7, 7], files.clone());
+        builder.commit().wait().unwrap();
+        let empty = BaseLayer::load_from_files([7, 7, 7, 7, 7], &files)
+            .wait()
+            .unwrap();
+        assert_eq!(0, empty.triple_count());
+    }
+
+    #[test]
+    fn reconcile_triples_removed_and_added_again() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([2, 2, 3, 4, 5], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.commit().wait().unwrap();
+
+        let child1 = Arc::new(
+            ChildLayer::load_from_files([2, 2, 3, 4, 5], base.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([3, 2, 3, 4, 5], child1.clone(), files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.remove_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+
+        let child2 = Arc::new(
+            ChildLayer::load_from_files([3, 2, 3, 4, 5], child1.clone(), &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let strings = |layer: &Arc<dyn Layer>| -> Vec<StringTriple> {
+            layer
+                .reconciled_triples()
+                .map(|t| layer.id_triple_to_string(&t).unwrap())
+                .collect()
+        };
+
+        assert_eq!(
+            vec![
+                StringTriple::new_value("cow", "says", "moo"),
+                StringTriple::new_value("duck", "says", "quack"),
+            ],
+            strings(&base)
+        );
+        assert_eq!(
+            vec![
+                StringTriple::new_value("duck", "says", "quack"),
+                StringTriple::new_value("pig", "says", "oink"),
+            ],
+            strings(&child1)
+        );
+        assert_eq!(
+            vec![
+                StringTriple::new_value("cow", "says", "moo"),
+                StringTriple::new_value("pig", "says", "oink"),
+            ],
+            strings(&child2)
+        );
+
+        for layer in &[base, child1, child2] {
+            let reconciled: Vec<_> = layer.reconciled_triples().collect();
+            let triples: Vec<_> = layer.triples().collect();
+            assert_eq!(triples, reconciled);
+        }
+    }
+
+    #[test]
+    fn reconcile_triples_of_deep_stack() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([0, 0, 0, 0, 0], files.clone());
+        for i in 0..10 {
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", &i.to_string()));
+        }
+        builder.commit().wait().unwrap();
+        let mut layer = Arc::new(
+            BaseLayer::load_from_files([0, 0, 0, 0, 0], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        // every layer removes one number and adds the next, and every
+        // third layer adds back a number removed before
+        let mut expected: BTreeSet<_> = (0..10).map(|i: u32| i.to_string()).collect();
+        for depth in 1..20u32 {
+            let files = child_layer_files();
+            let name = [depth, 0, 0, 0, 0];
+            let mut builder = SimpleLayerBuilder::from_parent(name, layer.clone(), files.clone());
+            let removed = (depth - 1).to_string();
+            let added = (depth + 9).to_string();
+            builder.remove_string_triple(&StringTriple::new_value("cow", "says", &removed));
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", &added));
+            expected.remove(&removed);
+            expected.insert(added);
+            if depth % 3 == 0 {
+                let back = (depth - 2).to_string();
+                builder.add_string_triple(&StringTriple::new_value("cow", "says", &back));
+                expected.insert(back);
+            }
+            builder.commit().wait().unwrap();
+
+            layer = Arc::new(
+                ChildLayer::load_from_files(name, layer.clone(), &files)
+                    .wait()
+                    .unwrap(),
+            );
+        }
+
+        let reconciled: Vec<_> = layer.reconciled_triples().collect();
+        let through_lookups: Vec<_> = layer.subjects().flat_map(|s| s.triples()).collect();
+        assert_eq!(through_lookups, reconciled);
+        assert_eq!(reconciled, layer.triples().collect::<Vec<_>>());
+
+        let values: BTreeSet<_> = reconciled
+            .iter()
+            .map(|t| match layer.id_object(t.object).unwrap() {
+                ObjectType::Value(value) => value,
+                ObjectType::Node(_) => panic!("expected a value"),
+            })
+            .collect();
+        assert_eq!(expected, values);
+    }
+
     #[test]
     fn find_triple_after_adjacent_removal() {
         let files = base_layer_files();
@@ -6,9 +6,21 @@
 mod base;
 mod builder;
 mod child;
+mod diff;
+mod export;
+mod import;
 mod layer;
+mod squash;
+mod translate;
+mod union;
 
 pub use base::*;
 pub use builder::*;
 pub use child::*;
+pub use diff::*;
+pub use export::*;
+pub use import::*;
 pub use layer::*;
+pub use squash::*;
+pub use translate::*;
+pub use union::*;
@@ -0,0 +1,1099 @@
+//! Squashing a layer stack into a single base layer
+//!
+//! Squashing rewrites the triples that are visible in a layer into a
+//! brand-new base layer. Since the dictionaries of the new layer are
+//! sorted differently than the dictionaries of the layer stack, every
+//! id changes. Translating the ids requires sorting the triples a few
+//! times over. To keep memory use bounded regardless of the size of
+//! the layer, this is done with an external merge sort: sorted runs
+//! are spilled to temporary files, which are then merged.
+//!
+//! The same machinery builds base layers whose added triples were
+//! spilled to disk by a `SimpleLayerBuilder`, as the string triples
+//! of such a layer need the same translation into ids.
+use super::base::*;
+use super::builder::{write_collation, CommitPhase, CommitProgressCallback, PhaseProgress};
+use super::layer::*;
+use crate::storage::*;
+use crate::structure::{run_blocking, BloomFilterBuilder, BytewiseCollation, Collation};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use futures::prelude::*;
+use futures::{future, stream};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::{NamedTempFile, TempPath};
+
+/// The number of records that are sorted in memory before being spilled to disk.
+const SQUASH_RUN_SIZE: usize = 1 << 20;
+
+const NODE_TERM: u8 = 0;
+const VALUE_TERM: u8 = 1;
+
+/// A record that can be spilled to and read back from a temporary file.
+trait SpillRecord: Ord + Sized {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    /// Read a record, or None if the end of the file is reached.
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>>;
+}
+
+fn read_first_u64<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    match reader.read_u64::<BigEndian>() {
+        Ok(num) => Ok(Some(num)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_string<R: Read>(reader: &mut R, len: u64) -> io::Result<String> {
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl SpillRecord for (u64, u64) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<BigEndian>(self.0)?;
+        writer.write_u64::<BigEndian>(self.1)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match read_first_u64(reader)? {
+            None => Ok(None),
+            Some(first) => Ok(Some((first, reader.read_u64::<BigEndian>()?))),
+        }
+    }
+}
+
+impl SpillRecord for (u64, u64, u64) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<BigEndian>(self.0)?;
+        writer.write_u64::<BigEndian>(self.1)?;
+        writer.write_u64::<BigEndian>(self.2)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match read_first_u64(reader)? {
+            None => Ok(None),
+            Some(first) => Ok(Some((
+                first,
+                reader.read_u64::<BigEndian>()?,
+                reader.read_u64::<BigEndian>()?,
+            ))),
+        }
+    }
+}
+
+/// A dictionary term: its kind (node or value), its string and its id in the layer being squashed.
+impl SpillRecord for (u8, String, u64) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<BigEndian>(self.1.len() as u64)?;
+        writer.write_all(self.1.as_bytes())?;
+        writer.write_u8(self.0)?;
+        writer.write_u64::<BigEndian>(self.2)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match read_first_u64(reader)? {
+            None => Ok(None),
+            Some(len) => {
+                let string = read_string(reader, len)?;
+                let kind = reader.read_u8()?;
+                let id = reader.read_u64::<BigEndian>()?;
+                Ok(Some((kind, string, id)))
+            }
+        }
+    }
+}
+
+/// A dictionary term: its kind (node or value) and its string.
+impl SpillRecord for (u8, String) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_term(writer, self.0, &self.1)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match read_first_u64(reader)? {
+            None => Ok(None),
+            Some(len) => {
+                let string = read_string(reader, len)?;
+                Ok(Some((reader.read_u8()?, string)))
+            }
+        }
+    }
+}
+
+/// An object term followed by the ids of the subject and predicate pointing to it.
+impl SpillRecord for (u8, String, u64, u64) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_term(writer, self.0, &self.1)?;
+        writer.write_u64::<BigEndian>(self.2)?;
+        writer.write_u64::<BigEndian>(self.3)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match <(u8, String)>::read_from(reader)? {
+            None => Ok(None),
+            Some((kind, string)) => Ok(Some((
+                kind,
+                string,
+                reader.read_u64::<BigEndian>()?,
+                reader.read_u64::<BigEndian>()?,
+            ))),
+        }
+    }
+}
+
+impl SpillRecord for StringTriple {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.subject.write_to(writer)?;
+        self.predicate.write_to(writer)?;
+        let (kind, object) = object_term(&self.object);
+        write_term(writer, kind, object)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match String::read_from(reader)? {
+            None => Ok(None),
+            Some(subject) => {
+                let predicate = read_required(reader)?;
+                let object = match read_required(reader)? {
+                    (NODE_TERM, node) => ObjectType::Node(node),
+                    (_, value) => ObjectType::Value(value),
+                };
+                Ok(Some(StringTriple {
+                    subject,
+                    predicate,
+                    object,
+                }))
+            }
+        }
+    }
+}
+
+fn write_term<W: Write>(writer: &mut W, kind: u8, string: &str) -> io::Result<()> {
+    writer.write_u64::<BigEndian>(string.len() as u64)?;
+    writer.write_all(string.as_bytes())?;
+    writer.write_u8(kind)
+}
+
+/// Read a record that is not allowed to be missing.
+fn read_required<T: SpillRecord, R: Read>(reader: &mut R) -> io::Result<T> {
+    T::read_from(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+fn object_term(object: &ObjectType) -> (u8, &str) {
+    match object {
+        ObjectType::Node(node) => (NODE_TERM, node),
+        ObjectType::Value(value) => (VALUE_TERM, value),
+    }
+}
+
+impl SpillRecord for String {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<BigEndian>(self.len() as u64)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        match read_first_u64(reader)? {
+            None => Ok(None),
+            Some(len) => read_string(reader, len).map(Some),
+        }
+    }
+}
+
+/// A temporary file holding a sequence of records.
+///
+/// The file is deleted as soon as it is dropped.
+struct SpillFile<T> {
+    file: File,
+    /// The number of records in the file
+    len: u64,
+    _record: PhantomData<T>,
+}
+
+impl<T: SpillRecord> SpillFile<T> {
+    fn write<I: IntoIterator<Item = T>>(dir: &Path, records: I) -> io::Result<Self> {
+        let mut writer = SpillWriter::new(dir)?;
+        for record in records {
+            writer.push(&record)?;
+        }
+
+        writer.finish()
+    }
+
+    /// Read all records, starting from the beginning of the file.
+    fn read(&self) -> io::Result<SpillReader<T>> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(SpillReader {
+            reader: BufReader::new(file),
+            _record: PhantomData,
+        })
+    }
+}
+
+struct SpillWriter<T> {
+    writer: BufWriter<File>,
+    len: u64,
+    _record: PhantomData<T>,
+}
+
+impl<T: SpillRecord> SpillWriter<T> {
+    fn new(dir: &Path) -> io::Result<Self> {
+        Ok(SpillWriter {
+            writer: BufWriter::new(tempfile::tempfile_in(dir)?),
+            len: 0,
+            _record: PhantomData,
+        })
+    }
+
+    fn push(&mut self, record: &T) -> io::Result<()> {
+        record.write_to(&mut self.writer)?;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<SpillFile<T>> {
+        let len = self.len;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+
+        Ok(SpillFile {
+            file,
+            len,
+            _record: PhantomData,
+        })
+    }
+}
+
+struct SpillReader<T> {
+    reader: BufReader<File>,
+    _record: PhantomData<T>,
+}
+
+impl<T: SpillRecord> Iterator for SpillReader<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<io::Result<T>> {
+        T::read_from(&mut self.reader).transpose()
+    }
+}
+
+/// The order an `ExternalSorter` sorts its records in.
+type RecordOrder<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+/// Sorts and deduplicates an arbitrary number of records in bounded memory.
+///
+/// Records are collected in memory until `SQUASH_RUN_SIZE` of them
+/// are buffered, after which they're sorted and spilled to a
+/// temporary file as a run. `finish` merges all runs.
+struct ExternalSorter<T> {
+    dir: PathBuf,
+    buffer: Vec<T>,
+    runs: Vec<SpillFile<T>>,
+    order: RecordOrder<T>,
+}
+
+impl<T: 'static + SpillRecord> ExternalSorter<T> {
+    fn new(dir: &Path) -> Self {
+        Self::with_order(dir, Arc::new(T::cmp))
+    }
+
+    /// Create a sorter that sorts in the given order instead of the natural order of the records.
+    ///
+    /// Records that are not equal should not be ordered as equal, or
+    /// they may not come out in a consistent order.
+    fn with_order(dir: &Path, order: RecordOrder<T>) -> Self {
+        ExternalSorter {
+            dir: dir.to_owned(),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            order,
+        }
+    }
+
+    fn push(&mut self, record: T) -> io::Result<()> {
+        self.buffer.push(record);
+        if self.buffer.len() >= SQUASH_RUN_SIZE {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    /// Spill records that are already sorted and without duplicates as a run of their own.
+    fn push_run<I: IntoIterator<Item = T>>(&mut self, records: I) -> io::Result<()> {
+        self.runs.push(SpillFile::write(&self.dir, records)?);
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.sort_unstable_by(|a, b| (self.order)(a, b));
+        buffer.dedup();
+        self.runs.push(SpillFile::write(&self.dir, buffer)?);
+
+        Ok(())
+    }
+
+    /// Merge all records into a single sorted file without duplicates.
+    fn finish(mut self) -> io::Result<SpillFile<T>> {
+        if !self.buffer.is_empty() || self.runs.is_empty() {
+            self.spill()?;
+        }
+
+        if self.runs.len() == 1 {
+            return Ok(self.runs.pop().unwrap());
+        }
+
+        let mut readers = self
+            .runs
+            .iter()
+            .map(|run| run.read())
+            .collect::<io::Result<Vec<_>>>()?;
+        let order = &*self.order;
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some(record) = reader.next().transpose()? {
+                heap.push(MergeEntry {
+                    record,
+                    index,
+                    order,
+                });
+            }
+        }
+
+        let mut writer = SpillWriter::new(&self.dir)?;
+        let mut last: Option<T> = None;
+        while let Some(MergeEntry { record, index, .. }) = heap.pop() {
+            if let Some(next) = readers[index].next().transpose()? {
+                heap.push(MergeEntry {
+                    record: next,
+                    index,
+                    order,
+                });
+            }
+
+            if last.as_ref() != Some(&record) {
+                writer.push(&record)?;
+                last = Some(record);
+            }
+        }
+
+        writer.finish()
+    }
+}
+
+/// The next record of a run that is being merged.
+///
+/// Entries are ordered in reverse, so that the smallest record comes
+/// out of a `BinaryHeap` first.
+struct MergeEntry<'a, T> {
+    record: T,
+    index: usize,
+    order: &'a (dyn Fn(&T, &T) -> Ordering + Send + Sync),
+}
+
+impl<T> Ord for MergeEntry<'_, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.order)(&other.record, &self.record).then(other.index.cmp(&self.index))
+    }
+}
+
+impl<T> PartialOrd for MergeEntry<'_, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for MergeEntry<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for MergeEntry<'_, T> {}
+
+/// Compare two strings with the given collation, falling back to bytewise order for strings it considers equal.
+///
+/// This is the order in which a layer builder sorts its dictionaries.
+fn collate(collation: &dyn Collation, a: &str, b: &str) -> Ordering {
+    collation.compare(a, b).then_with(|| a.cmp(b))
+}
+
+/// Translates ids of the layer stack, or terms, into ids of the squashed layer.
+///
+/// This expects to be queried with ascending keys.
+struct SortedIdMapping<I: Iterator> {
+    mapping: std::iter::Peekable<I>,
+}
+
+impl<K: Ord + Debug, I: Iterator<Item = io::Result<(K, u64)>>> SortedIdMapping<I> {
+    fn new(mapping: I) -> Self {
+        SortedIdMapping {
+            mapping: mapping.peekable(),
+        }
+    }
+
+    fn translate(&mut self, id: K) -> io::Result<u64> {
+        loop {
+            match self.mapping.peek() {
+                Some(Ok((old, new))) if *old == id => return Ok(*new),
+                Some(Ok((old, _))) if *old < id => {
+                    self.mapping.next();
+                }
+                Some(Err(_)) => return Err(self.mapping.next().unwrap().unwrap_err()),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("no squashed id found for {:?}", id),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+struct SquashPlan {
+    nodes: SpillFile<String>,
+    predicates: Vec<String>,
+    values: SpillFile<String>,
+    triples: SpillFile<(u64, u64, u64)>,
+    collation: Arc<dyn Collation>,
+}
+
+/// Work out the dictionaries and the sorted id triples of the squashed layer.
+///
+/// The dictionaries are sorted with the collation of the given layer.
+fn plan_squash(layer: &dyn Layer, dir: &Path) -> io::Result<SquashPlan> {
+    let collation = layer.collation();
+    // Predicates are assumed to be few, so they are kept in memory.
+    let mut predicate_ids = BTreeSet::new();
+    let mut terms = ExternalSorter::with_order(dir, {
+        let collation = collation.clone();
+        Arc::new(
+            move |(a_kind, a, a_id): &(u8, String, u64), (b_kind, b, b_id): &(u8, String, u64)| {
+                a_kind
+                    .cmp(b_kind)
+                    .then_with(|| collate(&*collation, a, b))
+                    .then(a_id.cmp(b_id))
+            },
+        )
+    });
+    for triple in layer.reconciled_triples() {
+        let subject = layer
+            .id_subject_checked(triple.subject)?
+            .ok_or_else(|| dangling_id(triple.subject))?;
+        terms.push((NODE_TERM, subject, triple.subject))?;
+        match layer.id_object_checked(triple.object)? {
+            Some(ObjectType::Node(node)) => terms.push((NODE_TERM, node, triple.object))?,
+            Some(ObjectType::Value(value)) => terms.push((VALUE_TERM, value, triple.object))?,
+            None => return Err(dangling_id(triple.object)),
+        }
+        predicate_ids.insert(triple.predicate);
+    }
+
+    // Terms come out sorted by kind and collated string, so numbering
+    // the distinct terms in order yields the squashed ids: first all
+    // nodes, then all values.
+    let terms = terms.finish()?;
+    let mut nodes = SpillWriter::new(dir)?;
+    let mut values = SpillWriter::new(dir)?;
+    let mut mapping = ExternalSorter::new(dir);
+    let mut last: Option<(u8, String)> = None;
+    let mut next_id = 0;
+    for term in terms.read()? {
+        let (kind, string, id) = term?;
+        if last.as_ref().map(|(k, s)| (*k, s)) != Some((kind, &string)) {
+            next_id += 1;
+            if kind == NODE_TERM {
+                nodes.push(&string)?;
+            } else {
+                values.push(&string)?;
+            }
+            last = Some((kind, string));
+        }
+        mapping.push((id, next_id))?;
+    }
+    std::mem::drop(terms);
+    let mapping = mapping.finish()?;
+
+    let mut predicates = Vec::with_capacity(predicate_ids.len());
+    for id in predicate_ids {
+        let predicate = layer
+            .id_predicate_checked(id)?
+            .ok_or_else(|| dangling_id(id))?;
+        predicates.push((predicate, id));
+    }
+    predicates.sort_unstable_by(|(a, _), (b, _)| collate(&*collation, a, b));
+    let predicate_mapping: HashMap<u64, u64> = predicates
+        .iter()
+        .enumerate()
+        .map(|(index, (_, id))| (*id, index as u64 + 1))
+        .collect();
+
+    // Reconciled triples are sorted by subject, so subjects can be
+    // translated in a single pass over the mapping. After that, the
+    // triples are sorted by object to translate the objects.
+    let mut subjects = SortedIdMapping::new(mapping.read()?);
+    let mut by_object = ExternalSorter::new(dir);
+    for triple in layer.reconciled_triples() {
+        let subject = subjects.translate(triple.subject)?;
+        let predicate = predicate_mapping[&triple.predicate];
+        by_object.push((triple.object, subject, predicate))?;
+    }
+    let by_object = by_object.finish()?;
+
+    let mut objects = SortedIdMapping::new(mapping.read()?);
+    let mut triples = ExternalSorter::new(dir);
+    for triple in by_object.read()? {
+        let (object, subject, predicate) = triple?;
+        triples.push((subject, predicate, objects.translate(object)?))?;
+    }
+
+    Ok(SquashPlan {
+        nodes: nodes.finish()?,
+        predicates: predicates.into_iter().map(|(p, _)| p).collect(),
+        values: values.finish()?,
+        triples: triples.finish()?,
+        collation,
+    })
+}
+
+/// Work out the dictionaries and the sorted id triples of a base layer out of sorted string triples.
+///
+/// This goes through the triples three times, marking each triple as
+/// done every time.
+fn plan_string_triples(
+    triples: &SpillFile<StringTriple>,
+    dir: &Path,
+    progress: &PhaseProgress,
+) -> io::Result<SquashPlan> {
+    // Predicates are assumed to be few, so they are kept in memory.
+    let mut predicates = BTreeSet::new();
+    let mut terms = ExternalSorter::new(dir);
+    for triple in progress.track(triples.read()?) {
+        let triple = triple?;
+        let (kind, object) = object_term(&triple.object);
+        terms.push((kind, object.to_owned()))?;
+        terms.push((NODE_TERM, triple.subject))?;
+        predicates.insert(triple.predicate);
+    }
+
+    // Terms come out sorted by kind and string, so their position is
+    // their id: first all nodes, then all values.
+    let terms = terms.finish()?;
+    let numbered_terms = || -> io::Result<_> {
+        Ok(terms
+            .read()?
+            .enumerate()
+            .map(|(index, term)| term.map(|term| (term, index as u64 + 1))))
+    };
+    let mut nodes = SpillWriter::new(dir)?;
+    let mut values = SpillWriter::new(dir)?;
+    for term in terms.read()? {
+        match term? {
+            (NODE_TERM, node) => nodes.push(&node)?,
+            (_, value) => values.push(&value)?,
+        }
+    }
+
+    let predicates: Vec<String> = predicates.into_iter().collect();
+    let predicate_mapping: HashMap<&str, u64> = predicates
+        .iter()
+        .enumerate()
+        .map(|(index, predicate)| (predicate.as_str(), index as u64 + 1))
+        .collect();
+
+    // The triples are sorted by subject, so subjects can be
+    // translated in a single pass over the terms. After that, the
+    // triples are sorted by object to translate the objects.
+    let mut subjects = SortedIdMapping::new(numbered_terms()?);
+    let mut by_object = ExternalSorter::new(dir);
+    for triple in progress.track(triples.read()?) {
+        let triple = triple?;
+        let subject = subjects.translate((NODE_TERM, triple.subject))?;
+        let predicate = predicate_mapping[triple.predicate.as_str()];
+        let (kind, object) = object_term(&triple.object);
+        by_object.push((kind, object.to_owned(), subject, predicate))?;
+    }
+    let by_object = by_object.finish()?;
+
+    let mut objects = SortedIdMapping::new(numbered_terms()?);
+    let mut id_triples = ExternalSorter::new(dir);
+    for triple in progress.track(by_object.read()?) {
+        let (kind, object, subject, predicate) = triple?;
+        id_triples.push((subject, predicate, objects.translate((kind, object))?))?;
+    }
+
+    Ok(SquashPlan {
+        nodes: nodes.finish()?,
+        predicates,
+        values: values.finish()?,
+        triples: id_triples.finish()?,
+        collation: Arc::new(BytewiseCollation),
+    })
+}
+
+fn dangling_id(id: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("id {} could not be resolved to a string", id),
+    )
+}
+
+/// Build a base layer containing exactly the triples that are visible in the given layer.
+///
+/// Only strings that are used in at least one visible triple end up
+/// in the dictionaries of the new layer, which are sorted with the
+/// collation of the given layer. The triples are sorted with
+/// an external merge sort, spilling sorted runs to temporary files in
+/// `temp_dir`, so that the amount of memory used does not depend on
+/// the number of triples. Only the predicate strings are kept in
+/// memory. The temporary files are removed when this completes.
+///
+/// Reading the layer and sorting its triples is blocking work, so it
+/// is run with `tokio_threadpool::blocking` when on a threadpool.
+pub fn build_squashed_base_layer<F: 'static + FileLoad + FileStore + Clone>(
+    files: BaseLayerFiles<F>,
+    layer: Arc<dyn Layer>,
+    temp_dir: PathBuf,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    let progress: CommitProgressCallback = Arc::new(|_| {});
+    run_blocking(move || plan_squash(&*layer, &temp_dir))
+        .and_then(move |plan| write_planned_base_layer(&files, plan, &progress))
+}
+
+/// Triples added to a base layer builder that were spilled to disk.
+///
+/// The triples are spilled as sorted runs, which are merged on
+/// commit. See `build_spilled_base_layer`. Runs are shared between
+/// clones of a builder, and every commit opens them anew, so that
+/// the clones do not get in each other's way. A run is deleted once
+/// no builder refers to it anymore.
+#[derive(Clone)]
+pub(crate) struct SpilledTriples {
+    dir: PathBuf,
+    /// The spilled runs, with the number of triples in each
+    runs: Vec<(Arc<TempPath>, u64)>,
+}
+
+impl SpilledTriples {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        SpilledTriples {
+            dir,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Spill the given triples, which have to be sorted and without duplicates.
+    pub(crate) fn spill<I: IntoIterator<Item = StringTriple>>(
+        &mut self,
+        triples: I,
+    ) -> io::Result<()> {
+        let file = NamedTempFile::new_in(&self.dir)?;
+        let mut writer = SpillWriter {
+            writer: BufWriter::new(file.reopen()?),
+            len: 0,
+            _record: PhantomData,
+        };
+        for triple in triples {
+            writer.push(&triple)?;
+        }
+        let len = writer.finish()?.len;
+        self.runs.push((Arc::new(file.into_temp_path()), len));
+
+        Ok(())
+    }
+
+    /// The number of spilled triples, counting triples that were spilled more than once.
+    fn len(&self) -> u64 {
+        self.runs.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Merge all runs, together with the given sorted triples, into a single sorted run.
+    fn merge(self, remaining: Vec<StringTriple>) -> io::Result<SpillFile<StringTriple>> {
+        let mut sorter = ExternalSorter::new(&self.dir);
+        for (run, len) in self.runs.iter() {
+            sorter.runs.push(SpillFile {
+                file: File::open(&**run)?,
+                len: *len,
+                _record: PhantomData,
+            });
+        }
+        sorter.push_run(remaining)?;
+
+        sorter.finish()
+    }
+}
+
+/// String triples that are written to a temporary file as they are added, in sorted order.
+///
+/// Only the last added triple is kept in memory.
+pub(crate) struct SortedTripleSpill {
+    writer: SpillWriter<StringTriple>,
+    last: Option<StringTriple>,
+}
+
+impl SortedTripleSpill {
+    pub(crate) fn new(dir: &Path) -> io::Result<Self> {
+        Ok(SortedTripleSpill {
+            writer: SpillWriter::new(dir)?,
+            last: None,
+        })
+    }
+
+    /// Add a triple, skipping it if it was just added.
+    ///
+    /// Returns an `InvalidInput` error if the triple comes before the
+    /// previously added triple.
+    pub(crate) fn push(&mut self, triple: StringTriple) -> io::Result<()> {
+        match &self.last {
+            Some(last) if *last == triple => return Ok(()),
+            Some(last) if *last > triple => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("triple {:?} is out of order", triple),
+                ))
+            }
+            _ => {}
+        }
+        self.writer.push(&triple)?;
+        self.last = Some(triple);
+
+        Ok(())
+    }
+
+    /// Read back all added triples, in order.
+    pub(crate) fn into_triples(
+        self,
+    ) -> io::Result<impl Iterator<Item = io::Result<StringTriple>> + Send> {
+        self.writer.finish()?.read()
+    }
+}
+
+/// Build a base layer out of string triples that are already sorted.
+///
+/// The triples are checked and spilled to a temporary file in
+/// `temp_dir` as they come in, after which they're translated into
+/// the dictionaries and sorted id triples of the new layer, like when
+/// squashing a layer. Only the predicates are kept in memory. The
+/// dictionaries are sorted bytewise, and the work is run with
+/// `tokio_threadpool::blocking`.
+///
+/// An out-of-order triple results in an `InvalidInput` error before
+/// anything is written to the layer files.
+pub(crate) fn build_sorted_base_layer<F, I>(
+    files: BaseLayerFiles<F>,
+    triples: I,
+    temp_dir: PathBuf,
+) -> impl Future<Item = (), Error = io::Error> + Send
+where
+    F: 'static + FileLoad + FileStore + Clone,
+    I: 'static + Iterator<Item = io::Result<StringTriple>> + Send,
+{
+    let progress: CommitProgressCallback = Arc::new(|_| {});
+    run_blocking({
+        let progress = progress.clone();
+        move || {
+            let mut spill = SortedTripleSpill::new(&temp_dir)?;
+            for triple in triples {
+                spill.push(triple?)?;
+            }
+            let triples = spill.writer.finish()?;
+            let translating = PhaseProgress::start(&progress, CommitPhase::Sorting, (0, 40), 0);
+
+            plan_string_triples(&triples, &temp_dir, &translating)
+        }
+    })
+    .and_then(move |plan| write_planned_base_layer(&files, plan, &progress))
+}
+
+/// Build a base layer out of spilled triples and the triples that were still in memory.
+///
+/// The spilled runs are merged into a single sorted run of string
+/// triples, which is then translated into the dictionaries and the
+/// sorted id triples of the new layer, like when squashing a layer.
+/// The dictionaries are sorted bytewise. Like squashing, the merge
+/// and translation run with `tokio_threadpool::blocking`.
+///
+/// Progress is reported in a different order than for a commit that
+/// fits in memory, since the triples are sorted before the
+/// dictionaries can be written. The callback is not told that the
+/// commit is done.
+pub(crate) fn build_spilled_base_layer<F: 'static + FileLoad + FileStore + Clone>(
+    files: BaseLayerFiles<F>,
+    spilled: SpilledTriples,
+    remaining: Vec<StringTriple>,
+    bloom_filter_rate: Option<f64>,
+    progress: CommitProgressCallback,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    // merged triples can only be fewer, due to duplicates between runs
+    let sorting = PhaseProgress::start(
+        &progress,
+        CommitPhase::Sorting,
+        (0, 40),
+        3 * (spilled.len() + remaining.len() as u64),
+    );
+    run_blocking(move || {
+        let dir = spilled.dir.clone();
+        let triples = spilled.merge(remaining)?;
+        let plan = plan_string_triples(&triples, &dir, &sorting)?;
+        let bloom_filter = match bloom_filter_rate {
+            None => None,
+            Some(rate) => {
+                let mut builder = BloomFilterBuilder::new(plan.triples.read()?.count(), rate);
+                for triple in plan.triples.read()? {
+                    let (subject, predicate, object) = triple?;
+                    builder.insert(subject, predicate, object);
+                }
+                Some(builder)
+            }
+        };
+
+        Ok((plan, bloom_filter))
+    })
+    .and_then(move |(plan, bloom_filter)| {
+        let bloom_filter_file = files.bloom_filter_file.clone();
+        write_planned_base_layer(&files, plan, &progress).and_then(move |_| match bloom_filter {
+            None => future::Either::A(future::ok(())),
+            Some(builder) => {
+                future::Either::B(builder.finalize(bloom_filter_file.open_write()).map(|_| ()))
+            }
+        })
+    })
+}
+
+/// Write the planned dictionaries and triples as a base layer.
+///
+/// This reports progress from 40 percent on, as it comes after the
+/// triples were sorted.
+fn write_planned_base_layer<F: 'static + FileLoad + FileStore + Clone>(
+    files: &BaseLayerFiles<F>,
+    plan: SquashPlan,
+    progress: &CommitProgressCallback,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    let SquashPlan {
+        nodes,
+        predicates,
+        values,
+        triples,
+        collation,
+    } = plan;
+    let builder = BaseLayerFileBuilder::from_files(files);
+    let write_collation = write_collation(&files.collation_file, &*collation);
+    let progress = progress.clone();
+    let dictionaries = PhaseProgress::start(
+        &progress,
+        CommitPhase::WritingDictionaries,
+        (40, 60),
+        nodes.len + predicates.len() as u64 + values.len,
+    );
+    let dictionaries2 = dictionaries.clone();
+    let dictionaries3 = dictionaries.clone();
+
+    future::result(nodes.read())
+        .and_then(move |nodes| {
+            stream::iter_result(dictionaries.track(nodes))
+                .fold(builder, |b, node| b.add_node(&node).map(|(_, b)| b))
+        })
+        .and_then(move |b| {
+            b.add_predicates(dictionaries2.track(predicates.into_iter()))
+                .map(|(_, b)| b)
+        })
+        .and_then(move |b| {
+            future::result(values.read()).and_then(move |values| {
+                stream::iter_result(dictionaries3.track(values))
+                    .fold(b, |b, value| b.add_value(&value).map(|(_, b)| b))
+            })
+        })
+        .and_then(|b| b.into_phase2())
+        .and_then({
+            let progress = progress.clone();
+            move |b| {
+                let writing = PhaseProgress::start(
+                    &progress,
+                    CommitPhase::WritingTriples,
+                    (60, 90),
+                    triples.len,
+                );
+                future::result(triples.read()).and_then(move |triples| {
+                    stream::iter_result(writing.track(triples))
+                        .fold(b, |b, (s, p, o)| b.add_triple(s, p, o))
+                })
+            }
+        })
+        .and_then(move |b| {
+            PhaseProgress::start(&progress, CommitPhase::Finalizing, (90, 100), 0);
+            b.finalize()
+        })
+        .and_then(|_| write_collation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::builder::{LayerBuilder, SimpleLayerBuilder};
+    use crate::layer::child::tests::child_layer_files;
+    use crate::layer::child::ChildLayer;
+    use crate::structure::CaseInsensitiveCollation;
+    use futures::sync::oneshot;
+    use tempfile::tempdir;
+
+    #[test]
+    fn external_sorter_merges_runs_without_duplicates() {
+        let dir = tempdir().unwrap();
+        let mut sorter = ExternalSorter::new(dir.path());
+        for i in (0..10u64).rev() {
+            sorter.push((i % 4, i)).unwrap();
+            sorter.spill().unwrap();
+        }
+        sorter.push((3, 7)).unwrap();
+
+        let sorted: Vec<_> = sorter
+            .finish()
+            .unwrap()
+            .read()
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let mut expected: Vec<_> = (0..10u64).map(|i| (i % 4, i)).collect();
+        expected.sort_unstable();
+
+        assert_eq!(expected, sorted);
+    }
+
+    #[test]
+    fn squash_child_layer_into_base_layer() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "sheep"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([5, 4, 3, 2, 1], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_node("duck", "likes", "sheep"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "horse"));
+        builder.commit().wait().unwrap();
+        let child = Arc::new(
+            ChildLayer::load_from_files([5, 4, 3, 2, 1], base, &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let dir = tempdir().unwrap();
+        let files = base_layer_files();
+        build_squashed_base_layer(files.clone(), child.clone(), dir.path().to_owned())
+            .wait()
+            .unwrap();
+        let squashed = BaseLayer::load_from_files([0, 0, 0, 0, 1], &files)
+            .wait()
+            .unwrap();
+
+        let mut expected: Vec<_> = child
+            .triples()
+            .map(|t| child.id_triple_to_string(&t).unwrap())
+            .collect();
+        expected.sort();
+        let mut result: Vec<_> = squashed
+            .triples()
+            .map(|t| squashed.id_triple_to_string(&t).unwrap())
+            .collect();
+        result.sort();
+
+        assert_eq!(expected, result);
+        assert_eq!(4, squashed.node_dict_len());
+        assert_eq!(3, squashed.value_dict_len());
+        assert_eq!(None, squashed.object_node_id("sheep"));
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn squash_on_runtime() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let files = base_layer_files();
+        oneshot::spawn(
+            build_squashed_base_layer(files.clone(), base, dir.path().to_owned()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        runtime.shutdown_now();
+        let squashed = BaseLayer::load_from_files([0, 0, 0, 0, 1], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!(2, squashed.triple_count());
+    }
+
+    #[test]
+    fn squash_keeps_collation_of_layer() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.set_collation(Arc::new(CaseInsensitiveCollation));
+        builder.add_string_triple(&StringTriple::new_value("b", "P", "x"));
+        builder.add_string_triple(&StringTriple::new_value("A", "p", "X"));
+        builder.add_string_triple(&StringTriple::new_node("a", "p", "B"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let dir = tempdir().unwrap();
+        let files = base_layer_files();
+        build_squashed_base_layer(files.clone(), base.clone(), dir.path().to_owned())
+            .wait()
+            .unwrap();
+        let squashed = BaseLayer::load_from_files([0, 0, 0, 0, 1], &files)
+            .wait()
+            .unwrap();
+
+        assert_eq!("case_insensitive", squashed.collation().name());
+        let nodes: Vec<_> = (0..squashed.node_dict_len())
+            .map(|id| squashed.node_dict_get(id).unwrap())
+            .collect();
+        assert_eq!(vec!["A", "a", "B", "b"], nodes);
+        let predicates: Vec<_> = (0..squashed.predicate_dict_len())
+            .map(|id| squashed.predicate_dict_get(id).unwrap())
+            .collect();
+        assert_eq!(vec!["P", "p"], predicates);
+        for triple in base.triples() {
+            let triple = base.id_triple_to_string(&triple).unwrap();
+            assert!(squashed.string_triple_exists(&triple), "{:?}", triple);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Translation of ids between layers that do not share a dictionary.
+//!
+//! Layers that are not part of the same stack assign different ids
+//! to the same strings. Comparing such layers means resolving ids of
+//! one layer to strings, and looking those strings up in the other
+//! layer. An `IdTranslator` remembers every id it translated, so that
+//! each string is only resolved once.
+use super::layer::*;
+use std::collections::HashMap;
+
+/// Translates ids from the id space of one layer to that of another.
+///
+/// Translations are cached, including ids that don't exist in the
+/// other layer, so after the first time an id is seen, translating it
+/// is a hash lookup. The cache is never cleared, so a translator
+/// should not outlive the operation it was made for.
+pub struct IdTranslator<'a> {
+    from: &'a dyn Layer,
+    to: &'a dyn Layer,
+    cache: TranslationCache,
+}
+
+impl<'a> IdTranslator<'a> {
+    /// Create a translator from the ids of `from` to the ids of `to`.
+    pub fn new(from: &'a dyn Layer, to: &'a dyn Layer) -> Self {
+        IdTranslator {
+            from,
+            to,
+            cache: TranslationCache::default(),
+        }
+    }
+
+    /// The id of the given subject in the target layer, or None if it is not there.
+    pub fn translate_subject(&mut self, id: u64) -> Option<u64> {
+        self.cache.translate_subject(self.from, self.to, id)
+    }
+
+    /// The id of the given predicate in the target layer, or None if it is not there.
+    pub fn translate_predicate(&mut self, id: u64) -> Option<u64> {
+        self.cache.translate_predicate(self.from, self.to, id)
+    }
+
+    /// The id of the given node or value object in the target layer, or None if it is not there.
+    pub fn translate_object(&mut self, id: u64) -> Option<u64> {
+        self.cache.translate_object(self.from, self.to, id)
+    }
+
+    /// The given triple in the ids of the target layer, or None if one of its parts is not there.
+    ///
+    /// This only translates ids. It does not check whether the
+    /// target layer actually contains the triple.
+    pub fn translate_triple(&mut self, triple: IdTriple) -> Option<IdTriple> {
+        Some(IdTriple::new(
+            self.translate_subject(triple.subject)?,
+            self.translate_predicate(triple.predicate)?,
+            self.translate_object(triple.object)?,
+        ))
+    }
+}
+
+/// The cached translations between two layers, without the layers themselves.
+///
+/// This is what `IdTranslator` is built on. It is used directly
+/// where the layers are owned elsewhere, like by the members of a
+/// union. Every call has to pass the same pair of layers.
+///
+/// Nodes and values share an id space, so each id is either a node
+/// or a value. Node ids and value ids are cached separately, and an
+/// id is only cached once it is known which of the two it is, so
+/// translating a value id as a subject can't hide its translation as
+/// an object.
+#[derive(Default)]
+pub(crate) struct TranslationCache {
+    nodes: HashMap<u64, Option<u64>>,
+    predicates: HashMap<u64, Option<u64>>,
+    values: HashMap<u64, Option<u64>>,
+}
+
+impl TranslationCache {
+    pub(crate) fn translate_subject(
+        &mut self,
+        from: &dyn Layer,
+        to: &dyn Layer,
+        id: u64,
+    ) -> Option<u64> {
+        // subjects are nodes, so a value id never translates to a subject
+        let translated = self.translate_object(from, to, id);
+        if self.values.contains_key(&id) {
+            None
+        } else {
+            translated
+        }
+    }
+
+    pub(crate) fn translate_predicate(
+        &mut self,
+        from: &dyn Layer,
+        to: &dyn Layer,
+        id: u64,
+    ) -> Option<u64> {
+        *self
+            .predicates
+            .entry(id)
+            .or_insert_with(|| from.id_predicate(id).and_then(|p| to.predicate_id(&p)))
+    }
+
+    pub(crate) fn translate_object(
+        &mut self,
+        from: &dyn Layer,
+        to: &dyn Layer,
+        id: u64,
+    ) -> Option<u64> {
+        if let Some(&translated) = self.nodes.get(&id).or_else(|| self.values.get(&id)) {
+            return translated;
+        }
+
+        match from.id_object(id) {
+            None => None,
+            Some(ObjectType::Node(node)) => {
+                let translated = to.object_node_id(&node);
+                self.nodes.insert(id, translated);
+                translated
+            }
+            Some(ObjectType::Value(value)) => {
+                let translated = to.object_value_id(&value);
+                self.values.insert(id, translated);
+                translated
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::{BaseLayer, LayerBuilder, SimpleLayerBuilder};
+    use futures::prelude::*;
+
+    fn base_layer(name: [u32; 5], triples: &[StringTriple]) -> impl Layer {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new(name, files.clone());
+        for triple in triples {
+            builder.add_string_triple(triple);
+        }
+        builder.commit().wait().unwrap();
+
+        BaseLayer::load_from_files(name, &files).wait().unwrap()
+    }
+
+    #[test]
+    fn translate_ids_between_unrelated_layers() {
+        let from = base_layer(
+            [1, 2, 3, 4, 5],
+            &[
+                StringTriple::new_value("cow", "says", "moo"),
+                StringTriple::new_node("cow", "likes", "duck"),
+                StringTriple::new_value("duck", "says", "cow"),
+                StringTriple::new_value("pig", "says", "oink"),
+            ],
+        );
+        let to = base_layer(
+            [5, 4, 3, 2, 1],
+            &[
+                StringTriple::new_value("aardvark", "eats", "ants"),
+                StringTriple::new_value("cow", "says", "moo"),
+                StringTriple::new_node("duck", "likes", "cow"),
+            ],
+        );
+        let mut translator = IdTranslator::new(&from, &to);
+
+        for _ in 0..2 {
+            let triple = from
+                .string_triple_to_id(&StringTriple::new_value("cow", "says", "moo"))
+                .unwrap();
+            assert_eq!(
+                to.string_triple_to_id(&StringTriple::new_value("cow", "says", "moo")),
+                translator.translate_triple(triple)
+            );
+        }
+
+        // the node cow exists in both, but the value cow only exists in from
+        let node = from.object_node_id("cow").unwrap();
+        assert_eq!(to.object_node_id("cow"), translator.translate_object(node));
+        let value = from.object_value_id("cow").unwrap();
+        assert_eq!(None, translator.translate_object(value));
+
+        // translating a value id as a subject does not affect its translation as an object
+        let mut translator = IdTranslator::new(&from, &to);
+        let moo = from.object_value_id("moo").unwrap();
+        assert_eq!(None, translator.translate_subject(moo));
+        assert_eq!(to.object_value_id("moo"), translator.translate_object(moo));
+        assert_eq!(None, translator.translate_subject(moo));
+
+        let pig = from.subject_id("pig").unwrap();
+        assert_eq!(None, translator.translate_subject(pig));
+        let likes = from.predicate_id("likes").unwrap();
+        assert_eq!(
+            to.predicate_id("likes"),
+            translator.translate_predicate(likes)
+        );
+
+        // the ids can all be translated, but the triple itself is not in to
+        let triple = from
+            .string_triple_to_id(&StringTriple::new_node("cow", "likes", "duck"))
+            .unwrap();
+        let translated = translator.translate_triple(triple).unwrap();
+        assert!(!to.id_triple_exists(translated));
+    }
+}
@@ -0,0 +1,911 @@
+//! A read-only view over the union of several layers.
+//!
+//! The layers making up a union don't need to be related. Each of
+//! them has its own id space, so the union presents a combined id
+//! space of its own, in which equal strings get equal ids, no matter
+//! which member layer they came from.
+use super::layer::*;
+use super::translate::TranslationCache;
+use crate::structure::{BytewiseCollation, Collation};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A layer containing all the triples of a list of member layers.
+///
+/// No triples are copied when a union is constructed. Instead, all
+/// queries are answered by querying the members, and translating
+/// the ids they return.
+///
+/// Ids in the union are laid out like those of a base layer: first
+/// all nodes, then all values. The nodes of the first member come
+/// first, then the nodes of the second member, and so on, and the
+/// same goes for values and predicates. A string that occurs in
+/// more than one member always gets the id it has through the first
+/// member that contains it. The ids that the other members would
+/// have given it are never handed out by the union.
+///
+/// Constructing a union only reads the dictionary sizes of the
+/// members. Looking up a string tries each member in order. Turning
+/// an id returned by a member into a union id resolves it to a
+/// string, and then looks up that string in each earlier member, so
+/// most queries are a factor of the number of members slower than
+/// they would be on a single layer. These translations are cached
+/// for every pair of members, so each id is only resolved once. The
+/// caches live as long as the union, and grow up to the size of the
+/// dictionaries of the members. Lookups by subject, predicate or
+/// object collect their results in memory before returning
+/// them. Iterating over all subjects or objects first collects the
+/// ids of all subjects or objects of all members.
+#[derive(Clone)]
+pub struct UnionLayer {
+    inner: Arc<UnionLayerInner>,
+}
+
+impl UnionLayer {
+    /// Create a union of the given layers.
+    ///
+    /// The order of the layers determines the layout of the union's
+    /// id space, but not its contents.
+    pub fn new(layers: Vec<Arc<dyn Layer>>) -> UnionLayer {
+        let name = union_name(&layers);
+        let mut node_count = 0;
+        let mut value_count = 0;
+        let mut predicate_count = 0;
+        let mut members = Vec::with_capacity(layers.len());
+        for layer in layers {
+            let mut stack = Vec::new();
+            let mut current: Option<&dyn Layer> = Some(&*layer);
+            while let Some(l) = current {
+                stack.push((l.node_dict_len() as u64, l.value_dict_len() as u64));
+                current = l.parent();
+            }
+            stack.reverse();
+
+            let member = UnionMember {
+                node_offset: node_count,
+                value_offset: value_count,
+                predicate_offset: predicate_count,
+                stack,
+                layer,
+            };
+            node_count += member.node_count();
+            value_count += member.value_count();
+            predicate_count += member.layer.predicate_count() as u64;
+            members.push(member);
+        }
+
+        let translations = (0..members.len() * members.len())
+            .map(|_| Mutex::new(TranslationCache::default()))
+            .collect();
+
+        UnionLayer {
+            inner: Arc::new(UnionLayerInner {
+                name,
+                members,
+                translations,
+                node_count,
+                value_count,
+                predicate_count,
+            }),
+        }
+    }
+}
+
+/// Derive a name for a union from the names of its members.
+fn union_name(layers: &[Arc<dyn Layer>]) -> [u32; 5] {
+    let mut name = [0; 5];
+    for (i, part) in name.iter_mut().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        i.hash(&mut hasher);
+        for layer in layers {
+            layer.name().hash(&mut hasher);
+        }
+        *part = hasher.finish() as u32;
+    }
+
+    name
+}
+
+struct UnionMember {
+    layer: Arc<dyn Layer>,
+    /// The node and value dictionary lengths of every layer in the stack, starting at the base layer.
+    stack: Vec<(u64, u64)>,
+    node_offset: u64,
+    value_offset: u64,
+    predicate_offset: u64,
+}
+
+impl UnionMember {
+    fn node_count(&self) -> u64 {
+        self.stack.iter().map(|(nodes, _)| nodes).sum()
+    }
+
+    fn value_count(&self) -> u64 {
+        self.stack.iter().map(|(_, values)| values).sum()
+    }
+
+    /// Translate an id of this member into whether it is a node, and its position among all nodes or values in the stack.
+    fn ordinal(&self, id: u64) -> Option<(bool, u64)> {
+        if id == 0 {
+            return None;
+        }
+        let mut offset = 0;
+        let mut nodes_before = 0;
+        let mut values_before = 0;
+        for &(nodes, values) in self.stack.iter() {
+            if id <= offset + nodes {
+                return Some((true, nodes_before + id - offset - 1));
+            } else if id <= offset + nodes + values {
+                return Some((false, values_before + id - offset - nodes - 1));
+            }
+            offset += nodes + values;
+            nodes_before += nodes;
+            values_before += values;
+        }
+
+        None
+    }
+
+    /// Translate a position among all nodes or values in the stack back into an id of this member.
+    fn id(&self, is_node: bool, ordinal: u64) -> Option<u64> {
+        let mut offset = 0;
+        let mut nodes_before = 0;
+        let mut values_before = 0;
+        for &(nodes, values) in self.stack.iter() {
+            if is_node && ordinal < nodes_before + nodes {
+                return Some(offset + ordinal - nodes_before + 1);
+            } else if !is_node && ordinal < values_before + values {
+                return Some(offset + nodes + ordinal - values_before + 1);
+            }
+            offset += nodes + values;
+            nodes_before += nodes;
+            values_before += values;
+        }
+
+        None
+    }
+}
+
+struct UnionLayerInner {
+    name: [u32; 5],
+    members: Vec<UnionMember>,
+    /// The translations from the ids of one member to those of another, indexed by `from * members.len() + to`.
+    translations: Vec<Mutex<TranslationCache>>,
+    node_count: u64,
+    value_count: u64,
+    predicate_count: u64,
+}
+
+impl UnionLayerInner {
+    fn encode_object(&self, member: usize, is_node: bool, ordinal: u64) -> u64 {
+        let member = &self.members[member];
+        if is_node {
+            member.node_offset + ordinal + 1
+        } else {
+            self.node_count + member.value_offset + ordinal + 1
+        }
+    }
+
+    fn decode_object(&self, id: u64) -> Option<(usize, bool, u64)> {
+        if id == 0 {
+            return None;
+        }
+        let ordinal = id - 1;
+        if ordinal < self.node_count {
+            self.members
+                .iter()
+                .position(|m| ordinal < m.node_offset + m.node_count())
+                .map(|i| (i, true, ordinal - self.members[i].node_offset))
+        } else {
+            let ordinal = ordinal - self.node_count;
+            self.members
+                .iter()
+                .position(|m| ordinal < m.value_offset + m.value_count())
+                .map(|i| (i, false, ordinal - self.members[i].value_offset))
+        }
+    }
+
+    fn object(&self, id: u64) -> Option<ObjectType> {
+        let (member, is_node, ordinal) = self.decode_object(id)?;
+        let member = &self.members[member];
+        member
+            .id(is_node, ordinal)
+            .and_then(|id| member.layer.id_object(id))
+    }
+
+    /// Find the union id of an object, using the first member that knows it.
+    fn find_object<F: Fn(&dyn Layer) -> Option<u64>>(&self, f: F) -> Option<u64> {
+        self.members.iter().enumerate().find_map(|(i, member)| {
+            f(&*member.layer)
+                .and_then(|id| member.ordinal(id))
+                .map(|(is_node, ordinal)| self.encode_object(i, is_node, ordinal))
+        })
+    }
+
+    fn translation(&self, from: usize, to: usize) -> MutexGuard<'_, TranslationCache> {
+        self.translations[from * self.members.len() + to]
+            .lock()
+            .expect("union translation cache lock should not be poisoned")
+    }
+
+    /// Translate a node or value id of one member into an id of another member.
+    fn translate_object(&self, from: usize, to: usize, id: u64) -> Option<u64> {
+        self.translation(from, to).translate_object(
+            &*self.members[from].layer,
+            &*self.members[to].layer,
+            id,
+        )
+    }
+
+    /// Translate a predicate id of one member into a predicate id of another member.
+    fn translate_predicate(&self, from: usize, to: usize, id: u64) -> Option<u64> {
+        self.translation(from, to).translate_predicate(
+            &*self.members[from].layer,
+            &*self.members[to].layer,
+            id,
+        )
+    }
+
+    /// Translate a union node or value id into an id of the given member.
+    fn to_member_object(&self, member: usize, id: u64) -> Option<u64> {
+        let (owner, is_node, ordinal) = self.decode_object(id)?;
+        let owner_id = self.members[owner].id(is_node, ordinal)?;
+        if owner == member {
+            return Some(owner_id);
+        }
+
+        self.translate_object(owner, member, owner_id)
+    }
+
+    /// Translate a node or value id of the given member into a union id.
+    fn member_object_to_union(&self, member: usize, id: u64) -> Option<u64> {
+        let (is_node, ordinal) = self.members[member].ordinal(id)?;
+        let earlier = (0..member).find_map(|i| {
+            self.translate_object(member, i, id)
+                .and_then(|id| self.members[i].ordinal(id))
+                .map(|(is_node, ordinal)| self.encode_object(i, is_node, ordinal))
+        });
+
+        Some(earlier.unwrap_or_else(|| self.encode_object(member, is_node, ordinal)))
+    }
+
+    fn decode_predicate(&self, id: u64) -> Option<(usize, u64)> {
+        if id == 0 || id > self.predicate_count {
+            return None;
+        }
+        self.members
+            .iter()
+            .rposition(|m| id > m.predicate_offset)
+            .map(|i| (i, id - self.members[i].predicate_offset))
+    }
+
+    fn predicate(&self, id: u64) -> Option<String> {
+        let (member, id) = self.decode_predicate(id)?;
+        self.members[member].layer.id_predicate(id)
+    }
+
+    /// Translate a union predicate id into a predicate id of the given member.
+    fn to_member_predicate(&self, member: usize, id: u64) -> Option<u64> {
+        let (owner, local) = self.decode_predicate(id)?;
+        if owner == member {
+            return Some(local);
+        }
+
+        self.translate_predicate(owner, member, local)
+    }
+
+    /// Translate a predicate id of the given member into a union predicate id.
+    fn member_predicate_to_union(&self, member: usize, id: u64) -> Option<u64> {
+        let earlier = (0..member).find_map(|i| {
+            self.translate_predicate(member, i, id)
+                .map(|id| self.members[i].predicate_offset + id)
+        });
+
+        Some(earlier.unwrap_or(self.members[member].predicate_offset + id))
+    }
+
+    fn union_object(&self, member: usize, id: u64) -> u64 {
+        self.member_object_to_union(member, id)
+            .expect("member layer returned an object id it could not resolve")
+    }
+
+    fn union_predicate(&self, member: usize, id: u64) -> u64 {
+        self.member_predicate_to_union(member, id)
+            .expect("member layer returned a predicate id it could not resolve")
+    }
+
+    fn subject_lookup(&self, subject: u64) -> Option<UnionSubjectLookup> {
+        let mut predicates: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+        for (m, member) in self.members.iter().enumerate() {
+            let lookup = match self
+                .to_member_object(m, subject)
+                .and_then(|s| member.layer.lookup_subject(s))
+            {
+                Some(lookup) => lookup,
+                None => continue,
+            };
+            for predicate_lookup in lookup.predicates() {
+                let mut objects = predicate_lookup.objects().peekable();
+                if objects.peek().is_none() {
+                    continue;
+                }
+                let predicate = self.union_predicate(m, predicate_lookup.predicate());
+                predicates
+                    .entry(predicate)
+                    .or_default()
+                    .extend(objects.map(|o| self.union_object(m, o)));
+            }
+        }
+
+        if predicates.is_empty() {
+            None
+        } else {
+            Some(UnionSubjectLookup {
+                subject,
+                predicates: predicates
+                    .into_iter()
+                    .map(|(p, objects)| (p, Arc::new(objects.into_iter().collect())))
+                    .collect(),
+            })
+        }
+    }
+
+    fn object_lookup(&self, object: u64) -> Option<UnionObjectLookup> {
+        let mut pairs = BTreeSet::new();
+        for (m, member) in self.members.iter().enumerate() {
+            if let Some(lookup) = self
+                .to_member_object(m, object)
+                .and_then(|o| member.layer.lookup_object(o))
+            {
+                pairs.extend(
+                    lookup
+                        .subject_predicate_pairs()
+                        .map(|(s, p)| (self.union_object(m, s), self.union_predicate(m, p))),
+                );
+            }
+        }
+
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(UnionObjectLookup {
+                object,
+                pairs: Arc::new(pairs.into_iter().collect()),
+            })
+        }
+    }
+
+    fn predicate_lookup(&self, predicate: u64) -> Option<UnionPredicateLookup> {
+        let mut subjects: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+        for (m, member) in self.members.iter().enumerate() {
+            let lookup = match self
+                .to_member_predicate(m, predicate)
+                .and_then(|p| member.layer.lookup_predicate(p))
+            {
+                Some(lookup) => lookup,
+                None => continue,
+            };
+            for pair in lookup.subject_predicate_pairs() {
+                let mut objects = pair.objects().peekable();
+                if objects.peek().is_none() {
+                    continue;
+                }
+                let subject = self.union_object(m, pair.subject());
+                subjects
+                    .entry(subject)
+                    .or_default()
+                    .extend(objects.map(|o| self.union_object(m, o)));
+            }
+        }
+
+        if subjects.is_empty() {
+            None
+        } else {
+            Some(UnionPredicateLookup {
+                predicate,
+                subjects: subjects
+                    .into_iter()
+                    .map(|(s, objects)| (s, Arc::new(objects.into_iter().collect())))
+                    .collect(),
+            })
+        }
+    }
+}
+
+impl Layer for UnionLayer {
+    fn name(&self) -> [u32; 5] {
+        self.inner.name
+    }
+
+    fn parent(&self) -> Option<&dyn Layer> {
+        None
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Base
+    }
+
+    /// The collation of the first member.
+    ///
+    /// The members are expected to share a collation. An empty union
+    /// is sorted bytewise.
+    fn collation(&self) -> Arc<dyn Collation> {
+        self.inner
+            .members
+            .first()
+            .map(|member| member.layer.collation())
+            .unwrap_or_else(|| Arc::new(BytewiseCollation))
+    }
+
+    fn node_and_value_count(&self) -> usize {
+        (self.inner.node_count + self.inner.value_count) as usize
+    }
+
+    fn predicate_count(&self) -> usize {
+        self.inner.predicate_count as usize
+    }
+
+    fn predicate_dict_get(&self, id: usize) -> Option<String> {
+        self.id_predicate(id as u64 + 1)
+    }
+
+    fn predicate_dict_len(&self) -> usize {
+        self.inner.predicate_count as usize
+    }
+
+    fn predicate_dict_id(&self, predicate: &str) -> Option<u64> {
+        self.predicate_id(predicate).map(|id| id - 1)
+    }
+
+    fn node_dict_id(&self, subject: &str) -> Option<u64> {
+        self.object_node_id(subject).map(|id| id - 1)
+    }
+
+    fn node_dict_get(&self, id: usize) -> Option<String> {
+        self.id_subject(id as u64 + 1)
+    }
+
+    fn node_dict_len(&self) -> usize {
+        self.inner.node_count as usize
+    }
+
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a> {
+        let mut result = Vec::new();
+        for (m, member) in self.inner.members.iter().enumerate() {
+            let mut current: Option<&dyn Layer> = Some(&*member.layer);
+            while let Some(layer) = current {
+                let offset = layer
+                    .parent()
+                    .map_or(0, |parent| parent.node_and_value_count() as u64);
+                result.extend(
+                    layer
+                        .node_dict_entries_with_prefix(prefix)
+                        .map(|(id, s)| (self.inner.union_object(m, 1 + id + offset) - 1, s)),
+                );
+                current = layer.parent();
+            }
+        }
+        result.sort_by(|(_, s1), (_, s2)| s1.cmp(s2));
+        result.dedup_by(|(_, s1), (_, s2)| s1 == s2);
+
+        Box::new(result.into_iter())
+    }
+
+    fn value_dict_id(&self, value: &str) -> Option<u64> {
+        self.object_value_id(value)
+            .map(|id| id - self.inner.node_count - 1)
+    }
+
+    fn value_dict_len(&self) -> usize {
+        self.inner.value_count as usize
+    }
+
+    fn value_dict_get(&self, id: usize) -> Option<String> {
+        match self.id_object(self.inner.node_count + id as u64 + 1) {
+            Some(ObjectType::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn subject_id(&self, subject: &str) -> Option<u64> {
+        self.inner.find_object(|layer| layer.subject_id(subject))
+    }
+
+    fn predicate_id(&self, predicate: &str) -> Option<u64> {
+        self.inner.members.iter().find_map(|member| {
+            member
+                .layer
+                .predicate_id(predicate)
+                .map(|id| member.predicate_offset + id)
+        })
+    }
+
+    fn object_node_id(&self, object: &str) -> Option<u64> {
+        self.inner.find_object(|layer| layer.object_node_id(object))
+    }
+
+    fn object_value_id(&self, object: &str) -> Option<u64> {
+        self.inner
+            .find_object(|layer| layer.object_value_id(object))
+    }
+
+    fn id_subject(&self, id: u64) -> Option<String> {
+        match self.id_object(id) {
+            Some(ObjectType::Node(node)) => Some(node),
+            _ => None,
+        }
+    }
+
+    fn id_predicate(&self, id: u64) -> Option<String> {
+        self.inner.predicate(id)
+    }
+
+    fn id_object(&self, id: u64) -> Option<ObjectType> {
+        self.inner.object(id)
+    }
+
+    fn subject_additions(&self) -> Box<dyn Iterator<Item = Box<dyn LayerSubjectLookup>>> {
+        let mut subjects = BTreeSet::new();
+        for (m, member) in self.inner.members.iter().enumerate() {
+            subjects.extend(
+                member
+                    .layer
+                    .subjects()
+                    .map(|s| self.inner.union_object(m, s.subject())),
+            );
+        }
+
+        let inner = self.inner.clone();
+        Box::new(subjects.into_iter().filter_map(move |s| {
+            inner
+                .subject_lookup(s)
+                .map(|l| Box::new(l) as Box<dyn LayerSubjectLookup>)
+        }))
+    }
+
+    fn subject_removals(&self) -> Box<dyn Iterator<Item = Box<dyn LayerSubjectLookup>>> {
+        Box::new(std::iter::empty())
+    }
+
+    fn lookup_subject_addition(&self, subject: u64) -> Option<Box<dyn LayerSubjectLookup>> {
+        self.inner
+            .subject_lookup(subject)
+            .map(|l| Box::new(l) as Box<dyn LayerSubjectLookup>)
+    }
+
+    fn lookup_subject_removal(&self, _subject: u64) -> Option<Box<dyn LayerSubjectLookup>> {
+        None
+    }
+
+    fn object_additions(&self) -> Box<dyn Iterator<Item = Box<dyn LayerObjectLookup>>> {
+        let mut objects = BTreeSet::new();
+        for (m, member) in self.inner.members.iter().enumerate() {
+            objects.extend(
+                member
+                    .layer
+                    .objects()
+                    .map(|o| self.inner.union_object(m, o.object())),
+            );
+        }
+
+        let inner = self.inner.clone();
+        Box::new(objects.into_iter().filter_map(move |o| {
+            inner
+                .object_lookup(o)
+                .map(|l| Box::new(l) as Box<dyn LayerObjectLookup>)
+        }))
+    }
+
+    fn object_removals(&self) -> Box<dyn Iterator<Item = Box<dyn LayerObjectLookup>>> {
+        Box::new(std::iter::empty())
+    }
+
+    fn lookup_object_addition(&self, object: u64) -> Option<Box<dyn LayerObjectLookup>> {
+        self.inner
+            .object_lookup(object)
+            .map(|l| Box::new(l) as Box<dyn LayerObjectLookup>)
+    }
+
+    fn lookup_object_removal(&self, _object: u64) -> Option<Box<dyn LayerObjectLookup>> {
+        None
+    }
+
+    fn lookup_predicate_addition(&self, predicate: u64) -> Option<Box<dyn LayerPredicateLookup>> {
+        self.inner
+            .predicate_lookup(predicate)
+            .map(|l| Box::new(l) as Box<dyn LayerPredicateLookup>)
+    }
+
+    fn lookup_predicate_removal(&self, _predicate: u64) -> Option<Box<dyn LayerPredicateLookup>> {
+        None
+    }
+
+    fn clone_boxed(&self) -> Box<dyn Layer> {
+        Box::new(self.clone())
+    }
+
+    fn count_matching(
+        &self,
+        subject: Option<u64>,
+        predicate: Option<u64>,
+        object: Option<u64>,
+    ) -> usize {
+        // the triple count counts triples that occur in several members more than once
+        count_matching_with_lookups(self, subject, predicate, object)
+    }
+
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        let mut counts = LayerTripleCounts::default();
+        for member in self.inner.members.iter() {
+            let member_counts = stack_triple_counts(&*member.layer);
+            counts.subjects += member_counts.subjects;
+            counts.subject_predicate_pairs += member_counts.subject_predicate_pairs;
+            counts.triples += member_counts.triples;
+        }
+
+        counts
+    }
+
+    fn predicate_addition_count(&self, predicate: u64) -> usize {
+        (0..self.inner.members.len())
+            .filter_map(|m| {
+                self.inner
+                    .to_member_predicate(m, predicate)
+                    .map(|p| stack_predicate_count(&*self.inner.members[m].layer, p))
+            })
+            .sum()
+    }
+}
+
+type ObjectList = Arc<Vec<u64>>;
+
+struct UnionSubjectLookup {
+    subject: u64,
+    predicates: Vec<(u64, ObjectList)>,
+}
+
+impl LayerSubjectLookup for UnionSubjectLookup {
+    fn subject(&self) -> u64 {
+        self.subject
+    }
+
+    fn predicates(&self) -> Box<dyn Iterator<Item = Box<dyn LayerSubjectPredicateLookup>>> {
+        let subject = self.subject;
+        Box::new(
+            self.predicates
+                .clone()
+                .into_iter()
+                .map(move |(predicate, objects)| {
+                    Box::new(UnionSubjectPredicateLookup {
+                        subject,
+                        predicate,
+                        objects,
+                    }) as Box<dyn LayerSubjectPredicateLookup>
+                }),
+        )
+    }
+
+    fn lookup_predicate(&self, predicate: u64) -> Option<Box<dyn LayerSubjectPredicateLookup>> {
+        self.predicates
+            .binary_search_by_key(&predicate, |(p, _)| *p)
+            .ok()
+            .map(|index| {
+                Box::new(UnionSubjectPredicateLookup {
+                    subject: self.subject,
+                    predicate,
+                    objects: self.predicates[index].1.clone(),
+                }) as Box<dyn LayerSubjectPredicateLookup>
+            })
+    }
+}
+
+struct UnionSubjectPredicateLookup {
+    subject: u64,
+    predicate: u64,
+    objects: ObjectList,
+}
+
+impl LayerSubjectPredicateLookup for UnionSubjectPredicateLookup {
+    fn subject(&self) -> u64 {
+        self.subject
+    }
+
+    fn predicate(&self) -> u64 {
+        self.predicate
+    }
+
+    fn objects(&self) -> Box<dyn Iterator<Item = u64>> {
+        let objects = self.objects.clone();
+        Box::new((0..objects.len()).map(move |index| objects[index]))
+    }
+
+    fn has_object(&self, object: u64) -> bool {
+        self.objects.binary_search(&object).is_ok()
+    }
+}
+
+struct UnionObjectLookup {
+    object: u64,
+    pairs: Arc<Vec<(u64, u64)>>,
+}
+
+impl LayerObjectLookup for UnionObjectLookup {
+    fn object(&self) -> u64 {
+        self.object
+    }
+
+    fn subject_predicate_pairs(&self) -> Box<dyn Iterator<Item = (u64, u64)>> {
+        let pairs = self.pairs.clone();
+        Box::new((0..pairs.len()).map(move |index| pairs[index]))
+    }
+}
+
+struct UnionPredicateLookup {
+    predicate: u64,
+    subjects: Vec<(u64, ObjectList)>,
+}
+
+impl LayerPredicateLookup for UnionPredicateLookup {
+    fn predicate(&self) -> u64 {
+        self.predicate
+    }
+
+    fn subject_predicate_pairs(
+        &self,
+    ) -> Box<dyn Iterator<Item = Box<dyn LayerSubjectPredicateLookup>>> {
+        let predicate = self.predicate;
+        Box::new(
+            self.subjects
+                .clone()
+                .into_iter()
+                .map(move |(subject, objects)| {
+                    Box::new(UnionSubjectPredicateLookup {
+                        subject,
+                        predicate,
+                        objects,
+                    }) as Box<dyn LayerSubjectPredicateLookup>
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::base::tests::base_layer_files;
+    use crate::layer::base::BaseLayer;
+    use crate::layer::builder::{LayerBuilder, SimpleLayerBuilder};
+    use crate::layer::child::tests::child_layer_files;
+    use crate::layer::child::ChildLayer;
+    use crate::structure::CaseInsensitiveCollation;
+    use futures::prelude::*;
+
+    fn example_union() -> UnionLayer {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.commit().wait().unwrap();
+        let first = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([2, 3, 4, 5, 6], files.clone());
+        builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+        builder.add_string_triple(&StringTriple::new_node("duck", "likes", "cow"));
+        builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        builder.add_string_triple(&StringTriple::new_value("horse", "says", "neigh"));
+        builder.commit().wait().unwrap();
+        let base = Arc::new(
+            BaseLayer::load_from_files([2, 3, 4, 5, 6], &files)
+                .wait()
+                .unwrap(),
+        ) as Arc<dyn Layer>;
+
+        let files = child_layer_files();
+        let mut builder =
+            SimpleLayerBuilder::from_parent([3, 4, 5, 6, 7], base.clone(), files.clone());
+        builder.remove_string_triple(&StringTriple::new_value("horse", "says", "neigh"));
+        builder.add_string_triple(&StringTriple::new_node("pig", "likes", "cow"));
+        builder.commit().wait().unwrap();
+        let second = ChildLayer::load_from_files([3, 4, 5, 6, 7], base, &files)
+            .wait()
+            .unwrap();
+
+        UnionLayer::new(vec![Arc::new(first), Arc::new(second)])
+    }
+
+    #[test]
+    fn union_contains_triples_of_all_members() {
+        let union = example_union();
+
+        let mut triples: Vec<_> = union
+            .triples()
+            .map(|t| union.id_triple_to_string(&t).unwrap())
+            .collect();
+        triples.sort();
+
+        let mut expected = vec![
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_node("cow", "likes", "duck"),
+            StringTriple::new_value("duck", "says", "quack"),
+            StringTriple::new_value("pig", "says", "oink"),
+            StringTriple::new_node("duck", "likes", "cow"),
+            StringTriple::new_node("pig", "likes", "cow"),
+        ];
+        expected.sort();
+
+        assert_eq!(expected, triples);
+        assert!(!union.string_triple_exists(&StringTriple::new_value("horse", "says", "neigh")));
+    }
+
+    #[test]
+    fn union_ids_are_consistent_across_members() {
+        let union = example_union();
+
+        for subject in &["cow", "duck", "pig", "horse"] {
+            let id = union.subject_id(subject).unwrap();
+            assert_eq!(Some(*subject), union.id_subject(id).as_deref());
+            assert_eq!(Some(id), union.object_node_id(subject));
+        }
+        for predicate in &["says", "likes"] {
+            let id = union.predicate_id(predicate).unwrap();
+            assert_eq!(Some(*predicate), union.id_predicate(id).as_deref());
+        }
+        let quack = union.object_value_id("quack").unwrap();
+        assert_eq!(
+            Some(ObjectType::Value("quack".to_owned())),
+            union.id_object(quack)
+        );
+
+        let cow = union.subject_id("cow").unwrap();
+        let duck = union.subject_id("duck").unwrap();
+        let pig = union.subject_id("pig").unwrap();
+        let likes = union.predicate_id("likes").unwrap();
+
+        // duck appears in both members, but is only listed once
+        let lookup = union.lookup_object(cow).unwrap();
+        let pairs: Vec<_> = lookup.subject_predicate_pairs().collect();
+        assert_eq!(vec![(duck, likes), (pig, likes)], pairs);
+
+        let subjects: Vec<_> = union
+            .lookup_predicate(likes)
+            .unwrap()
+            .subject_predicate_pairs()
+            .map(|sp| sp.subject())
+            .collect();
+        assert_eq!(vec![cow, duck, pig], subjects);
+
+        assert_eq!(
+            1,
+            union
+                .triples()
+                .filter(|t| union.id_triple_to_string(t).unwrap()
+                    == StringTriple::new_value("duck", "says", "quack"))
+                .count()
+        );
+    }
+
+    #[test]
+    fn union_has_collation_of_first_member() {
+        let files = base_layer_files();
+        let mut builder = SimpleLayerBuilder::new([1, 2, 3, 4, 5], files.clone());
+        builder.set_collation(Arc::new(CaseInsensitiveCollation));
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.commit().wait().unwrap();
+        let member = BaseLayer::load_from_files([1, 2, 3, 4, 5], &files)
+            .wait()
+            .unwrap();
+
+        let union = UnionLayer::new(vec![Arc::new(member)]);
+        assert_eq!("case_insensitive", union.collation().name());
+        assert_eq!("bytewise", UnionLayer::new(Vec::new()).collation().name());
+    }
+}
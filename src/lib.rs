@@ -37,4 +37,4 @@ pub mod structure;
 
 pub use layer::Layer;
 pub use store::sync::{open_sync_directory_store, open_sync_memory_store};
-pub use store::{open_directory_store, open_memory_store};
+pub use store::{default_layer_store, open_directory_store, open_memory_store};
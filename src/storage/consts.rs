@@ -72,6 +72,10 @@ pub struct Filenames {
     pub neg_predicate_wavelet_tree_bit_index_sblocks: &'static str,
 
     pub parent: &'static str,
+    pub metadata: &'static str,
+    pub bloom_filter: &'static str,
+    pub collation: &'static str,
+    pub format_version: &'static str,
 }
 
 pub const FILENAMES: Filenames = Filenames {
@@ -156,4 +160,8 @@ pub const FILENAMES: Filenames = Filenames {
         "neg_predicate_wavelet_tree_bit_index_sblocks.logarray",
 
     parent: "parent.hex",
+    metadata: "metadata.json",
+    bloom_filter: "triples.bloom",
+    collation: "dictionary.collation",
+    format_version: "format_version",
 };
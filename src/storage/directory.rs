@@ -1,36 +1,111 @@
 //! Directory-based implementation of storage traits.
+use crate::layer::Layer;
 use futures::prelude::*;
 use locking::*;
 use memmap::*;
+use std::collections::HashMap;
 use std::io::{self, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
 use tokio::fs::{self, *};
 use tokio::prelude::*;
 
 use super::*;
+use crate::structure::run_blocking;
+use byteorder::{BigEndian, ByteOrder};
+use fs2::FileExt;
+use siphasher::sip::SipHasher13;
+use std::io::{Read, Write};
 
 const PREFIX_DIR_SIZE: usize = 3;
 
+/// The compression codec used for layer files in a directory store.
+///
+/// Only adjacency-list and dictionary files are compressed. Since
+/// compressed data can't be memory-mapped directly, a compressed file
+/// is always decompressed into a heap buffer when it is mapped or
+/// read, rather than being memory-mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd compression with the given compression level.
+    Zstd(i32),
+}
+
+const COMPRESSED_EXTENSION: &str = "zst";
+
+fn is_compressible_file(name: &str) -> bool {
+    name.contains("adjacency_list") || name.contains("dictionary")
+}
+
 #[derive(Clone)]
 pub struct FileBackedStore {
     path: PathBuf,
+    compression: Compression,
 }
 
 impl FileBackedStore {
     pub fn new<P: Into<PathBuf>>(path: P) -> FileBackedStore {
-        FileBackedStore { path: path.into() }
+        FileBackedStore {
+            path: path.into(),
+            compression: Compression::None,
+        }
+    }
+
+    /// Create a file backed store which compresses its contents.
+    ///
+    /// The file on disk starts with the uncompressed size as a
+    /// big-endian u64, followed by the compressed data.
+    pub fn new_compressed<P: Into<PathBuf>>(path: P, compression: Compression) -> FileBackedStore {
+        FileBackedStore {
+            path: path.into(),
+            compression,
+        }
     }
 
-    fn open_read_from_std(&self, offset: usize) -> std::fs::File {
+    fn try_open_read_from_std(&self, offset: usize) -> io::Result<std::fs::File> {
         let mut options = std::fs::OpenOptions::new();
         options.read(true);
-        let mut file = options.open(&self.path).unwrap();
+        let mut file = options.open(&self.path)?;
+
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        Ok(file)
+    }
+
+    fn read_decompressed(&self) -> io::Result<Vec<u8>> {
+        let data = std::fs::read(&self.path)?;
+        decompress(&data)
+    }
+}
+
+fn missing_size_header_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "compressed file is missing its size header",
+    )
+}
 
-        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 8 {
+        return Err(missing_size_header_error());
+    }
 
-        file
+    let size = BigEndian::read_u64(&data[0..8]) as usize;
+    let result = zstd::stream::decode_all(&data[8..])?;
+    if result.len() != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected compressed file to contain {} bytes but it contained {}",
+                size,
+                result.len()
+            ),
+        ));
     }
+
+    Ok(result)
 }
 
 enum FileBacking {
@@ -53,92 +128,475 @@ impl AsRef<[u8]> for SharedMmap {
 
 const MMAP_TRESHOLD: usize = 4096 * 16;
 
+/// A reader for a file backed store.
+///
+/// Compressed files are decompressed in full when opened, and read
+/// from memory. If the file could not be opened or decompressed,
+/// the error is returned by the first read.
+pub enum FileBackedReader {
+    File(File),
+    Decompressed(io::Cursor<Vec<u8>>),
+    Failed(Option<io::Error>),
+}
+
+impl Read for FileBackedReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            FileBackedReader::File(f) => f.read(buf),
+            FileBackedReader::Decompressed(c) => c.read(buf),
+            FileBackedReader::Failed(e) => Err(take_open_error(e)),
+        }
+    }
+}
+
+/// Take the error a file failed to open with, for reporting it on first use.
+fn take_open_error(error: &mut Option<io::Error>) -> io::Error {
+    error
+        .take()
+        .unwrap_or_else(|| io::Error::other("file could not be opened"))
+}
+
+impl AsyncRead for FileBackedReader {}
+
 impl FileLoad for FileBackedStore {
-    type Read = File;
+    type Read = FileBackedReader;
     type Map = SharedMmap;
 
-    fn size(&self) -> usize {
-        let m = std::fs::metadata(&self.path).unwrap();
-        m.len() as usize
+    fn exists(&self) -> bool {
+        self.path.exists()
     }
 
-    fn open_read_from(&self, offset: usize) -> File {
-        let f = self.open_read_from_std(offset);
+    fn size(&self) -> io::Result<usize> {
+        match self.compression {
+            Compression::None => {
+                let m = std::fs::metadata(&self.path)?;
+                Ok(m.len() as usize)
+            }
+            _ => {
+                // only the size header is read, so this does not
+                // check that the rest of the file decompresses
+                let mut f = self.try_open_read_from_std(0)?;
+                let mut buf = [0; 8];
+                f.read_exact(&mut buf).map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => missing_size_header_error(),
+                    _ => e,
+                })?;
+                Ok(BigEndian::read_u64(&buf) as usize)
+            }
+        }
+    }
 
-        File::from_std(f)
+    fn open_read_from(&self, offset: usize) -> FileBackedReader {
+        match self.compression {
+            Compression::None => match self.try_open_read_from_std(offset) {
+                Ok(f) => FileBackedReader::File(File::from_std(f)),
+                Err(e) => FileBackedReader::Failed(Some(e)),
+            },
+            _ => match self.read_decompressed() {
+                Ok(data) => {
+                    let mut cursor = io::Cursor::new(data);
+                    cursor.set_position(offset as u64);
+
+                    FileBackedReader::Decompressed(cursor)
+                }
+                Err(e) => FileBackedReader::Failed(Some(e)),
+            },
+        }
     }
 
     fn map(&self) -> Box<dyn Future<Item = SharedMmap, Error = std::io::Error> + Send> {
         let file = self.clone();
+        if file.compression != Compression::None {
+            return Box::new(future::lazy(move || {
+                let f = match file.try_open_read_from_std(0) {
+                    Ok(f) => File::from_std(f),
+                    Err(e) => return future::Either::A(future::err(e)),
+                };
+                future::Either::B(tokio::io::read_to_end(f, Vec::new()).and_then(|(_, data)| {
+                    let vec = decompress(&data)?;
+                    if vec.is_empty() {
+                        Ok(SharedMmap(None))
+                    } else {
+                        Ok(SharedMmap(Some(Arc::new(FileBacking::Vec(vec)))))
+                    }
+                }))
+            }));
+        }
+
         Box::new(future::lazy(move || {
-            if file.size() == 0 {
+            let size = match file.size() {
+                Ok(size) => size,
+                Err(e) => return future::Either::A(future::err(e)),
+            };
+            if size == 0 {
                 future::Either::A(future::ok(SharedMmap(None)))
-            } else if file.size() < MMAP_TRESHOLD {
-                let f = file.open_read_from(0);
+            } else if size < MMAP_TRESHOLD {
+                let f = match file.try_open_read_from_std(0) {
+                    Ok(f) => File::from_std(f),
+                    Err(e) => return future::Either::A(future::err(e)),
+                };
                 future::Either::B(
-                    tokio::io::read_to_end(f, Vec::with_capacity(file.size()))
+                    tokio::io::read_to_end(f, Vec::with_capacity(size))
                         .map(|(_, vec)| SharedMmap(Some(Arc::new(FileBacking::Vec(vec))))),
                 )
             } else {
-                let f = file.open_read_from_std(0);
                 // unsafe justification: we opened this file specifically to do memory mapping, and will do nothing else with it.
-                future::Either::A(future::ok(SharedMmap(Some(Arc::new(FileBacking::Mmap(
-                    unsafe { Mmap::map(&f) }.unwrap(),
-                ))))))
+                let map = file
+                    .try_open_read_from_std(0)
+                    .and_then(|f| unsafe { Mmap::map(&f) })
+                    .map(|map| SharedMmap(Some(Arc::new(FileBacking::Mmap(map)))));
+                future::Either::A(future::result(map))
             }
         }))
     }
 }
 
-impl FileStore for FileBackedStore {
-    type Write = File;
+/// A writer for a compressed file.
+///
+/// All data is collected in memory. The compressed result is written
+/// out on flush, shutdown or `finish`, which report any error. The
+/// layer builders flush every file they write, so that errors reach
+/// the commit. Dropping a writer with unwritten data makes a
+/// best-effort attempt to persist it, but any error is lost.
+pub struct CompressedFileWriter {
+    path: PathBuf,
+    level: i32,
+    data: Vec<u8>,
+    pos: usize,
+    dirty: bool,
+}
 
-    fn open_write_from(&self, offset: usize) -> File {
-        let mut options = std::fs::OpenOptions::new();
-        options.read(true).write(true).create(true);
-        let mut file = options.open(&self.path).unwrap();
+impl CompressedFileWriter {
+    /// Write out any pending data, returning an error if this fails.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    fn persist(&mut self) -> Result<(), io::Error> {
+        let compressed = zstd::stream::encode_all(&self.data[..], self.level)?;
+        let mut contents = vec![0; 8];
+        BigEndian::write_u64(&mut contents, self.data.len() as u64);
+        contents.extend(compressed);
+        std::fs::write(&self.path, contents)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl Write for CompressedFileWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.data.len() < self.pos + buf.len() {
+            self.data.resize(self.pos + buf.len(), 0);
+        }
+
+        self.data[self.pos..self.pos + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len();
+        self.dirty = true;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        if self.dirty {
+            self.persist()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsyncWrite for CompressedFileWriter {
+    fn shutdown(&mut self) -> Result<Async<()>, io::Error> {
+        self.flush().map(Async::Ready)
+    }
+}
+
+impl Drop for CompressedFileWriter {
+    fn drop(&mut self) {
+        if self.dirty {
+            // errors can't be reported from drop. Callers that care
+            // should flush or call `finish` first.
+            let _ = self.persist();
+        }
+    }
+}
 
-        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+/// A writer for a file backed store.
+///
+/// If the file could not be opened, the error is returned by the
+/// first write, flush or shutdown.
+pub enum FileBackedWriter {
+    File(File),
+    Compressed(CompressedFileWriter),
+    Failed(Option<io::Error>),
+}
+
+impl Write for FileBackedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            FileBackedWriter::File(f) => f.write(buf),
+            FileBackedWriter::Compressed(c) => c.write(buf),
+            FileBackedWriter::Failed(e) => Err(take_open_error(e)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self {
+            FileBackedWriter::File(f) => f.flush(),
+            FileBackedWriter::Compressed(c) => c.flush(),
+            FileBackedWriter::Failed(e) => Err(take_open_error(e)),
+        }
+    }
+}
 
-        File::from_std(file)
+impl AsyncWrite for FileBackedWriter {
+    fn shutdown(&mut self) -> Result<Async<()>, io::Error> {
+        match self {
+            FileBackedWriter::File(f) => f.shutdown(),
+            FileBackedWriter::Compressed(c) => c.shutdown(),
+            FileBackedWriter::Failed(e) => Err(take_open_error(e)),
+        }
+    }
+}
+
+impl FileStore for FileBackedStore {
+    type Write = FileBackedWriter;
+
+    /// Open the file for writing from the given offset.
+    ///
+    /// For a compressed file, the existing contents are read and
+    /// decompressed in full, and the whole file is compressed and
+    /// written out again on flush. Opening a compressed file is
+    /// therefore O(file size), even when only appending to it.
+    fn open_write_from(&self, offset: usize) -> FileBackedWriter {
+        match self.compression {
+            Compression::None => {
+                let mut options = std::fs::OpenOptions::new();
+                options.read(true).write(true).create(true);
+                let file = options.open(&self.path).and_then(|mut file| {
+                    file.seek(SeekFrom::Start(offset as u64))?;
+                    Ok(file)
+                });
+
+                match file {
+                    Ok(file) => FileBackedWriter::File(File::from_std(file)),
+                    Err(e) => FileBackedWriter::Failed(Some(e)),
+                }
+            }
+            Compression::Zstd(level) => {
+                let data = if self.path.exists() {
+                    self.read_decompressed()
+                } else {
+                    Ok(Vec::new())
+                };
+                let mut data = match data {
+                    Ok(data) => data,
+                    Err(e) => return FileBackedWriter::Failed(Some(e)),
+                };
+                data.truncate(offset);
+                data.resize(offset, 0);
+
+                FileBackedWriter::Compressed(CompressedFileWriter {
+                    path: self.path.clone(),
+                    level,
+                    data,
+                    pos: offset,
+                    dirty: false,
+                })
+            }
+        }
     }
 }
 
+/// Returns a stream of the names of all directories directly under the given path.
+fn subdirectory_names(path: PathBuf) -> impl Stream<Item = String, Error = io::Error> + Send {
+    fs::read_dir(path)
+        .flatten_stream()
+        .map(|direntry| (direntry.file_name(), direntry))
+        .and_then(|(dir_name, direntry)| {
+            future::poll_fn(move || direntry.poll_file_type())
+                .map(move |ft| (dir_name, ft.is_dir()))
+        })
+        .filter_map(|(dir_name, is_dir)| match is_dir {
+            true => Some(dir_name),
+            false => None,
+        })
+        .and_then(|dir_name| {
+            dir_name
+                .to_str()
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected non-utf8 directory name",
+                ))
+                .map(|s| s.to_owned())
+        })
+}
+
 #[derive(Clone)]
 pub struct DirectoryLayerStore {
     path: PathBuf,
+    compression: Compression,
+    deduplicate: bool,
 }
 
 impl DirectoryLayerStore {
     pub fn new<P: Into<PathBuf>>(path: P) -> DirectoryLayerStore {
-        DirectoryLayerStore { path: path.into() }
+        DirectoryLayerStore {
+            path: path.into(),
+            compression: Compression::None,
+            deduplicate: false,
+        }
+    }
+
+    /// Create a directory layer store which writes adjacency-list and
+    /// dictionary files using the given compression codec.
+    ///
+    /// Layers are always readable regardless of the compression that
+    /// was configured when they were written.
+    pub fn with_compression<P: Into<PathBuf>>(
+        path: P,
+        compression: Compression,
+    ) -> DirectoryLayerStore {
+        DirectoryLayerStore {
+            path: path.into(),
+            compression,
+            deduplicate: false,
+        }
+    }
+
+    /// Reuse existing layers instead of storing identical copies.
+    ///
+    /// When a layer is committed, its files are compared against
+    /// those of the layers committed earlier with deduplication
+    /// enabled. If an identical layer is found, the new layer is
+    /// removed, and the commit returns the existing layer
+    /// instead. This means that the committed layer may have another
+    /// name than the builder it was built with.
+    ///
+    /// Layers are looked up by content hash in an index kept in the
+    /// store directory, so finding a duplicate does not depend on the
+    /// number of layers in the store. Hashing a layer does require
+    /// reading all of its files once more on commit.
+    pub fn with_deduplication(mut self) -> DirectoryLayerStore {
+        self.deduplicate = true;
+
+        self
+    }
+
+    fn file_path(&self, directory: [u32; 5], name: &str) -> PathBuf {
+        let mut p = self.path.clone();
+        let dir_name = name_to_string(directory);
+        p.push(&dir_name[0..PREFIX_DIR_SIZE]);
+        p.push(dir_name);
+        p.push(name);
+
+        p
+    }
+}
+
+/// The map of all directory layer caches, along with the size at which it is next pruned.
+#[derive(Default)]
+struct DirectoryLayerCacheMap {
+    layers: HashMap<(PathBuf, [u32; 5]), Weak<dyn Layer>>,
+    prune_at: usize,
+}
+
+/// The smallest size at which the directory layer cache map is pruned.
+const DIRECTORY_LAYER_CACHE_MIN_PRUNE: usize = 64;
+
+impl DirectoryLayerCacheMap {
+    fn insert(&mut self, key: (PathBuf, [u32; 5]), layer: Weak<dyn Layer>) {
+        // Layers that were dropped in the meantime are cleaned up
+        // once the map has doubled in size since the last cleanup, so
+        // the map doesn't keep growing, while inserting stays
+        // amortized O(1).
+        if self.layers.len() >= self.prune_at.max(DIRECTORY_LAYER_CACHE_MIN_PRUNE) {
+            self.layers.retain(|_, weak| weak.strong_count() != 0);
+            self.prune_at = self.layers.len() * 2;
+        }
+
+        self.layers.insert(key, layer);
+    }
+}
+
+lazy_static! {
+    static ref DIRECTORY_LAYER_CACHE: RwLock<DirectoryLayerCacheMap> =
+        RwLock::new(Default::default());
+}
+
+/// A layer cache for a directory layer store, shared by every store in the process that uses the same directory.
+///
+/// Entries are keyed by directory and layer name, so when two stores
+/// are opened on the same directory, a layer loaded through one of
+/// them is reused by the other, together with its memory maps. Like
+/// `LockingHashMapLayerCache`, this only keeps weak references, so
+/// the layers are dropped as soon as nothing else refers to them.
+#[derive(Clone)]
+pub struct DirectoryLayerCache {
+    path: PathBuf,
+}
+
+impl DirectoryLayerCache {
+    pub fn new<P: Into<PathBuf>>(path: P) -> DirectoryLayerCache {
+        let path = path.into();
+        // different spellings of the same directory should share a cache
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+
+        DirectoryLayerCache { path }
+    }
+}
+
+impl LayerCache for DirectoryLayerCache {
+    fn get_layer_from_cache(&self, name: [u32; 5]) -> Option<Arc<dyn Layer>> {
+        let key = (self.path.clone(), name);
+        let cache = DIRECTORY_LAYER_CACHE
+            .read()
+            .expect("rwlock read should always succeed");
+        let result = cache.layers.get(&key).and_then(|weak| weak.upgrade());
+        let stale = result.is_none() && cache.layers.contains_key(&key);
+        std::mem::drop(cache);
+
+        if stale {
+            DIRECTORY_LAYER_CACHE
+                .write()
+                .expect("rwlock write should always succeed")
+                .layers
+                .remove(&key);
+        }
+
+        result
+    }
+
+    fn cache_layer(&self, layer: Arc<dyn Layer>) {
+        DIRECTORY_LAYER_CACHE
+            .write()
+            .expect("rwlock write should always succeed")
+            .insert((self.path.clone(), layer.name()), Arc::downgrade(&layer));
+    }
+
+    fn invalidate(&self, name: [u32; 5]) {
+        DIRECTORY_LAYER_CACHE
+            .write()
+            .expect("rwlock write should always succeed")
+            .layers
+            .remove(&(self.path.clone(), name));
     }
 }
 
 impl PersistentLayerStore for DirectoryLayerStore {
     type File = FileBackedStore;
     fn directories(&self) -> Box<dyn Future<Item = Vec<[u32; 5]>, Error = std::io::Error> + Send> {
+        let path = self.path.clone();
         Box::new(
-            fs::read_dir(self.path.clone())
-                .flatten_stream()
-                .map(|direntry| (direntry.file_name(), direntry))
-                .and_then(|(dir_name, direntry)| {
-                    future::poll_fn(move || direntry.poll_file_type())
-                        .map(move |ft| (dir_name, ft.is_dir()))
-                })
-                .filter_map(|(dir_name, is_dir)| match is_dir {
-                    true => Some(dir_name),
-                    false => None,
-                })
-                .and_then(|dir_name| {
-                    dir_name
-                        .to_str()
-                        .ok_or(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "unexpected non-utf8 directory name",
-                        ))
-                        .map(|s| s.to_owned())
+            subdirectory_names(self.path.clone())
+                .filter(|prefix| prefix.len() == PREFIX_DIR_SIZE)
+                .map(move |prefix| {
+                    let mut p = path.clone();
+                    p.push(prefix);
+                    subdirectory_names(p)
                 })
+                .flatten()
                 .and_then(|s| string_to_name(&s))
                 .collect(),
         )
@@ -174,12 +632,30 @@ impl PersistentLayerStore for DirectoryLayerStore {
         directory: [u32; 5],
         name: &str,
     ) -> Box<dyn Future<Item = Self::File, Error = io::Error> + Send> {
-        let mut p = self.path.clone();
-        let dir_name = name_to_string(directory);
-        p.push(&dir_name[0..PREFIX_DIR_SIZE]);
-        p.push(dir_name);
-        p.push(name);
-        Box::new(future::ok(FileBackedStore::new(p)))
+        let p = self.file_path(directory, name);
+        let compressed_p = p.with_extension(COMPRESSED_EXTENSION);
+        let compression = match is_compressible_file(name) {
+            true => self.compression,
+            false => Compression::None,
+        };
+
+        // A file that already exists is opened with the compression it
+        // was written with, regardless of the configured compression.
+        Box::new(future::lazy(move || {
+            if p.exists() {
+                future::ok(FileBackedStore::new(p))
+            } else if compressed_p.exists() {
+                future::ok(FileBackedStore::new_compressed(
+                    compressed_p,
+                    Compression::Zstd(0),
+                ))
+            } else {
+                match compression {
+                    Compression::None => future::ok(FileBackedStore::new(p)),
+                    _ => future::ok(FileBackedStore::new_compressed(compressed_p, compression)),
+                }
+            }
+        }))
     }
 
     fn file_exists(
@@ -187,401 +663,2134 @@ impl PersistentLayerStore for DirectoryLayerStore {
         directory: [u32; 5],
         file: &str,
     ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
-        let mut p = self.path.clone();
-        let dir_name = name_to_string(directory);
-        p.push(&dir_name[0..PREFIX_DIR_SIZE]);
-        p.push(dir_name);
-        p.push(file);
-        Box::new(fs::metadata(p).then(|result| match result {
-            Ok(f) => Ok(f.is_file()),
-            Err(_) => Ok(false),
-        }))
-    }
-}
-
-#[derive(Clone)]
-pub struct DirectoryLabelStore {
-    path: PathBuf,
-}
-
-impl DirectoryLabelStore {
-    pub fn new<P: Into<PathBuf>>(path: P) -> DirectoryLabelStore {
-        DirectoryLabelStore { path: path.into() }
-    }
-}
-
-fn get_label_from_file(path: PathBuf) -> impl Future<Item = Label, Error = std::io::Error> + Send {
-    let label = path.file_stem().unwrap().to_str().unwrap().to_owned();
-
-    LockedFile::open(path)
-        .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
-        .and_then(move |(_f, data)| {
-            let s = String::from_utf8_lossy(&data);
-            let lines: Vec<&str> = s.lines().collect();
-            if lines.len() != 2 {
-                let result: Box<dyn Future<Item = _, Error = _> + Send> =
-                    Box::new(future::err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!(
-                            "expected label file to have two lines. contents were ({:?})",
-                            lines
-                        ),
-                    )));
-                return result;
-            }
-            let version_str = &lines[0];
-            let layer_str = &lines[1];
-
-            let version = u64::from_str_radix(version_str, 10);
-            if version.is_err() {
-                return Box::new(future::err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!(
-                        "expected first line of label file to be a number but it was {}",
-                        version_str
-                    ),
-                )));
-            }
-
-            if layer_str.len() == 0 {
-                Box::new(future::ok(Label {
-                    name: label,
-                    layer: None,
-                    version: version.unwrap(),
-                }))
-            } else {
-                let layer = layer::string_to_name(layer_str);
-                Box::new(layer.into_future().map(|layer| Label {
-                    name: label,
-                    layer: Some(layer),
-                    version: version.unwrap(),
-                }))
-            }
-        })
-}
-
-impl LabelStore for DirectoryLabelStore {
-    fn labels(&self) -> Box<dyn Future<Item = Vec<Label>, Error = std::io::Error> + Send> {
+        let p = self.file_path(directory, file);
+        let compressed_p = p.with_extension(COMPRESSED_EXTENSION);
         Box::new(
-            fs::read_dir(self.path.clone())
-                .flatten_stream()
-                .map(|direntry| (direntry.file_name(), direntry))
-                .and_then(|(dir_name, direntry)| {
-                    future::poll_fn(move || direntry.poll_file_type())
-                        .map(move |ft| (dir_name, ft.is_file()))
-                })
-                .filter(|(file_name, is_file)| {
-                    file_name.to_str().unwrap().ends_with(".label") && *is_file
-                })
-                .and_then(|(file_name, _)| get_label_from_file(file_name.into()))
-                .collect(),
+            fs::metadata(p)
+                .or_else(move |_| fs::metadata(compressed_p))
+                .then(|result| match result {
+                    Ok(f) => Ok(f.is_file()),
+                    Err(_) => Ok(false),
+                }),
         )
     }
 
-    fn create_label(
+    fn directory_size(
         &self,
-        label: &str,
-    ) -> Box<dyn Future<Item = Label, Error = std::io::Error> + Send> {
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
         let mut p = self.path.clone();
-        let label = label.to_owned();
-        p.push(format!("{}.label", label));
-        let contents = format!("0\n\n").into_bytes();
+        let name = name_to_string(name);
+        p.push(&name[0..PREFIX_DIR_SIZE]);
+        p.push(name);
+
         Box::new(
-            fs::metadata(p.clone())
-                .then(move |metadata| match metadata {
-                    Ok(_) => future::err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "database already exists",
-                    )),
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::NotFound => future::ok(p),
-                        _ => future::err(e),
-                    },
-                })
-                .and_then(|p| {
-                    ExclusiveLockedFile::create_and_open(p)
-                        .and_then(|f| tokio::io::write_all(f, contents))
-                        .map(move |_| Label::new_empty(&label))
+            fs::read_dir(p)
+                .flatten_stream()
+                .and_then(|direntry| future::poll_fn(move || direntry.poll_metadata()))
+                .filter(|metadata| metadata.is_file())
+                .fold(0, |size, metadata| {
+                    future::ok::<_, io::Error>(size + metadata.len())
                 }),
         )
     }
 
-    fn get_label(
+    // The metadata is written to a temporary file first, which then
+    // replaces the old metadata file. Readers therefore either see
+    // the old or the new metadata, and never a partially written one.
+    fn write_metadata_file(
         &self,
-        label: &str,
-    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
-        let label = label.to_owned();
-        let mut p = self.path.clone();
-        p.push(format!("{}.label", label));
+        dir_name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let contents = serde_json::to_vec(&metadata).expect("string map should always serialize");
+        let path = self.file_path(dir_name, consts::FILENAMES.metadata);
+        let tmp = path.with_extension("json.tmp");
+        let dir = self.file_path(dir_name, "");
 
         Box::new(
-            get_label_from_file(p)
-                .map(|label| Some(label))
-                .or_else(move |e| {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        Ok(None)
-                    } else {
-                        Err(e)
-                    }
-                }),
+            File::create(tmp.clone())
+                .and_then(|tmp_file| tokio::io::write_all(tmp_file, contents))
+                .and_then(|(mut tmp_file, _)| future::poll_fn(move || tmp_file.poll_sync_all()))
+                .and_then(move |_| fs::rename(tmp, path))
+                .and_then(move |_| File::open(dir))
+                .and_then(|mut dir| future::poll_fn(move || dir.poll_sync_all())),
         )
     }
 
-    fn set_label_option(
+    fn sync_all(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(sync_directory_blocking(self.path.clone(), true, |_| true))
+    }
+
+    fn deduplicate_directory(
         &self,
-        label: &Label,
-        layer: Option<[u32; 5]>,
-    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
-        let mut p = self.path.clone();
-        p.push(format!("{}.label", label.name));
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        if !self.deduplicate {
+            return Box::new(future::ok(name));
+        }
 
-        let old_label = label.clone();
-        let new_label = label.with_updated_layer(layer);
-        let contents = match new_label.layer {
-            None => format!("{}\n\n", new_label.version).into_bytes(),
-            Some(layer) => {
-                format!("{}\n{}\n", new_label.version, layer::name_to_string(layer)).into_bytes()
-            }
-        };
+        let path = self.path.clone();
+        Box::new(run_blocking(move || {
+            deduplicate_layer_directory(&path, name)
+        }))
+    }
 
-        Box::new(self.get_label(&label.name).and_then(move |l| {
-            if l == Some(old_label) {
-                // all good, let's a go
-                // TODO: this box should not be necessary here
-                let result: Box<dyn Future<Item = _, Error = _> + Send> = Box::new(
-                    ExclusiveLockedFile::open(p)
-                        .and_then(|f| tokio::io::write_all(f, contents))
-                        .map(|_| Some(new_label)),
-                );
-                result
-            } else {
-                Box::new(future::ok(None))
+    fn delete_directory(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let path = self.path.clone();
+
+        Box::new(run_blocking(move || delete_layer_directory(&path, name)))
+    }
+
+    fn copy_directory_to(
+        &self,
+        name: [u32; 5],
+        path: PathBuf,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let from = layer_directory(&self.path, name);
+
+        Box::new(run_blocking(move || {
+            copy_layer_directory(&from, &layer_directory(&path, name))?;
+            match std::fs::read_to_string(from.join(CONTENT_HASH_FILE)) {
+                Ok(hash) => add_content_index_entry(&path, &hash, name),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
             }
         }))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::layer::*;
-    use futures::sync::oneshot;
-    use tempfile::tempdir;
-    use tokio::runtime::Runtime;
+/// The file in which a deduplicating store records the content hash of a layer.
+const CONTENT_HASH_FILE: &str = "content_hash";
 
-    #[test]
-    fn write_and_read_file_backed() {
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("foo");
-        let file = FileBackedStore::new(file_path);
-        let runtime = Runtime::new().unwrap();
+/// The directory in which a deduplicating store indexes layers by content hash.
+///
+/// For every hash, it contains a directory with an empty file per
+/// layer with that hash. Its name is longer than a prefix directory,
+/// so it is never mistaken for one.
+const CONTENT_INDEX_DIR: &str = "content_index";
 
-        let w = file.open_write();
-        let task = tokio::io::write_all(w, [1, 2, 3])
-            .and_then(move |_| tokio::io::read_to_end(file.open_read(), Vec::new()))
-            .map(move |(_, buf)| buf);
+fn content_index_directory(root: &Path, hash: &str) -> PathBuf {
+    let mut p = root.to_path_buf();
+    p.push(CONTENT_INDEX_DIR);
+    p.push(hash);
 
-        let buf = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
-        runtime.shutdown_now();
+    p
+}
 
-        assert_eq!(vec![1, 2, 3], buf);
+fn add_content_index_entry(root: &Path, hash: &str, name: [u32; 5]) -> io::Result<()> {
+    let dir = content_index_directory(root, hash);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::File::create(dir.join(name_to_string(name)))?;
+
+    Ok(())
+}
+
+fn remove_content_index_entry(root: &Path, hash: &str, name: [u32; 5]) -> io::Result<()> {
+    match std::fs::remove_file(content_index_directory(root, hash).join(name_to_string(name))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
     }
+}
 
-    #[test]
+fn layer_directory(root: &Path, name: [u32; 5]) -> PathBuf {
+    let name = name_to_string(name);
+    let mut p = root.to_path_buf();
+    p.push(&name[0..PREFIX_DIR_SIZE]);
+    p.push(name);
+
+    p
+}
+
+/// The names of the files that make up the content of a layer directory, in sorted order.
+fn layer_content_file_names(dir: &Path) -> io::Result<Vec<std::ffi::OsString>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name() != CONTENT_HASH_FILE {
+            names.push(entry.file_name());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+struct HashWriter<'a>(&'a mut SipHasher13);
+
+impl<'a> Write for HashWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        std::hash::Hasher::write(self.0, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash the names and contents of the files of a layer directory.
+///
+/// The hash is persisted next to the layer, so it uses SipHash-1-3
+/// with fixed keys and feeds it lengths as fixed-width big-endian
+/// bytes, which keeps it the same across Rust releases and platforms.
+///
+/// The hash is only used to find candidate duplicates. Candidates are
+/// always compared byte for byte, so a collision can't cause a layer
+/// to be replaced by a different one.
+fn layer_content_hash(dir: &Path) -> io::Result<String> {
+    use std::hash::Hasher;
+
+    let mut hasher = SipHasher13::new_with_keys(0, 0);
+    for name in layer_content_file_names(dir)? {
+        let name_bytes = name.to_string_lossy();
+        let name_bytes = name_bytes.as_bytes();
+        hasher.write(&(name_bytes.len() as u64).to_be_bytes());
+        hasher.write(name_bytes);
+        let mut file = std::fs::File::open(dir.join(&name))?;
+        hasher.write(&file.metadata()?.len().to_be_bytes());
+        io::copy(&mut file, &mut HashWriter(&mut hasher))?;
+    }
+
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+fn files_are_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = std::fs::File::open(a)?;
+    let mut b = std::fs::File::open(b)?;
+    let len = a.metadata()?.len();
+    if len != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut a_buf = vec![0; 1 << 16];
+    let mut b_buf = vec![0; 1 << 16];
+    let mut remaining = len;
+    while remaining != 0 {
+        let chunk = std::cmp::min(remaining, a_buf.len() as u64) as usize;
+        a.read_exact(&mut a_buf[..chunk])?;
+        b.read_exact(&mut b_buf[..chunk])?;
+        if a_buf[..chunk] != b_buf[..chunk] {
+            return Ok(false);
+        }
+        remaining -= chunk as u64;
+    }
+
+    Ok(true)
+}
+
+fn layers_have_same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let names = layer_content_file_names(a)?;
+    if names != layer_content_file_names(b)? {
+        return Ok(false);
+    }
+
+    for name in names {
+        if !files_are_equal(&a.join(&name), &b.join(&name))? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Look for a layer with the same content as the given layer.
+///
+/// Candidates are found through the content index. If one has the
+/// same content, the given layer is removed and the name of the
+/// existing layer is returned. Otherwise, the given layer is added to
+/// the index so later layers can be compared against it, and its own
+/// name is returned.
+fn deduplicate_layer_directory(root: &Path, name: [u32; 5]) -> io::Result<[u32; 5]> {
+    let dir = layer_directory(root, name);
+    let hash = layer_content_hash(&dir)?;
+
+    let candidates = match std::fs::read_dir(content_index_directory(root, &hash)) {
+        Ok(candidates) => candidates,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return index_layer_directory(root, &dir, &hash, name)
+        }
+        Err(e) => return Err(e),
+    };
+
+    for candidate in candidates {
+        let candidate_name = match candidate?.file_name().to_str().map(string_to_name) {
+            Some(Ok(candidate_name)) if candidate_name != name => candidate_name,
+            _ => continue,
+        };
+
+        let candidate_dir = layer_directory(root, candidate_name);
+        if !candidate_dir.exists() {
+            // the layer was removed without going through this store
+            remove_content_index_entry(root, &hash, candidate_name)?;
+            continue;
+        }
+
+        if layers_have_same_content(&dir, &candidate_dir)? {
+            std::fs::remove_dir_all(&dir)?;
+            return Ok(candidate_name);
+        }
+    }
+
+    index_layer_directory(root, &dir, &hash, name)
+}
+
+fn index_layer_directory(
+    root: &Path,
+    dir: &Path,
+    hash: &str,
+    name: [u32; 5],
+) -> io::Result<[u32; 5]> {
+    std::fs::write(dir.join(CONTENT_HASH_FILE), hash)?;
+    // the index entry is added last, so that an indexed layer is always complete
+    add_content_index_entry(root, hash, name)?;
+
+    Ok(name)
+}
+
+/// Remove a layer directory, along with its content index entry if it has one.
+fn delete_layer_directory(root: &Path, name: [u32; 5]) -> io::Result<()> {
+    let dir = layer_directory(root, name);
+    match std::fs::read_to_string(dir.join(CONTENT_HASH_FILE)) {
+        Ok(hash) => remove_content_index_entry(root, &hash, name)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    std::fs::remove_dir_all(&dir)
+}
+
+/// Copy all files of a layer directory into a new directory, and fsync them.
+///
+/// Every file is read while holding a shared lock on it.
+fn copy_layer_directory(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let mut source = std::fs::File::open(entry.path())?;
+        FileExt::lock_shared(&source)?;
+        let result = std::fs::File::create(to.join(entry.file_name())).and_then(|mut target| {
+            io::copy(&mut source, &mut target)?;
+            target.sync_all()
+        });
+        FileExt::unlock(&source)?;
+        result?;
+    }
+
+    std::fs::File::open(to)?.sync_all()
+}
+
+/// Fsync the files in the given directory that match the filter, followed by the directory itself.
+///
+/// If `recursive` is true, subdirectories are synced as well.
+fn sync_directory(path: &Path, recursive: bool, filter: fn(&Path) -> bool) -> io::Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let entry_path = entry.path();
+        if file_type.is_dir() {
+            if recursive {
+                sync_directory(&entry_path, recursive, filter)?;
+            }
+        } else if file_type.is_file() && filter(&entry_path) {
+            std::fs::File::open(&entry_path)?.sync_all()?;
+        }
+    }
+
+    std::fs::File::open(path)?.sync_all()
+}
+
+fn sync_directory_blocking(
+    path: PathBuf,
+    recursive: bool,
+    filter: fn(&Path) -> bool,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    run_blocking(move || sync_directory(&path, recursive, filter))
+}
+
+/// A label store that keeps each label in a file in a directory
+///
+/// Besides the label file, every label has a version log, which
+/// records the layer of each version of the label. This allows
+/// `get_label_at_version` to return earlier versions of a label. The
+/// version log grows by one line of around 50 bytes for every update
+/// of the label. For a label that is updated a million times, this
+/// comes to about 50MB. Use `compact` to discard the history when it
+/// is no longer needed.
+#[derive(Clone)]
+pub struct DirectoryLabelStore {
+    path: PathBuf,
+    lock_pool: Option<LockPool>,
+}
+
+impl DirectoryLabelStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> DirectoryLabelStore {
+        DirectoryLabelStore {
+            path: path.into(),
+            lock_pool: None,
+        }
+    }
+
+    /// Wait for label file locks on the given lock pool
+    ///
+    /// By default, lock waits use the blocking pool of the tokio
+    /// runtime. See `LockPool`.
+    pub fn with_lock_pool(self, pool: LockPool) -> DirectoryLabelStore {
+        DirectoryLabelStore {
+            lock_pool: Some(pool),
+            ..self
+        }
+    }
+
+    /// Write a label file for the given label, keeping its version.
+    ///
+    /// This is used to copy labels from another store. An existing
+    /// label with the same name is overwritten.
+    pub(crate) fn import_label(
+        &self,
+        label: &Label,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let p = label_file_path(&self.path, &label.name);
+        let contents = label_file_contents(label);
+
+        let log = version_log_path(&self.path, &label.name);
+        let entry = version_log_entry(label);
+
+        ExclusiveLockedFile::create_and_open(p, self.lock_pool.clone())
+            .and_then(move |f| append_version_log(log, entry).map(|_| f))
+            .and_then(|f| tokio::io::write_all(f, contents))
+            .and_then(|(f, _)| f.truncate())
+            .and_then(|f| f.do_shutdown())
+    }
+}
+
+/// Encode a label name for use as a file name.
+///
+/// Path separators, `%` and a leading `.` are percent-encoded, so
+/// that any label, including namespaced ones like `tenant1/people`,
+/// maps to a single file inside the label directory. Other characters
+/// are kept as they are, so label names that were valid before keep
+/// their file name.
+fn encode_label_name(label: &str) -> String {
+    let mut encoded = String::with_capacity(label.len());
+    for (i, c) in label.char_indices() {
+        match c {
+            '%' | '/' | '\\' | '\0' => encoded.push_str(&format!("%{:02X}", c as u8)),
+            '.' if i == 0 => encoded.push_str("%2E"),
+            _ => encoded.push(c),
+        }
+    }
+
+    encoded
+}
+
+/// Decode a file name produced by `encode_label_name` back to the label name.
+///
+/// Label files created before label names were encoded may contain
+/// a `%` that does not start a valid escape. Such a file name is
+/// returned as it is.
+fn decode_label_name(encoded: &str) -> String {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match byte {
+                Some(byte) => bytes.push(byte),
+                None => return encoded.to_owned(),
+            }
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).unwrap_or_else(|_| encoded.to_owned())
+}
+
+/// The file name of the given label, without its extension.
+///
+/// This is the encoded label name, unless there is no label file by
+/// that name, but there is one named after the label as it is. Such
+/// a file was created before label names were encoded, and keeps
+/// being used.
+fn label_file_stem(dir: &Path, label: &str) -> String {
+    let encoded = encode_label_name(label);
+    if encoded != label
+        && !label.contains(['/', '\\', '\0'])
+        && !dir.join(format!("{}.label", encoded)).exists()
+        && dir.join(format!("{}.label", label)).exists()
+    {
+        label.to_owned()
+    } else {
+        encoded
+    }
+}
+
+/// The path of the label file for the given label.
+fn label_file_path(dir: &Path, label: &str) -> PathBuf {
+    dir.join(format!("{}.label", label_file_stem(dir, label)))
+}
+
+/// The path of the version log for the given label.
+fn version_log_path(dir: &Path, label: &str) -> PathBuf {
+    dir.join(format!("{}.versions", label_file_stem(dir, label)))
+}
+
+/// The line in the version log for the given version of a label.
+///
+/// Each line consists of the version and the layer name separated by
+/// a space, where the layer name is empty if the label has no head.
+fn version_log_entry(label: &Label) -> Vec<u8> {
+    match label.layer {
+        None => format!("{} \n", label.version).into_bytes(),
+        Some(layer) => format!("{} {}\n", label.version, layer::name_to_string(layer)).into_bytes(),
+    }
+}
+
+/// Append an entry to a version log.
+///
+/// This should only be called while holding the exclusive lock on the
+/// label file, so that entries are appended in order of version. The
+/// entry is appended before the label file is rewritten, so a failure
+/// to record the version leaves the label untouched. An entry for a
+/// version that was never written to the label file is harmless: it
+/// is newer than the label, so it is never looked up, and it is
+/// superseded by the entry appended when that version is written.
+fn append_version_log(
+    path: PathBuf,
+    entry: Vec<u8>,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|f| tokio::io::write_all(f, entry))
+        .map(|_| ())
+}
+
+/// Find the layer of the given version in the contents of a version log.
+///
+/// If a version appears more than once, the last entry wins. A last
+/// line without a newline was cut off in the middle of a write, and
+/// is ignored. Returns None if the version is not in the log.
+fn find_version_in_log(
+    label: &str,
+    data: &[u8],
+    version: u64,
+) -> Result<Option<Option<[u32; 5]>>, io::Error> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("version log for {} is corrupt", label),
+        )
+    };
+
+    let complete = match data.iter().rposition(|&b| b == b'\n') {
+        None => return Ok(None),
+        Some(end) => &data[..end],
+    };
+    let complete = std::str::from_utf8(complete).map_err(|_| invalid())?;
+
+    for line in complete.lines().rev() {
+        let mut parts = line.splitn(2, ' ');
+        let line_version: u64 = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(invalid)?;
+        let layer = parts.next().ok_or_else(invalid)?;
+        if line_version != version {
+            continue;
+        }
+
+        return if layer.is_empty() {
+            Ok(Some(None))
+        } else {
+            layer::string_to_name(layer)
+                .map(|layer| Some(Some(layer)))
+                .map_err(|_| invalid())
+        };
+    }
+
+    Ok(None)
+}
+
+/// Rewrite the version log of a label to only contain its current version.
+///
+/// This holds the exclusive lock on the label file, so the label
+/// can't be updated in the meantime. The new log is written to a
+/// temporary file first, which then replaces the old log. Readers of
+/// the version log therefore either see the old or the new log, and
+/// never a partially written one.
+fn compact_version_log(
+    dir: &Path,
+    label: String,
+    lock_pool: Option<LockPool>,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    let log = version_log_path(dir, &label);
+    let tmp = dir.join(format!("{}.versions.tmp", encode_label_name(&label)));
+
+    ExclusiveLockedFile::open(label_file_path(dir, &label), lock_pool)
+        .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
+        .and_then(move |(f, data)| parse_label_file(label, &data).map(|label| (f, label)))
+        .and_then(move |(f, label)| {
+            let entry = version_log_entry(&label);
+            File::create(tmp.clone())
+                .and_then(|tmp_file| tokio::io::write_all(tmp_file, entry))
+                .and_then(|(mut tmp_file, _)| future::poll_fn(move || tmp_file.poll_sync_all()))
+                .and_then(move |_| fs::rename(tmp, log))
+                // the lock on the label file is released once it is dropped here
+                .map(move |_| std::mem::drop(f))
+        })
+}
+
+/// The largest label file we are willing to read.
+///
+/// A label file holds a version and a layer name, which fits in well
+/// under a hundred bytes. Anything much larger is not a label file,
+/// and is rejected instead of read into memory.
+const MAX_LABEL_FILE_SIZE: u64 = 4096;
+
+fn get_label_from_file(
+    path: PathBuf,
+    lock_pool: Option<LockPool>,
+) -> impl Future<Item = Label, Error = std::io::Error> + Send {
+    let label = decode_label_name(path.file_stem().unwrap().to_str().unwrap());
+
+    LockedFile::open(path, lock_pool)
+        .and_then(|f| tokio::io::read_to_end(f.take(MAX_LABEL_FILE_SIZE + 1), Vec::new()))
+        .and_then(move |(_f, data)| {
+            if data.len() as u64 > MAX_LABEL_FILE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "label file for {} is larger than the maximum of {} bytes",
+                        label, MAX_LABEL_FILE_SIZE
+                    ),
+                ));
+            }
+
+            parse_label_file(label, &data)
+        })
+}
+
+/// The contents of a label file for a label that is being deleted.
+///
+/// A label file is overwritten with this before it is removed, while
+/// holding its exclusive lock. Writers that were waiting for the lock
+/// find this instead of a label, so they don't write to a file that
+/// is no longer there. If the process stops before the file is
+/// removed, the label is still treated as deleted.
+const DELETED_LABEL_FILE: &[u8] = b"deleted\n";
+
+fn label_deleted_error(label: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("label {} was deleted", label),
+    )
+}
+
+/// Parse the contents of a label file.
+///
+/// A label file normally consists of two lines, a version and a layer
+/// name, where the layer name is empty if the label has no head. An
+/// empty file, as left behind by a crash right after creating or
+/// truncating it, is read as a label without a head at version 0. A
+/// file that only contains a version line was cut off in the middle
+/// of a write. This is reported as an error that names the label,
+/// since the head it pointed to can't be recovered from it. A label
+/// that was deleted is reported as a `NotFound` error.
+fn parse_label_file(label: String, data: &[u8]) -> Result<Label, std::io::Error> {
+    if data == DELETED_LABEL_FILE {
+        return Err(label_deleted_error(&label));
+    }
+    let s = std::str::from_utf8(data).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("label file for {} is not valid UTF-8", label),
+        )
+    })?;
+    let lines: Vec<&str> = s.lines().collect();
+    if lines.iter().all(|line| line.trim().is_empty()) {
+        return Ok(Label::new_empty(&label));
+    }
+    if lines.len() == 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "label file for {} is truncated: it contains a version but no layer line",
+                label
+            ),
+        ));
+    }
+    if lines.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected label file for {} to have two lines. contents were ({:?})",
+                label, lines
+            ),
+        ));
+    }
+    let version_str = &lines[0];
+    let layer_str = &lines[1];
+
+    let version = u64::from_str_radix(version_str, 10);
+    if version.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected first line of label file for {} to be a number but it was {}",
+                label, version_str
+            ),
+        ));
+    }
+
+    if layer_str.len() == 0 {
+        Ok(Label {
+            name: label,
+            layer: None,
+            version: version.unwrap(),
+        })
+    } else {
+        let layer = layer::string_to_name(layer_str).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("label file for {} has an invalid layer name: {}", label, e),
+            )
+        })?;
+        Ok(Label {
+            name: label,
+            layer: Some(layer),
+            version: version.unwrap(),
+        })
+    }
+}
+
+fn label_file_contents(label: &Label) -> Vec<u8> {
+    match label.layer {
+        None => format!("{}\n\n", label.version).into_bytes(),
+        Some(layer) => {
+            format!("{}\n{}\n", label.version, layer::name_to_string(layer)).into_bytes()
+        }
+    }
+}
+
+/// Record the given label in its version log, and write it to its locked label file.
+fn write_locked_label(
+    f: ExclusiveLockedFile,
+    label: &Label,
+    log: PathBuf,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    let contents = label_file_contents(label);
+    let entry = version_log_entry(label);
+    append_version_log(log, entry)
+        .and_then(move |_| f.seek(SeekFrom::Start(0)))
+        .and_then(|(f, _)| tokio::io::write_all(f, contents))
+        .and_then(|(f, _)| f.truncate())
+        .and_then(|f| f.do_shutdown())
+}
+
+fn remove_file_if_exists(path: PathBuf) -> impl Future<Item = (), Error = io::Error> + Send {
+    fs::remove_file(path).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    })
+}
+
+/// The paths of all label files in the given directory.
+fn label_file_paths(dir: PathBuf) -> impl Stream<Item = PathBuf, Error = io::Error> + Send {
+    fs::read_dir(dir)
+        .flatten_stream()
+        .map(|direntry| (direntry.path(), direntry))
+        .and_then(|(path, direntry)| {
+            future::poll_fn(move || direntry.poll_file_type()).map(move |ft| (path, ft.is_file()))
+        })
+        .filter(|(path, is_file)| path.to_str().unwrap().ends_with(".label") && *is_file)
+        .map(|(path, _)| path)
+}
+
+impl LabelStore for DirectoryLabelStore {
+    fn labels(&self) -> Box<dyn Future<Item = Vec<Label>, Error = std::io::Error> + Send> {
+        let lock_pool = self.lock_pool.clone();
+        Box::new(
+            label_file_paths(self.path.clone())
+                .and_then(move |path| {
+                    // labels that are deleted in the meantime are skipped
+                    get_label_from_file(path, lock_pool.clone()).then(|result| match result {
+                        Ok(label) => Ok(Some(label)),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                        Err(e) => Err(e),
+                    })
+                })
+                .filter_map(|label| label)
+                .collect(),
+        )
+    }
+
+    fn label_names(&self) -> Box<dyn Future<Item = Vec<String>, Error = std::io::Error> + Send> {
+        let lock_pool = self.lock_pool.clone();
+        Box::new(
+            label_file_paths(self.path.clone())
+                .and_then(move |path| {
+                    // unlike in labels, label files that can't be parsed
+                    // are skipped too, as they may still be written to
+                    get_label_from_file(path, lock_pool.clone()).then(|result| match result {
+                        Ok(label) => Ok(Some(label.name)),
+                        Err(e)
+                            if e.kind() == io::ErrorKind::NotFound
+                                || e.kind() == io::ErrorKind::InvalidData =>
+                        {
+                            Ok(None)
+                        }
+                        Err(e) => Err(e),
+                    })
+                })
+                .filter_map(|name| name)
+                .collect(),
+        )
+    }
+
+    fn create_label(
+        &self,
+        label: &str,
+    ) -> Box<dyn Future<Item = Label, Error = std::io::Error> + Send> {
+        let p = label_file_path(&self.path, label);
+        let log = version_log_path(&self.path, label);
+        let label = Label::new_empty(label);
+        let lock_pool = self.lock_pool.clone();
+        let name = label.name.clone();
+        // The label file is created with create_new, so if several
+        // processes or threads create the same label at once, exactly
+        // one of them wins.
+        Box::new(
+            ExclusiveLockedFile::create_and_open(p.clone(), lock_pool.clone())
+                .then(move |result| match result {
+                    Ok(f) => future::Either::A(future::ok(f)),
+                    // a label that was deleted, but whose file was
+                    // never removed, can be created again
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => future::Either::B(
+                        ExclusiveLockedFile::open(p, lock_pool)
+                            .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
+                            .and_then(move |(f, data)| {
+                                if data == DELETED_LABEL_FILE {
+                                    Ok(f)
+                                } else {
+                                    Err(label_already_exists_error(&name))
+                                }
+                            }),
+                    ),
+                    Err(e) => future::Either::A(future::err(e)),
+                })
+                .and_then(move |f| write_locked_label(f, &label, log).map(|_| label)),
+        )
+    }
+
+    fn get_label(
+        &self,
+        label: &str,
+    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
+        let p = label_file_path(&self.path, label);
+
+        Box::new(
+            get_label_from_file(p, self.lock_pool.clone())
+                .map(|label| Some(label))
+                .or_else(move |e| {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        Ok(None)
+                    } else {
+                        Err(e)
+                    }
+                }),
+        )
+    }
+
+    fn set_label_option(
+        &self,
+        label: &Label,
+        layer: Option<[u32; 5]>,
+    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
+        let p = label_file_path(&self.path, &label.name);
+        let log = version_log_path(&self.path, &label.name);
+
+        let old_label = label.clone();
+        let new_label = label.with_updated_layer(layer);
+
+        // The label is checked while holding the lock, so it can't
+        // change or be deleted between checking and writing it.
+        Box::new(
+            ExclusiveLockedFile::open(p, self.lock_pool.clone())
+                .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
+                .and_then(move |(f, data)| {
+                    parse_label_file(old_label.name.clone(), &data)
+                        .map(|current| (f, current == old_label))
+                })
+                .and_then(move |(f, unchanged)| {
+                    if unchanged {
+                        future::Either::A(
+                            write_locked_label(f, &new_label, log).map(|_| Some(new_label)),
+                        )
+                    } else {
+                        future::Either::B(future::ok(None))
+                    }
+                })
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(None),
+                    _ => Err(e),
+                }),
+        )
+    }
+
+    fn set_labels(
+        &self,
+        updates: Vec<(Label, Option<[u32; 5]>)>,
+    ) -> Box<dyn Future<Item = Option<Vec<Label>>, Error = std::io::Error> + Send> {
+        // Label files are always locked in order of their name. As
+        // long as every update of multiple labels does this, two such
+        // updates can never end up waiting on each other.
+        let mut order: Vec<_> = (0..updates.len()).collect();
+        order.sort_by(|&a, &b| updates[a].0.name.cmp(&updates[b].0.name));
+        if order
+            .windows(2)
+            .any(|w| updates[w[0]].0.name == updates[w[1]].0.name)
+        {
+            return Box::new(future::err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a label can only be updated once at a time",
+            )));
+        }
+
+        let path = self.path.clone();
+        let log_path = self.path.clone();
+        let lock_pool = self.lock_pool.clone();
+        let sorted: Vec<_> = order
+            .iter()
+            .map(|&i| (i, updates[i].0.clone(), updates[i].1))
+            .collect();
+
+        Box::new(
+            stream::iter_ok(sorted)
+                .and_then(move |(index, label, layer)| {
+                    ExclusiveLockedFile::open(
+                        label_file_path(&path, &label.name),
+                        lock_pool.clone(),
+                    )
+                    .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
+                    .and_then(move |(f, data)| {
+                        parse_label_file(label.name.clone(), &data)
+                            .map(|current| (index, f, current == label, label, layer))
+                    })
+                })
+                .collect()
+                .and_then(move |locked| {
+                    // all labels are locked at this point
+                    if locked.iter().any(|(_, _, unchanged, _, _)| !unchanged) {
+                        // dropping the files releases the locks
+                        return future::Either::A(future::ok(None));
+                    }
+
+                    let locked: Vec<_> = locked
+                        .into_iter()
+                        .map(|(index, f, _, label, layer)| {
+                            (index, f, label.with_updated_layer(layer))
+                        })
+                        .collect();
+                    let entries: Vec<_> = locked
+                        .iter()
+                        .map(|(_, _, new_label)| {
+                            (
+                                version_log_path(&log_path, &new_label.name),
+                                version_log_entry(new_label),
+                            )
+                        })
+                        .collect();
+
+                    // every version log is appended before any label
+                    // file is touched, so that a failure to do so
+                    // leaves all labels as they were
+                    future::Either::B(
+                        stream::iter_ok(entries)
+                            .for_each(|(log, entry)| append_version_log(log, entry))
+                            .and_then(move |_| {
+                                stream::iter_ok(locked)
+                                    .and_then(|(index, f, new_label)| {
+                                        let contents = label_file_contents(&new_label);
+                                        f.seek(SeekFrom::Start(0))
+                                            .and_then(|(f, _)| tokio::io::write_all(f, contents))
+                                            .and_then(|(f, _)| f.truncate())
+                                            .map(move |f| (index, f, new_label))
+                                    })
+                                    .collect()
+                            })
+                            .and_then(|written| {
+                                // only release the locks once every label has been written
+                                let mut new_labels: Vec<_> = written
+                                    .iter()
+                                    .map(|(index, _, new_label)| (*index, new_label.clone()))
+                                    .collect();
+                                new_labels.sort_by_key(|(index, _)| *index);
+
+                                stream::iter_ok(written)
+                                    .and_then(|(_, f, _)| f.do_shutdown())
+                                    .collect()
+                                    .map(move |_| {
+                                        Some(new_labels.into_iter().map(|(_, l)| l).collect())
+                                    })
+                            }),
+                    )
+                }),
+        )
+    }
+
+    fn get_label_at_version(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
+        let log = version_log_path(&self.path, name);
+        Box::new(self.get_label(name).and_then(move |label| {
+            let label = match label {
+                None => return future::Either::A(future::ok(None)),
+                Some(label) => label,
+            };
+            if label.version == version {
+                return future::Either::A(future::ok(Some(label)));
+            }
+            if label.version < version {
+                return future::Either::A(future::ok(None));
+            }
+
+            // entries for earlier versions were fully written before the current version was
+            future::Either::B(
+                fs::read(log)
+                    .or_else(|e| match e.kind() {
+                        io::ErrorKind::NotFound => Ok(Vec::new()),
+                        _ => Err(e),
+                    })
+                    .and_then(move |data| {
+                        find_version_in_log(&label.name, &data, version).map(|layer| {
+                            layer.map(|layer| Label {
+                                name: label.name,
+                                layer,
+                                version,
+                            })
+                        })
+                    }),
+            )
+        }))
+    }
+
+    fn delete_label(
+        &self,
+        name: &str,
+    ) -> Box<dyn Future<Item = bool, Error = std::io::Error> + Send> {
+        let p = label_file_path(&self.path, name);
+        let log = version_log_path(&self.path, name);
+
+        // Other writers may be waiting for the lock, holding on to the
+        // label file. They will find the deleted marker once they get
+        // the lock. The version log is removed first, so a label file
+        // never outlives it.
+        Box::new(
+            ExclusiveLockedFile::open(p.clone(), self.lock_pool.clone())
+                .and_then(|f| tokio::io::read_to_end(f, Vec::new()))
+                .and_then(|(f, data)| {
+                    let existed = data != DELETED_LABEL_FILE;
+                    f.seek(SeekFrom::Start(0))
+                        .and_then(|(f, _)| tokio::io::write_all(f, DELETED_LABEL_FILE))
+                        .and_then(|(f, _)| f.truncate())
+                        .map(move |f| (f, existed))
+                })
+                .and_then(move |(f, existed)| {
+                    remove_file_if_exists(log)
+                        .and_then(move |_| remove_file_if_exists(p))
+                        // the lock is released once the file is dropped here
+                        .map(move |_| {
+                            std::mem::drop(f);
+                            existed
+                        })
+                })
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(false),
+                    _ => Err(e),
+                }),
+        )
+    }
+
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let path = self.path.clone();
+        let lock_pool = self.lock_pool.clone();
+        Box::new(self.labels().and_then(move |labels| {
+            stream::iter_ok(labels).for_each(move |label| {
+                // labels that are deleted in the meantime have no log left to compact
+                compact_version_log(&path, label.name, lock_pool.clone()).or_else(|e| {
+                    match e.kind() {
+                        io::ErrorKind::NotFound => Ok(()),
+                        _ => Err(e),
+                    }
+                })
+            })
+        }))
+    }
+
+    fn flush(&self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        Box::new(sync_directory_blocking(self.path.clone(), false, |p| {
+            p.extension()
+                .map(|e| e == "label" || e == "versions")
+                .unwrap_or(false)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::*;
+    use futures::sync::oneshot;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn write_and_read_file_backed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("foo");
+        let file = FileBackedStore::new(file_path);
+        let runtime = Runtime::new().unwrap();
+
+        let w = file.open_write();
+        let task = tokio::io::write_all(w, [1, 2, 3])
+            .and_then(move |_| tokio::io::read_to_end(file.open_read(), Vec::new()))
+            .map(move |(_, buf)| buf);
+
+        let buf = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(vec![1, 2, 3], buf);
+    }
+
+    #[test]
     fn write_and_map_file_backed() {
         let dir = tempdir().unwrap();
-        let file_path = dir.path().join("foo");
-        let file = FileBackedStore::new(file_path);
+        let file_path = dir.path().join("foo");
+        let file = FileBackedStore::new(file_path);
+        let runtime = Runtime::new().unwrap();
+
+        let w = file.open_write();
+        let task = tokio::io::write_all(w, [1, 2, 3]).and_then(move |_| file.map());
+
+        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(&vec![1, 2, 3][..], &map.as_ref()[..]);
+    }
+
+    #[test]
+    fn write_and_map_small_file_backed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("foo");
+        let file = FileBackedStore::new(file_path);
+        let runtime = Runtime::new().unwrap();
+
+        let w = file.open_write();
+        let mut contents = vec![0u8; MMAP_TRESHOLD - 1];
+        for i in 0..MMAP_TRESHOLD - 1 {
+            contents[i] = (i as usize % 256) as u8;
+        }
+
+        let task = tokio::io::write_all(w, contents.clone()).and_then(move |_| file.map());
+
+        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        if let SharedMmap(Some(filebacking)) = map {
+            if let FileBacking::Vec(vec) = &*filebacking {
+                assert_eq!(contents, &vec[..]);
+            } else {
+                panic!("expected small file to be read rather than mmapped");
+            }
+        } else {
+            panic!("expected file to have some backing");
+        }
+    }
+
+    #[test]
+    fn write_and_map_large_file_backed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("foo");
+        let file = FileBackedStore::new(file_path);
+        let runtime = Runtime::new().unwrap();
+
+        let w = file.open_write();
+        let mut contents = vec![0u8; MMAP_TRESHOLD];
+        for i in 0..MMAP_TRESHOLD {
+            contents[i] = (i as usize % 256) as u8;
+        }
+
+        let task = tokio::io::write_all(w, contents.clone()).and_then(move |_| file.map());
+
+        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        if let SharedMmap(Some(filebacking)) = map {
+            if let FileBacking::Mmap(mmap) = &*filebacking {
+                assert_eq!(contents, mmap.as_ref());
+            } else {
+                panic!("expected file of sufficient size to be mmapped rather than read");
+            }
+        } else {
+            panic!("expected file to have some backing");
+        }
+    }
+
+    #[test]
+    fn create_layers_from_directory_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLayerStore::new(dir.path());
+        let task = store
+            .create_base_layer()
+            .and_then(|mut builder| {
+                let base_name = builder.name();
+
+                builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+                builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+                builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+
+                builder.commit_boxed().map(move |_| base_name)
+            })
+            .and_then(move |base_name| {
+                store
+                    .create_child_layer(base_name)
+                    .and_then(|mut builder| {
+                        let child_name = builder.name();
+
+                        builder.remove_string_triple(&StringTriple::new_value(
+                            "duck", "says", "quack",
+                        ));
+                        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+
+                        builder.commit_boxed().map(move |_| child_name)
+                    })
+                    .and_then(move |child_name| store.get_layer(child_name))
+            });
+
+        let layer = oneshot::spawn(task, &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        runtime.shutdown_now();
+
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "pig")));
+        assert!(!layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+    }
+
+    #[test]
+    fn write_and_map_compressed_file_backed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("foo.zst");
+        let file = FileBackedStore::new_compressed(file_path, Compression::Zstd(3));
+        let runtime = Runtime::new().unwrap();
+
+        let w = file.open_write();
+        let contents = vec![42u8; MMAP_TRESHOLD * 2];
+        let file2 = file.clone();
+        let task = tokio::io::write_all(w, contents.clone())
+            .and_then(|(w, _)| tokio::io::flush(w))
+            .and_then(move |_| file.map())
+            .and_then(move |map| {
+                tokio::io::read_to_end(file2.open_read_from(8), Vec::new())
+                    .map(move |(_, buf)| (map, buf, file2.size().unwrap()))
+            });
+
+        let (map, buf, size) = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(&contents[..], map.as_ref());
+        assert_eq!(&contents[8..], &buf[..]);
+        assert_eq!(contents.len(), size);
+        assert!(std::fs::metadata(dir.path().join("foo.zst")).unwrap().len() < 1024);
+    }
+
+    #[test]
+    fn missing_file_reports_size_and_map_errors() {
+        let dir = tempdir().unwrap();
+        let file = FileBackedStore::new(dir.path().join("missing"));
+        let compressed =
+            FileBackedStore::new_compressed(dir.path().join("missing.zst"), Compression::Zstd(3));
+
+        assert!(file.size().is_err());
+        assert!(compressed.size().is_err());
+        assert!(file.map().wait().is_err());
+    }
+
+    #[test]
+    fn compressed_writer_finish_reports_errors() {
+        let dir = tempdir().unwrap();
+        let file = FileBackedStore::new_compressed(
+            dir.path().join("no_such_dir").join("foo.zst"),
+            Compression::Zstd(3),
+        );
+
+        let mut w = match file.open_write() {
+            FileBackedWriter::Compressed(w) => w,
+            _ => panic!("expected a compressed writer"),
+        };
+        w.write_all(&[1, 2, 3]).unwrap();
+        assert!(w.finish().is_err());
+
+        // dropping an unwritable writer should not panic either
+        let mut w = file.open_write();
+        w.write_all(&[1, 2, 3]).unwrap();
+        drop(w);
+    }
+
+    #[test]
+    fn compressed_logarray_finalize_reports_write_errors() {
+        let dir = tempdir().unwrap();
+        let file = FileBackedStore::new_compressed(
+            dir.path().join("no_such_dir").join("foo.zst"),
+            Compression::Zstd(3),
+        );
+
+        let builder = crate::structure::LogArrayFileBuilder::new(file.open_write(), 5);
+        let task = builder
+            .push_all(futures::stream::iter_ok(vec![1, 2, 3]))
+            .and_then(|b| b.finalize());
+
+        assert!(task.wait().is_err());
+    }
+
+    #[test]
+    fn compressed_layer_commit_reports_write_errors() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLayerStore::with_compression(dir.path(), Compression::Zstd(3));
+        let dir_path = dir.path().to_path_buf();
+        let task = store.create_base_layer().and_then(move |mut builder| {
+            let name = name_to_string(builder.name());
+            std::fs::remove_dir_all(dir_path.join(&name[0..PREFIX_DIR_SIZE]).join(&name)).unwrap();
+
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+            builder.commit_boxed()
+        });
+
+        let result = oneshot::spawn(task, &runtime.executor()).wait();
+        runtime.shutdown_now();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn corrupt_compressed_file_reports_errors() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("foo.zst");
+        std::fs::write(&file_path, [0u8, 0, 0, 0, 0, 0, 0, 16, 1, 2, 3]).unwrap();
+        let file = FileBackedStore::new_compressed(file_path, Compression::Zstd(3));
+
+        assert!(file.map().wait().is_err());
+        assert!(tokio::io::read_to_end(file.open_read(), Vec::new())
+            .wait()
+            .is_err());
+        assert!(tokio::io::write_all(file.open_write_from(0), vec![1, 2, 3])
+            .wait()
+            .is_err());
+
+        let truncated_path = dir.path().join("truncated.zst");
+        std::fs::write(&truncated_path, [0u8, 0, 0]).unwrap();
+        let truncated = FileBackedStore::new_compressed(truncated_path, Compression::Zstd(3));
+        assert!(truncated.size().is_err());
+
+        let missing =
+            FileBackedStore::new_compressed(dir.path().join("missing.zst"), Compression::Zstd(3));
+        assert!(tokio::io::read_to_end(missing.open_read(), Vec::new())
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn deduplicate_and_delete_layer_directories_outside_runtime() {
+        let mut runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLayerStore::new(dir.path()).with_deduplication();
+        let name1 = runtime.block_on(store.create_directory()).unwrap();
+        let name2 = runtime.block_on(store.create_directory()).unwrap();
+        let name3 = runtime.block_on(store.create_directory()).unwrap();
+        let name4 = runtime.block_on(store.create_directory()).unwrap();
+        runtime.shutdown_now();
+
+        // without a threadpool, the blocking work runs inline
+        assert_eq!(name1, store.deduplicate_directory(name1).wait().unwrap());
+        assert_eq!(name1, store.deduplicate_directory(name2).wait().unwrap());
+        assert!(!layer_directory(dir.path(), name2).exists());
+
+        let hash =
+            std::fs::read_to_string(layer_directory(dir.path(), name1).join(CONTENT_HASH_FILE))
+                .unwrap();
+        let index_entry = content_index_directory(dir.path(), &hash).join(name_to_string(name1));
+        assert!(index_entry.exists());
+
+        store.delete_directory(name1).wait().unwrap();
+        assert!(!layer_directory(dir.path(), name1).exists());
+        assert!(!index_entry.exists());
+
+        // stale index entries of layers removed behind the store's back are skipped
+        assert_eq!(name3, store.deduplicate_directory(name3).wait().unwrap());
+        std::fs::remove_dir_all(layer_directory(dir.path(), name3)).unwrap();
+        assert_eq!(name4, store.deduplicate_directory(name4).wait().unwrap());
+    }
+
+    #[test]
+    fn replace_layer_metadata_in_directory_store() {
+        let mut runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLayerStore::new(dir.path());
+        let name = runtime.block_on(store.create_directory()).unwrap();
+
+        let mut long = HashMap::new();
+        long.insert(
+            "description".to_owned(),
+            "a rather long description".to_owned(),
+        );
+        runtime
+            .block_on(store.set_layer_metadata(name, long))
+            .unwrap();
+        let mut short = HashMap::new();
+        short.insert("a".to_owned(), "b".to_owned());
+        runtime
+            .block_on(store.set_layer_metadata(name, short.clone()))
+            .unwrap();
+
+        assert_eq!(short, runtime.block_on(store.layer_metadata(name)).unwrap());
+        let files: Vec<_> = std::fs::read_dir(store.file_path(name, ""))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec![std::ffi::OsString::from("metadata.json")], files);
+    }
+
+    #[test]
+    fn create_compressed_layers_from_directory_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLayerStore::with_compression(dir.path(), Compression::Zstd(3));
+        let uncompressed_store = DirectoryLayerStore::new(dir.path());
+        let task = store
+            .create_base_layer()
+            .and_then(|mut builder| {
+                let base_name = builder.name();
+
+                builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+                builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
+                builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+
+                builder.commit_boxed().map(move |_| base_name)
+            })
+            .and_then(move |base_name| {
+                store
+                    .create_child_layer(base_name)
+                    .and_then(|mut builder| {
+                        let child_name = builder.name();
+
+                        builder.remove_string_triple(&StringTriple::new_value(
+                            "duck", "says", "quack",
+                        ));
+                        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+
+                        builder.commit_boxed().map(move |_| child_name)
+                    })
+                    .and_then(move |child_name| uncompressed_store.get_layer(child_name))
+            });
+
+        let layer = oneshot::spawn(task, &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        runtime.shutdown_now();
+
+        let mut p = dir.path().to_path_buf();
+        let name = name_to_string(layer.name());
+        p.push(&name[0..PREFIX_DIR_SIZE]);
+        p.push(&name);
+        assert!(p.join("pos_sp_o_adjacency_list_nums.zst").exists());
+        assert!(p.join("node_dictionary_blocks.zst").exists());
+        assert!(p.join("pos_predicate_wavelet_tree_bits.bitarray").exists());
+
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "pig")));
+        assert!(!layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+    }
+
+    #[test]
+    fn directory_create_and_retrieve_equal_label() {
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+        let runtime = Runtime::new().unwrap();
+
+        let task = store
+            .create_label("foo")
+            .and_then(move |stored| store.get_label("foo").map(|retrieved| (stored, retrieved)));
+
+        let (stored, retrieved) = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(None, stored.layer);
+        assert_eq!(stored, retrieved.unwrap());
+    }
+
+    #[test]
+    fn directory_concurrent_create_label_succeeds_once() {
+        let dir = tempdir().unwrap();
+        let runtime = Runtime::new().unwrap();
+
+        let tasks: Vec<_> = (0..32)
+            .map(|_| {
+                let store = DirectoryLabelStore::new(dir.path());
+                oneshot::spawn(
+                    store.create_label("foo").then(Ok::<_, ()>),
+                    &runtime.executor(),
+                )
+            })
+            .collect();
+        let results = future::join_all(tasks).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(1, results.iter().filter(|r| r.is_ok()).count());
+        for result in results {
+            if let Err(e) = result {
+                assert_eq!(io::ErrorKind::AlreadyExists, e.kind());
+            }
+        }
+    }
+
+    #[test]
+    fn directory_update_label_succeeds() {
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+        let runtime = Runtime::new().unwrap();
+
+        let task = store.create_label("foo").and_then(move |stored| {
+            store
+                .set_label(&stored, [6, 7, 8, 9, 10])
+                .and_then(move |_| store.get_label("foo"))
+        });
+
+        let retrieved = oneshot::spawn(task, &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        runtime.shutdown_now();
+        assert_eq!(Some([6, 7, 8, 9, 10]), retrieved.layer);
+    }
+
+    #[test]
+    fn directory_failed_version_log_append_leaves_label_unchanged() {
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+        let runtime = Runtime::new().unwrap();
+        let executor = runtime.executor();
+
+        let stored = oneshot::spawn(store.create_label("foo"), &executor)
+            .wait()
+            .unwrap();
+
+        // a directory in place of the version log makes appending fail
+        let log = version_log_path(dir.path(), "foo");
+        std::fs::remove_file(&log).unwrap();
+        std::fs::create_dir(&log).unwrap();
+
+        assert!(
+            oneshot::spawn(store.set_label(&stored, [6, 7, 8, 9, 10]), &executor)
+                .wait()
+                .is_err()
+        );
+        let retrieved = oneshot::spawn(store.get_label("foo"), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored, retrieved);
+
+        std::fs::remove_dir(&log).unwrap();
+        let updated = oneshot::spawn(store.set_label(&stored, [6, 7, 8, 9, 10]), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
+        let at_version = oneshot::spawn(store.get_label_at_version("foo", 1), &executor)
+            .wait()
+            .unwrap();
+        runtime.shutdown_now();
+
+        assert_eq!(Some(updated), at_version);
+    }
+
+    #[test]
+    fn directory_update_label_twice_from_same_label_object_fails() {
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+        let runtime = Runtime::new().unwrap();
+
+        let task = store.create_label("foo").and_then(move |stored1| {
+            store
+                .set_label(&stored1, [6, 7, 8, 9, 10])
+                .and_then(move |stored2| {
+                    store
+                        .set_label(&stored1, [10, 9, 8, 7, 6])
+                        .map(|stored3| (stored2, stored3))
+                })
+        });
+
+        let (stored2, stored3) = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        runtime.shutdown_now();
+
+        assert!(stored2.is_some());
+        assert!(stored3.is_none());
+    }
+
+    #[test]
+    fn directory_create_label_twice_errors() {
+        let runtime = Runtime::new().unwrap();
+        let executor = runtime.executor();
+
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+
+        oneshot::spawn(store.create_label("foo"), &executor)
+            .wait()
+            .unwrap();
+        let result = oneshot::spawn(store.create_label("foo"), &executor).wait();
+        runtime.shutdown_now();
+
+        assert!(result.is_err());
+
+        let error = result.err().unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+    }
+
+    #[test]
+    fn directory_empty_label_file_has_no_head() {
         let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.label"), b"").unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
 
-        let w = file.open_write();
-        let task = tokio::io::write_all(w, [1, 2, 3]).and_then(move |_| file.map());
-
-        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        let task = store.get_label("foo").and_then(move |label| {
+            let label = label.unwrap();
+            store
+                .set_label(&label, [1, 2, 3, 4, 5])
+                .and_then(move |_| store.get_label("foo"))
+        });
+        let retrieved = oneshot::spawn(task, &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
         runtime.shutdown_now();
 
-        assert_eq!(&vec![1, 2, 3][..], &map.as_ref()[..]);
+        assert_eq!(1, retrieved.version);
+        assert_eq!(Some([1, 2, 3, 4, 5]), retrieved.layer);
     }
 
     #[test]
-    fn write_and_map_small_file_backed() {
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("foo");
-        let file = FileBackedStore::new(file_path);
+    fn directory_truncated_label_file_errors() {
         let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.label"), b"3\n").unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
 
-        let w = file.open_write();
-        let mut contents = vec![0u8; MMAP_TRESHOLD - 1];
-        for i in 0..MMAP_TRESHOLD - 1 {
-            contents[i] = (i as usize % 256) as u8;
-        }
+        let result = oneshot::spawn(store.get_label("foo"), &runtime.executor()).wait();
+        runtime.shutdown_now();
 
-        let task = tokio::io::write_all(w, contents.clone()).and_then(move |_| file.map());
+        let error = result.err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("foo"));
+    }
 
-        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
-        runtime.shutdown_now();
+    #[test]
+    fn find_versions_in_version_log() {
+        let layer1 = "0000000100000002000000030000000400000005";
+        let layer2 = "0000000600000007000000080000000900000010";
+        let log = format!("0 \n1 {}\n2 \n3 {}\n4 {}", layer1, layer2, layer1);
+
+        assert_eq!(
+            Some(None),
+            find_version_in_log("foo", log.as_bytes(), 0).unwrap()
+        );
+        assert_eq!(
+            Some(Some([1, 2, 3, 4, 5])),
+            find_version_in_log("foo", log.as_bytes(), 1).unwrap()
+        );
+        assert_eq!(
+            Some(None),
+            find_version_in_log("foo", log.as_bytes(), 2).unwrap()
+        );
+        assert_eq!(
+            Some(Some([6, 7, 8, 9, 0x10])),
+            find_version_in_log("foo", log.as_bytes(), 3).unwrap()
+        );
+        // the last line was cut off while writing
+        assert_eq!(None, find_version_in_log("foo", log.as_bytes(), 4).unwrap());
+
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            find_version_in_log("foo", b"one two\n", 1)
+                .unwrap_err()
+                .kind()
+        );
+    }
 
-        if let SharedMmap(Some(filebacking)) = map {
-            if let FileBacking::Vec(vec) = &*filebacking {
-                assert_eq!(contents, &vec[..]);
-            } else {
-                panic!("expected small file to be read rather than mmapped");
-            }
-        } else {
-            panic!("expected file to have some backing");
+    #[test]
+    fn label_names_round_trip_through_encoding() {
+        for name in &[
+            "foo",
+            "tenant1/people",
+            "a%2Fb",
+            "..",
+            ".hidden",
+            "x\\y",
+            "ünï.cödé",
+        ] {
+            let encoded = encode_label_name(name);
+            assert!(!encoded.contains('/'));
+            assert!(!encoded.starts_with('.'));
+            assert_eq!(*name, decode_label_name(&encoded));
         }
+
+        assert_eq!("foo", encode_label_name("foo"));
+        assert_eq!("tenant1%2Fpeople", encode_label_name("tenant1/people"));
+        assert_eq!("foo%2", decode_label_name("foo%2"));
+        assert_eq!("50%off", decode_label_name("50%off"));
     }
 
     #[test]
-    fn write_and_map_large_file_backed() {
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("foo");
-        let file = FileBackedStore::new(file_path);
+    fn directory_label_store_reads_label_files_with_unencoded_percent() {
         let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+        // a label file as created before label names were encoded
+        std::fs::write(dir.path().join("50%off.label"), "0\n\n").unwrap();
 
-        let w = file.open_write();
-        let mut contents = vec![0u8; MMAP_TRESHOLD];
-        for i in 0..MMAP_TRESHOLD {
-            contents[i] = (i as usize % 256) as u8;
+        let labels = oneshot::spawn(store.labels(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let names: Vec<_> = labels.into_iter().map(|label| label.name).collect();
+        assert_eq!(vec!["50%off".to_owned()], names);
+
+        let label = oneshot::spawn(store.get_label("50%off"), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let updated = oneshot::spawn(
+            store.set_label(&label, [1, 2, 3, 4, 5]),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap()
+        .unwrap();
+        assert_eq!(Some([1, 2, 3, 4, 5]), updated.layer);
+        assert!(!dir.path().join("50%25off.label").exists());
+        assert_eq!(
+            Some([1, 2, 3, 4, 5]),
+            oneshot::spawn(store.get_label("50%off"), &runtime.executor())
+                .wait()
+                .unwrap()
+                .unwrap()
+                .layer
+        );
+    }
+
+    #[test]
+    fn directory_label_store_waits_for_locks_on_lock_pool() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path()).with_lock_pool(LockPool::new(1).unwrap());
+        for name in &["foo", "bar"] {
+            oneshot::spawn(store.create_label(name), &runtime.executor())
+                .wait()
+                .unwrap();
         }
 
-        let task = tokio::io::write_all(w, contents.clone()).and_then(move |_| file.map());
+        // waiting for bar occupies the only thread of the lock pool
+        let bar_file = std::fs::File::open(dir.path().join("bar.label")).unwrap();
+        fs2::FileExt::lock_exclusive(&bar_file).unwrap();
+        let bar = oneshot::spawn(store.get_label("bar"), &runtime.executor());
+        std::thread::sleep(Duration::from_millis(100));
+
+        // so waiting for foo can't start until bar is unlocked, even
+        // though foo is unlocked long before that
+        let foo_file = std::fs::File::open(dir.path().join("foo.label")).unwrap();
+        fs2::FileExt::lock_exclusive(&foo_file).unwrap();
+        let foo_done = Arc::new(AtomicBool::new(false));
+        let foo = oneshot::spawn(
+            store.get_label("foo").map({
+                let foo_done = foo_done.clone();
+                move |label| {
+                    foo_done.store(true, Ordering::SeqCst);
+                    label
+                }
+            }),
+            &runtime.executor(),
+        );
+        std::thread::sleep(Duration::from_millis(100));
+        fs2::FileExt::unlock(&foo_file).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!foo_done.load(Ordering::SeqCst));
+
+        fs2::FileExt::unlock(&bar_file).unwrap();
+        let bar = bar.wait().unwrap();
+        let foo = foo.wait().unwrap();
+        runtime.shutdown_now();
 
-        let map = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        assert_eq!(Some(Label::new_empty("bar")), bar);
+        assert_eq!(Some(Label::new_empty("foo")), foo);
+    }
+
+    #[test]
+    fn lock_pool_rejects_zero_size() {
+        let error = LockPool::new(0).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn directory_label_file_with_invalid_utf8_errors() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.label"), b"3\n\xff\n").unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+
+        let result = oneshot::spawn(store.get_label("foo"), &runtime.executor()).wait();
         runtime.shutdown_now();
 
-        if let SharedMmap(Some(filebacking)) = map {
-            if let FileBacking::Mmap(mmap) = &*filebacking {
-                assert_eq!(contents, mmap.as_ref());
-            } else {
-                panic!("expected file of sufficient size to be mmapped rather than read");
-            }
-        } else {
-            panic!("expected file to have some backing");
-        }
+        let error = result.err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("not valid UTF-8"));
     }
 
     #[test]
-    fn create_layers_from_directory_store() {
+    fn directory_layer_cache_is_shared_between_stores() {
         let runtime = Runtime::new().unwrap();
         let dir = tempdir().unwrap();
-        let store = DirectoryLayerStore::new(dir.path());
-        let task = store
-            .create_base_layer()
-            .and_then(|mut builder| {
-                let base_name = builder.name();
+        let store1 = CachedLayerStore::new(
+            DirectoryLayerStore::new(dir.path()),
+            DirectoryLayerCache::new(dir.path()),
+        );
+        let store2 = CachedLayerStore::new(
+            DirectoryLayerStore::new(dir.path().join(".")),
+            DirectoryLayerCache::new(dir.path().join(".")),
+        );
+
+        let mut builder = oneshot::spawn(store1.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let name = builder.name();
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        oneshot::spawn(builder.commit_boxed(), &runtime.executor())
+            .wait()
+            .unwrap();
 
-                builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
-                builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink"));
-                builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack"));
+        let layer1 = oneshot::spawn(store1.get_layer(name), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let layer2 = oneshot::spawn(store2.get_layer(name), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert!(Arc::ptr_eq(&layer1, &layer2));
 
-                builder.commit_boxed().map(move |_| base_name)
-            })
-            .and_then(move |base_name| {
-                store
-                    .create_child_layer(base_name)
-                    .and_then(|mut builder| {
-                        let child_name = builder.name();
+        let cache = DirectoryLayerCache::new(dir.path());
+        std::mem::drop(layer1);
+        assert!(cache.get_layer_from_cache(name).is_some());
+        std::mem::drop(layer2);
+        assert!(cache.get_layer_from_cache(name).is_none());
+    }
 
-                        builder.remove_string_triple(&StringTriple::new_value(
-                            "duck", "says", "quack",
-                        ));
-                        builder.add_string_triple(&StringTriple::new_node("cow", "likes", "pig"));
+    #[test]
+    fn directory_layer_cache_map_prunes_dropped_layers() {
+        let store = crate::storage::memory::MemoryLayerStore::new();
+        let builder = store.create_base_layer().wait().unwrap();
+        let name = builder.name();
+        builder.commit_boxed().wait().unwrap();
+        let live = store.get_layer(name).wait().unwrap().unwrap();
+
+        let mut map = DirectoryLayerCacheMap::default();
+        map.insert((PathBuf::from("live"), live.name()), Arc::downgrade(&live));
+        for i in 0..1000 {
+            let dropped: Weak<dyn Layer> = Weak::<BaseLayer<SharedMmap>>::new();
+            map.insert((PathBuf::from("dropped"), [i, 0, 0, 0, 0]), dropped);
+        }
 
-                        builder.commit_boxed().map(move |_| child_name)
-                    })
-                    .and_then(move |child_name| store.get_layer(child_name))
-            });
+        assert!(map.layers.len() <= DIRECTORY_LAYER_CACHE_MIN_PRUNE);
+        assert!(map.layers[&(PathBuf::from("live"), live.name())]
+            .upgrade()
+            .is_some());
+    }
 
-        let layer = oneshot::spawn(task, &runtime.executor())
+    #[test]
+    fn directory_oversized_label_file_errors() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let mut contents = b"3\n".to_vec();
+        contents.resize(MAX_LABEL_FILE_SIZE as usize * 2, b'a');
+        std::fs::write(dir.path().join("foo.label"), contents).unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+
+        let result = oneshot::spawn(store.get_label("foo"), &runtime.executor()).wait();
+        runtime.shutdown_now();
+
+        let error = result.err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("foo"));
+    }
+
+    #[test]
+    fn directory_clear_label_leaves_readable_file() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
+
+        let task = store.create_label("foo").and_then(move |label| {
+            store
+                .set_label(&label, [1, 2, 3, 4, 5])
+                .and_then(move |label| store.clear_label(&label.unwrap()).map(|_| store))
+                .and_then(|store| store.get_label("foo"))
+        });
+        let retrieved = oneshot::spawn(task, &runtime.executor())
             .wait()
             .unwrap()
             .unwrap();
         runtime.shutdown_now();
 
-        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
-        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
-        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "pig")));
-        assert!(!layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+        assert_eq!(2, retrieved.version);
+        assert_eq!(None, retrieved.layer);
     }
 
     #[test]
-    fn directory_create_and_retrieve_equal_label() {
+    fn directory_delete_label_removes_label_files() {
+        let runtime = Runtime::new().unwrap();
         let dir = tempdir().unwrap();
         let store = DirectoryLabelStore::new(dir.path());
+
+        let foo = oneshot::spawn(store.create_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(dir.path().join("foo.label").exists());
+        assert!(dir.path().join("foo.versions").exists());
+
+        let deleted = oneshot::spawn(store.delete_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(deleted);
+        assert!(!dir.path().join("foo.label").exists());
+        assert!(!dir.path().join("foo.versions").exists());
+
+        let deleted_again = oneshot::spawn(store.delete_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(!deleted_again);
+
+        let set = oneshot::spawn(store.set_label(&foo, [1, 2, 3, 4, 5]), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(None, set);
+        assert_eq!(
+            None,
+            oneshot::spawn(store.get_label("foo"), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        let recreated = oneshot::spawn(store.create_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        runtime.shutdown_now();
+        assert_eq!(Label::new_empty("foo"), recreated);
+    }
+
+    #[test]
+    fn directory_label_names_skip_unreadable_label_files() {
         let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = DirectoryLabelStore::new(dir.path());
 
-        let task = store
-            .create_label("foo")
-            .and_then(move |stored| store.get_label("foo").map(|retrieved| (stored, retrieved)));
+        oneshot::spawn(store.create_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(store.create_label("bar/baz"), &runtime.executor())
+            .wait()
+            .unwrap();
+        std::fs::write(dir.path().join("truncated.label"), b"3\n").unwrap();
+        std::fs::write(dir.path().join("deleted.label"), DELETED_LABEL_FILE).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"not a label").unwrap();
 
-        let (stored, retrieved) = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        let mut names = oneshot::spawn(store.label_names(), &runtime.executor())
+            .wait()
+            .unwrap();
         runtime.shutdown_now();
+        names.sort();
 
-        assert_eq!(None, stored.layer);
-        assert_eq!(stored, retrieved.unwrap());
+        assert_eq!(vec!["bar/baz".to_owned(), "foo".to_owned()], names);
     }
 
     #[test]
-    fn directory_update_label_succeeds() {
+    fn directory_deleted_label_file_can_be_recreated() {
+        let runtime = Runtime::new().unwrap();
         let dir = tempdir().unwrap();
         let store = DirectoryLabelStore::new(dir.path());
-        let runtime = Runtime::new().unwrap();
 
-        let task = store.create_label("foo").and_then(move |stored| {
-            store
-                .set_label(&stored, [6, 7, 8, 9, 10])
-                .and_then(move |_| store.get_label("foo"))
-        });
+        // a label file left behind by a deletion that didn't finish
+        std::fs::write(dir.path().join("foo.label"), DELETED_LABEL_FILE).unwrap();
 
-        let retrieved = oneshot::spawn(task, &runtime.executor())
+        let labels = oneshot::spawn(store.labels(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(labels.is_empty());
+        assert_eq!(
+            None,
+            oneshot::spawn(store.get_label("foo"), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        let created = oneshot::spawn(store.create_label("foo"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let retrieved = oneshot::spawn(store.get_label("foo"), &runtime.executor())
             .wait()
-            .unwrap()
             .unwrap();
         runtime.shutdown_now();
-        assert_eq!(Some([6, 7, 8, 9, 10]), retrieved.layer);
+        assert_eq!(Some(created), retrieved);
     }
 
     #[test]
-    fn directory_update_label_twice_from_same_label_object_fails() {
+    fn directory_failed_version_log_append_leaves_all_labels_unchanged() {
+        let runtime = Runtime::new().unwrap();
+        let executor = runtime.executor();
+
         let dir = tempdir().unwrap();
         let store = DirectoryLabelStore::new(dir.path());
-        let runtime = Runtime::new().unwrap();
 
-        let task = store.create_label("foo").and_then(move |stored1| {
-            store
-                .set_label(&stored1, [6, 7, 8, 9, 10])
-                .and_then(move |stored2| {
-                    store
-                        .set_label(&stored1, [10, 9, 8, 7, 6])
-                        .map(|stored3| (stored2, stored3))
-                })
-        });
+        let bar = oneshot::spawn(store.create_label("bar"), &executor)
+            .wait()
+            .unwrap();
+        let foo = oneshot::spawn(store.create_label("foo"), &executor)
+            .wait()
+            .unwrap();
 
-        let (stored2, stored3) = oneshot::spawn(task, &runtime.executor()).wait().unwrap();
+        // labels are written in order of their name, so bar comes first
+        let log = version_log_path(dir.path(), "foo");
+        std::fs::remove_file(&log).unwrap();
+        std::fs::create_dir(&log).unwrap();
+
+        assert!(oneshot::spawn(
+            store.set_labels(vec![
+                (foo.clone(), Some([6, 7, 8, 9, 10])),
+                (bar.clone(), Some([6, 7, 8, 9, 10])),
+            ]),
+            &executor,
+        )
+        .wait()
+        .is_err());
+
+        let foo2 = oneshot::spawn(store.get_label("foo"), &executor)
+            .wait()
+            .unwrap();
+        let bar2 = oneshot::spawn(store.get_label("bar"), &executor)
+            .wait()
+            .unwrap();
         runtime.shutdown_now();
 
-        assert!(stored2.is_some());
-        assert!(stored3.is_none());
+        assert_eq!(Some(foo), foo2);
+        assert_eq!(Some(bar), bar2);
     }
 
     #[test]
-    fn directory_create_label_twice_errors() {
+    fn directory_update_labels_fails_if_one_changed() {
         let runtime = Runtime::new().unwrap();
         let executor = runtime.executor();
 
         let dir = tempdir().unwrap();
         let store = DirectoryLabelStore::new(dir.path());
 
-        oneshot::spawn(store.create_label("foo"), &executor)
+        let foo = oneshot::spawn(store.create_label("foo"), &executor)
             .wait()
             .unwrap();
-        let result = oneshot::spawn(store.create_label("foo"), &executor).wait();
+        let bar = oneshot::spawn(store.create_label("bar"), &executor)
+            .wait()
+            .unwrap();
+
+        oneshot::spawn(store.set_label(&bar, [1, 2, 3, 4, 5]), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
+
+        let result = oneshot::spawn(
+            store.set_labels(vec![
+                (foo.clone(), Some([6, 7, 8, 9, 10])),
+                (bar.clone(), Some([6, 7, 8, 9, 10])),
+            ]),
+            &executor,
+        )
+        .wait()
+        .unwrap();
+        assert!(result.is_none());
+
+        let bar = oneshot::spawn(store.get_label("bar"), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
+        let result = oneshot::spawn(
+            store.set_labels(vec![
+                (foo.clone(), Some([6, 7, 8, 9, 10])),
+                (bar.clone(), None),
+            ]),
+            &executor,
+        )
+        .wait()
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            vec!["foo", "bar"],
+            result.iter().map(|l| &l.name[..]).collect::<Vec<_>>()
+        );
+
+        let foo = oneshot::spawn(store.get_label("foo"), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
+        let bar = oneshot::spawn(store.get_label("bar"), &executor)
+            .wait()
+            .unwrap()
+            .unwrap();
         runtime.shutdown_now();
 
-        assert!(result.is_err());
+        assert_eq!(Some([6, 7, 8, 9, 10]), foo.layer);
+        assert_eq!(1, foo.version);
+        assert_eq!(None, bar.layer);
+        assert_eq!(2, bar.version);
+    }
 
-        let error = result.err().unwrap();
-        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    #[test]
+    fn layer_content_hash_is_stable() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"hello").unwrap();
+        std::fs::write(dir.path().join("b"), b"").unwrap();
+        std::fs::write(dir.path().join(CONTENT_HASH_FILE), b"ignored").unwrap();
+
+        assert_eq!("95103f609d69d8fc", layer_content_hash(dir.path()).unwrap());
     }
 }
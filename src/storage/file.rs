@@ -1,5 +1,6 @@
 //! storage traits that the builders and loaders can rely on
 
+use futures::future;
 use futures::prelude::*;
 use tokio::prelude::*;
 
@@ -15,7 +16,8 @@ pub trait FileLoad: Clone + Send + Sync {
     type Read: AsyncRead + Send;
     type Map: AsRef<[u8]> + Clone + Send + Sync;
 
-    fn size(&self) -> usize;
+    fn exists(&self) -> bool;
+    fn size(&self) -> std::io::Result<usize>;
     fn open_read(&self) -> Self::Read {
         self.open_read_from(0)
     }
@@ -58,6 +60,9 @@ pub struct BaseLayerFiles<F: 'static + FileLoad + FileStore> {
     pub o_ps_adjacency_list_files: AdjacencyListFiles<F>,
 
     pub predicate_wavelet_tree_files: BitIndexFiles<F>,
+
+    pub bloom_filter_file: F,
+    pub collation_file: F,
 }
 
 #[derive(Clone)]
@@ -72,37 +77,49 @@ pub struct BaseLayerMaps<M: 'static + AsRef<[u8]> + Clone + Send + Sync> {
     pub o_ps_adjacency_list_maps: AdjacencyListMaps<M>,
 
     pub predicate_wavelet_tree_maps: BitIndexMaps<M>,
+
+    pub bloom_filter_map: Option<M>,
+    pub collation_map: Option<M>,
 }
 
 impl<F: FileLoad + FileStore> BaseLayerFiles<F> {
     pub fn map_all(&self) -> impl Future<Item = BaseLayerMaps<F::Map>, Error = std::io::Error> {
-        let dict_futs = vec![
-            self.node_dictionary_files.map_all(),
+        let dict_futs = self.node_dictionary_files.map_all().join3(
             self.predicate_dictionary_files.map_all(),
             self.value_dictionary_files.map_all(),
-        ];
+        );
 
-        let aj_futs = vec![
-            self.s_p_adjacency_list_files.map_all(),
+        let aj_futs = self.s_p_adjacency_list_files.map_all().join3(
             self.sp_o_adjacency_list_files.map_all(),
             self.o_ps_adjacency_list_files.map_all(),
-        ];
+        );
 
-        future::join_all(dict_futs)
-            .join(future::join_all(aj_futs))
-            .join(self.predicate_wavelet_tree_files.map_all())
+        dict_futs
+            .join4(
+                aj_futs,
+                self.predicate_wavelet_tree_files.map_all(),
+                map_optional(&self.bloom_filter_file).join(map_optional(&self.collation_file)),
+            )
             .map(
-                |((dict_results, aj_results), predicate_wavelet_tree_maps)| BaseLayerMaps {
-                    node_dictionary_maps: dict_results[0].clone(),
-                    predicate_dictionary_maps: dict_results[1].clone(),
-                    value_dictionary_maps: dict_results[2].clone(),
+                |(
+                    (node_dictionary_maps, predicate_dictionary_maps, value_dictionary_maps),
+                    (s_p_adjacency_list_maps, sp_o_adjacency_list_maps, o_ps_adjacency_list_maps),
+                    predicate_wavelet_tree_maps,
+                    (bloom_filter_map, collation_map),
+                )| BaseLayerMaps {
+                    node_dictionary_maps,
+                    predicate_dictionary_maps,
+                    value_dictionary_maps,
 
-                    s_p_adjacency_list_maps: aj_results[0].clone(),
-                    sp_o_adjacency_list_maps: aj_results[1].clone(),
+                    s_p_adjacency_list_maps,
+                    sp_o_adjacency_list_maps,
 
-                    o_ps_adjacency_list_maps: aj_results[2].clone(),
+                    o_ps_adjacency_list_maps,
 
                     predicate_wavelet_tree_maps,
+
+                    bloom_filter_map,
+                    collation_map,
                 },
             )
     }
@@ -128,6 +145,9 @@ pub struct ChildLayerFiles<F: 'static + FileLoad + FileStore + Clone + Send + Sy
 
     pub pos_predicate_wavelet_tree_files: BitIndexFiles<F>,
     pub neg_predicate_wavelet_tree_files: BitIndexFiles<F>,
+
+    pub bloom_filter_file: F,
+    pub collation_file: F,
 }
 
 #[derive(Clone)]
@@ -150,66 +170,100 @@ pub struct ChildLayerMaps<M: 'static + AsRef<[u8]> + Clone + Send + Sync> {
 
     pub pos_predicate_wavelet_tree_maps: BitIndexMaps<M>,
     pub neg_predicate_wavelet_tree_maps: BitIndexMaps<M>,
+
+    pub bloom_filter_map: Option<M>,
+    pub collation_map: Option<M>,
 }
 
 impl<F: FileLoad + FileStore + Clone> ChildLayerFiles<F> {
     pub fn map_all(&self) -> impl Future<Item = ChildLayerMaps<F::Map>, Error = std::io::Error> {
-        let dict_futs = vec![
-            self.node_dictionary_files.map_all(),
+        let dict_futs = self.node_dictionary_files.map_all().join3(
             self.predicate_dictionary_files.map_all(),
             self.value_dictionary_files.map_all(),
-        ];
+        );
 
-        let sub_futs = vec![
-            self.pos_subjects_file.map(),
+        let sub_futs = self.pos_subjects_file.map().join4(
             self.pos_objects_file.map(),
             self.neg_subjects_file.map(),
             self.neg_objects_file.map(),
-        ];
+        );
 
-        let aj_futs = vec![
-            self.pos_s_p_adjacency_list_files.map_all(),
+        let pos_aj_futs = self.pos_s_p_adjacency_list_files.map_all().join3(
             self.pos_sp_o_adjacency_list_files.map_all(),
             self.pos_o_ps_adjacency_list_files.map_all(),
-            self.neg_s_p_adjacency_list_files.map_all(),
+        );
+
+        let neg_aj_futs = self.neg_s_p_adjacency_list_files.map_all().join3(
             self.neg_sp_o_adjacency_list_files.map_all(),
             self.neg_o_ps_adjacency_list_files.map_all(),
-        ];
+        );
 
-        let wt_futs = vec![
-            self.pos_predicate_wavelet_tree_files.map_all(),
+        let wt_futs = self.pos_predicate_wavelet_tree_files.map_all().join4(
             self.neg_predicate_wavelet_tree_files.map_all(),
-        ];
+            map_optional(&self.bloom_filter_file),
+            map_optional(&self.collation_file),
+        );
 
-        future::join_all(dict_futs)
-            .join(future::join_all(sub_futs))
-            .join(future::join_all(aj_futs))
-            .join(future::join_all(wt_futs))
+        dict_futs
+            .join5(sub_futs, pos_aj_futs, neg_aj_futs, wt_futs)
             .map(
-                |(((dict_results, sub_results), aj_results), wt_results)| ChildLayerMaps {
-                    node_dictionary_maps: dict_results[0].clone(),
-                    predicate_dictionary_maps: dict_results[1].clone(),
-                    value_dictionary_maps: dict_results[2].clone(),
-
-                    pos_subjects_map: sub_results[0].clone(),
-                    pos_objects_map: sub_results[1].clone(),
-                    neg_subjects_map: sub_results[2].clone(),
-                    neg_objects_map: sub_results[3].clone(),
-
-                    pos_s_p_adjacency_list_maps: aj_results[0].clone(),
-                    pos_sp_o_adjacency_list_maps: aj_results[1].clone(),
-                    pos_o_ps_adjacency_list_maps: aj_results[2].clone(),
-                    neg_s_p_adjacency_list_maps: aj_results[3].clone(),
-                    neg_sp_o_adjacency_list_maps: aj_results[4].clone(),
-                    neg_o_ps_adjacency_list_maps: aj_results[5].clone(),
-
-                    pos_predicate_wavelet_tree_maps: wt_results[0].clone(),
-                    neg_predicate_wavelet_tree_maps: wt_results[1].clone(),
+                |(
+                    (node_dictionary_maps, predicate_dictionary_maps, value_dictionary_maps),
+                    (pos_subjects_map, pos_objects_map, neg_subjects_map, neg_objects_map),
+                    (
+                        pos_s_p_adjacency_list_maps,
+                        pos_sp_o_adjacency_list_maps,
+                        pos_o_ps_adjacency_list_maps,
+                    ),
+                    (
+                        neg_s_p_adjacency_list_maps,
+                        neg_sp_o_adjacency_list_maps,
+                        neg_o_ps_adjacency_list_maps,
+                    ),
+                    (
+                        pos_predicate_wavelet_tree_maps,
+                        neg_predicate_wavelet_tree_maps,
+                        bloom_filter_map,
+                        collation_map,
+                    ),
+                )| ChildLayerMaps {
+                    node_dictionary_maps,
+                    predicate_dictionary_maps,
+                    value_dictionary_maps,
+
+                    pos_subjects_map,
+                    pos_objects_map,
+                    neg_subjects_map,
+                    neg_objects_map,
+
+                    pos_s_p_adjacency_list_maps,
+                    pos_sp_o_adjacency_list_maps,
+                    pos_o_ps_adjacency_list_maps,
+                    neg_s_p_adjacency_list_maps,
+                    neg_sp_o_adjacency_list_maps,
+                    neg_o_ps_adjacency_list_maps,
+
+                    pos_predicate_wavelet_tree_maps,
+                    neg_predicate_wavelet_tree_maps,
+
+                    bloom_filter_map,
+                    collation_map,
                 },
             )
     }
 }
 
+/// Map a file that may not exist, returning None if it doesn't.
+fn map_optional<F: FileLoad>(
+    file: &F,
+) -> impl Future<Item = Option<F::Map>, Error = std::io::Error> {
+    if file.exists() {
+        future::Either::A(file.map().map(Some))
+    } else {
+        future::Either::B(future::ok(None))
+    }
+}
+
 #[derive(Clone)]
 pub struct DictionaryMaps<M: 'static + AsRef<[u8]> + Clone + Send + Sync> {
     pub blocks_map: M,
@@ -230,11 +284,13 @@ pub struct DictionaryFiles<F: 'static + FileLoad + FileStore> {
 
 impl<F: 'static + FileLoad + FileStore> DictionaryFiles<F> {
     pub fn map_all(&self) -> impl Future<Item = DictionaryMaps<F::Map>, Error = std::io::Error> {
-        let futs = vec![self.blocks_file.map(), self.offsets_file.map()];
-        future::join_all(futs).map(|results| DictionaryMaps {
-            blocks_map: results[0].clone(),
-            offsets_map: results[1].clone(),
-        })
+        self.blocks_file
+            .map()
+            .join(self.offsets_file.map())
+            .map(|(blocks_map, offsets_map)| DictionaryMaps {
+                blocks_map,
+                offsets_map,
+            })
     }
 }
 
@@ -272,15 +328,13 @@ pub struct BitIndexFiles<F: 'static + FileLoad + FileStore> {
 
 impl<F: 'static + FileLoad + FileStore> BitIndexFiles<F> {
     pub fn map_all(&self) -> impl Future<Item = BitIndexMaps<F::Map>, Error = std::io::Error> {
-        let futs = vec![
-            self.bits_file.map(),
-            self.blocks_file.map(),
-            self.sblocks_file.map(),
-        ];
-        future::join_all(futs).map(|results| BitIndexMaps {
-            bits_map: results[0].clone(),
-            blocks_map: results[1].clone(),
-            sblocks_map: results[2].clone(),
-        })
+        self.bits_file
+            .map()
+            .join3(self.blocks_file.map(), self.sblocks_file.map())
+            .map(|(bits_map, blocks_map, sblocks_map)| BitIndexMaps {
+                bits_map,
+                blocks_map,
+                sblocks_map,
+            })
     }
 }
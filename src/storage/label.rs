@@ -1,3 +1,4 @@
+use futures::future;
 use futures::prelude::*;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -32,8 +33,63 @@ impl Label {
     }
 }
 
+/// The error returned when creating a database that already exists.
+///
+/// It is wrapped in an `io::Error` of kind `AlreadyExists`, from which
+/// it can be retrieved with `DatabaseAlreadyExists::from_io_error`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DatabaseAlreadyExists {
+    pub name: String,
+}
+
+impl DatabaseAlreadyExists {
+    /// Returns the `DatabaseAlreadyExists` error wrapped in the given error, if any.
+    pub fn from_io_error(error: &std::io::Error) -> Option<&DatabaseAlreadyExists> {
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<DatabaseAlreadyExists>())
+    }
+}
+
+impl std::fmt::Display for DatabaseAlreadyExists {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(formatter, "database {} already exists", self.name)
+    }
+}
+
+impl std::error::Error for DatabaseAlreadyExists {}
+
+impl From<DatabaseAlreadyExists> for std::io::Error {
+    fn from(error: DatabaseAlreadyExists) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::AlreadyExists, error)
+    }
+}
+
+/// The error returned when creating a label that already exists.
+pub(crate) fn label_already_exists_error(name: &str) -> std::io::Error {
+    DatabaseAlreadyExists {
+        name: name.to_owned(),
+    }
+    .into()
+}
+
 pub trait LabelStore: Send + Sync {
     fn labels(&self) -> Box<dyn Future<Item = Vec<Label>, Error = std::io::Error> + Send>;
+    /// Returns the names of all labels.
+    ///
+    /// Unlike `labels`, this skips labels that can't be read, such as
+    /// label files that are only partially written.
+    fn label_names(&self) -> Box<dyn Future<Item = Vec<String>, Error = std::io::Error> + Send> {
+        Box::new(
+            self.labels()
+                .map(|labels| labels.into_iter().map(|label| label.name).collect()),
+        )
+    }
+    /// Create a new label without a layer.
+    ///
+    /// If the label already exists, this returns an error of kind
+    /// `AlreadyExists`. When the same label is created concurrently,
+    /// exactly one of the calls succeeds.
     fn create_label(
         &self,
         name: &str,
@@ -62,4 +118,64 @@ pub trait LabelStore: Send + Sync {
     ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
         self.set_label_option(label, None)
     }
+
+    /// Delete a label, returning false if it did not exist.
+    ///
+    /// Only the label and its history are removed. The layers it
+    /// pointed to are kept, as they may be shared with other labels.
+    /// An update of the label that is in progress either completes
+    /// before the label is deleted, or finds that the label is gone.
+    fn delete_label(
+        &self,
+        name: &str,
+    ) -> Box<dyn Future<Item = bool, Error = std::io::Error> + Send>;
+
+    /// Get a label as it was at the given version.
+    ///
+    /// This returns None if the label does not exist, or if the
+    /// version is not known to this store. By default, only the
+    /// current version of a label is known. Stores that keep a
+    /// history of their labels override this.
+    fn get_label_at_version(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
+        Box::new(
+            self.get_label(name)
+                .map(move |label| label.filter(|label| label.version == version)),
+        )
+    }
+
+    /// Update several labels at once.
+    ///
+    /// Each label is set to its paired layer, but only if none of the
+    /// labels were changed since they were retrieved. If any of them
+    /// were, no label is updated and None is returned. Otherwise, the
+    /// updated labels are returned in the given order.
+    ///
+    /// Stores that write each label separately can't make the update
+    /// atomic in the face of I/O errors. If writing one of the labels
+    /// fails, the labels written before it keep their new layer, and
+    /// the error is returned.
+    fn set_labels(
+        &self,
+        updates: Vec<(Label, Option<[u32; 5]>)>,
+    ) -> Box<dyn Future<Item = Option<Vec<Label>>, Error = std::io::Error> + Send>;
+
+    /// Discard the history of every label, keeping only its current version.
+    ///
+    /// After this, `get_label_at_version` only knows the current
+    /// version of each label. The version counters are kept as they
+    /// are. For stores that keep no history, this does nothing.
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    /// Ensure that all label updates made so far are durably stored.
+    ///
+    /// For stores that keep nothing on disk, this does nothing.
+    fn flush(&self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        Box::new(future::ok(()))
+    }
 }
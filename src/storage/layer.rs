@@ -1,18 +1,24 @@
 use super::consts::FILENAMES;
 use super::file::*;
-use crate::layer::{BaseLayer, ChildLayer, Layer, LayerBuilder, LayerType, SimpleLayerBuilder};
+use crate::layer::{
+    build_base_layer_from_sorted_triples, build_squashed_base_layer, BaseLayer, ChildLayer, Layer,
+    LayerBuilder, LayerType, SimpleLayerBuilder, StringTriple,
+};
 use std::io;
-use std::sync::{Arc, Weak};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
 
 use futures::future;
 use futures::prelude::*;
 use std::sync::RwLock;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 pub trait LayerCache: 'static + Send + Sync {
     fn get_layer_from_cache(&self, name: [u32; 5]) -> Option<Arc<dyn Layer>>;
     fn cache_layer(&self, layer: Arc<dyn Layer>);
+    /// Forget the given layer, if it was cached.
+    fn invalidate(&self, _name: [u32; 5]) {}
 }
 
 pub struct NoCache;
@@ -46,6 +52,26 @@ pub trait LayerStore: 'static + Send + Sync {
     fn create_base_layer(
         &self,
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send>;
+    /// Create a base layer out of triples that are already sorted, returning its name.
+    ///
+    /// The triples are written out in the given order, without sorting
+    /// them first. Triples that are out of order result in an
+    /// `InvalidInput` error. The triples are spilled to a temporary
+    /// file in `temp_dir` rather than held in memory.
+    fn create_base_layer_from_sorted_triples(
+        &self,
+        triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send>;
+    /// Create a base layer containing the triples that are visible in the given layer, returning its name.
+    ///
+    /// The given layer does not need to be stored in this store.
+    /// Temporary files used for sorting are put in `temp_dir`.
+    fn create_squashed_base_layer(
+        &self,
+        layer: Arc<dyn Layer>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send>;
     fn create_child_layer_with_cache(
         &self,
         parent: [u32; 5],
@@ -57,6 +83,91 @@ pub trait LayerStore: 'static + Send + Sync {
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send> {
         self.create_child_layer_with_cache(parent, NOCACHE.clone())
     }
+
+    /// Returns the metadata stored for the given layer.
+    ///
+    /// Layers without any stored metadata return an empty map.
+    fn layer_metadata(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = HashMap<String, String>, Error = io::Error> + Send>;
+    /// Store the given metadata for a layer, replacing any existing metadata.
+    fn set_layer_metadata(
+        &self,
+        name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send>;
+
+    /// Ensure that all layer data written so far is durably stored.
+    ///
+    /// For stores that keep nothing on disk, this does nothing.
+    fn flush(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    /// Returns the number of bytes the given layer takes up on disk.
+    ///
+    /// This only counts the layer itself, not its ancestors. For
+    /// stores that keep nothing on disk, this is 0.
+    fn layer_disk_size(
+        &self,
+        _name: [u32; 5],
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
+        Box::new(future::ok(0))
+    }
+
+    /// Copy the files of the given layer into a directory store at `path`.
+    ///
+    /// Only the layer itself is copied, not its ancestors. Stores
+    /// that keep nothing on disk return an error.
+    fn copy_layer_to_directory(
+        &self,
+        _name: [u32; 5],
+        _path: PathBuf,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(future::err(not_a_directory_store_error()))
+    }
+
+    /// Returns the name of the parent of the given layer, or None if it is a base layer.
+    ///
+    /// Returns a `NotFound` error if the layer does not exist.
+    fn layer_parent(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = Option<[u32; 5]>, Error = io::Error> + Send> {
+        Box::new(self.get_layer(name).and_then(|layer| match layer {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "layer not found")),
+            Some(layer) => Ok(layer.parent().map(|parent| parent.name())),
+        }))
+    }
+
+    /// Returns the name under which a freshly committed layer should be known.
+    ///
+    /// Stores that deduplicate layers may find an existing layer with
+    /// the same content, in which case the given layer is removed and
+    /// the name of the existing layer is returned. By default, this
+    /// returns the given name.
+    fn deduplicate_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        Box::new(future::ok(name))
+    }
+
+    /// Remove the given layer from this store, returning false if it didn't exist.
+    ///
+    /// This does not check whether the layer is still in use. See
+    /// `Store::delete_layer` for a version that does. By default,
+    /// this returns an error.
+    fn delete_layer(
+        &self,
+        _name: [u32; 5],
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        Box::new(future::err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "layer store does not support deleting layers",
+        )))
+    }
 }
 
 pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
@@ -77,6 +188,11 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
         directory: [u32; 5],
         file: &str,
     ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send>;
+    /// Returns the total size in bytes of all files in the given directory.
+    fn directory_size(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send>;
 
     fn layer_type(
         &self,
@@ -114,6 +230,8 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
             FILENAMES.base_predicate_wavelet_tree_bits,
             FILENAMES.base_predicate_wavelet_tree_bit_index_blocks,
             FILENAMES.base_predicate_wavelet_tree_bit_index_sblocks,
+            FILENAMES.bloom_filter,
+            FILENAMES.collation,
         ];
 
         let clone = self.clone();
@@ -162,6 +280,8 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
                         blocks_file: files[19].clone(),
                         sblocks_file: files[20].clone(),
                     },
+                    bloom_filter_file: files[21].clone(),
+                    collation_file: files[22].clone(),
                 },
             ),
         )
@@ -212,6 +332,8 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
             FILENAMES.neg_predicate_wavelet_tree_bits,
             FILENAMES.neg_predicate_wavelet_tree_bit_index_blocks,
             FILENAMES.neg_predicate_wavelet_tree_bit_index_sblocks,
+            FILENAMES.bloom_filter,
+            FILENAMES.collation,
         ];
 
         let cloned = self.clone();
@@ -295,6 +417,8 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
                         blocks_file: files[38].clone(),
                         sblocks_file: files[39].clone(),
                     },
+                    bloom_filter_file: files[40].clone(),
+                    collation_file: files[41].clone(),
                 },
             ),
         )
@@ -311,6 +435,7 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
             self.get_file(dir_name, FILENAMES.parent)
                 .map(|f| f.open_write())
                 .and_then(|writer| tokio::io::write_all(writer, parent_string))
+                .and_then(|(writer, _)| tokio::io::flush(writer))
                 .map(|_| ()),
         )
     }
@@ -327,6 +452,148 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
         )
     }
 
+    /// Write the metadata file of a layer, replacing any existing one.
+    ///
+    /// By default, this writes over the file in place. Stores that can
+    /// replace a file atomically should do so instead.
+    fn write_metadata_file(
+        &self,
+        dir_name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let contents = serde_json::to_vec(&metadata).expect("string map should always serialize");
+
+        Box::new(
+            self.get_file(dir_name, FILENAMES.metadata)
+                .map(|f| f.open_write())
+                .and_then(|writer| tokio::io::write_all(writer, contents))
+                .and_then(|(writer, _)| tokio::io::flush(writer))
+                .map(|_| ()),
+        )
+    }
+
+    fn read_metadata_file(
+        &self,
+        dir_name: [u32; 5],
+    ) -> Box<dyn Future<Item = HashMap<String, String>, Error = std::io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(
+            self.file_exists(dir_name, FILENAMES.metadata)
+                .and_then(move |exists| match exists {
+                    false => future::Either::A(future::ok(HashMap::new())),
+                    true => future::Either::B(
+                        cloned
+                            .get_file(dir_name, FILENAMES.metadata)
+                            .and_then(|f| f.map())
+                            .and_then(|map| {
+                                serde_json::from_slice(map.as_ref()).map_err(|e| {
+                                    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                                })
+                            }),
+                    ),
+                }),
+        )
+    }
+
+    fn write_format_version_file(
+        &self,
+        dir_name: [u32; 5],
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        Box::new(
+            self.get_file(dir_name, FILENAMES.format_version)
+                .map(|f| f.open_write())
+                .and_then(|writer| tokio::io::write_all(writer, LAYER_FORMAT_VERSION.to_string()))
+                .and_then(|(writer, _)| tokio::io::flush(writer))
+                .map(|_| ()),
+        )
+    }
+
+    /// Check that the files in the given directory are in a format this crate understands.
+    ///
+    /// Directories without a format version file predate format
+    /// versioning, and are in format version 1.
+    fn check_format_version(
+        &self,
+        dir_name: [u32; 5],
+    ) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(
+            self.file_exists(dir_name, FILENAMES.format_version)
+                .and_then(move |exists| match exists {
+                    false => future::Either::A(future::ok(())),
+                    true => future::Either::B(
+                        cloned
+                            .get_file(dir_name, FILENAMES.format_version)
+                            .and_then(|f| f.map())
+                            .and_then(move |map| {
+                                let contents = String::from_utf8_lossy(map.as_ref());
+                                match contents.trim().parse::<u32>() {
+                                    Ok(LAYER_FORMAT_VERSION) => Ok(()),
+                                    Ok(version) => Err(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!(
+                                            "layer {} has format version {}, but only format version {} is supported",
+                                            name_to_string(dir_name),
+                                            version,
+                                            LAYER_FORMAT_VERSION
+                                        ),
+                                    )),
+                                    Err(_) => Err(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!(
+                                            "layer {} has an unreadable format version",
+                                            name_to_string(dir_name)
+                                        ),
+                                    )),
+                                }
+                            }),
+                    ),
+                }),
+        )
+    }
+
+    /// Ensure that all files in this store are durably stored.
+    ///
+    /// By default this does nothing.
+    fn sync_all(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    /// Returns the name under which a freshly written directory should be known.
+    ///
+    /// By default this returns the given name. See
+    /// `LayerStore::deduplicate_layer`.
+    fn deduplicate_directory(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        Box::new(future::ok(name))
+    }
+
+    /// Remove the given directory and everything in it.
+    ///
+    /// By default this returns an error.
+    fn delete_directory(
+        &self,
+        _name: [u32; 5],
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(future::err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "layer store does not support deleting layers",
+        )))
+    }
+
+    /// Copy all files of the given directory into a directory store at `path`.
+    ///
+    /// By default this returns an error.
+    fn copy_directory_to(
+        &self,
+        _name: [u32; 5],
+        _path: PathBuf,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(future::err(not_a_directory_store_error()))
+    }
+
     fn retrieve_layer_stack_names(
         &self,
         name: [u32; 5],
@@ -358,6 +625,12 @@ pub trait PersistentLayerStore: 'static + Send + Sync + Clone {
     }
 }
 
+/// The version of the on-disk layer format written by this crate.
+///
+/// Loading a layer that was written in any other format version
+/// results in an error, rather than in misreading its files.
+pub const LAYER_FORMAT_VERSION: u32 = 1;
+
 pub fn name_to_string(name: [u32; 5]) -> String {
     format!(
         "{:08x}{:08x}{:08x}{:08x}{:08x}",
@@ -411,35 +684,42 @@ impl<F: 'static + FileLoad + FileStore + Clone, T: 'static + PersistentLayerStor
 
         let cloned = self.clone();
         let cloned2 = self.clone();
-        let mut result = Vec::new();
-        result.push(name);
+        let result = vec![name];
+        let mut seen = HashSet::new();
+        seen.insert(name);
         Box::new(self.directory_exists(name).and_then(move |b| {
             match b {
                 false => future::Either::A(future::ok(None)),
                 true => future::Either::B(
                     future::loop_fn(
-                        (cloned.clone(), cache, result),
-                        |(retriever, cache, mut result)| {
+                        (cloned.clone(), cache, result, seen),
+                        |(retriever, cache, mut result, mut seen)| {
                             match cache.get_layer_from_cache(*result.last().unwrap()) {
-                                None => future::Either::A(
-                                    retriever.layer_type(*result.last().unwrap()).and_then(|t| {
-                                        match t {
-                                            LayerType::Base => future::Either::A(future::ok(
-                                                future::Loop::Break((None, result, cache)),
-                                            )),
-                                            LayerType::Child => future::Either::B(
-                                                retriever
-                                                    .read_parent_file(*result.last().unwrap())
-                                                    .map(|p| {
+                                None => {
+                                    let id = *result.last().unwrap();
+                                    future::Either::A(
+                                        retriever
+                                            .check_format_version(id)
+                                            .join(retriever.layer_type(id))
+                                            .and_then(move |(_, t)| match t {
+                                                LayerType::Base => future::Either::A(future::ok(
+                                                    future::Loop::Break((None, result, cache)),
+                                                )),
+                                                LayerType::Child => future::Either::B(
+                                                    retriever.read_parent_file(id).and_then(|p| {
+                                                        // a corrupt store could have parent files pointing in a circle
+                                                        if !seen.insert(p) {
+                                                            return Err(cyclic_ancestry_error(p));
+                                                        }
                                                         result.push(p);
-                                                        future::Loop::Continue((
-                                                            retriever, cache, result,
-                                                        ))
+                                                        Ok(future::Loop::Continue((
+                                                            retriever, cache, result, seen,
+                                                        )))
                                                     }),
-                                            ),
-                                        }
-                                    }),
-                                ),
+                                                ),
+                                            }),
+                                    )
+                                }
                                 Some(layer) => {
                                     // remove found cached layer from ids to retrieve
                                     result.pop().unwrap();
@@ -499,9 +779,45 @@ impl<F: 'static + FileLoad + FileStore + Clone, T: 'static + PersistentLayerStor
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send> {
         let cloned = self.clone();
         Box::new(self.create_directory().and_then(move |dir_name| {
-            cloned.base_layer_files(dir_name).map(move |blf| {
-                Box::new(SimpleLayerBuilder::new(dir_name, blf)) as Box<dyn LayerBuilder>
-            })
+            cloned
+                .write_format_version_file(dir_name)
+                .and_then(move |_| cloned.base_layer_files(dir_name))
+                .map(move |blf| {
+                    Box::new(SimpleLayerBuilder::new(dir_name, blf)) as Box<dyn LayerBuilder>
+                })
+        }))
+    }
+
+    fn create_base_layer_from_sorted_triples(
+        &self,
+        triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(self.create_directory().and_then(move |dir_name| {
+            cloned
+                .write_format_version_file(dir_name)
+                .and_then(move |_| cloned.base_layer_files(dir_name))
+                .and_then(move |blf| {
+                    build_base_layer_from_sorted_triples(blf, triples, temp_dir)
+                        .map(move |_| dir_name)
+                })
+        }))
+    }
+
+    fn create_squashed_base_layer(
+        &self,
+        layer: Arc<dyn Layer>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(self.create_directory().and_then(move |dir_name| {
+            cloned
+                .write_format_version_file(dir_name)
+                .and_then(move |_| cloned.base_layer_files(dir_name))
+                .and_then(move |blf| {
+                    build_squashed_base_layer(blf, layer, temp_dir).map(move |_| dir_name)
+                })
         }))
     }
 
@@ -523,8 +839,13 @@ impl<F: 'static + FileLoad + FileStore + Clone, T: 'static + PersistentLayerStor
                 .and_then(move |parent_layer| {
                     cloned.create_directory().and_then(move |dir_name| {
                         cloned
-                            .write_parent_file(dir_name, parent)
+                            .write_format_version_file(dir_name)
                             .and_then(move |_| {
+                                cloned
+                                    .write_parent_file(dir_name, parent)
+                                    .map(move |_| cloned)
+                            })
+                            .and_then(move |cloned| {
                                 cloned.child_layer_files(dir_name).map(move |clf| {
                                     Box::new(SimpleLayerBuilder::from_parent(
                                         dir_name,
@@ -537,6 +858,100 @@ impl<F: 'static + FileLoad + FileStore + Clone, T: 'static + PersistentLayerStor
                 }),
         )
     }
+
+    fn layer_metadata(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = HashMap<String, String>, Error = io::Error> + Send> {
+        self.read_metadata_file(name)
+    }
+
+    fn set_layer_metadata(
+        &self,
+        name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.write_metadata_file(name, metadata)
+    }
+
+    fn flush(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.sync_all()
+    }
+
+    fn layer_disk_size(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
+        self.directory_size(name)
+    }
+
+    fn copy_layer_to_directory(
+        &self,
+        name: [u32; 5],
+        path: PathBuf,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.copy_directory_to(name, path)
+    }
+
+    fn layer_parent(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = Option<[u32; 5]>, Error = io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(self.directory_exists(name).and_then(move |exists| {
+            if !exists {
+                return future::Either::A(future::err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "layer not found",
+                )));
+            }
+
+            future::Either::B(cloned.layer_type(name).and_then(move |t| match t {
+                LayerType::Base => future::Either::A(future::ok(None)),
+                LayerType::Child => future::Either::B(cloned.read_parent_file(name).map(Some)),
+            }))
+        }))
+    }
+
+    fn deduplicate_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        self.deduplicate_directory(name)
+    }
+
+    fn delete_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        let cloned = self.clone();
+        Box::new(self.directory_exists(name).and_then(move |exists| {
+            if exists {
+                future::Either::A(cloned.delete_directory(name).map(|_| true))
+            } else {
+                future::Either::B(future::ok(false))
+            }
+        }))
+    }
+}
+
+/// The error for copying layers out of a store that isn't backed by a directory.
+fn not_a_directory_store_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "layer store is not backed by a directory",
+    )
+}
+
+/// The error for a layer that turns out to be its own ancestor.
+pub(crate) fn cyclic_ancestry_error(name: [u32; 5]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "layer {} occurs more than once in its own ancestry",
+            name_to_string(name)
+        ),
+    )
 }
 
 // locking isn't really ideal but the lock window will be relatively small so it shouldn't hurt performance too much except on heavy updates.
@@ -586,6 +1001,69 @@ impl LayerCache for LockingHashMapLayerCache {
             .expect("rwlock write should always succeed");
         cache.insert(layer.name(), Arc::downgrade(&layer));
     }
+
+    fn invalidate(&self, name: [u32; 5]) {
+        self.cache
+            .write()
+            .expect("rwlock write should always succeed")
+            .remove(&name);
+    }
+}
+
+/// A layer cache that keeps the most recently used layers loaded.
+///
+/// Other caches only hold on to a layer for as long as something
+/// else does. This wraps another cache and also keeps up to
+/// `capacity` of the most recently retrieved or cached layers alive,
+/// so that opening them again does not require loading them from
+/// storage. Note that keeping a layer alive also keeps its ancestors
+/// alive.
+pub struct RetainingLayerCache<C: LayerCache> {
+    inner: C,
+    capacity: usize,
+    recent: Mutex<VecDeque<Arc<dyn Layer>>>,
+}
+
+impl<C: LayerCache> RetainingLayerCache<C> {
+    pub fn new(inner: C, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn retain(&self, layer: &Arc<dyn Layer>) {
+        let mut recent = self.recent.lock().expect("mutex should not be poisoned");
+        let name = layer.name();
+        recent.retain(|l| l.name() != name);
+        recent.push_front(layer.clone());
+        recent.truncate(self.capacity);
+    }
+}
+
+impl<C: LayerCache> LayerCache for RetainingLayerCache<C> {
+    fn get_layer_from_cache(&self, name: [u32; 5]) -> Option<Arc<dyn Layer>> {
+        let layer = self.inner.get_layer_from_cache(name);
+        if let Some(layer) = layer.as_ref() {
+            self.retain(layer);
+        }
+
+        layer
+    }
+
+    fn cache_layer(&self, layer: Arc<dyn Layer>) {
+        self.retain(&layer);
+        self.inner.cache_layer(layer);
+    }
+
+    fn invalidate(&self, name: [u32; 5]) {
+        self.recent
+            .lock()
+            .expect("mutex should not be poisoned")
+            .retain(|l| l.name() != name);
+        self.inner.invalidate(name);
+    }
 }
 
 #[derive(Clone)]
@@ -629,6 +1107,23 @@ impl LayerStore for CachedLayerStore {
         self.inner.create_base_layer()
     }
 
+    fn create_base_layer_from_sorted_triples(
+        &self,
+        triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        self.inner
+            .create_base_layer_from_sorted_triples(triples, temp_dir)
+    }
+
+    fn create_squashed_base_layer(
+        &self,
+        layer: Arc<dyn Layer>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        self.inner.create_squashed_base_layer(layer, temp_dir)
+    }
+
     fn create_child_layer_with_cache(
         &self,
         parent: [u32; 5],
@@ -636,6 +1131,65 @@ impl LayerStore for CachedLayerStore {
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send> {
         self.inner.create_child_layer_with_cache(parent, cache)
     }
+
+    fn layer_metadata(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = HashMap<String, String>, Error = io::Error> + Send> {
+        self.inner.layer_metadata(name)
+    }
+
+    fn set_layer_metadata(
+        &self,
+        name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.inner.set_layer_metadata(name, metadata)
+    }
+
+    fn flush(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.inner.flush()
+    }
+
+    fn layer_disk_size(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
+        self.inner.layer_disk_size(name)
+    }
+
+    fn copy_layer_to_directory(
+        &self,
+        name: [u32; 5],
+        path: PathBuf,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        self.inner.copy_layer_to_directory(name, path)
+    }
+
+    fn layer_parent(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = Option<[u32; 5]>, Error = io::Error> + Send> {
+        self.inner.layer_parent(name)
+    }
+
+    fn deduplicate_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        self.inner.deduplicate_layer(name)
+    }
+
+    fn delete_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        let cache = self.cache.clone();
+        Box::new(self.inner.delete_layer(name).map(move |deleted| {
+            cache.invalidate(name);
+            deleted
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -680,6 +1234,48 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn directory_layer_with_unknown_format_version_errors() {
+        let dir = tempdir().unwrap();
+        let runtime = Runtime::new().unwrap();
+        let store = DirectoryLayerStore::new(dir.path());
+        let mut builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let name = builder.name();
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        oneshot::spawn(builder.commit_boxed(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let name_string = name_to_string(name);
+        let version_path = dir
+            .path()
+            .join(&name_string[0..3])
+            .join(&name_string)
+            .join(FILENAMES.format_version);
+        assert_eq!(
+            LAYER_FORMAT_VERSION.to_string(),
+            std::fs::read_to_string(&version_path).unwrap()
+        );
+
+        std::fs::write(&version_path, "2").unwrap();
+        let error = oneshot::spawn(store.get_layer(name), &runtime.executor())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("format version 2"));
+
+        // layers written before format versioning have no version file
+        std::fs::remove_file(&version_path).unwrap();
+        let layer = oneshot::spawn(store.get_layer(name), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+    }
+
     #[test]
     fn cached_directory_layer_store_returns_same_layer_multiple_times() {
         let dir = tempdir().unwrap();
@@ -765,9 +1361,122 @@ pub mod tests {
         assert_eq!(1, Arc::weak_count(&layer));
     }
 
+    #[test]
+    fn retaining_layer_cache_keeps_recent_layers_alive() {
+        let store = CachedLayerStore::new(
+            MemoryLayerStore::new(),
+            RetainingLayerCache::new(LockingHashMapLayerCache::new(), 1),
+        );
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            let mut builder = store.create_base_layer().wait().unwrap();
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+            names.push(builder.name());
+            builder.commit_boxed().wait().unwrap();
+        }
+
+        let weak = Arc::downgrade(&store.get_layer(names[0]).wait().unwrap().unwrap());
+        assert!(weak.upgrade().is_some());
+
+        // retrieving another layer pushes the first one out
+        store.get_layer(names[1]).wait().unwrap().unwrap();
+        assert!(weak.upgrade().is_none());
+    }
+
     #[test]
     fn retrieve_layer_stack_names_retrieves_correctly() {
         //let store = CachedLayerStore::new(MemoryLayerStore::new());
         //let builder = store.create_base_layer().wait().unwrap();
     }
+
+    type MockFiles = HashMap<([u32; 5], String), MemoryBackedStore>;
+
+    /// A persistent layer store keeping its files in memory, which lets tests write arbitrary files
+    #[derive(Clone, Default)]
+    struct MockPersistentLayerStore {
+        directories: Arc<std::sync::Mutex<HashSet<[u32; 5]>>>,
+        files: Arc<std::sync::Mutex<MockFiles>>,
+    }
+
+    impl PersistentLayerStore for MockPersistentLayerStore {
+        type File = MemoryBackedStore;
+
+        fn directories(&self) -> Box<dyn Future<Item = Vec<[u32; 5]>, Error = io::Error> + Send> {
+            let directories = self.directories.lock().unwrap();
+            Box::new(future::ok(directories.iter().cloned().collect()))
+        }
+
+        fn create_directory(&self) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+            let name = rand::random();
+            self.directories.lock().unwrap().insert(name);
+            Box::new(future::ok(name))
+        }
+
+        fn directory_exists(
+            &self,
+            name: [u32; 5],
+        ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+            Box::new(future::ok(self.directories.lock().unwrap().contains(&name)))
+        }
+
+        fn get_file(
+            &self,
+            directory: [u32; 5],
+            name: &str,
+        ) -> Box<dyn Future<Item = Self::File, Error = io::Error> + Send> {
+            let mut files = self.files.lock().unwrap();
+            let file = files
+                .entry((directory, name.to_owned()))
+                .or_insert_with(MemoryBackedStore::new)
+                .clone();
+            Box::new(future::ok(file))
+        }
+
+        fn file_exists(
+            &self,
+            directory: [u32; 5],
+            file: &str,
+        ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+            let files = self.files.lock().unwrap();
+            Box::new(future::ok(
+                files.contains_key(&(directory, file.to_owned())),
+            ))
+        }
+
+        fn directory_size(
+            &self,
+            name: [u32; 5],
+        ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
+            let files = self.files.lock().unwrap();
+            Box::new(future::ok(
+                files
+                    .iter()
+                    .filter(|((directory, _), _)| *directory == name)
+                    .map(|(_, file)| file.size().unwrap() as u64)
+                    .sum(),
+            ))
+        }
+    }
+
+    #[test]
+    fn cyclic_parent_chain_errors() {
+        let store = MockPersistentLayerStore::default();
+
+        let layer1 = store.create_directory().wait().unwrap();
+        let layer2 = store.create_directory().wait().unwrap();
+        store.write_parent_file(layer1, layer2).wait().unwrap();
+        store.write_parent_file(layer2, layer1).wait().unwrap();
+
+        let self_referential = store.create_directory().wait().unwrap();
+        store
+            .write_parent_file(self_referential, self_referential)
+            .wait()
+            .unwrap();
+
+        for name in [layer1, layer2, self_referential].iter().cloned() {
+            let error = store.get_layer(name).wait().err().unwrap();
+            assert_eq!(io::ErrorKind::InvalidData, error.kind());
+            assert!(error.to_string().contains(&name_to_string(name)));
+        }
+    }
 }
@@ -1,11 +1,93 @@
 #![allow(unused)]
 use crate::storage::{layer, Label};
 use fs2::*;
+use futures::sync::oneshot;
 use std::io::{self, SeekFrom};
 use std::path::*;
+use std::sync::Arc;
 use tokio::fs;
 use tokio::prelude::*;
-use tokio_threadpool::blocking;
+use tokio_threadpool::{blocking, ThreadPool};
+
+/// A dedicated thread pool for waiting on file locks.
+///
+/// By default, a file lock that can't be acquired right away is
+/// waited for on the blocking pool of the tokio runtime, which is
+/// shared with other blocking operations. Under heavy lock
+/// contention, a lock pool keeps those waits from starving the
+/// runtime's blocking pool.
+///
+/// Dropping the last clone of a lock pool waits for its threads to
+/// finish any lock they are waiting for.
+#[derive(Clone)]
+pub struct LockPool {
+    pool: Arc<ThreadPool>,
+}
+
+impl LockPool {
+    /// Create a lock pool with the given number of threads.
+    ///
+    /// A size of 0 results in an `InvalidInput` error.
+    pub fn new(size: usize) -> Result<LockPool, io::Error> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "lock pool size should be at least 1",
+            ));
+        }
+
+        let pool = tokio_threadpool::Builder::new()
+            .pool_size(size)
+            .name_prefix("terminus-store-lock-")
+            .build();
+
+        Ok(LockPool {
+            pool: Arc::new(pool),
+        })
+    }
+
+    fn lock(
+        &self,
+        file: std::fs::File,
+        exclusive: bool,
+    ) -> impl Future<Item = std::fs::File, Error = io::Error> + Send {
+        let (sender, receiver) = oneshot::channel();
+        self.pool.spawn(future::lazy(move || {
+            let result = if exclusive {
+                file.lock_exclusive()
+            } else {
+                file.lock_shared()
+            };
+            // if the receiver is gone, dropping the file releases the lock again
+            let _ = sender.send(result.map(|_| file));
+
+            Ok(())
+        }));
+
+        receiver
+            .map_err(|_| io::Error::new(io::ErrorKind::Interrupted, "lock pool shut down"))
+            .and_then(|result| result)
+    }
+}
+
+/// Acquire a lock on the given file, waiting for it on the lock pool if one is given.
+fn lock_file(
+    file: std::fs::File,
+    exclusive: bool,
+    pool: Option<LockPool>,
+) -> Box<dyn Future<Item = std::fs::File, Error = io::Error> + Send> {
+    let locked = if exclusive {
+        file.try_lock_exclusive().is_ok()
+    } else {
+        file.try_lock_shared().is_ok()
+    };
+    match (locked, pool) {
+        (true, _) => Box::new(future::ok(file)),
+        (false, Some(pool)) => Box::new(pool.lock(file, exclusive)),
+        (false, None) if exclusive => Box::new(LockedFileLockFuture::new_exclusive(file)),
+        (false, None) => Box::new(LockedFileLockFuture::new_shared(file)),
+    }
+}
 
 pub struct LockedFileLockFuture {
     file: Option<std::fs::File>,
@@ -71,15 +153,13 @@ pub struct LockedFile {
 impl LockedFile {
     pub fn open<P: 'static + AsRef<Path> + Send>(
         path: P,
+        pool: Option<LockPool>,
     ) -> impl Future<Item = Self, Error = io::Error> + Send {
         fs::OpenOptions::new()
             .read(true)
             .open(path)
             .map(|f| f.into_std())
-            .and_then(|f| match f.try_lock_shared() {
-                Ok(()) => future::Either::A(future::ok(f)),
-                Err(_) => future::Either::B(LockedFileLockFuture::new_shared(f)),
-            })
+            .and_then(move |f| lock_file(f, false, pool))
             .map(|f| LockedFile {
                 file: Some(fs::File::from_std(f)),
             })
@@ -87,8 +167,9 @@ impl LockedFile {
 
     pub fn try_open<P: 'static + AsRef<Path> + Send>(
         path: P,
+        pool: Option<LockPool>,
     ) -> impl Future<Item = Option<Self>, Error = io::Error> + Send {
-        Self::open(path)
+        Self::open(path, pool)
             .map(|f| Some(f))
             .or_else(|e| match e.kind() {
                 io::ErrorKind::NotFound => Ok(None),
@@ -98,9 +179,10 @@ impl LockedFile {
 
     pub fn create_and_open<P: 'static + AsRef<Path> + Send>(
         path: P,
+        pool: Option<LockPool>,
     ) -> impl Future<Item = Self, Error = io::Error> + Send {
         let path = PathBuf::from(path.as_ref());
-        Self::try_open(path.clone()).and_then(move |f| match f {
+        Self::try_open(path.clone(), pool.clone()).and_then(move |f| match f {
             Some(file) => future::Either::A(future::ok(file)),
             None => future::Either::B(
                 fs::OpenOptions::new()
@@ -109,7 +191,7 @@ impl LockedFile {
                     .create(true)
                     .open(path.clone())
                     .and_then(|f| tokio::io::shutdown(f))
-                    .and_then(|_| Self::open(path)),
+                    .and_then(|_| Self::open(path, pool)),
             ),
         })
     }
@@ -142,6 +224,7 @@ pub struct ExclusiveLockedFile {
 impl ExclusiveLockedFile {
     pub fn create_and_open<P: 'static + AsRef<Path> + Send>(
         path: P,
+        pool: Option<LockPool>,
     ) -> impl Future<Item = Self, Error = io::Error> + Send {
         fs::OpenOptions::new()
             .create_new(true)
@@ -149,11 +232,7 @@ impl ExclusiveLockedFile {
             .write(true)
             .open(path)
             .map(|f| f.into_std())
-            .and_then(|f| match f.try_lock_exclusive() {
-                Ok(()) => Box::new(future::ok(f))
-                    as Box<dyn Future<Item = std::fs::File, Error = io::Error> + Send>,
-                Err(_) => Box::new(LockedFileLockFuture::new_exclusive(f)),
-            })
+            .and_then(move |f| lock_file(f, true, pool))
             .map(|f| ExclusiveLockedFile {
                 file: Some(fs::File::from_std(f)),
             })
@@ -161,17 +240,14 @@ impl ExclusiveLockedFile {
 
     pub fn open<P: 'static + AsRef<Path> + Send>(
         path: P,
+        pool: Option<LockPool>,
     ) -> impl Future<Item = Self, Error = io::Error> + Send {
         fs::OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)
             .map(|f| f.into_std())
-            .and_then(|f| match f.try_lock_exclusive() {
-                Ok(()) => Box::new(future::ok(f))
-                    as Box<dyn Future<Item = std::fs::File, Error = io::Error> + Send>,
-                Err(_) => Box::new(LockedFileLockFuture::new_exclusive(f)),
-            })
+            .and_then(move |f| lock_file(f, true, pool))
             .map(|f| ExclusiveLockedFile {
                 file: Some(fs::File::from_std(f)),
             })
@@ -249,22 +325,16 @@ impl Write for ExclusiveLockedFile {
 
 impl AsyncWrite for ExclusiveLockedFile {
     fn shutdown(&mut self) -> Result<Async<()>, io::Error> {
-        let result = self
-            .file
-            .as_mut()
-            .expect("tried to shutdown dropped ExclusiveLockedFile")
-            .shutdown();
+        let mut file = None;
+        std::mem::swap(&mut file, &mut self.file);
+        let file = file.expect("tried to shutdown dropped ExclusiveLockedFile");
 
-        match result {
-            Ok(Async::Ready(())) => {
-                let mut file = None;
-                std::mem::swap(&mut file, &mut self.file);
-                file.unwrap().into_std().unlock().unwrap();
-            }
-            _ => {}
-        };
+        // shutting down a tokio file just drops the underlying std
+        // file, after which it can no longer be unlocked. Instead, we
+        // unlock the std file ourselves and then drop it.
+        file.into_std().unlock()?;
 
-        result
+        Ok(Async::Ready(()))
     }
 }
 
@@ -1,13 +1,18 @@
 //! In-memory implementation of storage traits.
 use futures::prelude::*;
 use futures_locks;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
-use std::sync::{self, Arc};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{self, Arc, Mutex};
 use tokio::prelude::*;
 
 use super::*;
-use crate::layer::{BaseLayer, ChildLayer, Layer, LayerBuilder, SimpleLayerBuilder};
+use crate::layer::{
+    build_base_layer_from_sorted_triples, build_squashed_base_layer, BaseLayer, ChildLayer, Layer,
+    LayerBuilder, SimpleLayerBuilder, StringTriple,
+};
 
 pub struct MemoryBackedStoreWriter {
     vec: Arc<sync::RwLock<Vec<u8>>>,
@@ -78,15 +83,21 @@ impl AsRef<[u8]> for SharedVec {
     }
 }
 
+/// An in-memory file.
+///
+/// Like a file on disk, it only exists once it has been opened for
+/// writing.
 #[derive(Clone)]
 pub struct MemoryBackedStore {
     vec: Arc<sync::RwLock<Vec<u8>>>,
+    created: Arc<AtomicBool>,
 }
 
 impl MemoryBackedStore {
     pub fn new() -> MemoryBackedStore {
         MemoryBackedStore {
             vec: Default::default(),
+            created: Default::default(),
         }
     }
 }
@@ -95,6 +106,7 @@ impl FileStore for MemoryBackedStore {
     type Write = MemoryBackedStoreWriter;
 
     fn open_write_from(&self, pos: usize) -> MemoryBackedStoreWriter {
+        self.created.store(true, Ordering::SeqCst);
         MemoryBackedStoreWriter {
             vec: self.vec.clone(),
             pos,
@@ -106,8 +118,12 @@ impl FileLoad for MemoryBackedStore {
     type Read = MemoryBackedStoreReader;
     type Map = SharedVec;
 
-    fn size(&self) -> usize {
-        self.vec.read().unwrap().len()
+    fn exists(&self) -> bool {
+        self.created.load(Ordering::SeqCst)
+    }
+
+    fn size(&self) -> std::io::Result<usize> {
+        Ok(self.vec.read().unwrap().len())
     }
 
     fn open_read_from(&self, offset: usize) -> MemoryBackedStoreReader {
@@ -125,16 +141,94 @@ impl FileLoad for MemoryBackedStore {
     }
 }
 
+fn new_base_layer_files() -> BaseLayerFiles<MemoryBackedStore> {
+    let files: Vec<_> = (0..23).map(|_| MemoryBackedStore::new()).collect();
+    BaseLayerFiles {
+        node_dictionary_files: DictionaryFiles {
+            blocks_file: files[0].clone(),
+            offsets_file: files[1].clone(),
+        },
+        predicate_dictionary_files: DictionaryFiles {
+            blocks_file: files[2].clone(),
+            offsets_file: files[3].clone(),
+        },
+        value_dictionary_files: DictionaryFiles {
+            blocks_file: files[4].clone(),
+            offsets_file: files[5].clone(),
+        },
+        s_p_adjacency_list_files: AdjacencyListFiles {
+            bitindex_files: BitIndexFiles {
+                bits_file: files[6].clone(),
+                blocks_file: files[7].clone(),
+                sblocks_file: files[8].clone(),
+            },
+            nums_file: files[9].clone(),
+        },
+        sp_o_adjacency_list_files: AdjacencyListFiles {
+            bitindex_files: BitIndexFiles {
+                bits_file: files[10].clone(),
+                blocks_file: files[11].clone(),
+                sblocks_file: files[12].clone(),
+            },
+            nums_file: files[13].clone(),
+        },
+        o_ps_adjacency_list_files: AdjacencyListFiles {
+            bitindex_files: BitIndexFiles {
+                bits_file: files[14].clone(),
+                blocks_file: files[15].clone(),
+                sblocks_file: files[16].clone(),
+            },
+            nums_file: files[17].clone(),
+        },
+        predicate_wavelet_tree_files: BitIndexFiles {
+            bits_file: files[18].clone(),
+            blocks_file: files[19].clone(),
+            sblocks_file: files[20].clone(),
+        },
+        bloom_filter_file: files[21].clone(),
+        collation_file: files[22].clone(),
+    }
+}
+
+/// A function that generates the names of new layers.
+pub type LayerNameGenerator = Arc<dyn Fn() -> [u32; 5] + Send + Sync>;
+
+/// Returns a layer name generator that counts up from `[0, 0, 0, 0, 1]`.
+///
+/// This is meant for tests that need to know in advance which name a
+/// layer will get. See `MemoryLayerStore::with_name_generator`.
+pub fn sequential_layer_names() -> LayerNameGenerator {
+    let counter = AtomicU64::new(0);
+    Arc::new(move || {
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        [0, 0, 0, (n >> 32) as u32, n as u32]
+    })
+}
+
 #[derive(Clone)]
 pub struct MemoryLayerStore {
     layers:
         futures_locks::RwLock<HashMap<[u32; 5], (Option<[u32; 5]>, LayerFiles<MemoryBackedStore>)>>,
+    metadata: futures_locks::RwLock<HashMap<[u32; 5], HashMap<String, String>>>,
+    name_generator: LayerNameGenerator,
 }
 
 impl MemoryLayerStore {
     pub fn new() -> MemoryLayerStore {
+        Self::with_name_generator(Arc::new(rand::random))
+    }
+
+    /// Create a memory layer store that names new layers using the given generator
+    ///
+    /// By default, layers get random names. Tests can pass a
+    /// deterministic generator such as `sequential_layer_names` to
+    /// compare exact layer names. The generator should never return
+    /// the same name twice.
+    pub fn with_name_generator(name_generator: LayerNameGenerator) -> MemoryLayerStore {
         MemoryLayerStore {
             layers: futures_locks::RwLock::new(HashMap::new()),
+            metadata: futures_locks::RwLock::new(HashMap::new()),
+            name_generator,
         }
     }
 }
@@ -165,6 +259,7 @@ impl LayerStore for MemoryLayerStore {
             let layers = layers.expect("rwlock read should always succeed");
 
             let mut ids = Vec::new();
+            let mut seen = HashSet::new();
             // collect ids until we get a cache hit
             let mut id = name;
             let mut first = true;
@@ -172,6 +267,9 @@ impl LayerStore for MemoryLayerStore {
             loop {
                 match cache.get_layer_from_cache(id) {
                     None => {
+                        if !seen.insert(id) {
+                            return future::Either::A(future::err(cyclic_ancestry_error(id)));
+                        }
                         ids.push(id);
                         if let Some((parent, _)) = layers.get(&id) {
                             first = false;
@@ -246,52 +344,9 @@ impl LayerStore for MemoryLayerStore {
     fn create_base_layer(
         &self,
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send> {
-        let name = rand::random();
+        let name = (self.name_generator)();
 
-        let files: Vec<_> = (0..21).map(|_| MemoryBackedStore::new()).collect();
-        let blf = BaseLayerFiles {
-            node_dictionary_files: DictionaryFiles {
-                blocks_file: files[0].clone(),
-                offsets_file: files[1].clone(),
-            },
-            predicate_dictionary_files: DictionaryFiles {
-                blocks_file: files[2].clone(),
-                offsets_file: files[3].clone(),
-            },
-            value_dictionary_files: DictionaryFiles {
-                blocks_file: files[4].clone(),
-                offsets_file: files[5].clone(),
-            },
-            s_p_adjacency_list_files: AdjacencyListFiles {
-                bitindex_files: BitIndexFiles {
-                    bits_file: files[6].clone(),
-                    blocks_file: files[7].clone(),
-                    sblocks_file: files[8].clone(),
-                },
-                nums_file: files[9].clone(),
-            },
-            sp_o_adjacency_list_files: AdjacencyListFiles {
-                bitindex_files: BitIndexFiles {
-                    bits_file: files[10].clone(),
-                    blocks_file: files[11].clone(),
-                    sblocks_file: files[12].clone(),
-                },
-                nums_file: files[13].clone(),
-            },
-            o_ps_adjacency_list_files: AdjacencyListFiles {
-                bitindex_files: BitIndexFiles {
-                    bits_file: files[14].clone(),
-                    blocks_file: files[15].clone(),
-                    sblocks_file: files[16].clone(),
-                },
-                nums_file: files[17].clone(),
-            },
-            predicate_wavelet_tree_files: BitIndexFiles {
-                bits_file: files[18].clone(),
-                blocks_file: files[19].clone(),
-                sblocks_file: files[20].clone(),
-            },
-        };
+        let blf = new_base_layer_files();
 
         Box::new(self.layers.write().then(move |layers| {
             layers
@@ -301,12 +356,57 @@ impl LayerStore for MemoryLayerStore {
         }))
     }
 
+    fn create_base_layer_from_sorted_triples(
+        &self,
+        triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        let name = (self.name_generator)();
+        let blf = new_base_layer_files();
+
+        let layers = self.layers.clone();
+        Box::new(
+            build_base_layer_from_sorted_triples(blf.clone(), triples, temp_dir).and_then(
+                move |_| {
+                    layers.write().then(move |layers| {
+                        layers
+                            .expect("rwlock write should always succeed")
+                            .insert(name, (None, LayerFiles::Base(blf)));
+                        Ok(name)
+                    })
+                },
+            ),
+        )
+    }
+
+    fn create_squashed_base_layer(
+        &self,
+        layer: Arc<dyn Layer>,
+        temp_dir: PathBuf,
+    ) -> Box<dyn Future<Item = [u32; 5], Error = io::Error> + Send> {
+        let name = (self.name_generator)();
+        let blf = new_base_layer_files();
+
+        let layers = self.layers.clone();
+        Box::new(
+            build_squashed_base_layer(blf.clone(), layer, temp_dir).and_then(move |_| {
+                layers.write().then(move |layers| {
+                    layers
+                        .expect("rwlock write should always succeed")
+                        .insert(name, (None, LayerFiles::Base(blf)));
+                    Ok(name)
+                })
+            }),
+        )
+    }
+
     fn create_child_layer_with_cache(
         &self,
         parent: [u32; 5],
         cache: Arc<dyn LayerCache>,
     ) -> Box<dyn Future<Item = Box<dyn LayerBuilder>, Error = io::Error> + Send> {
         let layers = self.layers.clone();
+        let name_generator = self.name_generator.clone();
         Box::new(
             self.get_layer_with_cache(parent, cache)
                 .and_then(|parent_layer| match parent_layer {
@@ -317,8 +417,8 @@ impl LayerStore for MemoryLayerStore {
                     Some(parent_layer) => future::ok(parent_layer),
                 })
                 .and_then(move |parent_layer| {
-                    let name = rand::random();
-                    let files: Vec<_> = (0..40).map(|_| MemoryBackedStore::new()).collect();
+                    let name = name_generator();
+                    let files: Vec<_> = (0..42).map(|_| MemoryBackedStore::new()).collect();
 
                     let clf = ChildLayerFiles {
                         node_dictionary_files: DictionaryFiles {
@@ -397,6 +497,8 @@ impl LayerStore for MemoryLayerStore {
                             blocks_file: files[38].clone(),
                             sblocks_file: files[39].clone(),
                         },
+                        bloom_filter_file: files[40].clone(),
+                        collation_file: files[41].clone(),
                     };
 
                     layers.write().then(move |layers| {
@@ -411,19 +513,110 @@ impl LayerStore for MemoryLayerStore {
                 }),
         )
     }
+
+    fn layer_metadata(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = HashMap<String, String>, Error = io::Error> + Send> {
+        Box::new(self.metadata.read().then(move |metadata| {
+            Ok(metadata
+                .expect("rwlock read should always succeed")
+                .get(&name)
+                .cloned()
+                .unwrap_or_default())
+        }))
+    }
+
+    fn set_layer_metadata(
+        &self,
+        name: [u32; 5],
+        metadata: HashMap<String, String>,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(self.metadata.write().then(move |stored| {
+            stored
+                .expect("rwlock write should always succeed")
+                .insert(name, metadata);
+            Ok(())
+        }))
+    }
+
+    fn layer_parent(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = Option<[u32; 5]>, Error = io::Error> + Send> {
+        Box::new(self.layers.read().then(move |layers| {
+            match layers
+                .expect("rwlock read should always succeed")
+                .get(&name)
+            {
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "layer not found")),
+                Some((parent, _)) => Ok(*parent),
+            }
+        }))
+    }
+
+    fn delete_layer(
+        &self,
+        name: [u32; 5],
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        let metadata = self.metadata.clone();
+        Box::new(
+            self.layers
+                .write()
+                .then(move |layers| {
+                    Ok::<_, io::Error>(
+                        layers
+                            .expect("rwlock write should always succeed")
+                            .remove(&name)
+                            .is_some(),
+                    )
+                })
+                .and_then(move |deleted| {
+                    metadata.write().then(move |stored| {
+                        stored
+                            .expect("rwlock write should always succeed")
+                            .remove(&name);
+                        Ok(deleted)
+                    })
+                }),
+        )
+    }
 }
 
+/// The layer of every version of each label
+type LabelHistory = HashMap<String, BTreeMap<u64, Option<[u32; 5]>>>;
+
+/// A label store that keeps its labels in memory
+///
+/// Every layer a label has pointed at is kept in memory as well, so
+/// that `get_label_at_version` can return any earlier version of a
+/// label, until `compact` is called.
 #[derive(Clone)]
 pub struct MemoryLabelStore {
     labels: futures_locks::RwLock<HashMap<String, Label>>,
+    // the layer of each version of a label. This is only changed
+    // while holding a lock on labels, so it agrees with the labels.
+    history: Arc<Mutex<LabelHistory>>,
 }
 
 impl MemoryLabelStore {
     pub fn new() -> MemoryLabelStore {
         MemoryLabelStore {
             labels: futures_locks::RwLock::new(HashMap::new()),
+            history: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    fn record_version(&self, label: &Label) {
+        let mut history = self
+            .history
+            .lock()
+            .expect("history mutex should not be poisoned");
+        history
+            .entry(label.name.clone())
+            .or_default()
+            .insert(label.version, label.layer);
+    }
 }
 
 impl LabelStore for MemoryLabelStore {
@@ -441,16 +634,15 @@ impl LabelStore for MemoryLabelStore {
         name: &str,
     ) -> Box<dyn Future<Item = Label, Error = std::io::Error> + Send> {
         let label = Label::new_empty(name);
+        let store = self.clone();
 
         Box::new(self.labels.write().then(move |l| {
             let mut labels = l.expect("rwlock write should always succeed");
             if labels.get(&label.name).is_some() {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "label already exists",
-                ))
+                Err(label_already_exists_error(&label.name))
             } else {
                 labels.insert(label.name.clone(), label.clone());
+                store.record_version(&label);
                 Ok(label)
             }
         }))
@@ -474,6 +666,7 @@ impl LabelStore for MemoryLabelStore {
         layer: Option<[u32; 5]>,
     ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
         let new_label = label.with_updated_layer(layer);
+        let store = self.clone();
 
         Box::new(self.labels.write().then(move |l| {
             let mut labels = l.expect("rwlock write should always succeed");
@@ -488,6 +681,7 @@ impl LabelStore for MemoryLabelStore {
                         Ok(None)
                     } else {
                         labels.insert(new_label.name.clone(), new_label.clone());
+                        store.record_version(&new_label);
 
                         Ok(Some(new_label))
                     }
@@ -495,6 +689,104 @@ impl LabelStore for MemoryLabelStore {
             }
         }))
     }
+
+    fn set_labels(
+        &self,
+        updates: Vec<(Label, Option<[u32; 5]>)>,
+    ) -> Box<dyn Future<Item = Option<Vec<Label>>, Error = std::io::Error> + Send> {
+        let store = self.clone();
+        Box::new(self.labels.write().then(move |l| {
+            let mut labels = l.expect("rwlock write should always succeed");
+
+            for (label, _) in updates.iter() {
+                match labels.get(&label.name) {
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "label does not exist",
+                        ))
+                    }
+                    Some(old_label) => {
+                        if old_label.version != label.version {
+                            return Ok(None);
+                        }
+                    }
+                }
+            }
+
+            let new_labels: Vec<_> = updates
+                .into_iter()
+                .map(|(label, layer)| label.with_updated_layer(layer))
+                .collect();
+            for new_label in new_labels.iter() {
+                labels.insert(new_label.name.clone(), new_label.clone());
+                store.record_version(new_label);
+            }
+
+            Ok(Some(new_labels))
+        }))
+    }
+
+    fn delete_label(
+        &self,
+        name: &str,
+    ) -> Box<dyn Future<Item = bool, Error = std::io::Error> + Send> {
+        let name = name.to_owned();
+        let history = self.history.clone();
+        Box::new(self.labels.write().then(move |l| {
+            let mut labels = l.expect("rwlock write should always succeed");
+            history
+                .lock()
+                .expect("history mutex should not be poisoned")
+                .remove(&name);
+
+            Ok(labels.remove(&name).is_some())
+        }))
+    }
+
+    fn get_label_at_version(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> Box<dyn Future<Item = Option<Label>, Error = std::io::Error> + Send> {
+        let name = name.to_owned();
+        let history = self.history.clone();
+        Box::new(self.labels.read().then(move |l| {
+            let labels = l.expect("rwlock read should always succeed");
+            if !labels.contains_key(&name) {
+                return Ok(None);
+            }
+
+            let history = history
+                .lock()
+                .expect("history mutex should not be poisoned");
+            Ok(history
+                .get(&name)
+                .and_then(|versions| versions.get(&version))
+                .map(|&layer| Label {
+                    name,
+                    layer,
+                    version,
+                }))
+        }))
+    }
+
+    fn compact(&self) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> {
+        let history = self.history.clone();
+        // labels can't be updated while the read lock is held
+        Box::new(self.labels.read().then(move |l| {
+            let labels = l.expect("rwlock read should always succeed");
+            let mut history = history
+                .lock()
+                .expect("history mutex should not be poisoned");
+            for (name, versions) in history.iter_mut() {
+                let current = labels.get(name).map(|label| label.version);
+                versions.retain(|&version, _| Some(version) == current);
+            }
+
+            Ok(())
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -516,6 +808,16 @@ mod tests {
         assert_eq!(vec![1, 2, 3], buf);
     }
 
+    #[test]
+    fn memory_backed_exists_once_opened_for_writing() {
+        let file = MemoryBackedStore::new();
+        assert!(!file.exists());
+
+        let _w = file.open_write();
+        assert!(file.exists());
+        assert_eq!(0, file.size().unwrap());
+    }
+
     #[test]
     fn write_and_map_memory_backed() {
         let file = MemoryBackedStore::new();
@@ -554,6 +856,22 @@ mod tests {
         assert!(!layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
     }
 
+    #[test]
+    fn memory_store_with_sequential_layer_names() {
+        let store = MemoryLayerStore::with_name_generator(sequential_layer_names());
+        let mut builder = store.create_base_layer().wait().unwrap();
+        assert_eq!([0, 0, 0, 0, 1], builder.name());
+        builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo"));
+        builder.commit_boxed().wait().unwrap();
+
+        let builder = store.create_child_layer([0, 0, 0, 0, 1]).wait().unwrap();
+        assert_eq!([0, 0, 0, 0, 2], builder.name());
+        builder.commit_boxed().wait().unwrap();
+
+        let layer = store.get_layer([0, 0, 0, 0, 2]).wait().unwrap().unwrap();
+        assert_eq!(Some([0, 0, 0, 0, 1]), layer.parent().map(|p| p.name()));
+    }
+
     #[test]
     fn memory_create_and_retrieve_equal_label() {
         let store = MemoryLabelStore::new();
@@ -579,6 +897,22 @@ mod tests {
         assert_eq!(1, store.get_label("foo").wait().unwrap().unwrap().version);
     }
 
+    #[test]
+    fn memory_delete_label() {
+        let store = MemoryLabelStore::new();
+        let foo = store.create_label("foo").wait().unwrap();
+        store.set_label(&foo, [6, 7, 8, 9, 10]).wait().unwrap();
+
+        assert!(store.delete_label("foo").wait().unwrap());
+        assert!(!store.delete_label("foo").wait().unwrap());
+        assert_eq!(None, store.get_label("foo").wait().unwrap());
+        assert_eq!(None, store.get_label_at_version("foo", 1).wait().unwrap());
+
+        let foo = store.create_label("foo").wait().unwrap();
+        assert_eq!(0, foo.version);
+        assert_eq!(None, foo.layer);
+    }
+
     #[test]
     fn memory_update_label_twice_from_same_label_object_fails() {
         let store = MemoryLabelStore::new();
@@ -17,11 +17,16 @@
 //! directory on a filesystem or some other mechanism). Each directory
 //! is given a unique name of 20 bytes in hexadecimal format, and
 //! stores the layer's primitive data structures as files inside that
-//! directory.
+//! directory, along with the version of the format these files were
+//! written in.
 //!
 //! A label store is a set of files. The file name is of the format
 //! `foo.label`, for database `foo`. This file contains the name of
-//! the layer this label is pointing at.
+//! the layer this label is pointing at. Path separators in a database
+//! name are percent-encoded, so the label of `tenant1/people` is
+//! stored in `tenant1%2Fpeople.label`. Next to it, `foo.versions`
+//! records the layer of every version of the label, so that earlier
+//! versions of a database can be looked up.
 mod consts;
 pub mod directory;
 mod file;
@@ -33,3 +38,4 @@ pub mod memory;
 pub use file::*;
 pub use label::*;
 pub use layer::*;
+pub use locking::LockPool;
@@ -1,24 +1,37 @@
 //! High-level API for working with terminus-store.
 //!
 //! It is expected that most users of this library will work exclusively with the types contained in this module.
+pub mod sharded;
 pub mod sync;
 
-use futures::future;
 use futures::prelude::*;
-use std::path::PathBuf;
-use std::sync::Arc;
+use futures::sync::mpsc;
+use futures::{future, stream};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use futures_locks::RwLock;
+use tokio::fs;
 
 use crate::layer::{
-    IdTriple, Layer, LayerBuilder, LayerObjectLookup, LayerPredicateLookup, LayerSubjectLookup,
-    ObjectType, StringTriple,
+    export_ntriples, parse_ntriples_line, CommitProgress, CommitProgressCallback, IdTriple, Layer,
+    LayerBuilder, LayerDiff, LayerObjectLookup, LayerPredicateLookup, LayerStatistics,
+    LayerSubjectLookup, LayerTripleCounts, LayerType, ObjectType, ScanHint, SortedTripleSpill,
+    StringTriple,
+};
+use crate::storage::directory::{
+    Compression, DirectoryLabelStore, DirectoryLayerCache, DirectoryLayerStore,
 };
-use crate::storage::directory::{DirectoryLabelStore, DirectoryLayerStore};
 use crate::storage::memory::{MemoryLabelStore, MemoryLayerStore};
-use crate::storage::{CachedLayerStore, LabelStore, LayerStore, LockingHashMapLayerCache};
+use crate::storage::{
+    cyclic_ancestry_error, CachedLayerStore, Label, LabelStore, LayerStore, LockPool,
+    LockingHashMapLayerCache, RetainingLayerCache,
+};
+use crate::structure::{check_false_positive_rate, run_blocking, Collation};
 
-use std::io;
+use std::io::{self, BufReader};
+use tokio::io::AsyncRead;
 
 /// A wrapper over a SimpleLayerBuilder, providing a thread-safe sharable interface
 ///
@@ -31,22 +44,62 @@ use std::io;
 pub struct StoreLayerBuilder {
     builder: RwLock<Option<Box<dyn LayerBuilder>>>,
     name: [u32; 5],
+    metadata: Arc<Mutex<HashMap<String, String>>>,
+    length_limits: Arc<Mutex<LengthLimits>>,
     store: Store,
 }
 
+/// Limits on the length of strings added through a `StoreLayerBuilder`
+#[derive(Clone, Copy, Default)]
+struct LengthLimits {
+    node: Option<usize>,
+    predicate: Option<usize>,
+    value: Option<usize>,
+}
+
+impl LengthLimits {
+    fn check(&self, triple: &StringTriple) -> Result<(), io::Error> {
+        let check = |kind: &str, s: &str, limit: Option<usize>| match limit {
+            Some(limit) if s.len() > limit => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} of {} bytes exceeds the maximum {} length of {} bytes",
+                    kind,
+                    s.len(),
+                    kind,
+                    limit
+                ),
+            )),
+            _ => Ok(()),
+        };
+
+        check("node", &triple.subject, self.node)?;
+        check("predicate", &triple.predicate, self.predicate)?;
+        match &triple.object {
+            ObjectType::Node(node) => check("node", node, self.node),
+            ObjectType::Value(value) => check("value", value, self.value),
+        }
+    }
+}
+
 impl StoreLayerBuilder {
     fn new(store: Store) -> impl Future<Item = Self, Error = io::Error> + Send {
-        store.layer_store.create_base_layer().map(|builder| Self {
-            name: builder.name(),
-            builder: RwLock::new(Some(builder)),
-            store,
-        })
+        store
+            .layer_store
+            .create_base_layer()
+            .map(|builder| Self::wrap(builder, store))
     }
 
-    fn wrap(builder: Box<dyn LayerBuilder>, store: Store) -> Self {
+    fn wrap(mut builder: Box<dyn LayerBuilder>, store: Store) -> Self {
+        if let Some(rate) = store.bloom_filter_rate {
+            builder.set_bloom_filter(rate);
+        }
+
         StoreLayerBuilder {
             name: builder.name(),
             builder: RwLock::new(Some(builder)),
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+            length_limits: Arc::new(Mutex::new(LengthLimits::default())),
             store,
         }
     }
@@ -68,17 +121,212 @@ impl StoreLayerBuilder {
     }
 
     /// Returns the name of the layer being built
+    ///
+    /// The name is fixed when the builder is created, and is the name
+    /// the layer will have after it is committed. It can therefore be
+    /// used to refer to the layer before the commit happens.
+    ///
+    /// The exception is a store that deduplicates layers. If an
+    /// identical layer already exists, the commit returns that layer,
+    /// and no layer with this name is kept. Use the name of the layer
+    /// returned by `commit` to refer to it afterwards.
     pub fn name(&self) -> [u32; 5] {
         self.name
     }
 
+    /// Returns the store this builder will commit its layer to
+    pub fn store(&self) -> Store {
+        self.store.clone()
+    }
+
+    /// The id the given subject has in the parent layer, or None if it is not known there
+    ///
+    /// See `LayerBuilder` for when ids are stable across layers.
+    pub fn subject_id(
+        &self,
+        subject: &str,
+    ) -> impl Future<Item = Option<u64>, Error = io::Error> + Send {
+        let subject = subject.to_owned();
+        self.with_builder(move |b| b.subject_id(&subject))
+    }
+
+    /// The id the given predicate has in the parent layer, or None if it is not known there
+    pub fn predicate_id(
+        &self,
+        predicate: &str,
+    ) -> impl Future<Item = Option<u64>, Error = io::Error> + Send {
+        let predicate = predicate.to_owned();
+        self.with_builder(move |b| b.predicate_id(&predicate))
+    }
+
+    /// The id the given node object has in the parent layer, or None if it is not known there
+    pub fn object_node_id(
+        &self,
+        object: &str,
+    ) -> impl Future<Item = Option<u64>, Error = io::Error> + Send {
+        let object = object.to_owned();
+        self.with_builder(move |b| b.object_node_id(&object))
+    }
+
+    /// The id the given value object has in the parent layer, or None if it is not known there
+    pub fn object_value_id(
+        &self,
+        object: &str,
+    ) -> impl Future<Item = Option<u64>, Error = io::Error> + Send {
+        let object = object.to_owned();
+        self.with_builder(move |b| b.object_value_id(&object))
+    }
+
     /// Add a string triple
+    ///
+    /// Returns an `InvalidInput` error if one of the strings exceeds
+    /// a length limit set on this builder.
     pub fn add_string_triple(
         &self,
         triple: &StringTriple,
     ) -> impl Future<Item = (), Error = io::Error> + Send {
         let triple = triple.clone();
-        self.with_builder(move |b| b.add_string_triple(&triple))
+        let limits = *self
+            .length_limits
+            .lock()
+            .expect("length limits mutex should not be poisoned");
+        match limits.check(&triple) {
+            Ok(()) => future::Either::A(self.with_builder(move |b| b.add_string_triple(&triple))),
+            Err(e) => future::Either::B(future::err(e)),
+        }
+    }
+
+    /// Add a triple whose object is a node
+    pub fn add_node_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        object: &str,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.add_string_triple(&StringTriple::new_node(subject, predicate, object))
+    }
+
+    /// Add a triple whose object is a value
+    pub fn add_value_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        value: &str,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.add_string_triple(&StringTriple::new_value(subject, predicate, value))
+    }
+
+    /// Add a string triple, but only if the condition triple exists in the parent layer
+    ///
+    /// Returns whether the condition was met, and the triple was
+    /// therefore added. The condition is checked against the layer
+    /// this builder builds on, so triples added or removed through
+    /// this builder are not taken into account. For a base layer
+    /// builder, the condition is never met. Like `add_string_triple`,
+    /// this returns an `InvalidInput` error if one of the strings of
+    /// the added triple exceeds a length limit.
+    pub fn add_if_exists(
+        &self,
+        to_add: &StringTriple,
+        condition: &StringTriple,
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let to_add = to_add.clone();
+        let condition = condition.clone();
+        let limits = *self
+            .length_limits
+            .lock()
+            .expect("length limits mutex should not be poisoned");
+        match limits.check(&to_add) {
+            Ok(()) => future::Either::A(self.with_builder(move |b| {
+                let exists = b
+                    .parent()
+                    .map(|parent| parent.string_triple_exists(&condition))
+                    .unwrap_or(false);
+                if exists {
+                    b.add_string_triple(&to_add);
+                }
+
+                exists
+            })),
+            Err(e) => future::Either::B(future::err(e)),
+        }
+    }
+
+    /// Read N-Triples from the given reader, and add them all to this builder
+    ///
+    /// Returns the number of triples that were read. The input is
+    /// read line by line, so it is never held in memory as a whole,
+    /// and the builder is locked once for the whole import, rather
+    /// than once per triple. See `parse_ntriples_line` for how the
+    /// terms of N-Triples map onto nodes and values.
+    ///
+    /// A line that can't be parsed results in an `InvalidData` error
+    /// that mentions its line number, and a string that exceeds a
+    /// length limit set on this builder in an `InvalidInput` error.
+    /// The triples read before such an error stay in the builder.
+    pub fn import_ntriples<R: AsyncRead + Send + 'static>(
+        &self,
+        reader: R,
+    ) -> impl Future<Item = usize, Error = io::Error> + Send {
+        let limits = *self
+            .length_limits
+            .lock()
+            .expect("length limits mutex should not be poisoned");
+        let lines = stream::unfold(Some(BufReader::new(reader)), |reader| {
+            reader.map(|reader| {
+                tokio::io::read_until(reader, b'\n', Vec::new()).map(|(reader, line)| {
+                    // an empty read means the end of the input was reached
+                    let reader = if line.is_empty() { None } else { Some(reader) };
+                    (line, reader)
+                })
+            })
+        });
+
+        self.builder.write().then(move |b| {
+            let builder = b.expect("rwlock write should always succeed");
+            if builder.is_none() {
+                return future::Either::A(future::err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "builder has already been committed",
+                )));
+            }
+
+            future::Either::B(
+                lines
+                    .fold(
+                        (builder, 0, 0),
+                        move |(mut builder, line_number, count), line| {
+                            let line_number = line_number + 1;
+                            let triple = std::str::from_utf8(&line)
+                                .map_err(|_| {
+                                    io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8")
+                                })
+                                .and_then(parse_ntriples_line)
+                                .map_err(|e| {
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!("line {}: {}", line_number, e),
+                                    )
+                                })?;
+
+                            let count = match triple {
+                                None => count,
+                                Some(triple) => {
+                                    limits.check(&triple)?;
+                                    builder
+                                        .as_mut()
+                                        .expect("builder was checked to be uncommitted")
+                                        .add_string_triple(&triple);
+                                    count + 1
+                                }
+                            };
+
+                            Ok::<_, io::Error>((builder, line_number, count))
+                        },
+                    )
+                    .map(|(_, _, count)| count),
+            )
+        })
     }
 
     /// Add an id triple
@@ -106,36 +354,396 @@ impl StoreLayerBuilder {
         self.with_builder(move |b| b.remove_id_triple(triple))
     }
 
+    /// Remove every triple in the parent layer that has the given subject
+    ///
+    /// Returns the number of triples that were removed.
+    pub fn remove_subject(
+        &self,
+        subject: &str,
+    ) -> impl Future<Item = usize, Error = io::Error> + Send {
+        let subject = subject.to_owned();
+        self.with_builder(move |b| b.remove_subject(&subject))
+    }
+
+    /// Remove every triple in the parent layer that has the given node as subject or object
+    ///
+    /// Returns the number of triples that were removed.
+    pub fn remove_subject_fully(
+        &self,
+        subject: &str,
+    ) -> impl Future<Item = usize, Error = io::Error> + Send {
+        let subject = subject.to_owned();
+        self.with_builder(move |b| b.remove_subject_fully(&subject))
+    }
+
+    /// Drop added string triples that already exist in the parent layer
+    ///
+    /// This is off by default. See `LayerBuilder::set_skip_existing`.
+    pub fn set_skip_existing(
+        &self,
+        skip_existing: bool,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.with_builder(move |b| b.set_skip_existing(skip_existing))
+    }
+
+    /// Sort the dictionaries of the new layer with the given collation
+    ///
+    /// See `LayerBuilder::set_collation`. Layers that are not built
+    /// through a builder, like squashed layers and bulk loaded base
+    /// layers, are always sorted bytewise.
+    pub fn set_collation(
+        &self,
+        collation: Arc<dyn Collation>,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.with_builder(move |b| b.set_collation(collation))
+    }
+
+    /// Spill added triples to temporary files in the given directory once `threshold` of them are held in memory
+    ///
+    /// See `LayerBuilder::set_spill_threshold`. This is meant for
+    /// building very large base layers, and has no effect on child
+    /// layers.
+    pub fn set_spill_threshold<P: Into<PathBuf>>(
+        &self,
+        threshold: usize,
+        temp_dir: P,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let temp_dir = temp_dir.into();
+        self.with_builder(move |b| b.set_spill_threshold(threshold, temp_dir))
+    }
+
+    /// Limit the length in bytes of value objects added as string triples
+    ///
+    /// Adding a string triple with a longer value returns an
+    /// `InvalidInput` error. There is no limit by default.
+    pub fn set_max_value_length(
+        &self,
+        max: Option<usize>,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let limits = self.length_limits.clone();
+        self.with_builder(move |_| {
+            limits
+                .lock()
+                .expect("length limits mutex should not be poisoned")
+                .value = max;
+        })
+    }
+
+    /// Limit the length in bytes of subjects and node objects added as string triples
+    ///
+    /// There is no limit by default.
+    pub fn set_max_node_length(
+        &self,
+        max: Option<usize>,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let limits = self.length_limits.clone();
+        self.with_builder(move |_| {
+            limits
+                .lock()
+                .expect("length limits mutex should not be poisoned")
+                .node = max;
+        })
+    }
+
+    /// Limit the length in bytes of predicates added as string triples
+    ///
+    /// There is no limit by default.
+    pub fn set_max_predicate_length(
+        &self,
+        max: Option<usize>,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let limits = self.length_limits.clone();
+        self.with_builder(move |_| {
+            limits
+                .lock()
+                .expect("length limits mutex should not be poisoned")
+                .predicate = max;
+        })
+    }
+
+    /// Set a metadata entry for the layer being built
+    ///
+    /// Metadata is stored alongside the layer when it is committed,
+    /// and can be retrieved using `StoreLayer::metadata`.
+    pub fn set_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let metadata = self.metadata.clone();
+        let key = key.to_owned();
+        let value = value.to_owned();
+        self.with_builder(move |_| {
+            metadata
+                .lock()
+                .expect("metadata mutex should not be poisoned")
+                .insert(key, value);
+        })
+    }
+
     /// Commit the layer to storage
+    ///
+    /// If the layer store deduplicates layers, and an identical layer
+    /// already exists, that layer is returned instead of a new one.
     pub fn commit(&self) -> impl Future<Item = StoreLayer, Error = std::io::Error> + Send {
+        self.commit_with_progress(|_| {})
+    }
+
+    /// Commit the layer to storage, reporting progress to the given callback
+    ///
+    /// The callback is invoked at the start of every phase of writing
+    /// the layer, which allows showing progress for large commits.
+    /// See `CommitProgress`.
+    pub fn commit_with_progress<F: 'static + Fn(CommitProgress) + Send + Sync>(
+        &self,
+        progress: F,
+    ) -> impl Future<Item = StoreLayer, Error = std::io::Error> + Send {
+        let progress: CommitProgressCallback = Arc::new(progress);
         let store = self.store.clone();
         let name = self.name;
-        self.builder.write().then(move |b| {
-            let mut swap = b.expect("rwlock write should always succeed");
-            let mut builder = None;
+        let metadata = self.metadata.clone();
+        let builder = self.builder.clone();
+        self.store.while_writing(move || {
+            builder.write().then(move |b| {
+                let mut swap = b.expect("rwlock write should always succeed");
+                let mut builder = None;
 
-            std::mem::swap(&mut builder, &mut swap);
+                std::mem::swap(&mut builder, &mut swap);
 
-            let result: Box<dyn Future<Item = _, Error = _> + Send> = match builder {
-                None => Box::new(future::err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "builder has already been committed",
-                ))),
-                Some(builder) => Box::new(builder.commit_boxed().and_then(move |_| {
-                    store.layer_store.get_layer(name).map(move |layer| {
-                        StoreLayer::wrap(
-                            layer.expect("layer that was just created was not found in store"),
-                            store,
-                        )
-                    })
-                })),
-            };
+                let result: Box<dyn Future<Item = _, Error = _> + Send> = match builder {
+                    None => Box::new(future::err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "builder has already been committed",
+                    ))),
+                    Some(builder) => Box::new(
+                        builder
+                            .commit_boxed_with_progress(progress)
+                            .and_then({
+                                let store = store.clone();
+                                move |_| {
+                                    let metadata = metadata
+                                        .lock()
+                                        .expect("metadata mutex should not be poisoned")
+                                        .clone();
+                                    let result: Box<dyn Future<Item = _, Error = _> + Send> =
+                                        if metadata.is_empty() {
+                                            Box::new(future::ok(()))
+                                        } else {
+                                            store.layer_store.set_layer_metadata(name, metadata)
+                                        };
 
-            result
+                                    result
+                                }
+                            })
+                            .and_then({
+                                let store = store.clone();
+                                move |_| store.layer_store.deduplicate_layer(name)
+                            })
+                            .and_then(move |name| {
+                                store.layer_store.get_layer(name).and_then(move |layer| {
+                                    let layer = StoreLayer::wrap(
+                                        layer.expect(
+                                            "layer that was just created was not found in store",
+                                        ),
+                                        store.clone(),
+                                    );
+
+                                    store.after_commit(layer)
+                                })
+                            }),
+                    ),
+                };
+
+                result
+            })
+        })
+    }
+
+    /// Commit the layer to storage, marking it as staged
+    ///
+    /// A staged layer is one that is deliberately kept out of any
+    /// database until it is approved. Until it becomes reachable
+    /// from a database label, it is listed by
+    /// `Store::staged_layers`. The mark is stored as an entry in the
+    /// layer metadata, which is removed again if the commit fails.
+    pub fn commit_staged(&self) -> impl Future<Item = StoreLayer, Error = std::io::Error> + Send {
+        let inserted = self
+            .metadata
+            .lock()
+            .expect("metadata mutex should not be poisoned")
+            .insert(STAGED_METADATA_KEY.to_owned(), "true".to_owned())
+            .is_none();
+
+        let metadata = self.metadata.clone();
+        self.commit().or_else(move |e| {
+            if inserted {
+                metadata
+                    .lock()
+                    .expect("metadata mutex should not be poisoned")
+                    .remove(STAGED_METADATA_KEY);
+            }
+
+            Err(e)
+        })
+    }
+}
+
+/// A builder for base layers whose triples arrive in subject order
+///
+/// This is meant for append-heavy workloads such as time series,
+/// where subjects are added in increasing order. Rather than sorting
+/// all triples on commit like `StoreLayerBuilder` does, this builder
+/// checks the subject order as triples come in, and only sorts the
+/// triples of a single subject among themselves. Only the triples of
+/// the current subject are held in memory. Those of earlier subjects
+/// are written to a temporary file in the directory configured with
+/// `Store::with_squash_temp_dir`, which is run as blocking work. On
+/// commit, the triples are written out in the order they are in, in
+/// the same way as `Store::bulk_load_base_presorted`.
+///
+/// Subjects are compared as strings, so numeric subjects like
+/// timestamps need to be padded to a fixed width to be in order.
+pub struct AppendOnlyLayerBuilder {
+    state: Arc<Mutex<Option<AppendOnlyState>>>,
+    store: Store,
+}
+
+struct AppendOnlyState {
+    // the triples of all earlier subjects, created on the first subject change
+    spill: Option<SortedTripleSpill>,
+    // the triples of the subject that is currently being added
+    current: Vec<StringTriple>,
+}
+
+impl AppendOnlyState {
+    fn flush_current_subject(&mut self, temp_dir: &Path) -> io::Result<()> {
+        self.current.sort_unstable();
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.get_or_insert(SortedTripleSpill::new(temp_dir)?),
+        };
+        for triple in self.current.drain(..) {
+            spill.push(triple)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn builder_unusable_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "builder has already been committed, or failed to write its triples",
+    )
+}
+
+impl AppendOnlyLayerBuilder {
+    fn new(store: Store) -> Self {
+        AppendOnlyLayerBuilder {
+            state: Arc::new(Mutex::new(Some(AppendOnlyState {
+                spill: None,
+                current: Vec::new(),
+            }))),
+            store,
+        }
+    }
+
+    /// Add a string triple
+    ///
+    /// Returns an `InvalidInput` error if the subject of the triple
+    /// comes before the subject of the previously added triple. If the
+    /// triples of the previous subject can't be written to the
+    /// temporary file, the builder can't be used anymore.
+    pub fn add_string_triple(
+        &self,
+        triple: &StringTriple,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let state = self.state.clone();
+        let temp_dir = self.store.temp_dir();
+        let triple = triple.clone();
+        run_blocking(move || {
+            let mut guard = state
+                .lock()
+                .expect("append-only builder mutex should not be poisoned");
+            let state = guard.as_mut().ok_or_else(builder_unusable_error)?;
+            let current_subject = state.current.first().map(|t| t.subject.as_str());
+            match current_subject {
+                Some(current_subject) if current_subject > triple.subject.as_str() => {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "subject {} was added after subject {}",
+                            triple.subject, current_subject
+                        ),
+                    ))
+                }
+                Some(current_subject) if current_subject < triple.subject.as_str() => {
+                    if let Err(e) = state.flush_current_subject(&temp_dir) {
+                        *guard = None;
+                        return Err(e);
+                    }
+                    state.current.push(triple);
+                    Ok(())
+                }
+                _ => {
+                    state.current.push(triple);
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Add a triple whose object is a node
+    pub fn add_node_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        object: &str,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.add_string_triple(&StringTriple::new_node(subject, predicate, object))
+    }
+
+    /// Add a triple whose object is a value
+    pub fn add_value_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        value: &str,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.add_string_triple(&StringTriple::new_value(subject, predicate, value))
+    }
+
+    /// Commit the layer to storage
+    ///
+    /// The resulting layer is unattached to any database label. Use
+    /// `set_head` on a `NamedGraph` to attach it.
+    pub fn commit(&self) -> impl Future<Item = StoreLayer, Error = io::Error> + Send {
+        let state = self.state.clone();
+        let temp_dir = self.store.temp_dir();
+        let store = self.store.clone();
+        run_blocking(move || {
+            let mut state = state
+                .lock()
+                .expect("append-only builder mutex should not be poisoned")
+                .take()
+                .ok_or_else(builder_unusable_error)?;
+            state.flush_current_subject(&temp_dir)?;
+
+            state.spill.map(|spill| spill.into_triples()).transpose()
+        })
+        .and_then(move |triples| {
+            let triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send> = match triples {
+                Some(triples) => Box::new(triples),
+                None => Box::new(std::iter::empty()),
+            };
+            store.create_base_layer_from_sorted(triples)
         })
     }
 }
 
+/// The layer metadata key used to mark a layer as staged.
+const STAGED_METADATA_KEY: &str = "terminus-store:staged";
+
 /// A layer that keeps track of the store it came out of, allowing the creation of a layer builder on top of this layer
 #[derive(Clone)]
 pub struct StoreLayer {
@@ -149,6 +757,17 @@ impl StoreLayer {
         StoreLayer { layer, store }
     }
 
+    /// Wrap an arbitrary layer as a `StoreLayer` belonging to the given store
+    ///
+    /// This is meant for building fixtures in tests. The layer does
+    /// not have to be stored in `store`, but operations that go
+    /// through the store, like `open_write`, then only work if a
+    /// layer with the same name is stored there as well.
+    #[cfg(feature = "test-util")]
+    pub fn from_parts(layer: Arc<dyn Layer>, store: Store) -> Self {
+        StoreLayer::wrap(layer, store)
+    }
+
     /// Create a layer builder based on this layer
     pub fn open_write(&self) -> impl Future<Item = StoreLayerBuilder, Error = io::Error> + Send {
         let store = self.store.clone();
@@ -158,6 +777,77 @@ impl StoreLayer {
             .map(move |layer| StoreLayerBuilder::wrap(layer, store))
     }
 
+    /// Returns the metadata that was stored with this layer
+    ///
+    /// Layers that were committed without metadata return an empty map.
+    pub fn metadata(
+        &self,
+    ) -> impl Future<Item = HashMap<String, String>, Error = io::Error> + Send {
+        self.store.layer_store.layer_metadata(self.layer.name())
+    }
+
+    /// Create a new base layer containing exactly the triples that are visible in this layer
+    ///
+    /// The new layer has no parent, so lookups in it no longer have
+    /// to go through a stack of layers. It is committed to the store
+    /// but not attached to any database label. Use `set_head` on a
+    /// `NamedGraph` to attach it.
+    ///
+    /// Squashing sorts the triples with an external merge sort, so
+    /// memory use is bounded regardless of the size of the layer.
+    /// Sorted runs are spilled to the directory configured with
+    /// `Store::with_squash_temp_dir`, or to the system's temporary
+    /// directory if none was configured.
+    pub fn squash(&self) -> impl Future<Item = StoreLayer, Error = io::Error> + Send {
+        let store = self.store.clone();
+        let temp_dir = store.temp_dir();
+        self.store
+            .layer_store
+            .create_squashed_base_layer(self.layer.clone(), temp_dir)
+            .and_then({
+                let store = store.clone();
+                move |name| store.layer_store.deduplicate_layer(name)
+            })
+            .and_then(move |name| {
+                store.layer_store.get_layer(name).map(move |layer| {
+                    StoreLayer::wrap(
+                        layer.expect("layer that was just created was not found in store"),
+                        store,
+                    )
+                })
+            })
+    }
+
+    /// Returns the triples added and removed between the given ancestor and this layer
+    ///
+    /// Only the changes made by the layers in between are gone
+    /// through. The triples of the diff are sorted, so the same
+    /// changes always result in the same diff. See
+    /// `LayerDiff::from_ancestor` for details. This returns an error
+    /// of kind `InvalidInput` if `ancestor` is not an ancestor of this
+    /// layer, and of kind `InvalidData` if the layers are corrupt.
+    pub fn delta_from(
+        &self,
+        ancestor: &StoreLayer,
+    ) -> impl Future<Item = LayerDiff, Error = io::Error> + Send {
+        future::result(LayerDiff::from_ancestor(&*ancestor.layer, &*self.layer))
+    }
+
+    /// Write all triples that are visible in this layer to the given writer as N-Triples
+    ///
+    /// Triples are resolved and written one at a time, so the output
+    /// is never held in memory as a whole. Since the writer is
+    /// synchronous, the export runs with `tokio_threadpool::blocking`
+    /// when on a threadpool. See `export_ntriples` for the details of
+    /// the output.
+    pub fn export_ntriples<W: io::Write + Send>(
+        &self,
+        mut writer: W,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let layer = self.layer.clone();
+        run_blocking(move || export_ntriples(&*layer, &mut writer))
+    }
+
     pub fn parent(&self) -> Option<StoreLayer> {
         let parent = self.layer.parent();
 
@@ -178,6 +868,50 @@ impl Layer for StoreLayer {
         self.layer.parent()
     }
 
+    fn layer_type(&self) -> LayerType {
+        self.layer.layer_type()
+    }
+
+    fn may_contain_triple(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        self.layer.may_contain_triple(subject, predicate, object)
+    }
+
+    fn scan_hint(&self, hint: ScanHint) {
+        self.layer.scan_hint(hint)
+    }
+
+    fn statistics(&self) -> Arc<LayerStatistics> {
+        self.layer.statistics()
+    }
+
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        self.layer.triple_addition_counts()
+    }
+
+    fn triple_removal_counts(&self) -> LayerTripleCounts {
+        self.layer.triple_removal_counts()
+    }
+
+    fn predicate_addition_count(&self, predicate: u64) -> usize {
+        self.layer.predicate_addition_count(predicate)
+    }
+
+    fn predicate_removal_count(&self, predicate: u64) -> usize {
+        self.layer.predicate_removal_count(predicate)
+    }
+
+    fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
+        self.layer.triples()
+    }
+
+    fn collation(&self) -> Arc<dyn Collation> {
+        self.layer.collation()
+    }
+
+    fn triples_for_subjects(&self, subjects: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        self.layer.triples_for_subjects(subjects)
+    }
+
     fn node_and_value_count(&self) -> usize {
         self.layer.node_and_value_count()
     }
@@ -190,6 +924,13 @@ impl Layer for StoreLayer {
         self.layer.node_dict_len()
     }
 
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a> {
+        self.layer.node_dict_entries_with_prefix(prefix)
+    }
+
     fn node_dict_get(&self, id: usize) -> Option<String> {
         self.layer.node_dict_get(id)
     }
@@ -206,6 +947,18 @@ impl Layer for StoreLayer {
         self.layer.value_dict_get(id)
     }
 
+    fn node_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.layer.node_dict_get_checked(id)
+    }
+
+    fn predicate_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.layer.predicate_dict_get_checked(id)
+    }
+
+    fn value_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.layer.value_dict_get_checked(id)
+    }
+
     fn predicate_dict_id(&self, predicate: &str) -> Option<u64> {
         self.layer.predicate_dict_id(predicate)
     }
@@ -318,7 +1071,48 @@ impl NamedGraph {
 
     /// Returns the layer this database points at
     pub fn head(&self) -> impl Future<Item = Option<StoreLayer>, Error = io::Error> + Send {
-        let store = self.store.clone();
+        self.head_with_version().map(|(head, _)| head)
+    }
+
+    /// Returns the name of the layer this database points at, or None if it has no head
+    ///
+    /// Unlike `head`, this only reads the database label, and does
+    /// not load the layer.
+    pub fn head_name(&self) -> impl Future<Item = Option<[u32; 5]>, Error = io::Error> + Send {
+        self.store
+            .label_store
+            .get_label(&self.label)
+            .and_then(|label| match label {
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "database not found",
+                )),
+                Some(label) => Ok(label.layer),
+            })
+    }
+
+    /// Returns true if this database has a head layer without any triples
+    ///
+    /// A database without a head is not considered empty, as it
+    /// doesn't point at any data at all, so this returns false for
+    /// it. Use `head_name` to check whether a database has a head. A
+    /// head layer whose triples were all removed again is empty.
+    pub fn is_empty(&self) -> impl Future<Item = bool, Error = io::Error> + Send {
+        self.head().map(|head| match head {
+            None => false,
+            Some(layer) => layer.triples().next().is_none(),
+        })
+    }
+
+    /// Returns the layer this database points at, along with the version of the database label
+    ///
+    /// Both are taken from a single read of the label, so the version
+    /// is the one that belongs to this head. The version goes up by
+    /// one every time the head of the database is changed.
+    pub fn head_with_version(
+        &self,
+    ) -> impl Future<Item = (Option<StoreLayer>, u64), Error = io::Error> + Send {
+        let store = self.store.clone();
         store
             .label_store
             .get_label(&self.label)
@@ -328,12 +1122,16 @@ impl NamedGraph {
                     "database not found",
                 ))),
                 Some(new_label) => {
+                    let version = new_label.version;
                     let result: Box<dyn Future<Item = _, Error = _> + Send> = match new_label.layer
                     {
-                        None => Box::new(future::ok(None)),
+                        None => Box::new(future::ok((None, version))),
                         Some(layer) => {
                             Box::new(store.layer_store.get_layer(layer).map(move |layer| {
-                                layer.map(move |layer| StoreLayer::wrap(layer, store))
+                                (
+                                    layer.map(move |layer| StoreLayer::wrap(layer, store)),
+                                    version,
+                                )
                             }))
                         }
                     };
@@ -342,64 +1140,415 @@ impl NamedGraph {
             })
     }
 
+    /// Open a read-only snapshot of this database as it was at the given version
+    ///
+    /// This returns None if the database does not exist, did not have
+    /// a head at that version, or if the version is not known to the
+    /// label store. Memory stores and directory stores remember every
+    /// version. See `DirectoryLabelStore` for what this costs on disk.
+    pub fn head_at_version(
+        &self,
+        version: u64,
+    ) -> impl Future<Item = Option<NamedGraphSnapshot>, Error = io::Error> + Send {
+        let store = self.store.clone();
+        self.store
+            .label_store
+            .get_label_at_version(&self.label, version)
+            .and_then(move |label| {
+                let (label, head) = match label.and_then(|l| l.layer.map(|head| (l.name, head))) {
+                    None => return future::Either::A(future::ok(None)),
+                    Some(label_and_head) => label_and_head,
+                };
+
+                future::Either::B(store.layer_store.get_layer(head).map(move |head| {
+                    head.map(|head| NamedGraphSnapshot {
+                        label,
+                        head: StoreLayer::wrap(head, store),
+                    })
+                }))
+            })
+    }
+
+    /// Create a layer builder on top of the current head of this database
+    ///
+    /// If the database doesn't have a head yet, this returns a
+    /// builder for a new base layer instead.
+    pub fn open_write(&self) -> impl Future<Item = StoreLayerBuilder, Error = io::Error> + Send {
+        let store = self.store.clone();
+        self.head().and_then(move |head| {
+            let result: Box<dyn Future<Item = _, Error = _> + Send> = match head {
+                Some(layer) => Box::new(layer.open_write()),
+                None => Box::new(store.create_base_layer()),
+            };
+
+            result
+        })
+    }
+
     /// Set the database label to the given layer if it is a valid ancestor, returning false otherwise
     pub fn set_head(
         &self,
         layer: &StoreLayer,
     ) -> impl Future<Item = bool, Error = io::Error> + Send {
         let store = self.store.clone();
+        let sync_store = self.store.clone();
         let layer_name = layer.name();
+        let depth = layer.chain_depth();
         let cloned_layer = layer.layer.clone();
-        store
-            .label_store
-            .get_label(&self.label)
-            .and_then(move |label| {
-                let result: Box<dyn Future<Item = _, Error = _> + Send> = match label {
-                    None => Box::new(future::err(io::Error::new(
+        let label = self.label.clone();
+        self.store.while_writing(move || {
+            store
+                .check_layer_exists(layer_name)
+                .and_then({
+                    let store = store.clone();
+                    move |_| store.label_store.get_label(&label)
+                })
+                .and_then(move |label| {
+                    let result: Box<dyn Future<Item = _, Error = _> + Send> = match label {
+                        None => Box::new(future::err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "label not found",
+                        ))),
+                        Some(label) => Box::new(
+                            {
+                                let result: Box<dyn Future<Item = _, Error = _> + Send> =
+                                    match label.layer {
+                                        None => Box::new(future::ok(true)),
+                                        Some(layer_name) => Box::new(
+                                            store.layer_store.get_layer(layer_name).map(move |l| {
+                                                l.map(|l| l.is_ancestor_of(&*cloned_layer))
+                                                    .unwrap_or(false)
+                                            }),
+                                        ),
+                                    };
+
+                                result
+                            }
+                            .and_then(move |b| {
+                                let result: Box<dyn Future<Item = _, Error = _> + Send> = if b {
+                                    let name = label.name.clone();
+                                    // the label may have been changed or deleted since it was read
+                                    Box::new(
+                                        store.label_store.set_label(&label, layer_name).and_then(
+                                            move |set| {
+                                                if set.is_some() {
+                                                    store.notify_change(&name, layer_name);
+                                                }
+                                                store.limit_head_depth(set, depth)
+                                            },
+                                        ),
+                                    )
+                                } else {
+                                    Box::new(future::ok(false))
+                                };
+
+                                result
+                            }),
+                        ),
+                    };
+                    result
+                })
+                .and_then(move |set| sync_store.sync_label_change(set))
+        })
+    }
+
+    /// Set the database label to the given layer if the label is still at the given version, returning false otherwise
+    ///
+    /// The version to compare against can be retrieved with
+    /// `head_with_version`. Unlike `set_head`, this does not require
+    /// the current head to be an ancestor of the new head. Comparing
+    /// the version and setting the head happen as one atomic
+    /// operation, so if two writers both observed the same version,
+    /// only one of them will succeed.
+    pub fn set_head_if_version(
+        &self,
+        layer: &StoreLayer,
+        expected_version: u64,
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let store = self.store.clone();
+        let sync_store = self.store.clone();
+        let layer_name = layer.name();
+        let depth = layer.chain_depth();
+        let label = self.label.clone();
+        self.store.while_writing(move || {
+            store
+                .check_layer_exists(layer_name)
+                .and_then({
+                    let store = store.clone();
+                    move |_| store.label_store.get_label(&label)
+                })
+                .and_then(move |label| match label {
+                    None => future::Either::A(future::err(io::Error::new(
                         io::ErrorKind::NotFound,
                         "label not found",
                     ))),
-                    Some(label) => Box::new(
-                        {
-                            let result: Box<dyn Future<Item = _, Error = _> + Send> = match label
-                                .layer
-                            {
-                                None => Box::new(future::ok(true)),
-                                Some(layer_name) => Box::new(
-                                    store.layer_store.get_layer(layer_name).map(move |l| {
-                                        l.map(|l| l.is_ancestor_of(&*cloned_layer)).unwrap_or(false)
+                    Some(label) if label.version != expected_version => {
+                        future::Either::A(future::ok(false))
+                    }
+                    Some(label) => {
+                        future::Either::B(store.label_store.set_label(&label, layer_name).and_then(
+                            move |result| {
+                                if result.is_some() {
+                                    store.notify_change(&label.name, layer_name);
+                                }
+                                store.limit_head_depth(result, depth)
+                            },
+                        ))
+                    }
+                })
+                .and_then(move |set| sync_store.sync_label_change(set))
+        })
+    }
+
+    /// Replace the head of this database with a squashed copy of it, returning false if it was not replaced
+    ///
+    /// A squashed layer is not a descendant of the head it was made
+    /// from, so `set_head` won't accept it. This squashes the head
+    /// like `StoreLayer::squash`, keeping its metadata, and then
+    /// points the database at the squashed layer, as long as the head
+    /// did not move in the meantime. Nothing is replaced if the head
+    /// has no ancestors. The old layers are not deleted.
+    pub fn squash_head(&self) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let store = self.store.clone();
+        let label = self.label.clone();
+        self.store.while_writing(move || {
+            store
+                .label_store
+                .get_label(&label)
+                .and_then(move |label| match label {
+                    None => future::Either::A(future::err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "label not found",
+                    ))),
+                    Some(label) => future::Either::B(
+                        store
+                            .compact_head(label, SquashedHeads::new())
+                            .map(|(_, _, changed)| changed),
+                    ),
+                })
+        })
+    }
+}
+
+/// A read-only view of a database, pinned to a layer from its history
+///
+/// Unlike a `NamedGraph`, a snapshot does not follow its label when
+/// the label moves to a new layer. Its head is always the layer it
+/// was opened at.
+#[derive(Clone)]
+pub struct NamedGraphSnapshot {
+    label: String,
+    head: StoreLayer,
+}
+
+impl NamedGraphSnapshot {
+    pub fn name(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns the layer this snapshot is pinned to
+    pub fn head(&self) -> &StoreLayer {
+        &self.head
+    }
+
+    /// Returns the name of the layer this snapshot is pinned to
+    pub fn head_name(&self) -> [u32; 5] {
+        self.head.name()
+    }
+}
+
+/// A set of database heads to be set all at once
+///
+/// Staged heads are only applied on `commit`. Either all databases
+/// are moved to their new head, or none are.
+pub struct Transaction {
+    store: Store,
+    heads: Vec<(String, [u32; 5], Arc<dyn Layer>)>,
+}
+
+impl Transaction {
+    fn new(store: Store) -> Self {
+        Transaction {
+            store,
+            heads: Vec::new(),
+        }
+    }
+
+    /// Stage the given layer as the new head of the given database
+    ///
+    /// This replaces any head staged earlier for the same database.
+    pub fn set_head(&mut self, database: &NamedGraph, layer: &StoreLayer) {
+        self.stage(database.name(), layer);
+    }
+
+    fn stage(&mut self, database: &str, layer: &StoreLayer) {
+        self.heads.retain(|(name, _, _)| name != database);
+        self.heads
+            .push((database.to_owned(), layer.name(), layer.layer.clone()));
+    }
+
+    /// Set all staged heads, returning false if none were set
+    ///
+    /// Like `NamedGraph::set_head`, this requires the current head of
+    /// every database to be an ancestor of its new head. If that is
+    /// not the case for any of the databases, or if any of them moved
+    /// to another head in the meantime, no head is set at all.
+    ///
+    /// This is not atomic with regard to I/O errors. When the heads
+    /// are stored in a directory, an error while writing the label
+    /// files may leave some databases at their new head and others at
+    /// their old one. See `LabelStore::set_labels`.
+    pub fn commit(self) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let store = self.store.clone();
+        let checks = self
+            .heads
+            .into_iter()
+            .map(move |(name, layer_name, layer)| {
+                let store = store.clone();
+                store
+                    .check_layer_exists(layer_name)
+                    .and_then({
+                        let store = store.clone();
+                        move |_| store.label_store.get_label(&name)
+                    })
+                    .and_then(move |label| match label {
+                        None => future::Either::A(future::err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "label not found",
+                        ))),
+                        Some(label) => {
+                            let valid = match label.layer {
+                                None => future::Either::A(future::ok(true)),
+                                Some(current) => future::Either::B(
+                                    store.layer_store.get_layer(current).map(move |l| {
+                                        l.map(|l| l.is_ancestor_of(&*layer)).unwrap_or(false)
                                     }),
                                 ),
                             };
 
-                            result
+                            future::Either::B(valid.map(move |valid| (label, layer_name, valid)))
                         }
-                        .and_then(move |b| {
-                            let result: Box<dyn Future<Item = _, Error = _> + Send> = if b {
-                                Box::new(
-                                    store
-                                        .label_store
-                                        .set_label(&label, layer_name)
-                                        .map(|_| true),
-                                )
-                            } else {
-                                Box::new(future::ok(false))
-                            };
+                    })
+            });
 
-                            result
-                        }),
-                    ),
-                };
-                result
-            })
+        let store = self.store;
+        let sync_store = store.clone();
+        store.clone().while_writing(move || {
+            future::join_all(checks)
+                .and_then(move |checked| {
+                    if checked.iter().any(|(_, _, valid)| !valid) {
+                        future::Either::A(future::ok(false))
+                    } else {
+                        let changes: Vec<_> = checked
+                            .iter()
+                            .map(|(label, layer_name, _)| (label.name.clone(), *layer_name))
+                            .collect();
+                        let updates = checked
+                            .into_iter()
+                            .map(|(label, layer_name, _)| (label, Some(layer_name)))
+                            .collect();
+                        future::Either::B(store.label_store.set_labels(updates).map(
+                            move |result| {
+                                if result.is_some() {
+                                    for (name, layer_name) in changes {
+                                        store.notify_change(&name, layer_name);
+                                    }
+                                }
+                                result.is_some()
+                            },
+                        ))
+                    }
+                })
+                .and_then(move |set| sync_store.sync_label_change(set))
+        })
+    }
+}
+
+/// What a change stream does with events its consumer has not picked up yet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChangeStreamPolicy {
+    /// Keep all pending events, no matter how many there are
+    #[default]
+    Buffer,
+    /// Keep at most the given number of pending events, dropping any
+    /// new events that come in while the buffer is full
+    DropNewest(usize),
+}
+
+/// Return an `InvalidInput` error if the given policy can't hold any events
+fn check_change_stream_policy(policy: ChangeStreamPolicy) -> Result<(), io::Error> {
+    match policy {
+        ChangeStreamPolicy::DropNewest(0) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "change stream capacity should be at least 1",
+        )),
+        _ => Ok(()),
+    }
+}
+
+enum ChangeSender {
+    Buffer(mpsc::UnboundedSender<(String, [u32; 5])>),
+    DropNewest(mpsc::Sender<(String, [u32; 5])>),
+}
+
+impl ChangeSender {
+    /// Send an event, returning false if the receiving end is gone
+    fn send(&mut self, event: (String, [u32; 5])) -> bool {
+        match self {
+            ChangeSender::Buffer(sender) => sender.unbounded_send(event).is_ok(),
+            ChangeSender::DropNewest(sender) => match sender.try_send(event) {
+                Ok(()) => true,
+                Err(e) => !e.is_disconnected(),
+            },
+        }
     }
 }
 
+/// Heads squashed by `Store::compact`, mapped to their squashed layer
+type SquashedHeads = HashMap<[u32; 5], [u32; 5]>;
+
+/// What `Store::compact` did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// The number of databases whose head was replaced by a squashed base layer
+    pub chains_shortened: usize,
+    /// The number of layers that were deleted
+    pub layers_deleted: usize,
+    /// The number of bytes freed on disk, which is 0 for memory stores
+    pub bytes_reclaimed: u64,
+}
+
+/// The outcome of `Store::verify_all`, as pairs of database name and verification result
+pub type VerifyAllResult = Vec<(String, Result<(), String>)>;
+
 /// A store, storing a set of layers and database labels pointing to these layers
+///
+/// Dropping a store does not guarantee that everything written
+/// through it is durably stored. Call `flush` before shutting down
+/// to ensure this.
 #[derive(Clone)]
 pub struct Store {
     label_store: Arc<dyn LabelStore>,
     layer_store: Arc<dyn LayerStore>,
+    bloom_filter_rate: Option<f64>,
+    squash_temp_dir: Option<PathBuf>,
+    sync_on_commit: bool,
+    max_chain_depth: Option<usize>,
+    change_stream_policy: ChangeStreamPolicy,
+    change_senders: Arc<Mutex<Vec<ChangeSender>>>,
+    // held shared by writes and exclusively by compaction
+    maintenance_lock: RwLock<()>,
+    // set when built by `StoreBuilder` as a directory store, so copies can be opened the same way
+    directory_options: Option<DirectoryStoreOptions>,
+}
+
+/// The options of a directory store that live in its label and layer stores
+#[derive(Clone)]
+struct DirectoryStoreOptions {
+    compression: Compression,
+    deduplicate: bool,
+    cache_capacity: usize,
+    lock_pool: Option<LockPool>,
 }
 
 impl Store {
@@ -411,12 +1560,244 @@ impl Store {
         Store {
             label_store: Arc::new(label_store),
             layer_store: Arc::new(layer_store),
+            bloom_filter_rate: None,
+            squash_temp_dir: None,
+            sync_on_commit: false,
+            max_chain_depth: None,
+            change_stream_policy: ChangeStreamPolicy::default(),
+            change_senders: Arc::new(Mutex::new(Vec::new())),
+            maintenance_lock: RwLock::new(()),
+            directory_options: None,
+        }
+    }
+
+    /// Create a store that keeps everything in memory, for use in tests
+    ///
+    /// This is the same as `open_memory_store`.
+    #[cfg(feature = "test-util")]
+    pub fn memory_for_tests() -> Store {
+        open_memory_store()
+    }
+
+    /// Build a bloom filter for every layer committed through this store
+    ///
+    /// A bloom filter allows `triple_exists` to quickly answer false
+    /// for most triples that were never added, without going through
+    /// the layer indexes. This pays off for large layers that are
+    /// queried for many missing triples.
+    ///
+    /// The filter claims to contain a missing triple with the given
+    /// false positive rate, in which case the indexes are still
+    /// consulted. A lower rate costs more space: about 9.6 bits per
+    /// added triple for a rate of 1%, and about 14.4 bits per added
+    /// triple for a rate of 0.1%. Layers that were committed without
+    /// a bloom filter keep working as before.
+    ///
+    /// A rate that is not between 0 and 1 results in an
+    /// `InvalidInput` error.
+    pub fn with_bloom_filter(mut self, false_positive_rate: f64) -> Result<Store, io::Error> {
+        check_false_positive_rate(false_positive_rate)?;
+        self.bloom_filter_rate = Some(false_positive_rate);
+
+        Ok(self)
+    }
+
+    /// Put the temporary files used for squashing layers in the given directory
+    ///
+    /// Squashing a large layer spills sorted runs of triples to disk.
+    /// Pointing this at fast scratch storage speeds up squashing. By
+    /// default, the system's temporary directory is used. Loads of
+    /// presorted triples, including `AppendOnlyLayerBuilder`, spill
+    /// their triples to the same directory.
+    pub fn with_squash_temp_dir<P: Into<PathBuf>>(mut self, path: P) -> Store {
+        self.squash_temp_dir = Some(path.into());
+
+        self
+    }
+
+    /// The directory to put temporary files in. See `Store::with_squash_temp_dir`.
+    fn temp_dir(&self) -> PathBuf {
+        self.squash_temp_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Set what change streams opened after this call do when their consumer lags behind
+    ///
+    /// By default, change streams buffer every event. A capacity of
+    /// 0 for `ChangeStreamPolicy::DropNewest` results in an
+    /// `InvalidInput` error.
+    pub fn with_change_stream_policy(
+        mut self,
+        policy: ChangeStreamPolicy,
+    ) -> Result<Store, io::Error> {
+        check_change_stream_policy(policy)?;
+        self.change_stream_policy = policy;
+
+        Ok(self)
+    }
+
+    /// Return a stream of database head changes made through this store
+    ///
+    /// Every successful `NamedGraph::set_head`,
+    /// `NamedGraph::set_head_if_version` and `Transaction::commit`
+    /// yields the name of the database and the name of its new head,
+    /// in the order in which the heads were set. Only changes made
+    /// after this call, through this store or one of its clones, are
+    /// reported. Changes made by other processes sharing the same
+    /// storage are not seen.
+    ///
+    /// If the stream is not consumed fast enough, events are
+    /// buffered or dropped according to the store's
+    /// `ChangeStreamPolicy`. Dropping the stream unsubscribes it.
+    pub fn change_stream(&self) -> impl Stream<Item = (String, [u32; 5]), Error = io::Error> {
+        let (sender, receiver): (_, Box<dyn Stream<Item = _, Error = ()> + Send>) =
+            match self.change_stream_policy {
+                ChangeStreamPolicy::Buffer => {
+                    let (sender, receiver) = mpsc::unbounded();
+                    (ChangeSender::Buffer(sender), Box::new(receiver))
+                }
+                ChangeStreamPolicy::DropNewest(capacity) => {
+                    // the channel always has room for one message per
+                    // sender on top of its buffer
+                    let (sender, receiver) = mpsc::channel(capacity - 1);
+                    (ChangeSender::DropNewest(sender), Box::new(receiver))
+                }
+            };
+
+        self.change_senders
+            .lock()
+            .expect("change senders mutex should not be poisoned")
+            .push(sender);
+
+        receiver.map_err(|_| unreachable!("receiving a change event cannot fail"))
+    }
+
+    /// Apply the commit options of this store to a freshly committed layer
+    fn after_commit(
+        &self,
+        layer: StoreLayer,
+    ) -> Box<dyn Future<Item = StoreLayer, Error = io::Error> + Send> {
+        if self.sync_on_commit {
+            Box::new(self.layer_store.flush().map(move |_| layer))
+        } else {
+            Box::new(future::ok(layer))
+        }
+    }
+
+    /// Run the future returned by `f` while holding the maintenance lock of this store shared
+    ///
+    /// Commits and head changes run this way, so they can go on
+    /// alongside each other, but not while `compact` holds the lock
+    /// exclusively. The lock is shared between clones of this store,
+    /// but not with other stores or processes using the same
+    /// storage.
+    fn while_writing<T, F, R>(&self, f: F) -> impl Future<Item = T, Error = io::Error> + Send
+    where
+        T: 'static + Send,
+        F: 'static + FnOnce() -> R + Send,
+        R: 'static + Future<Item = T, Error = io::Error> + Send,
+    {
+        self.maintenance_lock.read().then(move |guard| {
+            let guard = guard.expect("rwlock read should always succeed");
+            f().then(move |result| {
+                drop(guard);
+                result
+            })
+        })
+    }
+
+    /// Return a `NotFound` error if the given layer is no longer in the layer store
+    ///
+    /// A layer that was committed but not yet made reachable from a
+    /// database is deleted by `compact`. This keeps a database from
+    /// being pointed at such a layer afterwards.
+    fn check_layer_exists(
+        &self,
+        name: [u32; 5],
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.layer_store
+            .get_layer(name)
+            .and_then(|layer| match layer {
+                Some(_) => Ok(()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "layer not found, it may have been deleted by a compaction",
+                )),
+            })
+    }
+
+    /// Squash the head a label was just set to if its stack is deeper than the maximum chain depth, returning whether the label was set
+    ///
+    /// `label` is the label as returned by setting it, and `depth`
+    /// the chain depth of its new head. The squashed head replaces
+    /// the head in the same way as `NamedGraph::squash_head`, so it
+    /// is only put in place if nobody moved the label in between.
+    fn limit_head_depth(
+        &self,
+        label: Option<Label>,
+        depth: usize,
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        match (label, self.max_chain_depth) {
+            (Some(label), Some(max)) if depth > max => Box::new(
+                self.clone()
+                    .compact_head(label, SquashedHeads::new())
+                    .map(|_| true),
+            ),
+            (label, _) => Box::new(future::ok(label.is_some())),
+        }
+    }
+
+    /// Squash the given layer, copying its metadata to the squashed layer
+    fn squash_keeping_metadata(
+        &self,
+        layer: &StoreLayer,
+    ) -> Box<dyn Future<Item = StoreLayer, Error = io::Error> + Send> {
+        let layer_store = self.layer_store.clone();
+        let original = layer.name();
+        Box::new(layer.squash().and_then(move |squashed| {
+            let name = squashed.name();
+            layer_store
+                .layer_metadata(original)
+                .and_then(move |metadata| {
+                    let result: Box<dyn Future<Item = _, Error = _> + Send> = if metadata.is_empty()
+                    {
+                        Box::new(future::ok(()))
+                    } else {
+                        layer_store.set_layer_metadata(name, metadata)
+                    };
+
+                    result
+                })
+                .map(move |_| squashed)
+        }))
+    }
+
+    /// Flush the label store after a head change if this store syncs on commit
+    fn sync_label_change(
+        &self,
+        changed: bool,
+    ) -> Box<dyn Future<Item = bool, Error = io::Error> + Send> {
+        if changed && self.sync_on_commit {
+            Box::new(self.label_store.flush().map(move |_| changed))
+        } else {
+            Box::new(future::ok(changed))
         }
     }
 
+    fn notify_change(&self, label: &str, layer: [u32; 5]) {
+        self.change_senders
+            .lock()
+            .expect("change senders mutex should not be poisoned")
+            .retain_mut(|sender| sender.send((label.to_owned(), layer)));
+    }
+
     /// Create a new database with the given name
     ///
-    /// If the database already exists, this will return an error
+    /// If the database already exists, this will return an error of
+    /// kind `AlreadyExists` that wraps a `DatabaseAlreadyExists`. If
+    /// several callers create the same database at the same time,
+    /// exactly one of them succeeds.
     pub fn create(
         &self,
         label: &str,
@@ -427,78 +1808,2338 @@ impl Store {
             .map(move |label| NamedGraph::new(label.name, store))
     }
 
-    /// Open an existing database with the given name, or None if it does not exist
-    pub fn open(
-        &self,
-        label: &str,
-    ) -> impl Future<Item = Option<NamedGraph>, Error = std::io::Error> {
-        let store = self.clone();
-        self.label_store
-            .get_label(label)
-            .map(move |label| label.map(|label| NamedGraph::new(label.name, store)))
+    /// Open an existing database with the given name, or None if it does not exist
+    pub fn open(
+        &self,
+        label: &str,
+    ) -> impl Future<Item = Option<NamedGraph>, Error = std::io::Error> {
+        let store = self.clone();
+        self.label_store
+            .get_label(label)
+            .map(move |label| label.map(|label| NamedGraph::new(label.name, store)))
+    }
+
+    /// Delete the database with the given name, returning false if it did not exist
+    ///
+    /// This removes the database label and its history. The layers it
+    /// pointed at are kept, as other databases may share them. If the
+    /// head of the database is being set at the same time, that either
+    /// completes before the database is deleted, or returns false.
+    pub fn delete(&self, label: &str) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let store = self.clone();
+        self.label_store
+            .delete_label(label)
+            .and_then(move |deleted| store.sync_label_change(deleted))
+    }
+
+    /// Check that the head layer of every database can be read
+    ///
+    /// For every database, this loads its head layer and runs
+    /// `Layer::verify` on it, which reads all of its data and that of
+    /// its ancestors. The result holds the name of each database,
+    /// sorted by name, along with either `Ok(())` or a message
+    /// describing what is wrong. Databases without a head are always
+    /// ok. Only a failure to list the databases is returned as an
+    /// error.
+    pub fn verify_all(&self) -> impl Future<Item = VerifyAllResult, Error = io::Error> + Send {
+        let layer_store = self.layer_store.clone();
+        self.label_store.labels().and_then(move |mut labels| {
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            stream::iter_ok(labels)
+                .and_then(move |label| {
+                    let result: Box<dyn Future<Item = _, Error = io::Error> + Send> =
+                        match label.layer {
+                            None => Box::new(future::ok(Ok(()))),
+                            Some(head) => Box::new(layer_store.get_layer(head).then(|layer| {
+                                Ok(match layer {
+                                    Ok(Some(layer)) => layer.verify().map_err(|e| e.to_string()),
+                                    Ok(None) => Err("head layer not found".to_owned()),
+                                    Err(e) => Err(e.to_string()),
+                                })
+                            })),
+                        };
+
+                    result.map(move |result| (label.name, result))
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the names of all databases, in sorted order
+    ///
+    /// Databases whose label can't be read, for instance because it
+    /// is still being written, are left out.
+    pub fn databases(&self) -> impl Future<Item = Vec<String>, Error = io::Error> + Send {
+        self.databases_with_prefix("")
+    }
+
+    /// Returns the names of all databases whose name starts with the given prefix, in sorted order
+    ///
+    /// Database names can contain slashes, which allows them to be
+    /// grouped in namespaces like `tenant1/people`. Passing
+    /// `tenant1/` as the prefix then lists all databases in the
+    /// `tenant1` namespace.
+    pub fn databases_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Future<Item = Vec<String>, Error = io::Error> + Send {
+        let prefix = prefix.to_owned();
+        self.label_store.label_names().map(move |names| {
+            let mut names: Vec<String> = names
+                .into_iter()
+                .filter(|name| name.starts_with(&prefix))
+                .collect();
+            names.sort();
+
+            names
+        })
+    }
+
+    /// Open a read-only snapshot of a database at a layer from its history
+    ///
+    /// The layer should be the current head of the database or one of
+    /// its ancestors. This returns None if the database does not
+    /// exist, has no head, or if the layer is not part of the history
+    /// of its current head.
+    pub fn open_at(
+        &self,
+        label: &str,
+        layer: [u32; 5],
+    ) -> impl Future<Item = Option<NamedGraphSnapshot>, Error = io::Error> + Send {
+        let store = self.clone();
+        self.label_store.get_label(label).and_then(move |label| {
+            let (label, head) = match label.and_then(|l| l.layer.map(|head| (l.name, head))) {
+                None => return future::Either::A(future::ok(None)),
+                Some(label_and_head) => label_and_head,
+            };
+
+            future::Either::B(store.layer_store.get_layer(head).map(move |head| {
+                let head = head?;
+                let pinned: Arc<dyn Layer> = if head.name() == layer {
+                    head
+                } else {
+                    let mut cur = head.parent();
+                    while let Some(ancestor) = cur {
+                        if ancestor.name() == layer {
+                            break;
+                        }
+                        cur = ancestor.parent();
+                    }
+
+                    cur?.clone_boxed().into()
+                };
+
+                Some(NamedGraphSnapshot {
+                    label,
+                    head: StoreLayer::wrap(pinned, store),
+                })
+            }))
+        })
+    }
+
+    pub fn get_layer_from_id(
+        &self,
+        layer: [u32; 5],
+    ) -> impl Future<Item = Option<StoreLayer>, Error = std::io::Error> {
+        let store = self.clone();
+        self.layer_store
+            .get_layer(layer)
+            .map(move |layer| layer.map(move |l| StoreLayer::wrap(l, store)))
+    }
+
+    /// Returns the number of layers in the chain ending in the given layer.
+    ///
+    /// Unlike `StoreLayer::chain_depth`, this only follows the parent
+    /// pointers of the layer store, so none of the layers in the chain
+    /// need to be loaded. Returns a `NotFound` error if the layer or one
+    /// of its ancestors does not exist.
+    pub fn layer_chain_depth(
+        &self,
+        layer: [u32; 5],
+    ) -> impl Future<Item = usize, Error = io::Error> + Send {
+        let mut seen = HashSet::new();
+        seen.insert(layer);
+        future::loop_fn(
+            (self.layer_store.clone(), layer, 1, seen),
+            |(layer_store, name, depth, mut seen)| {
+                layer_store
+                    .layer_parent(name)
+                    .and_then(move |parent| match parent {
+                        None => Ok(future::Loop::Break(depth)),
+                        Some(parent) => {
+                            // a corrupt store could have parent files pointing in a circle
+                            if !seen.insert(parent) {
+                                return Err(cyclic_ancestry_error(parent));
+                            }
+                            Ok(future::Loop::Continue((
+                                layer_store,
+                                parent,
+                                depth + 1,
+                                seen,
+                            )))
+                        }
+                    })
+            },
+        )
+    }
+
+    /// Serialize the difference between two layers in this store
+    ///
+    /// The result can be applied to a copy of the `from` layer using
+    /// `apply_serialized_diff`, which results in a layer with the
+    /// same triples as the `to` layer. See `LayerDiff` for the format.
+    pub fn serialized_diff(
+        &self,
+        from: [u32; 5],
+        to: [u32; 5],
+    ) -> impl Future<Item = Vec<u8>, Error = io::Error> + Send {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "layer not found in store");
+        self.layer_store
+            .get_layer(from)
+            .join(self.layer_store.get_layer(to))
+            .and_then(move |(from, to)| {
+                let from = from.ok_or_else(not_found)?;
+                let to = to.ok_or_else(not_found)?;
+
+                Ok(LayerDiff::between(&*from, &*to)?.serialize())
+            })
+    }
+
+    /// Build a child layer on top of the given base layer, applying a serialized diff to it
+    ///
+    /// The diff is the output of `serialized_diff`. If it was not
+    /// computed against the given base layer, so that it adds a
+    /// triple which is already there or removes a triple which is not,
+    /// this returns an error.
+    ///
+    /// The resulting layer is unattached to any database label. Use
+    /// `set_head` on a `NamedGraph` to attach it.
+    pub fn apply_serialized_diff(
+        &self,
+        base: [u32; 5],
+        diff: &[u8],
+    ) -> impl Future<Item = StoreLayer, Error = io::Error> + Send {
+        let diff = LayerDiff::deserialize(diff);
+        self.get_layer_from_id(base)
+            .and_then(move |base| {
+                let base = base.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "base layer not found in store")
+                })?;
+                let diff = diff?;
+
+                let applies = diff.additions.iter().all(|t| !base.string_triple_exists(t))
+                    && diff.removals.iter().all(|t| base.string_triple_exists(t));
+                if !applies {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "diff does not apply to the given base layer",
+                    ));
+                }
+
+                Ok((base, diff))
+            })
+            .and_then(|(base, diff)| base.open_write().map(move |builder| (builder, diff)))
+            .and_then(|(builder, diff)| {
+                builder
+                    .with_builder(move |b| {
+                        for triple in diff.additions.iter() {
+                            b.add_string_triple(triple);
+                        }
+                        for triple in diff.removals.iter() {
+                            b.remove_string_triple(triple);
+                        }
+                    })
+                    .and_then(move |_| builder.commit())
+            })
+    }
+
+    /// Create a base layer builder, unattached to any database label
+    ///
+    /// After having committed it, use `set_head` on a `NamedGraph` to attach it.
+    pub fn create_base_layer(
+        &self,
+    ) -> impl Future<Item = StoreLayerBuilder, Error = io::Error> + Send {
+        StoreLayerBuilder::new(self.clone())
+    }
+
+    /// Create a builder for a base layer whose triples are added in subject order
+    ///
+    /// See `AppendOnlyLayerBuilder`. After having committed it, use
+    /// `set_head` on a `NamedGraph` to attach it.
+    pub fn create_base_layer_append_only(&self) -> AppendOnlyLayerBuilder {
+        AppendOnlyLayerBuilder::new(self.clone())
+    }
+
+    /// Start a transaction for setting the heads of multiple databases at once
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    /// Set the heads of several databases at once, returning false if none were set
+    ///
+    /// Each database is named together with its new head. This is
+    /// atomic: the heads are checked and set as a single
+    /// `Transaction`, so either every database moves to its new head,
+    /// or none of them does. As with `NamedGraph::set_head`, the
+    /// current head of every database has to be an ancestor of its new
+    /// head. If a database is named more than once, the last head
+    /// given for it is used. A database that does not exist is an
+    /// error.
+    pub fn set_heads(
+        &self,
+        updates: &[(String, &StoreLayer)],
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let mut transaction = self.transaction();
+        for (database, layer) in updates {
+            transaction.stage(database, layer);
+        }
+
+        transaction.commit()
+    }
+
+    /// Returns the names of all layers in this store
+    ///
+    /// This includes layers that are not reachable from any database label.
+    pub fn layer_names(&self) -> impl Future<Item = Vec<[u32; 5]>, Error = io::Error> + Send {
+        self.layer_store.layers()
+    }
+
+    /// Returns the number of bytes the layers of the given database take up on disk
+    ///
+    /// This sums the sizes of the head layer of the database and all
+    /// its ancestors. Layers that are shared with other databases are
+    /// counted in full. Use `exclusive_disk_size` to only count the
+    /// layers that are not shared. For memory stores, this is 0.
+    pub fn database_disk_size(
+        &self,
+        label: &str,
+    ) -> impl Future<Item = u64, Error = io::Error> + Send {
+        let store = self.clone();
+        self.label_store
+            .get_label(label)
+            .and_then(move |label| match label {
+                None => future::Either::A(future::err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "database not found",
+                ))),
+                Some(label) => future::Either::B(store.head_disk_size(label.layer, HashSet::new())),
+            })
+    }
+
+    /// Returns the number of bytes on disk taken up by layers only reachable from the given database
+    ///
+    /// This is like `database_disk_size`, except that layers which
+    /// are also an ancestor of the head of another database are not
+    /// counted. This is the amount of space that would become
+    /// reclaimable if the database were to be removed.
+    pub fn exclusive_disk_size(
+        &self,
+        label: &str,
+    ) -> impl Future<Item = u64, Error = io::Error> + Send {
+        let store = self.clone();
+        let label = label.to_owned();
+        self.label_store.labels().and_then(move |labels| {
+            let (own, others): (Vec<_>, Vec<_>) = labels.into_iter().partition(|l| l.name == label);
+            let head = match own.into_iter().next() {
+                None => {
+                    return future::Either::A(future::err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "database not found",
+                    )))
+                }
+                Some(own) => own.layer,
+            };
+
+            let ancestries = others
+                .into_iter()
+                .filter_map(|l| l.layer)
+                .map({
+                    let store = store.clone();
+                    move |layer| store.layer_ancestry(layer)
+                })
+                .collect::<Vec<_>>();
+
+            future::Either::B(future::join_all(ancestries).and_then(move |ancestries| {
+                let shared = ancestries.into_iter().flatten().collect();
+                store.head_disk_size(head, shared)
+            }))
+        })
+    }
+
+    /// Returns the names of all layers that were committed as staged, and are not yet part of a database
+    ///
+    /// Layers are staged by committing them with
+    /// `StoreLayerBuilder::commit_staged`. Once a staged layer, or
+    /// one of its descendants, becomes the head of a database, it is
+    /// no longer listed. Layers that are unreachable but were never
+    /// staged are not listed either. The result is sorted by name.
+    pub fn staged_layers(&self) -> impl Future<Item = Vec<[u32; 5]>, Error = io::Error> + Send {
+        let store = self.clone();
+        self.reachable_layers()
+            .join(self.layer_store.layers())
+            .and_then(move |(reachable, layers)| {
+                future::join_all(
+                    layers
+                        .into_iter()
+                        .filter(|name| !reachable.contains(name))
+                        .map(|name| {
+                            store
+                                .layer_store
+                                .layer_metadata(name)
+                                .map(move |metadata| (name, metadata))
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .map(|layers| {
+                let mut staged: Vec<_> = layers
+                    .into_iter()
+                    .filter(|(_, metadata)| metadata.contains_key(STAGED_METADATA_KEY))
+                    .map(|(name, _)| name)
+                    .collect();
+                staged.sort();
+
+                staged
+            })
+    }
+
+    /// Returns the names of all layers reachable from a database label
+    fn reachable_layers(&self) -> impl Future<Item = HashSet<[u32; 5]>, Error = io::Error> + Send {
+        let store = self.clone();
+        self.label_store.labels().and_then(move |labels| {
+            future::join_all(
+                labels
+                    .into_iter()
+                    .filter_map(|label| label.layer)
+                    .map(|layer| store.layer_ancestry(layer))
+                    .collect::<Vec<_>>(),
+            )
+            .map(|ancestries| ancestries.into_iter().flatten().collect())
+        })
+    }
+
+    /// Returns the names of the given layer and all its ancestors
+    fn layer_ancestry(
+        &self,
+        layer: [u32; 5],
+    ) -> impl Future<Item = Vec<[u32; 5]>, Error = io::Error> + Send {
+        self.layer_store
+            .get_layer(layer)
+            .and_then(|layer| match layer {
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "layer not found in store",
+                )),
+                Some(layer) => {
+                    let mut names = vec![layer.name()];
+                    let mut current: &dyn Layer = &*layer;
+                    while let Some(parent) = current.parent() {
+                        names.push(parent.name());
+                        current = parent;
+                    }
+
+                    Ok(names)
+                }
+            })
+    }
+
+    /// Sum the disk sizes of the given head and its ancestors, skipping excluded layers
+    fn head_disk_size(
+        &self,
+        head: Option<[u32; 5]>,
+        excluded: HashSet<[u32; 5]>,
+    ) -> Box<dyn Future<Item = u64, Error = io::Error> + Send> {
+        let head = match head {
+            None => return Box::new(future::ok(0)),
+            Some(head) => head,
+        };
+
+        let layer_store = self.layer_store.clone();
+        Box::new(self.layer_ancestry(head).and_then(move |names| {
+            future::join_all(
+                names
+                    .into_iter()
+                    .filter(|name| !excluded.contains(name))
+                    .map(|name| layer_store.layer_disk_size(name))
+                    .collect::<Vec<_>>(),
+            )
+            .map(|sizes| sizes.into_iter().sum())
+        }))
+    }
+
+    /// Ensure that all labels and layers written so far are durably stored
+    ///
+    /// For directory stores, this fsyncs all label and layer
+    /// files. For memory stores, this does nothing.
+    pub fn flush(&self) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.label_store
+            .flush()
+            .join(self.layer_store.flush())
+            .map(|_| ())
+    }
+
+    /// Discard the version history of all databases
+    ///
+    /// After this, `NamedGraph::head_at_version` only finds the
+    /// current version of each database. Version numbers keep
+    /// counting up from where they were. For directory stores, this
+    /// shrinks every version log to a single entry. Databases can be
+    /// read and written while this runs.
+    pub fn compact_labels(&self) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.label_store.compact()
+    }
+
+    /// Squash the head of every database into a base layer, and delete all layers no longer needed
+    ///
+    /// Every database whose head has ancestors gets a squashed copy of
+    /// its head as its new head, like `StoreLayer::squash`. Databases
+    /// that share a head share the squashed layer as well. A database
+    /// whose head moves while this runs is left as it is. Afterwards,
+    /// every layer that is not reachable from a database is deleted,
+    /// except for staged layers and their ancestors.
+    ///
+    /// This holds the maintenance lock of the store exclusively, so
+    /// commits and head changes made through this store or its
+    /// clones wait for it to finish, and it waits for those that are
+    /// already running. A layer that was committed before this
+    /// started, but was not yet set as the head of a database, is
+    /// not reachable and gets deleted. Setting it as a head
+    /// afterwards results in a `NotFound` error. The same goes for
+    /// layers that are still being built. Other stores and processes
+    /// using the same storage do not take the lock, so this should
+    /// only be run while nothing else is writing to the storage, such
+    /// as during a maintenance window.
+    ///
+    /// Layers are deleted whether or not the version log of a
+    /// database still refers to them. `NamedGraph::head_at_version`
+    /// returns None for a version whose head was deleted.
+    pub fn compact(&self) -> impl Future<Item = CompactReport, Error = io::Error> + Send {
+        let store = self.clone();
+        self.maintenance_lock.write().then(move |guard| {
+            let guard = guard.expect("rwlock write should always succeed");
+            store.compact_locked().then(move |result| {
+                drop(guard);
+                result
+            })
+        })
+    }
+
+    /// Compact the store, while holding the maintenance lock exclusively
+    fn compact_locked(&self) -> impl Future<Item = CompactReport, Error = io::Error> + Send {
+        let store = self.clone();
+        self.total_disk_size()
+            .join(self.label_store.labels())
+            .and_then(move |(bytes_before, labels)| {
+                stream::iter_ok::<_, io::Error>(labels)
+                    .fold(
+                        (store, HashMap::new(), 0),
+                        |(store, squashed, shortened), label| {
+                            store.compact_head(label, squashed).map(
+                                move |(store, squashed, changed)| {
+                                    (store, squashed, shortened + changed as usize)
+                                },
+                            )
+                        },
+                    )
+                    .map(move |(store, _, shortened)| (store, bytes_before, shortened))
+            })
+            .and_then(|(store, bytes_before, chains_shortened)| {
+                store
+                    .delete_unreachable_layers()
+                    .map(move |deleted| (store, bytes_before, chains_shortened, deleted))
+            })
+            .and_then(|(store, bytes_before, chains_shortened, layers_deleted)| {
+                store
+                    .total_disk_size()
+                    .map(move |bytes_after| CompactReport {
+                        chains_shortened,
+                        layers_deleted,
+                        bytes_reclaimed: bytes_before.saturating_sub(bytes_after),
+                    })
+            })
+    }
+
+    /// Point the given label at a squashed copy of its head, if its head has ancestors
+    ///
+    /// `squashed` maps heads that were squashed before to their
+    /// squashed layer, so each head is only squashed once.
+    fn compact_head(
+        self,
+        label: Label,
+        mut squashed: SquashedHeads,
+    ) -> Box<dyn Future<Item = (Store, SquashedHeads, bool), Error = io::Error> + Send> {
+        let head = match label.layer {
+            None => return Box::new(future::ok((self, squashed, false))),
+            Some(head) => head,
+        };
+
+        let new_head: Box<dyn Future<Item = _, Error = _> + Send> = match squashed.get(&head) {
+            Some(&new_head) => Box::new(future::ok((Some(new_head), squashed))),
+            None => {
+                let store = self.clone();
+                Box::new(self.get_layer_from_id(head).and_then(move |layer| {
+                    let result: Box<dyn Future<Item = _, Error = _> + Send> = match layer {
+                        Some(layer) if layer.chain_depth() > 1 => {
+                            Box::new(store.squash_keeping_metadata(&layer).map(move |layer| {
+                                squashed.insert(head, layer.name());
+                                (Some(layer.name()), squashed)
+                            }))
+                        }
+                        _ => Box::new(future::ok((None, squashed))),
+                    };
+
+                    result
+                }))
+            }
+        };
+
+        Box::new(new_head.and_then(move |(new_head, squashed)| {
+            let new_head = match new_head {
+                None => return future::Either::A(future::ok((self, squashed, false))),
+                Some(new_head) => new_head,
+            };
+
+            future::Either::B(self.label_store.set_label(&label, new_head).and_then(
+                move |result| {
+                    let changed = result.is_some();
+                    if changed {
+                        self.notify_change(&label.name, new_head);
+                    }
+
+                    self.sync_label_change(changed)
+                        .map(move |changed| (self, squashed, changed))
+                },
+            ))
+        }))
+    }
+
+    /// Delete every layer that is not reachable from a database or a staged layer, returning how many were deleted
+    ///
+    /// The layers are listed before finding out which are reachable,
+    /// so a layer that is committed and made reachable in between is
+    /// not deleted.
+    fn delete_unreachable_layers(&self) -> impl Future<Item = usize, Error = io::Error> + Send {
+        let store = self.clone();
+        self.layer_store
+            .layers()
+            .and_then(move |layers| {
+                let reachable = store.reachable_layers();
+                store
+                    .staged_layers()
+                    .and_then({
+                        let store = store.clone();
+                        move |staged| {
+                            future::join_all(
+                                staged
+                                    .into_iter()
+                                    .map(|layer| store.layer_ancestry(layer))
+                                    .collect::<Vec<_>>(),
+                            )
+                        }
+                    })
+                    .join(reachable)
+                    .map(move |(staged, reachable)| (store, staged, reachable, layers))
+            })
+            .and_then(|(store, staged, reachable, layers)| {
+                let keep: HashSet<_> = staged.into_iter().flatten().chain(reachable).collect();
+                future::join_all(
+                    layers
+                        .into_iter()
+                        .filter(|layer| !keep.contains(layer))
+                        .map(|layer| store.layer_store.delete_layer(layer))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .map(|deleted| deleted.into_iter().filter(|&deleted| deleted).count())
+    }
+
+    /// Returns the number of bytes all layers in this store take up on disk
+    fn total_disk_size(&self) -> impl Future<Item = u64, Error = io::Error> + Send {
+        let layer_store = self.layer_store.clone();
+        self.layer_store.layers().and_then(move |layers| {
+            future::join_all(
+                layers
+                    .into_iter()
+                    .map(|layer| layer_store.layer_disk_size(layer))
+                    .collect::<Vec<_>>(),
+            )
+            .map(|sizes| sizes.into_iter().sum())
+        })
+    }
+
+    /// Delete the given layer, returning false if it does not exist
+    ///
+    /// This refuses to delete a layer that is the head of a database
+    /// or an ancestor of one, or that is the parent of another layer,
+    /// returning an `InvalidInput` error instead. These checks are
+    /// not atomic with the deletion itself, so a layer should not be
+    /// deleted while other writers might still build on it or point
+    /// a database at it.
+    pub fn delete_layer(
+        &self,
+        name: [u32; 5],
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let store = self.clone();
+        let store2 = self.clone();
+        self.label_store
+            .labels()
+            .and_then(move |labels| {
+                future::join_all(
+                    labels
+                        .into_iter()
+                        .filter_map(|label| {
+                            let database = label.name;
+                            label.layer.map(|head| {
+                                store
+                                    .layer_ancestry(head)
+                                    .map(move |ancestry| (database, ancestry))
+                            })
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .and_then(move |ancestries| {
+                match ancestries
+                    .into_iter()
+                    .find(|(_, ancestry)| ancestry.contains(&name))
+                {
+                    Some((database, _)) => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("layer is in use by database {}", database),
+                    )),
+                    None => Ok(store2),
+                }
+            })
+            .and_then(move |store| {
+                store
+                    .layer_store
+                    .layers()
+                    .map(move |layers| (store, layers))
+            })
+            .and_then(move |(store, layers)| {
+                let parents: Vec<_> = layers
+                    .into_iter()
+                    .filter(|layer| *layer != name)
+                    .map(|layer| store.layer_store.layer_parent(layer))
+                    .collect();
+                future::join_all(parents).map(move |parents| (store, parents))
+            })
+            .and_then(move |(store, parents)| {
+                if parents.contains(&Some(name)) {
+                    future::Either::A(future::err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "layer is the parent of another layer",
+                    )))
+                } else {
+                    future::Either::B(store.layer_store.delete_layer(name))
+                }
+            })
+    }
+
+    /// Copy this store into a fresh directory, returning a store for the copy
+    ///
+    /// All databases are copied along with their versions, as are all
+    /// layers in the layer store, including staged layers and layers
+    /// not reachable from any head. Label and layer files are read
+    /// under a shared lock, so the copy never contains a partially
+    /// written file.
+    ///
+    /// Like `compact`, this holds the maintenance lock of the store
+    /// exclusively, so commits and head changes made through this
+    /// store or its clones wait for the copy to finish. Other stores
+    /// and processes using the same storage do not take the lock, and
+    /// a head they move during the copy may point at a layer that
+    /// was not copied. The target directory is created if needed,
+    /// and must be empty. This only works for stores whose layers are
+    /// kept in a directory.
+    ///
+    /// The returned store has the same options as this one. For a
+    /// store built with `StoreBuilder`, that includes the compression,
+    /// deduplication, cache capacity and lock pool of its directory.
+    /// A store created with `Store::new` doesn't know these, so its
+    /// copy is opened like `open_directory_store` does.
+    pub fn clone_to_directory(
+        &self,
+        path: PathBuf,
+    ) -> impl Future<Item = Store, Error = io::Error> + Send {
+        let store = self.clone();
+        self.maintenance_lock.write().then(move |guard| {
+            let guard = guard.expect("rwlock write should always succeed");
+            store.clone_to_directory_locked(path).then(move |result| {
+                drop(guard);
+                result
+            })
+        })
+    }
+
+    /// Open a directory store at the given path with the options of this store
+    fn open_copy(&self, path: PathBuf) -> Store {
+        let mut builder = StoreBuilder::new().directory(path);
+        if let Some(options) = self.directory_options.clone() {
+            builder = builder
+                .compression(options.compression)
+                .deduplication(options.deduplicate)
+                .cache_capacity(options.cache_capacity);
+            if let Some(pool) = options.lock_pool {
+                builder = builder.lock_pool(pool);
+            }
+        }
+
+        let mut copy = builder.build();
+        copy.bloom_filter_rate = self.bloom_filter_rate;
+        copy.squash_temp_dir = self.squash_temp_dir.clone();
+        copy.sync_on_commit = self.sync_on_commit;
+        copy.max_chain_depth = self.max_chain_depth;
+        copy.change_stream_policy = self.change_stream_policy;
+
+        copy
+    }
+
+    /// Copy this store into a fresh directory, while holding the maintenance lock exclusively
+    fn clone_to_directory_locked(
+        &self,
+        path: PathBuf,
+    ) -> impl Future<Item = Store, Error = io::Error> + Send {
+        let store = self.clone();
+        let target = path.clone();
+        fs::create_dir_all(path.clone())
+            .and_then(move |_| {
+                fs::read_dir(path)
+                    .flatten_stream()
+                    .into_future()
+                    .map_err(|(e, _)| e)
+            })
+            .and_then(|(entry, _)| match entry {
+                None => Ok(()),
+                Some(_) => Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "target directory for a store copy should be empty",
+                )),
+            })
+            .and_then(move |_| {
+                store
+                    .label_store
+                    .labels()
+                    .join(store.layer_store.layers())
+                    .map(move |(labels, names)| (store, labels, names))
+            })
+            .and_then(move |(store, labels, names)| {
+                let layer_store = store.layer_store.clone();
+                let layer_target = target.clone();
+                stream::iter_ok(names)
+                    .for_each(move |name| {
+                        layer_store.copy_layer_to_directory(name, layer_target.clone())
+                    })
+                    .and_then(move |_| {
+                        let label_store = DirectoryLabelStore::new(target.clone());
+                        stream::iter_ok(labels)
+                            .for_each(move |label| label_store.import_label(&label))
+                            .map(move |_| store.open_copy(target))
+                    })
+            })
+    }
+
+    /// Create a base layer out of triples that are already in sorted order
+    ///
+    /// Unlike a layer builder, this will not sort the triples before
+    /// writing them, which saves time on large loads. The triples are
+    /// streamed into the layer as they are written, so they are never
+    /// all held in memory. An out-of-order triple results in an
+    /// `InvalidInput` error.
+    ///
+    /// The resulting layer is unattached to any database label. Use
+    /// `set_head` on a `NamedGraph` to attach it.
+    pub fn bulk_load_base_presorted<I: 'static + Iterator<Item = StringTriple> + Send>(
+        &self,
+        triples: I,
+    ) -> Box<dyn Future<Item = StoreLayer, Error = io::Error> + Send> {
+        let mut last: Option<StringTriple> = None;
+        let checked = triples.map(move |triple| {
+            if last.as_ref().map(|last| last > &triple) == Some(true) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("triple {:?} is out of order", triple),
+                ));
+            }
+
+            last = Some(triple.clone());
+            Ok(triple)
+        });
+
+        self.create_base_layer_from_sorted(Box::new(checked))
+    }
+
+    fn create_base_layer_from_sorted(
+        &self,
+        triples: Box<dyn Iterator<Item = io::Result<StringTriple>> + Send>,
+    ) -> Box<dyn Future<Item = StoreLayer, Error = io::Error> + Send> {
+        let store = self.clone();
+        let layer_store = self.layer_store.clone();
+        let temp_dir = self.temp_dir();
+        Box::new(self.while_writing(move || {
+            layer_store
+                .create_base_layer_from_sorted_triples(triples, temp_dir)
+                .and_then({
+                    let store = store.clone();
+                    move |name| store.layer_store.deduplicate_layer(name)
+                })
+                .and_then(move |name| {
+                    store.layer_store.get_layer(name).map(move |layer| {
+                        StoreLayer::wrap(
+                            layer.expect("layer that was just created was not found in store"),
+                            store,
+                        )
+                    })
+                })
+        }))
+    }
+}
+
+enum StoreBackend {
+    Memory,
+    Directory(PathBuf),
+}
+
+/// A builder for a `Store`, collecting all of its options in one place
+///
+/// By default, this builds a memory store without any of the
+/// options enabled. Options are set through chained calls, after
+/// which `build` creates the store.
+pub struct StoreBuilder {
+    backend: StoreBackend,
+    compression: Compression,
+    deduplicate: bool,
+    cache_capacity: usize,
+    sync_on_commit: bool,
+    max_chain_depth: Option<usize>,
+    bloom_filter_rate: Option<f64>,
+    squash_temp_dir: Option<PathBuf>,
+    change_stream_policy: ChangeStreamPolicy,
+    lock_pool: Option<LockPool>,
+}
+
+impl Default for StoreBuilder {
+    fn default() -> Self {
+        StoreBuilder {
+            backend: StoreBackend::Memory,
+            compression: Compression::None,
+            deduplicate: false,
+            cache_capacity: 0,
+            sync_on_commit: false,
+            max_chain_depth: None,
+            bloom_filter_rate: None,
+            squash_temp_dir: None,
+            change_stream_policy: ChangeStreamPolicy::default(),
+            lock_pool: None,
+        }
+    }
+}
+
+impl StoreBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep all data in memory
+    pub fn memory(mut self) -> Self {
+        self.backend = StoreBackend::Memory;
+
+        self
+    }
+
+    /// Store all data in the given directory
+    pub fn directory<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.backend = StoreBackend::Directory(path.into());
+
+        self
+    }
+
+    /// Compress newly written layer files in a directory store
+    ///
+    /// See `DirectoryLayerStore::with_compression`. This has no
+    /// effect on memory stores.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+
+        self
+    }
+
+    /// Reuse existing layers instead of storing identical copies in a directory store
+    ///
+    /// See `DirectoryLayerStore::with_deduplication`. This has no
+    /// effect on memory stores.
+    ///
+    /// With deduplication, a committed layer may get another name than
+    /// `StoreLayerBuilder::name` returned, so use the name of the
+    /// layer returned by the commit instead.
+    pub fn deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+
+        self
+    }
+
+    /// Keep up to the given number of recently used layers loaded
+    ///
+    /// By default, a layer is only kept loaded for as long as it is
+    /// in use. See `RetainingLayerCache`.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+
+        self
+    }
+
+    /// Flush layers and labels to durable storage as part of every commit
+    ///
+    /// When enabled, committing a layer builder flushes the layer
+    /// store, and setting a database head flushes the label store,
+    /// before returning. See `Store::flush`. This makes commits
+    /// slower, but a successful commit will survive a crash.
+    pub fn sync_on_commit(mut self, sync: bool) -> Self {
+        self.sync_on_commit = sync;
+
+        self
+    }
+
+    /// Squash database heads that sit on top of a stack of more than the given depth
+    ///
+    /// When `NamedGraph::set_head` or
+    /// `NamedGraph::set_head_if_version` moves a database to a layer
+    /// with more than `depth` layers in its stack (counting the layer
+    /// itself), the layer is squashed into a new base layer, which
+    /// then replaces it as the head in the same way as
+    /// `NamedGraph::squash_head`. Its metadata is copied over.
+    /// Committed layers themselves are never replaced.
+    ///
+    /// A depth of 0 results in an `InvalidInput` error, as every
+    /// layer has a depth of at least 1.
+    pub fn max_chain_depth(mut self, depth: usize) -> Result<Self, io::Error> {
+        if depth == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum chain depth should be at least 1",
+            ));
+        }
+        self.max_chain_depth = Some(depth);
+
+        Ok(self)
+    }
+
+    /// Wait for file locks on a dedicated thread pool
+    ///
+    /// This only affects directory stores. By default, waiting for a
+    /// file lock uses the blocking pool of the tokio runtime. A lock
+    /// pool can be shared between several stores. See `LockPool`.
+    pub fn lock_pool(mut self, pool: LockPool) -> Self {
+        self.lock_pool = Some(pool);
+
+        self
+    }
+
+    /// Build a bloom filter for every committed layer
+    ///
+    /// See `Store::with_bloom_filter`. A rate that is not between 0
+    /// and 1 results in an `InvalidInput` error.
+    pub fn bloom_filter(mut self, false_positive_rate: f64) -> Result<Self, io::Error> {
+        check_false_positive_rate(false_positive_rate)?;
+        self.bloom_filter_rate = Some(false_positive_rate);
+
+        Ok(self)
+    }
+
+    /// Put the temporary files used for squashing layers in the given directory
+    ///
+    /// See `Store::with_squash_temp_dir`.
+    pub fn squash_temp_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.squash_temp_dir = Some(path.into());
+
+        self
+    }
+
+    /// Set what change streams do when their consumer lags behind
+    ///
+    /// See `Store::with_change_stream_policy`. A capacity of 0 for
+    /// `ChangeStreamPolicy::DropNewest` results in an `InvalidInput`
+    /// error.
+    pub fn change_stream_policy(mut self, policy: ChangeStreamPolicy) -> Result<Self, io::Error> {
+        check_change_stream_policy(policy)?;
+        self.change_stream_policy = policy;
+
+        Ok(self)
+    }
+
+    /// Build the store
+    pub fn build(self) -> Store {
+        let capacity = self.cache_capacity;
+        let mut store = match self.backend {
+            StoreBackend::Memory => Store::new(
+                MemoryLabelStore::new(),
+                CachedLayerStore::new(
+                    MemoryLayerStore::new(),
+                    RetainingLayerCache::new(LockingHashMapLayerCache::new(), capacity),
+                ),
+            ),
+            StoreBackend::Directory(path) => {
+                let mut label_store = DirectoryLabelStore::new(path.clone());
+                if let Some(pool) = self.lock_pool.clone() {
+                    label_store = label_store.with_lock_pool(pool);
+                }
+
+                let mut store = Store::new(
+                    label_store,
+                    directory_layer_store(path, self.compression, self.deduplicate, capacity),
+                );
+                store.directory_options = Some(DirectoryStoreOptions {
+                    compression: self.compression,
+                    deduplicate: self.deduplicate,
+                    cache_capacity: capacity,
+                    lock_pool: self.lock_pool,
+                });
+
+                store
+            }
+        };
+
+        // the rate and the policy were checked when they were set
+        store.bloom_filter_rate = self.bloom_filter_rate;
+        store.squash_temp_dir = self.squash_temp_dir;
+        store.sync_on_commit = self.sync_on_commit;
+        store.max_chain_depth = self.max_chain_depth;
+        store.change_stream_policy = self.change_stream_policy;
+
+        store
+    }
+}
+
+/// Create a cached directory layer store, as used by directory stores
+fn directory_layer_store(
+    path: PathBuf,
+    compression: Compression,
+    deduplicate: bool,
+    cache_capacity: usize,
+) -> CachedLayerStore {
+    let mut layer_store = DirectoryLayerStore::with_compression(path.clone(), compression);
+    if deduplicate {
+        layer_store = layer_store.with_deduplication();
+    }
+
+    CachedLayerStore::new(
+        layer_store,
+        RetainingLayerCache::new(DirectoryLayerCache::new(path), cache_capacity),
+    )
+}
+
+/// Create the layer store that `open_directory_store` uses for the given directory
+///
+/// This can be combined with a custom `LabelStore` using
+/// `Store::new`, to keep labels elsewhere while the layers are
+/// stored on disk. Layers loaded through this layer store are shared
+/// with any other directory store opened on the same directory.
+pub fn default_layer_store<P: Into<PathBuf>>(path: P) -> CachedLayerStore {
+    directory_layer_store(path.into(), Compression::default(), false, 0)
+}
+
+/// Open a store that is entirely in memory
+///
+/// This is useful for testing purposes, or if the database is only going to be used for caching purposes
+pub fn open_memory_store() -> Store {
+    StoreBuilder::new().memory().build()
+}
+
+/// Open a store that stores its data in the given directory
+///
+/// Stores opened on the same directory within one process share the
+/// layers they have loaded, rather than each mapping the layer files
+/// separately.
+pub fn open_directory_store<P: Into<PathBuf>>(path: P) -> Store {
+    StoreBuilder::new().directory(path).build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DatabaseAlreadyExists;
+    use futures::sync::oneshot;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn store_layer_from_parts() {
+        let other = open_memory_store();
+        let builder = other.create_base_layer().wait().unwrap();
+        builder
+            .add_string_triple(&StringTriple::new_value("cow", "says", "moo"))
+            .wait()
+            .unwrap();
+        let layer = builder.commit().wait().unwrap().layer;
+
+        let store = Store::memory_for_tests();
+        let name = layer.name();
+        let layer = StoreLayer::from_parts(layer, store.clone());
+        assert_eq!(name, layer.name());
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(store.databases_with_prefix("").wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn creating_existing_database_reports_database_already_exists() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+
+        for store in [open_memory_store(), open_directory_store(dir.path())] {
+            oneshot::spawn(store.create("foodb"), &runtime.executor())
+                .wait()
+                .unwrap();
+            let error = oneshot::spawn(store.create("foodb"), &runtime.executor())
+                .wait()
+                .err()
+                .unwrap();
+
+            assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+            assert_eq!(
+                Some(&DatabaseAlreadyExists {
+                    name: "foodb".to_owned()
+                }),
+                DatabaseAlreadyExists::from_io_error(&error)
+            );
+            assert_eq!("database foodb already exists", error.to_string());
+        }
+    }
+
+    #[test]
+    fn failed_staged_commit_removes_staged_mark() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        assert!(oneshot::spawn(builder.commit_staged(), &runtime.executor())
+            .wait()
+            .is_err());
+        assert!(!builder
+            .metadata
+            .lock()
+            .unwrap()
+            .contains_key(STAGED_METADATA_KEY));
+    }
+
+    #[test]
+    fn create_and_manipulate_memory_database() {
+        let runtime = Runtime::new().unwrap();
+
+        let store = open_memory_store();
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let head = oneshot::spawn(database.head(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(head.is_none());
+
+        let mut builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer2), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+        let layer2_name = layer2.name();
+
+        let layer = oneshot::spawn(database.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(layer2_name, layer.name());
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+    }
+
+    #[test]
+    fn export_ntriples_on_runtime() {
+        let runtime = Runtime::new().unwrap();
+
+        let store = open_memory_store();
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("export.nt");
+        let file = std::fs::File::create(&path).unwrap();
+        oneshot::spawn(layer.export_ntriples(file), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            "<cow> <says> \"moo\" .\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn open_write_on_empty_and_existing_database() {
+        let runtime = Runtime::new().unwrap();
+
+        let store = open_memory_store();
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(database.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(layer.parent().is_none());
+        assert!(
+            oneshot::spawn(database.set_head(&layer), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        let builder = oneshot::spawn(database.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        assert_eq!(layer.name(), layer2.parent().unwrap().name());
+        assert!(layer2.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer2.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+    }
+
+    #[test]
+    fn store_and_retrieve_directory_layer_metadata() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+
+        let store = open_directory_store(dir.path());
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        oneshot::spawn(
+            builder.set_metadata("message", "initial \"commit\""),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        oneshot::spawn(
+            builder.set_metadata("author", "someone"),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(oneshot::spawn(
+            builder.set_metadata("author", "someone else"),
+            &runtime.executor()
+        )
+        .wait()
+        .is_err());
+
+        let metadata = oneshot::spawn(layer.metadata(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(2, metadata.len());
+        assert_eq!("initial \"commit\"", metadata["message"]);
+        assert_eq!("someone", metadata["author"]);
+
+        let builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let metadata = oneshot::spawn(layer2.metadata(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(metadata.is_empty());
+    }
+
+    #[test]
+    fn create_and_manipulate_directory_database() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+
+        let store = open_directory_store(dir.path());
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let head = oneshot::spawn(database.head(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(head.is_none());
+
+        let mut builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer2), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+        let layer2_name = layer2.name();
+
+        let layer = oneshot::spawn(database.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(layer2_name, layer.name());
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+    }
+
+    #[test]
+    fn create_layer_and_retrieve_it_by_id() {
+        let runtime = Runtime::new().unwrap();
+
+        let store = open_memory_store();
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let id = layer.name();
+
+        let layer2 = oneshot::spawn(store.get_layer_from_id(id), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert!(layer2.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+    }
+
+    #[test]
+    fn bulk_load_presorted_triples_into_directory_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let triples = vec![
+            StringTriple::new_node("cow", "likes", "duck"),
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_node("duck", "likes", "cow"),
+            StringTriple::new_value("duck", "says", "quack"),
+        ];
+
+        let layer = oneshot::spawn(
+            store.bulk_load_base_presorted(triples.clone().into_iter()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(LayerType::Base, layer.layer_type());
+        assert_eq!(4, layer.triples().count());
+        for triple in triples.iter() {
+            assert!(layer.string_triple_exists(triple));
+        }
+
+        let layer2 = oneshot::spawn(store.get_layer_from_id(layer.name()), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let result: Vec<_> = layer2
+            .triples()
+            .map(|t| layer2.id_triple_to_string(&t).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                triples[0].clone(),
+                triples[1].clone(),
+                triples[3].clone(),
+                triples[4].clone(),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn bulk_load_out_of_order_triples_errors() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
+
+        let triples = vec![
+            StringTriple::new_value("duck", "says", "quack"),
+            StringTriple::new_value("cow", "says", "moo"),
+        ];
+
+        let result = oneshot::spawn(
+            store.bulk_load_base_presorted(triples.into_iter()),
+            &runtime.executor(),
+        )
+        .wait();
+
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn bulk_load_out_of_order_triples_into_directory_store_errors() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let triples = vec![
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_value("duck", "says", "quack"),
+            StringTriple::new_value("pig", "says", "oink"),
+            StringTriple::new_value("duck", "says", "quack"),
+        ];
+
+        let result = oneshot::spawn(
+            store.bulk_load_base_presorted(triples.into_iter()),
+            &runtime.executor(),
+        )
+        .wait();
+
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn flush_directory_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        oneshot::spawn(store.flush(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        // outside of a runtime, the files are synced inline
+        store.flush().wait().unwrap();
+    }
+
+    #[test]
+    fn list_layer_names_in_directory_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let mut names = oneshot::spawn(store.layer_names(), &runtime.executor())
+            .wait()
+            .unwrap();
+        names.sort();
+        let mut expected = vec![base.name(), child.name()];
+        expected.sort();
+
+        assert_eq!(expected, names);
+    }
+
+    #[test]
+    fn bloom_filter_rules_out_missing_triples() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path())
+            .with_bloom_filter(0.01)
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let store = open_directory_store(dir.path());
+        let layer = oneshot::spawn(store.get_layer_from_id(child.name()), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+
+        let cow_says_moo = layer
+            .string_triple_to_id(&StringTriple::new_value("cow", "says", "moo"))
+            .unwrap();
+        let pig_says_oink = layer
+            .string_triple_to_id(&StringTriple::new_value("pig", "says", "oink"))
+            .unwrap();
+        assert!(layer.may_contain_triple(
+            cow_says_moo.subject,
+            cow_says_moo.predicate,
+            cow_says_moo.object
+        ));
+        assert!(layer.id_triple_exists(cow_says_moo));
+        assert!(layer.id_triple_exists(pig_says_oink));
+        assert!(!layer.string_triple_exists(&StringTriple::new_value("pig", "says", "moo")));
+
+        let ruled_out = (1..100)
+            .filter(|&object| !layer.may_contain_triple(1000, 1000, object))
+            .count();
+        assert!(ruled_out > 0);
+    }
+
+    #[test]
+    fn custom_label_store_with_default_layer_store() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = Store::new(MemoryLabelStore::new(), default_layer_store(dir.path()));
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_value_triple("cow", "says", "moo"),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(
+            oneshot::spawn(database.set_head(&layer), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        assert!(!dir.path().join("foodb.label").exists());
+        let directory_store = open_directory_store(dir.path());
+        let loaded = oneshot::spawn(
+            directory_store.get_layer_from_id(layer.name()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap()
+        .unwrap();
+        assert!(loaded.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+    }
+
+    #[test]
+    fn builder_name_matches_committed_layer_and_store_is_shared() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let name = builder.name();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(name, layer.name());
+
+        let builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let layer2 = oneshot::spawn(
+            builder.store().get_layer_from_id(layer.name()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        assert!(layer2.is_some());
+    }
+
+    #[test]
+    fn transaction_sets_heads_of_multiple_databases() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let db1 = oneshot::spawn(store.create("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let db2 = oneshot::spawn(store.create("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer1 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let mut transaction = store.transaction();
+        transaction.set_head(&db1, &layer1);
+        transaction.set_head(&db2, &layer2);
+        assert!(oneshot::spawn(transaction.commit(), &runtime.executor())
+            .wait()
+            .unwrap());
+
+        let head1 = oneshot::spawn(db1.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let head2 = oneshot::spawn(db2.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(layer1.name(), head1.name());
+        assert_eq!(layer2.name(), head2.name());
+    }
+
+    #[test]
+    fn transaction_fails_when_a_database_moved() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let db1 = oneshot::spawn(store.create("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let db2 = oneshot::spawn(store.create("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let mut transaction = store.transaction();
+        transaction.set_head(&db1, &base);
+        transaction.set_head(&db2, &base);
+
+        // db2 moves on before the transaction is committed
+        assert!(oneshot::spawn(db2.set_head(&child), &runtime.executor())
+            .wait()
+            .unwrap());
+
+        assert!(!oneshot::spawn(transaction.commit(), &runtime.executor())
+            .wait()
+            .unwrap());
+
+        assert!(oneshot::spawn(db1.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .is_none());
+        let head2 = oneshot::spawn(db2.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(child.name(), head2.name());
+    }
+
+    #[test]
+    fn head_with_version_follows_set_head() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let (head, version) = oneshot::spawn(database.head_with_version(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(head.is_none());
+        assert_eq!(0, version);
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(database.set_head(&layer), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let (head, version) = oneshot::spawn(database.head_with_version(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(layer.name(), head.unwrap().name());
+        assert_eq!(1, version);
+    }
+
+    fn delete_layers_only_when_unused(store: Store) {
+        let runtime = Runtime::new().unwrap();
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let base = oneshot::spawn(
+            store.create_base_layer().and_then(|b| b.commit()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let head = oneshot::spawn(
+            base.open_write().and_then(|b| b.commit()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        oneshot::spawn(database.set_head(&head), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let unused_base = oneshot::spawn(
+            store.create_base_layer().and_then(|b| b.commit()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let unused_child = oneshot::spawn(
+            unused_base.open_write().and_then(|b| b.commit()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        for layer in &[base.name(), head.name(), unused_base.name()] {
+            let error = oneshot::spawn(store.delete_layer(*layer), &runtime.executor())
+                .wait()
+                .err()
+                .unwrap();
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        }
+
+        let unused_child_name = unused_child.name();
+        let unused_base_name = unused_base.name();
+        drop(unused_child);
+        drop(unused_base);
+        assert!(
+            oneshot::spawn(store.delete_layer(unused_child_name), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+        assert!(
+            oneshot::spawn(store.delete_layer(unused_base_name), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+        assert!(
+            !oneshot::spawn(store.delete_layer(unused_base_name), &runtime.executor())
+                .wait()
+                .unwrap()
+        );
+
+        assert!(oneshot::spawn(
+            store.get_layer_from_id(unused_base_name),
+            &runtime.executor()
+        )
+        .wait()
+        .unwrap()
+        .is_none());
+        let layers = oneshot::spawn(store.layer_store.layers(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(2, layers.len());
+    }
+
+    #[test]
+    fn delete_unused_memory_layers() {
+        delete_layers_only_when_unused(open_memory_store());
+    }
+
+    #[test]
+    fn delete_unused_directory_layers() {
+        let dir = tempdir().unwrap();
+        delete_layers_only_when_unused(open_directory_store(dir.path()));
+    }
+
+    #[test]
+    fn deduplicate_identical_directory_layers() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = Store::new(
+            DirectoryLabelStore::new(dir.path()),
+            CachedLayerStore::new(
+                DirectoryLayerStore::new(dir.path()).with_deduplication(),
+                LockingHashMapLayerCache::new(),
+            ),
+        );
+
+        let build = |triples: Vec<StringTriple>| {
+            let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+                .wait()
+                .unwrap();
+            for triple in triples {
+                oneshot::spawn(builder.add_string_triple(&triple), &runtime.executor())
+                    .wait()
+                    .unwrap();
+            }
+            oneshot::spawn(builder.commit(), &runtime.executor())
+                .wait()
+                .unwrap()
+        };
+        let triples = vec![
+            StringTriple::new_value("cow", "says", "moo"),
+            StringTriple::new_node("cow", "likes", "duck"),
+        ];
+
+        let layer1 = build(triples.clone());
+        let layer2 = build(triples.clone());
+        assert_eq!(layer1.name(), layer2.name());
+
+        let layers = oneshot::spawn(store.layer_store.layers(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(vec![layer1.name()], layers);
+
+        let layer3 = build(vec![StringTriple::new_value("duck", "says", "quack")]);
+        assert_ne!(layer1.name(), layer3.name());
+        let layers = oneshot::spawn(store.layer_store.layers(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(2, layers.len());
+    }
+
+    #[test]
+    fn clone_store_to_directory() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+
+        let db1 = oneshot::spawn(store.create("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let db2 = oneshot::spawn(store.create("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(store.create("empty"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        // a layer that no database points at
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        oneshot::spawn(db1.set_head(&base), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(db1.set_head(&child), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(db2.set_head(&base), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let copy_dir = tempdir().unwrap();
+        let copy = oneshot::spawn(
+            store.clone_to_directory(copy_dir.path().join("copy")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        // unreachable layers are copied too
+        let layers = oneshot::spawn(copy.layer_store.layers(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(3, layers.len());
+
+        let label = oneshot::spawn(copy.label_store.get_label("db1"), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, label.version);
+        assert_eq!(Some(child.name()), label.layer);
+
+        let copied_db1 = oneshot::spawn(copy.open("db1"), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        let head = oneshot::spawn(copied_db1.head(), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert!(head.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(head.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+
+        let empty = oneshot::spawn(copy.label_store.get_label("empty"), &runtime.executor())
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(None, empty.layer);
+
+        let error = oneshot::spawn(
+            store.clone_to_directory(copy_dir.path().join("copy")),
+            &runtime.executor(),
+        )
+        .wait()
+        .err()
+        .unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, error.kind());
+    }
+
+    #[test]
+    fn clone_store_to_directory_keeps_options() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let copy_dir = tempdir().unwrap();
+        let store = StoreBuilder::new()
+            .directory(dir.path())
+            .compression(Compression::Zstd(3))
+            .deduplication(true)
+            .cache_capacity(4)
+            .sync_on_commit(true)
+            .max_chain_depth(3)
+            .unwrap()
+            .bloom_filter(0.01)
+            .unwrap()
+            .squash_temp_dir(dir.path())
+            .build();
+
+        let copy = oneshot::spawn(
+            store.clone_to_directory(copy_dir.path().join("copy")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(Some(0.01), copy.bloom_filter_rate);
+        assert_eq!(Some(dir.path().to_path_buf()), copy.squash_temp_dir);
+        assert!(copy.sync_on_commit);
+        assert_eq!(Some(3), copy.max_chain_depth);
+        let options = copy.directory_options.clone().unwrap();
+        assert_eq!(Compression::Zstd(3), options.compression);
+        assert!(options.deduplicate);
+        assert_eq!(4, options.cache_capacity);
+
+        // layers committed to the copy are compressed and deduplicated
+        let build = |store: &Store| {
+            let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+                .wait()
+                .unwrap();
+            oneshot::spawn(
+                builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+                &runtime.executor(),
+            )
+            .wait()
+            .unwrap();
+            oneshot::spawn(builder.commit(), &runtime.executor())
+                .wait()
+                .unwrap()
+        };
+        let layer1 = build(&copy);
+        let layer2 = build(&copy);
+        assert_eq!(layer1.name(), layer2.name());
+
+        let name = crate::storage::name_to_string(layer1.name());
+        assert!(copy_dir
+            .path()
+            .join("copy")
+            .join(&name[0..3])
+            .join(&name)
+            .join("node_dictionary_blocks.zst")
+            .exists());
     }
 
-    pub fn get_layer_from_id(
-        &self,
-        layer: [u32; 5],
-    ) -> impl Future<Item = Option<StoreLayer>, Error = std::io::Error> {
-        let store = self.clone();
-        self.layer_store
-            .get_layer(layer)
-            .map(move |layer| layer.map(move |l| StoreLayer::wrap(l, store)))
-    }
+    #[test]
+    fn clone_to_directory_waits_for_writers() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+        let guard = store.maintenance_lock.read().wait().unwrap();
 
-    /// Create a base layer builder, unattached to any database label
-    ///
-    /// After having committed it, use `set_head` on a `NamedGraph` to attach it.
-    pub fn create_base_layer(
-        &self,
-    ) -> impl Future<Item = StoreLayerBuilder, Error = io::Error> + Send {
-        StoreLayerBuilder::new(self.clone())
+        let copy_dir = tempdir().unwrap();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let copy = oneshot::spawn(
+            store.clone_to_directory(copy_dir.path().join("copy")).map({
+                let done = done.clone();
+                move |copy| {
+                    done.store(true, std::sync::atomic::Ordering::SeqCst);
+                    copy
+                }
+            }),
+            &runtime.executor(),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!done.load(std::sync::atomic::Ordering::SeqCst));
+
+        drop(guard);
+        copy.wait().unwrap();
+        assert!(done.load(std::sync::atomic::Ordering::SeqCst));
     }
-}
 
-/// Open a store that is entirely in memory
-///
-/// This is useful for testing purposes, or if the database is only going to be used for caching purposes
-pub fn open_memory_store() -> Store {
-    Store::new(
-        MemoryLabelStore::new(),
-        CachedLayerStore::new(MemoryLayerStore::new(), LockingHashMapLayerCache::new()),
-    )
-}
+    #[test]
+    fn clone_memory_store_to_directory_fails() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
 
-/// Open a store that stores its data in the given directory
-pub fn open_directory_store<P: Into<PathBuf>>(path: P) -> Store {
-    let p = path.into();
-    Store::new(
-        DirectoryLabelStore::new(p.clone()),
-        CachedLayerStore::new(DirectoryLayerStore::new(p), LockingHashMapLayerCache::new()),
-    )
-}
+        let db = oneshot::spawn(store.create("db"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(db.set_head(&layer), &runtime.executor())
+            .wait()
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::sync::oneshot;
-    use tempfile::tempdir;
-    use tokio::runtime::Runtime;
+        let dir = tempdir().unwrap();
+        let error = oneshot::spawn(
+            store.clone_to_directory(dir.path().to_path_buf()),
+            &runtime.executor(),
+        )
+        .wait()
+        .err()
+        .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
 
     #[test]
-    fn create_and_manipulate_memory_database() {
+    fn compute_database_disk_sizes() {
         let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
 
-        let store = open_memory_store();
-        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+        let db1 = oneshot::spawn(store.create("db1"), &runtime.executor())
             .wait()
             .unwrap();
-
-        let head = oneshot::spawn(database.head(), &runtime.executor())
+        let db2 = oneshot::spawn(store.create("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(store.create("empty"), &runtime.executor())
             .wait()
             .unwrap();
-        assert!(head.is_none());
 
-        let mut builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
             .wait()
             .unwrap();
         oneshot::spawn(
@@ -507,62 +4148,109 @@ mod tests {
         )
         .wait()
         .unwrap();
-
-        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
-        assert!(
-            oneshot::spawn(database.set_head(&layer), &runtime.executor())
-                .wait()
-                .unwrap()
-        );
 
-        builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
             .wait()
             .unwrap();
         oneshot::spawn(
-            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
             &runtime.executor(),
         )
         .wait()
         .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
 
-        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+        oneshot::spawn(db1.set_head(&child), &runtime.executor())
             .wait()
             .unwrap();
-        assert!(
-            oneshot::spawn(database.set_head(&layer2), &runtime.executor())
+        oneshot::spawn(db2.set_head(&base), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let size1 = oneshot::spawn(store.database_disk_size("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let size2 = oneshot::spawn(store.database_disk_size("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let exclusive1 = oneshot::spawn(store.exclusive_disk_size("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let exclusive2 = oneshot::spawn(store.exclusive_disk_size("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        assert!(size2 > 0);
+        assert!(size1 > size2);
+        assert_eq!(size1 - size2, exclusive1);
+        assert_eq!(0, exclusive2);
+
+        assert_eq!(
+            0,
+            oneshot::spawn(store.database_disk_size("empty"), &runtime.executor())
                 .wait()
                 .unwrap()
         );
-        let layer2_name = layer2.name();
-
-        let layer = oneshot::spawn(database.head(), &runtime.executor())
+        let error = oneshot::spawn(store.database_disk_size("missing"), &runtime.executor())
             .wait()
-            .unwrap()
+            .err()
             .unwrap();
-
-        assert_eq!(layer2_name, layer.name());
-        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
-        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
     }
 
     #[test]
-    fn create_and_manipulate_directory_database() {
+    fn scan_hints_do_not_change_results() {
         let runtime = Runtime::new().unwrap();
         let dir = tempdir().unwrap();
-
         let store = open_directory_store(dir.path());
-        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
 
-        let head = oneshot::spawn(database.head(), &runtime.executor())
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
-        assert!(head.is_none());
 
-        let mut builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+        let expected: Vec<_> = child.triples().collect();
+
+        child.scan_hint(ScanHint::Sequential);
+        assert_eq!(expected, child.triples().collect::<Vec<_>>());
+
+        child.scan_hint(ScanHint::Normal);
+        assert_eq!(expected, child.triples().collect::<Vec<_>>());
+        assert_eq!(2, expected.len());
+    }
+
+    #[test]
+    fn apply_serialized_diff_reproduces_head() {
+        let runtime = Runtime::new().unwrap();
+        let store = open_memory_store();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
             .wait()
             .unwrap();
         oneshot::spawn(
@@ -571,51 +4259,179 @@ mod tests {
         )
         .wait()
         .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let base = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
 
-        let layer = oneshot::spawn(builder.commit(), &runtime.executor())
+        let builder = oneshot::spawn(base.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.remove_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_node("cow", "likes", "duck")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let child = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
-        assert!(
-            oneshot::spawn(database.set_head(&layer), &runtime.executor())
-                .wait()
-                .unwrap()
-        );
 
-        builder = oneshot::spawn(layer.open_write(), &runtime.executor())
+        let builder = oneshot::spawn(child.open_write(), &runtime.executor())
             .wait()
             .unwrap();
         oneshot::spawn(
-            builder.add_string_triple(&StringTriple::new_value("pig", "says", "oink")),
+            builder.add_string_triple(&StringTriple::new_value("duck", "says", "quack")),
             &runtime.executor(),
         )
         .wait()
         .unwrap();
+        let head = oneshot::spawn(builder.commit(), &runtime.executor())
+            .wait()
+            .unwrap();
 
-        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
+        let diff = oneshot::spawn(
+            store.serialized_diff(base.name(), head.name()),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let applied = oneshot::spawn(
+            store.apply_serialized_diff(base.name(), &diff),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(Some(base.name()), applied.parent().map(|p| p.name()));
+        let string_triples = |layer: &StoreLayer| {
+            let mut triples: Vec<_> = layer
+                .triples()
+                .map(|t| layer.id_triple_to_string(&t).unwrap())
+                .collect();
+            triples.sort();
+            triples
+        };
+        assert_eq!(string_triples(&head), string_triples(&applied));
+
+        // the diff does not apply cleanly on top of the head
+        let result = oneshot::spawn(
+            store.apply_serialized_diff(head.name(), &diff),
+            &runtime.executor(),
+        )
+        .wait();
+        assert_eq!(io::ErrorKind::InvalidData, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn change_stream_reports_new_heads() {
+        let runtime = Runtime::new().unwrap();
+
+        let store = open_memory_store();
+        let lagging_store = store
+            .clone()
+            .with_change_stream_policy(ChangeStreamPolicy::DropNewest(1))
+            .unwrap();
+        let changes = store.change_stream();
+        let lagging_changes = lagging_store.change_stream();
+
+        let database1 = oneshot::spawn(store.create("db1"), &runtime.executor())
+            .wait()
+            .unwrap();
+        let database2 = oneshot::spawn(store.create("db2"), &runtime.executor())
+            .wait()
+            .unwrap();
+
+        let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
+            .wait()
+            .unwrap();
+        oneshot::spawn(
+            builder.add_string_triple(&StringTriple::new_value("cow", "says", "moo")),
+            &runtime.executor(),
+        )
+        .wait()
+        .unwrap();
+        let layer1 = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
         assert!(
-            oneshot::spawn(database.set_head(&layer2), &runtime.executor())
+            oneshot::spawn(database1.set_head(&layer1), &runtime.executor())
                 .wait()
                 .unwrap()
         );
-        let layer2_name = layer2.name();
 
-        let layer = oneshot::spawn(database.head(), &runtime.executor())
+        let builder = oneshot::spawn(layer1.open_write(), &runtime.executor())
+            .wait()
+            .unwrap();
+        let layer2 = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
-            .unwrap()
             .unwrap();
+        let mut transaction = store.transaction();
+        transaction.set_head(&database1, &layer2);
+        transaction.set_head(&database2, &layer1);
+        assert!(oneshot::spawn(transaction.commit(), &runtime.executor())
+            .wait()
+            .unwrap());
 
-        assert_eq!(layer2_name, layer.name());
-        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
-        assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+        let events = changes.take(3).collect().wait().unwrap();
+        assert_eq!(
+            vec![
+                ("db1".to_owned(), layer1.name()),
+                ("db1".to_owned(), layer2.name()),
+                ("db2".to_owned(), layer1.name()),
+            ],
+            events
+        );
+
+        let lagging_events = lagging_changes.take(1).collect().wait().unwrap();
+        assert_eq!(vec![("db1".to_owned(), layer1.name())], lagging_events);
     }
 
     #[test]
-    fn create_layer_and_retrieve_it_by_id() {
+    fn compact_waits_for_running_writes() {
         let runtime = Runtime::new().unwrap();
-
         let store = open_memory_store();
+        let guard = store.maintenance_lock.read().wait().unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let compaction = oneshot::spawn(
+            store.compact().map({
+                let done = done.clone();
+                move |report| {
+                    done.store(true, std::sync::atomic::Ordering::SeqCst);
+                    report
+                }
+            }),
+            &runtime.executor(),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!done.load(std::sync::atomic::Ordering::SeqCst));
+
+        drop(guard);
+        compaction.wait().unwrap();
+        assert!(done.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn set_head_to_compacted_layer_errors() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempdir().unwrap();
+        let store = open_directory_store(dir.path());
+        let database = oneshot::spawn(store.create("foodb"), &runtime.executor())
+            .wait()
+            .unwrap();
+
         let builder = oneshot::spawn(store.create_base_layer(), &runtime.executor())
             .wait()
             .unwrap();
@@ -625,17 +4441,23 @@ mod tests {
         )
         .wait()
         .unwrap();
-
         let layer = oneshot::spawn(builder.commit(), &runtime.executor())
             .wait()
             .unwrap();
 
-        let id = layer.name();
+        let report = oneshot::spawn(store.compact(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert_eq!(1, report.layers_deleted);
 
-        let layer2 = oneshot::spawn(store.get_layer_from_id(id), &runtime.executor())
+        let error = oneshot::spawn(database.set_head(&layer), &runtime.executor())
             .wait()
-            .unwrap()
+            .err()
             .unwrap();
-        assert!(layer2.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        let head = oneshot::spawn(database.head(), &runtime.executor())
+            .wait()
+            .unwrap();
+        assert!(head.is_none());
     }
 }
@@ -0,0 +1,435 @@
+//! Distributing databases across several stores by subject
+//!
+//! A `ShardedStore` spreads the triples of its databases over a fixed
+//! list of stores, the shards. Every triple is stored in the shard its
+//! subject is assigned to by `shard_for_subject`, so all triples of a
+//! subject end up together. Lookups by subject only need to consult a
+//! single shard, whereas lookups by predicate or object have to ask
+//! every shard and merge the results.
+//!
+//! Every shard assigns its own ids, so ids cannot be compared across
+//! shards. The sharded types therefore only work with strings.
+//!
+//! The shards are independent stores. Setting the heads of a sharded
+//! database sets the head of the database in every shard in turn,
+//! which is not atomic. See `ShardedNamedGraph::set_head`.
+use futures::prelude::*;
+use futures::{future, stream};
+use std::io;
+
+use super::{NamedGraph, Store, StoreLayer, StoreLayerBuilder};
+use crate::layer::{IdTriple, Layer, ObjectType, StringTriple};
+
+/// Returns the shard of the given subject, out of `num_shards` shards.
+///
+/// The subject is hashed with a stable hash function, so the result
+/// is the same in every process and on every platform. Shards are
+/// assigned with jump consistent hashing: when the number of shards
+/// is increased from n to n + 1, only about 1 / (n + 1) of the
+/// subjects move, and all of those move to the new shard.
+///
+/// This panics if `num_shards` is 0.
+pub fn shard_for_subject(subject: &str, num_shards: usize) -> usize {
+    assert!(num_shards > 0, "there has to be at least one shard");
+
+    // 64-bit FNV-1a
+    let mut key: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in subject.bytes() {
+        key ^= byte as u64;
+        key = key.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    // jump consistent hash, as described by Lamping and Veach
+    let mut bucket: i64 = -1;
+    let mut jump: i64 = 0;
+    while jump < num_shards as i64 {
+        bucket = jump;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        jump = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+
+    bucket as usize
+}
+
+/// A set of stores, distributing the triples of their databases by subject
+#[derive(Clone)]
+pub struct ShardedStore {
+    shards: Vec<Store>,
+}
+
+impl ShardedStore {
+    /// Create a sharded store out of the given stores
+    ///
+    /// The order of the stores matters, as subjects are assigned to
+    /// shards by their position. The same stores have to be given in
+    /// the same order every time. Adding a store at the end moves as
+    /// few subjects as possible, but moving them is left to the
+    /// caller.
+    ///
+    /// This panics if no stores are given.
+    pub fn new(shards: Vec<Store>) -> ShardedStore {
+        assert!(!shards.is_empty(), "there has to be at least one shard");
+
+        ShardedStore { shards }
+    }
+
+    /// Returns the stores that make up this sharded store
+    pub fn shards(&self) -> &[Store] {
+        &self.shards
+    }
+
+    /// Returns the shard that stores the triples of the given subject
+    pub fn shard_for_subject(&self, subject: &str) -> usize {
+        shard_for_subject(subject, self.shards.len())
+    }
+
+    /// Create a new database with the given name in every shard
+    ///
+    /// If the database already exists in any of the shards, an error
+    /// is returned. It is then still created in the other shards.
+    pub fn create(
+        &self,
+        label: &str,
+    ) -> impl Future<Item = ShardedNamedGraph, Error = io::Error> + Send {
+        future::join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.create(label))
+                .collect::<Vec<_>>(),
+        )
+        .map(|graphs| ShardedNamedGraph { graphs })
+    }
+
+    /// Open an existing database with the given name, or None if it does not exist in every shard
+    pub fn open(
+        &self,
+        label: &str,
+    ) -> impl Future<Item = Option<ShardedNamedGraph>, Error = io::Error> {
+        future::join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.open(label))
+                .collect::<Vec<_>>(),
+        )
+        .map(|graphs| {
+            graphs
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .map(|graphs| ShardedNamedGraph { graphs })
+        })
+    }
+}
+
+/// A database that is spread across the shards of a `ShardedStore`
+pub struct ShardedNamedGraph {
+    graphs: Vec<NamedGraph>,
+}
+
+impl ShardedNamedGraph {
+    pub fn name(&self) -> &str {
+        self.graphs[0].name()
+    }
+
+    /// Returns the current heads of the database, or None if none of the shards has a head
+    pub fn head(&self) -> impl Future<Item = Option<ShardedLayer>, Error = io::Error> + Send {
+        future::join_all(self.graphs.iter().map(|g| g.head()).collect::<Vec<_>>()).map(|layers| {
+            if layers.iter().all(|l| l.is_none()) {
+                None
+            } else {
+                Some(ShardedLayer { layers })
+            }
+        })
+    }
+
+    /// Start a write on top of the current heads, or on top of nothing if there are no heads
+    pub fn open_write(&self) -> impl Future<Item = ShardedLayerBuilder, Error = io::Error> + Send {
+        future::join_all(
+            self.graphs
+                .iter()
+                .map(|g| g.open_write())
+                .collect::<Vec<_>>(),
+        )
+        .map(|builders| ShardedLayerBuilder { builders })
+    }
+
+    /// Set the head of the database in every shard, returning false if that failed for any shard
+    ///
+    /// As with `NamedGraph::set_head`, the head of every shard has to
+    /// be an ancestor of its new head. The shards are separate
+    /// stores, so this is not atomic: heads are set shard by shard,
+    /// and if setting the head of a shard fails, the heads set before
+    /// it are kept. Shards without a layer in the given heads are
+    /// left alone.
+    pub fn set_head(
+        &self,
+        heads: &ShardedLayer,
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        let sets: Vec<_> = self
+            .graphs
+            .iter()
+            .zip(heads.layers.iter())
+            .filter_map(|(graph, layer)| layer.as_ref().map(|layer| graph.set_head(layer)))
+            .collect();
+        let count = sets.len();
+
+        stream::iter_ok(sets)
+            .and_then(|set| set)
+            .take_while(|set| future::ok(*set))
+            .collect()
+            .map(move |set| set.len() == count)
+    }
+}
+
+/// A layer builder for every shard of a sharded database
+///
+/// Triples are added to and removed from the builder of the shard of
+/// their subject.
+pub struct ShardedLayerBuilder {
+    builders: Vec<StoreLayerBuilder>,
+}
+
+impl ShardedLayerBuilder {
+    fn builder_for(&self, subject: &str) -> &StoreLayerBuilder {
+        &self.builders[shard_for_subject(subject, self.builders.len())]
+    }
+
+    /// Add a string triple to the shard of its subject
+    pub fn add_string_triple(
+        &self,
+        triple: &StringTriple,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        self.builder_for(&triple.subject).add_string_triple(triple)
+    }
+
+    /// Remove a string triple from the shard of its subject, returning false if it did not exist there
+    pub fn remove_string_triple(
+        &self,
+        triple: &StringTriple,
+    ) -> impl Future<Item = bool, Error = io::Error> + Send {
+        self.builder_for(&triple.subject)
+            .remove_string_triple(triple)
+    }
+
+    /// Commit the builders of all shards
+    ///
+    /// Like committing a single builder, this does not set any heads.
+    /// Use `ShardedNamedGraph::set_head` for that.
+    pub fn commit(&self) -> impl Future<Item = ShardedLayer, Error = io::Error> + Send {
+        future::join_all(self.builders.iter().map(|b| b.commit()).collect::<Vec<_>>()).map(
+            |layers| ShardedLayer {
+                layers: layers.into_iter().map(Some).collect(),
+            },
+        )
+    }
+}
+
+/// The layers of a sharded database, one for every shard that has one
+#[derive(Clone)]
+pub struct ShardedLayer {
+    layers: Vec<Option<StoreLayer>>,
+}
+
+impl ShardedLayer {
+    /// Returns the layer of the given shard, if it has one
+    pub fn shard(&self, shard: usize) -> Option<&StoreLayer> {
+        self.layers[shard].as_ref()
+    }
+
+    fn layer_for(&self, subject: &str) -> Option<&StoreLayer> {
+        self.layers[shard_for_subject(subject, self.layers.len())].as_ref()
+    }
+
+    /// Returns the number of triples in all shards
+    pub fn triple_count(&self) -> usize {
+        self.layers
+            .iter()
+            .flatten()
+            .map(|layer| layer.triple_count())
+            .sum()
+    }
+
+    /// Start a write on top of these layers
+    ///
+    /// Shards without a layer get a builder for a new base layer.
+    pub fn open_write(
+        &self,
+        store: &ShardedStore,
+    ) -> impl Future<Item = ShardedLayerBuilder, Error = io::Error> + Send {
+        let builders: Vec<_> = self
+            .layers
+            .iter()
+            .zip(store.shards.iter())
+            .map(
+                |(layer, shard)| -> Box<dyn Future<Item = _, Error = _> + Send> {
+                    match layer {
+                        Some(layer) => Box::new(layer.open_write()),
+                        None => Box::new(shard.create_base_layer()),
+                    }
+                },
+            )
+            .collect();
+
+        future::join_all(builders).map(|builders| ShardedLayerBuilder { builders })
+    }
+
+    /// Returns true if the given triple exists
+    ///
+    /// This only consults the shard of the subject.
+    pub fn string_triple_exists(&self, triple: &StringTriple) -> bool {
+        self.layer_for(&triple.subject)
+            .map(|layer| layer.string_triple_exists(triple))
+            .unwrap_or(false)
+    }
+
+    /// Returns all triples with the given subject, sorted
+    ///
+    /// This only consults the shard of the subject.
+    pub fn triples_for_subject(&self, subject: &str) -> Vec<StringTriple> {
+        let layer = match self.layer_for(subject) {
+            Some(layer) => layer,
+            None => return Vec::new(),
+        };
+
+        let mut triples: Vec<_> = layer
+            .subject_id(subject)
+            .and_then(|id| layer.lookup_subject(id))
+            .map(|lookup| to_strings(layer, lookup.triples()))
+            .unwrap_or_default();
+        triples.sort();
+
+        triples
+    }
+
+    /// Returns all triples with the given predicate across all shards, sorted
+    pub fn triples_for_predicate(&self, predicate: &str) -> Vec<StringTriple> {
+        self.fan_out(|layer| {
+            layer
+                .predicate_id(predicate)
+                .and_then(|id| layer.lookup_predicate(id))
+                .map(|lookup| to_strings(layer, lookup.triples()))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Returns all triples with the given object across all shards, sorted
+    pub fn triples_for_object(&self, object: &ObjectType) -> Vec<StringTriple> {
+        self.fan_out(|layer| {
+            let id = match object {
+                ObjectType::Node(node) => layer.object_node_id(node),
+                ObjectType::Value(value) => layer.object_value_id(value),
+            };
+            id.and_then(|id| layer.lookup_object(id))
+                .map(|lookup| to_strings(layer, lookup.triples()))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Run a lookup on every shard, and merge the results in order
+    fn fan_out<F: Fn(&StoreLayer) -> Vec<StringTriple>>(&self, lookup: F) -> Vec<StringTriple> {
+        let mut triples: Vec<_> = self.layers.iter().flatten().flat_map(lookup).collect();
+        triples.sort();
+
+        triples
+    }
+}
+
+fn to_strings<I: Iterator<Item = IdTriple>>(layer: &StoreLayer, triples: I) -> Vec<StringTriple> {
+    triples
+        .filter_map(|t| layer.id_triple_to_string(&t))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::open_memory_store;
+
+    #[test]
+    fn growing_the_shards_only_moves_subjects_to_the_new_shard() {
+        let subjects: Vec<_> = (0..1000).map(|i| format!("node{}", i)).collect();
+        for num_shards in 1..10 {
+            let mut counts = vec![0; num_shards + 1];
+            for subject in subjects.iter() {
+                let shard = shard_for_subject(subject, num_shards);
+                assert_eq!(shard, shard_for_subject(subject, num_shards));
+                let grown = shard_for_subject(subject, num_shards + 1);
+                assert!(grown == shard || grown == num_shards);
+                counts[grown] += 1;
+            }
+
+            // every shard gets a fair share
+            for count in counts {
+                assert!(count > 1000 / (num_shards + 1) / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn route_triples_to_shards() {
+        let store = ShardedStore::new(vec![
+            open_memory_store(),
+            open_memory_store(),
+            open_memory_store(),
+        ]);
+        let database = store.create("animals").wait().unwrap();
+        assert!(database.head().wait().unwrap().is_none());
+
+        let builder = database.open_write().wait().unwrap();
+        let mut triples = Vec::new();
+        for i in 0..30 {
+            let subject = format!("animal{}", i);
+            triples.push(StringTriple::new_value(&subject, "says", "moo"));
+            triples.push(StringTriple::new_node(&subject, "likes", "animal0"));
+        }
+        for triple in triples.iter() {
+            builder.add_string_triple(triple).wait().unwrap();
+        }
+        let layer = builder.commit().wait().unwrap();
+        assert!(database.set_head(&layer).wait().unwrap());
+
+        let database = store.open("animals").wait().unwrap().unwrap();
+        let head = database.head().wait().unwrap().unwrap();
+        for i in 0..3 {
+            assert!(head.shard(i).unwrap().triples().count() > 0);
+        }
+        assert_eq!(60, head.triple_count());
+
+        let animal = head.triples_for_subject("animal7");
+        assert_eq!(
+            vec![
+                StringTriple::new_node("animal7", "likes", "animal0"),
+                StringTriple::new_value("animal7", "says", "moo"),
+            ],
+            animal
+        );
+        let shard = store.shard_for_subject("animal7");
+        assert!(head.shard(shard).unwrap().string_triple_exists(&animal[0]));
+
+        let mut likes: Vec<_> = triples
+            .iter()
+            .filter(|t| t.predicate == "likes")
+            .cloned()
+            .collect();
+        likes.sort();
+        assert_eq!(likes, head.triples_for_predicate("likes"));
+        assert_eq!(
+            likes,
+            head.triples_for_object(&ObjectType::Node("animal0".to_owned()))
+        );
+
+        let builder = head.open_write(&store).wait().unwrap();
+        assert!(builder
+            .remove_string_triple(&StringTriple::new_value("animal7", "says", "moo"))
+            .wait()
+            .unwrap());
+        let child = builder.commit().wait().unwrap();
+        assert!(database.set_head(&child).wait().unwrap());
+        let head = database.head().wait().unwrap().unwrap();
+        assert_eq!(29, head.triples_for_predicate("says").len());
+        assert_eq!(59, head.triple_count());
+        assert!(!head.string_triple_exists(&StringTriple::new_value("animal7", "says", "moo")));
+
+        // the old layers are not descendants of the new heads
+        assert!(!database.set_head(&layer).wait().unwrap());
+    }
+}
@@ -6,18 +6,24 @@
 use futures::future;
 use futures::prelude::*;
 use futures::sync::oneshot;
+use tokio::io::AsyncRead;
 use tokio::runtime::Runtime;
 
-use std::io;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::layer::{
-    IdTriple, Layer, LayerObjectLookup, LayerPredicateLookup, LayerSubjectLookup, ObjectType,
+    CommitProgress, IdTriple, Layer, LayerDiff, LayerObjectLookup, LayerPredicateLookup,
+    LayerStatistics, LayerSubjectLookup, LayerTripleCounts, LayerType, ObjectType, ScanHint,
     StringTriple, SubjectLookup,
 };
 use crate::store::{
-    open_directory_store, open_memory_store, NamedGraph, Store, StoreLayer, StoreLayerBuilder,
+    open_directory_store, open_memory_store, AppendOnlyLayerBuilder, CompactReport, NamedGraph,
+    NamedGraphSnapshot, Store, StoreLayer, StoreLayerBuilder, Transaction, VerifyAllResult,
 };
+use crate::structure::Collation;
 
 lazy_static! {
     static ref RUNTIME: Runtime = Runtime::new().unwrap();
@@ -49,6 +55,20 @@ fn task_sync<T: 'static + Send, F: 'static + Future<Item = T, Error = io::Error>
     .wait()
 }
 
+/// A reader that can be passed to the async api.
+///
+/// Reads block the thread they run on. The sync api waits for its
+/// futures anyway, so this only ties up a runtime thread for a while.
+struct BlockingReader<R>(R);
+
+impl<R: Read> Read for BlockingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> AsyncRead for BlockingReader<R> {}
+
 /// A wrapper over a SimpleLayerBuilder, providing a thread-safe sharable interface
 ///
 /// The SimpleLayerBuilder requires one to have a mutable reference to
@@ -67,15 +87,81 @@ impl SyncStoreLayerBuilder {
     }
 
     /// Returns the name of the layer being built
+    ///
+    /// The name is fixed when the builder is created, and is the name
+    /// the layer will have after it is committed.
     pub fn name(&self) -> [u32; 5] {
         self.inner.name()
     }
 
+    /// Returns the store this builder will commit its layer to
+    pub fn store(&self) -> SyncStore {
+        SyncStore::wrap(self.inner.store())
+    }
+
+    /// The id the given subject has in the parent layer, or None if it is not known there
+    pub fn subject_id(&self, subject: &str) -> Result<Option<u64>, io::Error> {
+        task_sync(self.inner.subject_id(subject))
+    }
+
+    /// The id the given predicate has in the parent layer, or None if it is not known there
+    pub fn predicate_id(&self, predicate: &str) -> Result<Option<u64>, io::Error> {
+        task_sync(self.inner.predicate_id(predicate))
+    }
+
+    /// The id the given node object has in the parent layer, or None if it is not known there
+    pub fn object_node_id(&self, object: &str) -> Result<Option<u64>, io::Error> {
+        task_sync(self.inner.object_node_id(object))
+    }
+
+    /// The id the given value object has in the parent layer, or None if it is not known there
+    pub fn object_value_id(&self, object: &str) -> Result<Option<u64>, io::Error> {
+        task_sync(self.inner.object_value_id(object))
+    }
+
     /// Add a string triple
     pub fn add_string_triple(&self, triple: &StringTriple) -> Result<(), io::Error> {
         task_sync(self.inner.add_string_triple(triple))
     }
 
+    /// Add a triple whose object is a node
+    pub fn add_node_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        object: &str,
+    ) -> Result<(), io::Error> {
+        task_sync(self.inner.add_node_triple(subject, predicate, object))
+    }
+
+    /// Add a triple whose object is a value
+    pub fn add_value_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        value: &str,
+    ) -> Result<(), io::Error> {
+        task_sync(self.inner.add_value_triple(subject, predicate, value))
+    }
+
+    /// Add a string triple, but only if the condition triple exists in the parent layer
+    ///
+    /// See `StoreLayerBuilder::add_if_exists`.
+    pub fn add_if_exists(
+        &self,
+        to_add: &StringTriple,
+        condition: &StringTriple,
+    ) -> Result<bool, io::Error> {
+        task_sync(self.inner.add_if_exists(to_add, condition))
+    }
+
+    /// Read N-Triples from the given reader, and add them all to this builder
+    ///
+    /// See `StoreLayerBuilder::import_ntriples`.
+    pub fn import_ntriples<R: Read + Send + 'static>(&self, reader: R) -> Result<usize, io::Error> {
+        task_sync(self.inner.import_ntriples(BlockingReader(reader)))
+    }
+
     /// Add an id triple
     pub fn add_id_triple(&self, triple: IdTriple) -> Result<bool, io::Error> {
         task_sync(self.inner.add_id_triple(triple))
@@ -91,12 +177,140 @@ impl SyncStoreLayerBuilder {
         task_sync(self.inner.remove_id_triple(triple))
     }
 
+    /// Remove every triple in the parent layer that has the given subject
+    ///
+    /// Returns the number of triples that were removed.
+    pub fn remove_subject(&self, subject: &str) -> Result<usize, io::Error> {
+        task_sync(self.inner.remove_subject(subject))
+    }
+
+    /// Remove every triple in the parent layer that has the given node as subject or object
+    ///
+    /// Returns the number of triples that were removed.
+    pub fn remove_subject_fully(&self, subject: &str) -> Result<usize, io::Error> {
+        task_sync(self.inner.remove_subject_fully(subject))
+    }
+
+    /// Drop added string triples that already exist in the parent layer
+    ///
+    /// This is off by default. See `LayerBuilder::set_skip_existing`.
+    pub fn set_skip_existing(&self, skip_existing: bool) -> Result<(), io::Error> {
+        task_sync(self.inner.set_skip_existing(skip_existing))
+    }
+
+    /// Sort the dictionaries of the new layer with the given collation
+    ///
+    /// See `StoreLayerBuilder::set_collation`.
+    pub fn set_collation(&self, collation: Arc<dyn Collation>) -> Result<(), io::Error> {
+        task_sync(self.inner.set_collation(collation))
+    }
+
+    /// Spill added triples to temporary files in the given directory once `threshold` of them are held in memory
+    ///
+    /// See `StoreLayerBuilder::set_spill_threshold`.
+    pub fn set_spill_threshold<P: Into<PathBuf>>(
+        &self,
+        threshold: usize,
+        temp_dir: P,
+    ) -> Result<(), io::Error> {
+        task_sync(self.inner.set_spill_threshold(threshold, temp_dir.into()))
+    }
+
+    /// Limit the length in bytes of value objects added as string triples
+    ///
+    /// Adding a string triple with a longer value returns an
+    /// `InvalidInput` error. There is no limit by default.
+    pub fn set_max_value_length(&self, max: Option<usize>) -> Result<(), io::Error> {
+        task_sync(self.inner.set_max_value_length(max))
+    }
+
+    /// Limit the length in bytes of subjects and node objects added as string triples
+    pub fn set_max_node_length(&self, max: Option<usize>) -> Result<(), io::Error> {
+        task_sync(self.inner.set_max_node_length(max))
+    }
+
+    /// Limit the length in bytes of predicates added as string triples
+    pub fn set_max_predicate_length(&self, max: Option<usize>) -> Result<(), io::Error> {
+        task_sync(self.inner.set_max_predicate_length(max))
+    }
+
+    /// Set a metadata entry for the layer being built
+    ///
+    /// Metadata is stored alongside the layer when it is committed,
+    /// and can be retrieved using `SyncStoreLayer::metadata`.
+    pub fn set_metadata(&self, key: &str, value: &str) -> Result<(), io::Error> {
+        task_sync(self.inner.set_metadata(key, value))
+    }
+
     /// Commit the layer to storage
     pub fn commit(&self) -> Result<SyncStoreLayer, io::Error> {
         let inner = task_sync(self.inner.commit());
 
         inner.map(|i| SyncStoreLayer::wrap(i))
     }
+
+    /// Commit the layer to storage, reporting progress to the given callback
+    ///
+    /// See `StoreLayerBuilder::commit_with_progress`.
+    pub fn commit_with_progress<F: 'static + Fn(CommitProgress) + Send + Sync>(
+        &self,
+        progress: F,
+    ) -> Result<SyncStoreLayer, io::Error> {
+        task_sync(self.inner.commit_with_progress(progress)).map(SyncStoreLayer::wrap)
+    }
+
+    /// Commit the layer to storage, marking it as staged
+    ///
+    /// See `StoreLayerBuilder::commit_staged` for what staging means.
+    pub fn commit_staged(&self) -> Result<SyncStoreLayer, io::Error> {
+        task_sync(self.inner.commit_staged()).map(SyncStoreLayer::wrap)
+    }
+}
+
+/// A builder for base layers whose triples arrive in subject order
+///
+/// See `AppendOnlyLayerBuilder`.
+pub struct SyncAppendOnlyLayerBuilder {
+    inner: AppendOnlyLayerBuilder,
+}
+
+impl SyncAppendOnlyLayerBuilder {
+    fn wrap(inner: AppendOnlyLayerBuilder) -> Self {
+        SyncAppendOnlyLayerBuilder { inner }
+    }
+
+    /// Add a string triple
+    ///
+    /// Returns an `InvalidInput` error if the subject of the triple
+    /// comes before the subject of the previously added triple.
+    pub fn add_string_triple(&self, triple: &StringTriple) -> Result<(), io::Error> {
+        task_sync(self.inner.add_string_triple(triple))
+    }
+
+    /// Add a triple whose object is a node
+    pub fn add_node_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        object: &str,
+    ) -> Result<(), io::Error> {
+        task_sync(self.inner.add_node_triple(subject, predicate, object))
+    }
+
+    /// Add a triple whose object is a value
+    pub fn add_value_triple(
+        &self,
+        subject: &str,
+        predicate: &str,
+        value: &str,
+    ) -> Result<(), io::Error> {
+        task_sync(self.inner.add_value_triple(subject, predicate, value))
+    }
+
+    /// Commit the layer to storage
+    pub fn commit(&self) -> Result<SyncStoreLayer, io::Error> {
+        task_sync(self.inner.commit()).map(SyncStoreLayer::wrap)
+    }
 }
 
 /// A layer that keeps track of the store it came out of, allowing the creation of a layer builder on top of this layer
@@ -117,6 +331,34 @@ impl SyncStoreLayer {
         inner.map(|i| SyncStoreLayerBuilder::wrap(i))
     }
 
+    /// Returns the metadata that was stored with this layer
+    ///
+    /// Layers that were committed without metadata return an empty map.
+    pub fn metadata(&self) -> Result<HashMap<String, String>, io::Error> {
+        task_sync(self.inner.metadata())
+    }
+
+    /// Create a new base layer containing exactly the triples that are visible in this layer
+    ///
+    /// See `StoreLayer::squash` for details.
+    pub fn squash(&self) -> Result<SyncStoreLayer, io::Error> {
+        task_sync(self.inner.squash()).map(SyncStoreLayer::wrap)
+    }
+
+    /// Returns the triples added and removed between the given ancestor and this layer
+    ///
+    /// See `StoreLayer::delta_from`.
+    pub fn delta_from(&self, ancestor: &SyncStoreLayer) -> Result<LayerDiff, io::Error> {
+        task_sync(self.inner.delta_from(&ancestor.inner))
+    }
+
+    /// Write all triples that are visible in this layer to the given writer as N-Triples
+    ///
+    /// See `StoreLayer::export_ntriples`.
+    pub fn export_ntriples<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        crate::layer::export_ntriples(&self.inner, writer)
+    }
+
     pub fn parent(&self) -> Option<SyncStoreLayer> {
         self.inner.parent().map(|p| SyncStoreLayer { inner: p })
     }
@@ -131,6 +373,50 @@ impl Layer for SyncStoreLayer {
         (&self.inner as &dyn Layer).parent()
     }
 
+    fn layer_type(&self) -> LayerType {
+        self.inner.layer_type()
+    }
+
+    fn may_contain_triple(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        self.inner.may_contain_triple(subject, predicate, object)
+    }
+
+    fn scan_hint(&self, hint: ScanHint) {
+        self.inner.scan_hint(hint)
+    }
+
+    fn statistics(&self) -> Arc<LayerStatistics> {
+        self.inner.statistics()
+    }
+
+    fn triple_addition_counts(&self) -> LayerTripleCounts {
+        self.inner.triple_addition_counts()
+    }
+
+    fn triple_removal_counts(&self) -> LayerTripleCounts {
+        self.inner.triple_removal_counts()
+    }
+
+    fn predicate_addition_count(&self, predicate: u64) -> usize {
+        self.inner.predicate_addition_count(predicate)
+    }
+
+    fn predicate_removal_count(&self, predicate: u64) -> usize {
+        self.inner.predicate_removal_count(predicate)
+    }
+
+    fn triples(&self) -> Box<dyn Iterator<Item = IdTriple>> {
+        self.inner.triples()
+    }
+
+    fn collation(&self) -> Arc<dyn Collation> {
+        self.inner.collation()
+    }
+
+    fn triples_for_subjects(&self, subjects: &[u64]) -> Box<dyn Iterator<Item = IdTriple>> {
+        self.inner.triples_for_subjects(subjects)
+    }
+
     fn node_dict_id(&self, subject: &str) -> Option<u64> {
         self.inner.node_dict_id(subject)
     }
@@ -143,6 +429,13 @@ impl Layer for SyncStoreLayer {
         self.inner.node_dict_len()
     }
 
+    fn node_dict_entries_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = (u64, String)> + 'a> {
+        self.inner.node_dict_entries_with_prefix(prefix)
+    }
+
     fn value_dict_len(&self) -> usize {
         self.inner.value_dict_len()
     }
@@ -151,6 +444,18 @@ impl Layer for SyncStoreLayer {
         self.inner.value_dict_get(id)
     }
 
+    fn node_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.inner.node_dict_get_checked(id)
+    }
+
+    fn predicate_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.inner.predicate_dict_get_checked(id)
+    }
+
+    fn value_dict_get_checked(&self, id: usize) -> io::Result<Option<String>> {
+        self.inner.value_dict_get_checked(id)
+    }
+
     fn node_dict_get(&self, id: usize) -> Option<String> {
         self.inner.node_dict_get(id)
     }
@@ -256,6 +561,34 @@ impl Layer for SyncStoreLayer {
     }
 }
 
+/// A read-only view of a database, pinned to a layer from its history
+///
+/// See `NamedGraphSnapshot`.
+#[derive(Clone)]
+pub struct SyncNamedGraphSnapshot {
+    inner: NamedGraphSnapshot,
+}
+
+impl SyncNamedGraphSnapshot {
+    fn wrap(inner: NamedGraphSnapshot) -> Self {
+        Self { inner }
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    /// Returns the layer this snapshot is pinned to
+    pub fn head(&self) -> SyncStoreLayer {
+        SyncStoreLayer::wrap(self.inner.head().clone())
+    }
+
+    /// Returns the name of the layer this snapshot is pinned to
+    pub fn head_name(&self) -> [u32; 5] {
+        self.inner.head_name()
+    }
+}
+
 /// A named graph in terminus-store.
 ///
 /// Named graphs in terminus-store are basically just a label pointing
@@ -283,13 +616,101 @@ impl SyncNamedGraph {
         inner.map(|i| i.map(|i| SyncStoreLayer::wrap(i)))
     }
 
+    /// Returns the name of the layer this database points at, or None if it has no head
+    pub fn head_name(&self) -> Result<Option<[u32; 5]>, io::Error> {
+        task_sync(self.inner.head_name())
+    }
+
+    /// Returns true if this database has a head layer without any triples
+    ///
+    /// See `NamedGraph::is_empty` for how this differs from having no head.
+    pub fn is_empty(&self) -> Result<bool, io::Error> {
+        task_sync(self.inner.is_empty())
+    }
+
+    /// Returns the layer this database points at, along with the version of the database label
+    ///
+    /// See `NamedGraph::head_with_version`.
+    pub fn head_with_version(&self) -> Result<(Option<SyncStoreLayer>, u64), io::Error> {
+        task_sync(self.inner.head_with_version())
+            .map(|(head, version)| (head.map(SyncStoreLayer::wrap), version))
+    }
+
+    /// Open a read-only snapshot of this database as it was at the given version
+    ///
+    /// See `NamedGraph::head_at_version` for when this returns None.
+    pub fn head_at_version(
+        &self,
+        version: u64,
+    ) -> Result<Option<SyncNamedGraphSnapshot>, io::Error> {
+        task_sync(self.inner.head_at_version(version)).map(|s| s.map(SyncNamedGraphSnapshot::wrap))
+    }
+
+    /// Create a layer builder on top of the current head of this database
+    ///
+    /// If the database doesn't have a head yet, this returns a
+    /// builder for a new base layer instead.
+    pub fn open_write(&self) -> Result<SyncStoreLayerBuilder, io::Error> {
+        let inner = task_sync(self.inner.open_write());
+
+        inner.map(SyncStoreLayerBuilder::wrap)
+    }
+
     /// Set the database label to the given layer if it is a valid ancestor, returning false otherwise
     pub fn set_head(&self, layer: &SyncStoreLayer) -> Result<bool, io::Error> {
         task_sync(self.inner.set_head(&layer.inner))
     }
+
+    /// Set the database label to the given layer if the label is still at the given version, returning false otherwise
+    ///
+    /// See `NamedGraph::set_head_if_version`.
+    pub fn set_head_if_version(
+        &self,
+        layer: &SyncStoreLayer,
+        expected_version: u64,
+    ) -> Result<bool, io::Error> {
+        task_sync(
+            self.inner
+                .set_head_if_version(&layer.inner, expected_version),
+        )
+    }
+
+    /// Replace the head of this database with a squashed copy of it, returning false if it was not replaced
+    ///
+    /// See `NamedGraph::squash_head`.
+    pub fn squash_head(&self) -> Result<bool, io::Error> {
+        task_sync(self.inner.squash_head())
+    }
+}
+
+/// A set of database heads to be set all at once
+pub struct SyncTransaction {
+    inner: Transaction,
+}
+
+impl SyncTransaction {
+    fn wrap(inner: Transaction) -> Self {
+        Self { inner }
+    }
+
+    /// Stage the given layer as the new head of the given database
+    ///
+    /// This replaces any head staged earlier for the same database.
+    pub fn set_head(&mut self, database: &SyncNamedGraph, layer: &SyncStoreLayer) {
+        self.inner.set_head(&database.inner, &layer.inner)
+    }
+
+    /// Set all staged heads, returning false if none were set
+    pub fn commit(self) -> Result<bool, io::Error> {
+        task_sync(self.inner.commit())
+    }
 }
 
 /// A store, storing a set of layers and database labels pointing to these layers
+///
+/// Dropping a store does not guarantee that everything written
+/// through it is durably stored. Call `flush` before shutting down
+/// to ensure this.
 pub struct SyncStore {
     inner: Store,
 }
@@ -303,9 +724,26 @@ impl SyncStore {
         Self { inner }
     }
 
+    /// Build a bloom filter for every layer committed through this store
+    ///
+    /// See `Store::with_bloom_filter` for the tradeoffs involved.
+    pub fn with_bloom_filter(self, false_positive_rate: f64) -> Result<SyncStore, io::Error> {
+        self.inner
+            .with_bloom_filter(false_positive_rate)
+            .map(Self::wrap)
+    }
+
+    /// Put the temporary files used for squashing layers in the given directory
+    ///
+    /// See `Store::with_squash_temp_dir`.
+    pub fn with_squash_temp_dir<P: Into<PathBuf>>(self, path: P) -> SyncStore {
+        Self::wrap(self.inner.with_squash_temp_dir(path))
+    }
+
     /// Create a new database with the given name
     ///
-    /// If the database already exists, this will return an error
+    /// If the database already exists, this will return an error of
+    /// kind `AlreadyExists` that wraps a `DatabaseAlreadyExists`.
     pub fn create(&self, label: &str) -> Result<SyncNamedGraph, io::Error> {
         let inner = task_sync(self.inner.create(label));
 
@@ -319,6 +757,52 @@ impl SyncStore {
         inner.map(|i| i.map(|i| SyncNamedGraph::wrap(i)))
     }
 
+    /// Delete the database with the given name, returning false if it did not exist
+    ///
+    /// See `Store::delete`.
+    pub fn delete(&self, label: &str) -> Result<bool, io::Error> {
+        task_sync(self.inner.delete(label))
+    }
+
+    /// Check that the head layer of every database can be read
+    ///
+    /// See `Store::verify_all`.
+    pub fn verify_all(&self) -> Result<VerifyAllResult, io::Error> {
+        task_sync(self.inner.verify_all())
+    }
+
+    /// Returns the names of all databases, in sorted order
+    ///
+    /// See `Store::databases`.
+    pub fn databases(&self) -> Result<Vec<String>, io::Error> {
+        task_sync(self.inner.databases())
+    }
+
+    /// Returns the names of all databases whose name starts with the given prefix, in sorted order
+    ///
+    /// See `Store::databases_with_prefix`.
+    pub fn databases_with_prefix(&self, prefix: &str) -> Result<Vec<String>, io::Error> {
+        task_sync(self.inner.databases_with_prefix(prefix))
+    }
+
+    /// Open a read-only snapshot of a database at a layer from its history
+    ///
+    /// See `Store::open_at` for when this returns None.
+    pub fn open_at(
+        &self,
+        label: &str,
+        layer: [u32; 5],
+    ) -> Result<Option<SyncNamedGraphSnapshot>, io::Error> {
+        task_sync(self.inner.open_at(label, layer)).map(|s| s.map(SyncNamedGraphSnapshot::wrap))
+    }
+
+    /// Returns the number of layers in the chain ending in the given layer.
+    ///
+    /// Only the parent pointers of the layer store are followed.
+    pub fn layer_chain_depth(&self, layer: [u32; 5]) -> Result<usize, io::Error> {
+        task_sync(self.inner.layer_chain_depth(layer))
+    }
+
     pub fn get_layer_from_id(
         &self,
         layer: [u32; 5],
@@ -328,6 +812,24 @@ impl SyncStore {
         inner.map(|layer| layer.map(|l| SyncStoreLayer::wrap(l)))
     }
 
+    /// Serialize the difference between two layers in this store
+    ///
+    /// See `Store::serialized_diff` for details.
+    pub fn serialized_diff(&self, from: [u32; 5], to: [u32; 5]) -> Result<Vec<u8>, io::Error> {
+        task_sync(self.inner.serialized_diff(from, to))
+    }
+
+    /// Build a child layer on top of the given base layer, applying a serialized diff to it
+    ///
+    /// See `Store::apply_serialized_diff` for details.
+    pub fn apply_serialized_diff(
+        &self,
+        base: [u32; 5],
+        diff: &[u8],
+    ) -> Result<SyncStoreLayer, io::Error> {
+        task_sync(self.inner.apply_serialized_diff(base, diff)).map(SyncStoreLayer::wrap)
+    }
+
     /// Create a base layer builder, unattached to any database label
     ///
     /// After having committed it, use `set_head` on a `NamedGraph` to attach it.
@@ -336,6 +838,105 @@ impl SyncStore {
 
         inner.map(|i| SyncStoreLayerBuilder::wrap(i))
     }
+
+    /// Create a builder for a base layer whose triples are added in subject order
+    ///
+    /// See `AppendOnlyLayerBuilder`. After having committed it, use
+    /// `set_head` on a `NamedGraph` to attach it.
+    pub fn create_base_layer_append_only(&self) -> SyncAppendOnlyLayerBuilder {
+        SyncAppendOnlyLayerBuilder::wrap(self.inner.create_base_layer_append_only())
+    }
+
+    /// Start a transaction for setting the heads of multiple databases at once
+    pub fn transaction(&self) -> SyncTransaction {
+        SyncTransaction::wrap(self.inner.transaction())
+    }
+
+    /// Set the heads of several databases at once, returning false if none were set
+    ///
+    /// Either every database moves to its new head, or none of them
+    /// does. See `Store::set_heads`.
+    pub fn set_heads(&self, updates: &[(String, &SyncStoreLayer)]) -> Result<bool, io::Error> {
+        let updates: Vec<_> = updates
+            .iter()
+            .map(|(database, layer)| (database.clone(), &layer.inner))
+            .collect();
+        task_sync(self.inner.set_heads(&updates))
+    }
+
+    /// Returns the names of all layers in this store
+    ///
+    /// This includes layers that are not reachable from any database label.
+    pub fn layer_names(&self) -> Result<Vec<[u32; 5]>, io::Error> {
+        task_sync(self.inner.layer_names())
+    }
+
+    /// Returns the number of bytes the layers of the given database take up on disk
+    ///
+    /// See `Store::database_disk_size` for details.
+    pub fn database_disk_size(&self, label: &str) -> Result<u64, io::Error> {
+        task_sync(self.inner.database_disk_size(label))
+    }
+
+    /// Returns the number of bytes on disk taken up by layers only reachable from the given database
+    ///
+    /// See `Store::exclusive_disk_size` for details.
+    pub fn exclusive_disk_size(&self, label: &str) -> Result<u64, io::Error> {
+        task_sync(self.inner.exclusive_disk_size(label))
+    }
+
+    /// Ensure that all labels and layers written so far are durably stored
+    pub fn flush(&self) -> Result<(), io::Error> {
+        task_sync(self.inner.flush())
+    }
+
+    /// Discard the version history of all databases
+    ///
+    /// See `Store::compact_labels`.
+    pub fn compact_labels(&self) -> Result<(), io::Error> {
+        task_sync(self.inner.compact_labels())
+    }
+
+    /// Squash the head of every database into a base layer, and delete all layers no longer needed
+    ///
+    /// See `Store::compact` for details.
+    pub fn compact(&self) -> Result<CompactReport, io::Error> {
+        task_sync(self.inner.compact())
+    }
+
+    /// Returns the names of all staged layers that are not yet part of a database
+    ///
+    /// See `Store::staged_layers` for details.
+    pub fn staged_layers(&self) -> Result<Vec<[u32; 5]>, io::Error> {
+        task_sync(self.inner.staged_layers())
+    }
+
+    /// Delete the given layer, returning false if it does not exist
+    ///
+    /// See `Store::delete_layer` for when a layer can be deleted.
+    pub fn delete_layer(&self, name: [u32; 5]) -> Result<bool, io::Error> {
+        task_sync(self.inner.delete_layer(name))
+    }
+
+    /// Copy this store into a fresh directory, returning a store for the copy
+    ///
+    /// See `Store::clone_to_directory` for what is copied.
+    pub fn clone_to_directory(&self, path: PathBuf) -> Result<SyncStore, io::Error> {
+        task_sync(self.inner.clone_to_directory(path)).map(SyncStore::wrap)
+    }
+
+    /// Create a base layer out of triples that are already in sorted order
+    ///
+    /// Unlike a layer builder, this will not sort the triples before
+    /// writing them. An out-of-order triple results in an error.
+    pub fn bulk_load_base_presorted<I: 'static + Iterator<Item = StringTriple> + Send>(
+        &self,
+        triples: I,
+    ) -> Result<SyncStoreLayer, io::Error> {
+        let inner = task_sync(self.inner.bulk_load_base_presorted(triples));
+
+        inner.map(SyncStoreLayer::wrap)
+    }
 }
 
 /// Open a store that is entirely in memory
@@ -353,6 +954,8 @@ pub fn open_sync_directory_store<P: Into<PathBuf>>(path: P) -> SyncStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::StoreBuilder;
+    use crate::structure::CaseInsensitiveCollation;
     use tempfile::tempdir;
 
     #[test]
@@ -388,10 +991,22 @@ mod tests {
     }
 
     #[test]
-    fn create_and_manipulate_sync_directory_database() {
-        let dir = tempdir().unwrap();
-        let store = open_sync_directory_store(dir.path());
-        let database = store.create("foodb").unwrap();
+    fn store_and_retrieve_sync_memory_layer_metadata() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.set_metadata("message", "hello").unwrap();
+        let layer = builder.commit().unwrap();
+
+        let metadata = layer.metadata().unwrap();
+        assert_eq!(1, metadata.len());
+        assert_eq!("hello", metadata["message"]);
+    }
+
+    #[test]
+    fn create_and_manipulate_sync_directory_database() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        let database = store.create("foodb").unwrap();
 
         let head = database.head().unwrap();
         assert!(head.is_none());
@@ -420,6 +1035,1147 @@ mod tests {
         assert!(layer.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
     }
 
+    #[test]
+    fn set_head_if_version_compares_versions() {
+        let store = open_sync_memory_store();
+        let database = store.create("foodb").unwrap();
+
+        let layer1 = store.create_base_layer().unwrap().commit().unwrap();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let layer2 = builder.commit().unwrap();
+
+        let (_, version) = database.head_with_version().unwrap();
+        assert!(database.set_head_if_version(&layer1, version).unwrap());
+        assert!(!database.set_head_if_version(&layer2, version).unwrap());
+        assert_eq!(layer1.name(), database.head().unwrap().unwrap().name());
+
+        // layer2 is not a descendant of layer1, but that doesn't matter here
+        assert!(database.set_head_if_version(&layer2, version + 1).unwrap());
+        let (head, new_version) = database.head_with_version().unwrap();
+        assert_eq!(layer2.name(), head.unwrap().name());
+        assert_eq!(version + 2, new_version);
+    }
+
+    #[test]
+    fn list_staged_layers() {
+        let store = open_sync_memory_store();
+        let database = store.create("foodb").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let staged_base = builder.commit_staged().unwrap();
+        let builder = staged_base.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let staged_child = builder.commit_staged().unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let unstaged = builder.commit().unwrap();
+
+        let mut expected = vec![staged_base.name(), staged_child.name()];
+        expected.sort();
+        assert_eq!(expected, store.staged_layers().unwrap());
+        assert!(!store.staged_layers().unwrap().contains(&unstaged.name()));
+
+        database.set_head(&staged_child).unwrap();
+        assert!(store.staged_layers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn squash_directory_layer_stack() {
+        let dir = tempdir().unwrap();
+        let scratch = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path()).with_squash_temp_dir(scratch.path());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        builder.add_node_triple("cow", "likes", "duck").unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        builder
+            .remove_string_triple(&StringTriple::new_node("cow", "likes", "duck"))
+            .unwrap();
+        let child = builder.commit().unwrap();
+
+        let squashed = child.squash().unwrap();
+        assert!(squashed.parent().is_none());
+        assert_ne!(child.name(), squashed.name());
+
+        let mut triples: Vec<_> = squashed
+            .triples()
+            .map(|t| squashed.id_triple_to_string(&t).unwrap())
+            .collect();
+        triples.sort();
+        assert_eq!(
+            vec![
+                StringTriple::new_value("cow", "says", "moo"),
+                StringTriple::new_value("duck", "says", "quack"),
+            ],
+            triples
+        );
+
+        let reopened = store.get_layer_from_id(squashed.name()).unwrap().unwrap();
+        assert!(reopened.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+        assert!(!reopened.string_triple_exists(&StringTriple::new_node("cow", "likes", "duck")));
+    }
+
+    #[test]
+    fn squash_database_head() {
+        let store = open_sync_memory_store();
+        let database = store.create("foodb").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        assert!(database.set_head(&base).unwrap());
+        // a base layer has nothing to squash
+        assert!(!database.squash_head().unwrap());
+
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        builder.set_metadata("author", "farmer").unwrap();
+        let child = builder.commit().unwrap();
+        assert!(database.set_head(&child).unwrap());
+
+        assert!(database.squash_head().unwrap());
+        let head = database.head().unwrap().unwrap();
+        assert!(head.parent().is_none());
+        assert_ne!(child.name(), head.name());
+        assert_eq!(2, head.triple_count());
+        assert_eq!(
+            Some(&"farmer".to_owned()),
+            head.metadata().unwrap().get("author")
+        );
+
+        // the new head can be written on top of as usual
+        let builder = head.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let next = builder.commit().unwrap();
+        assert!(database.set_head(&next).unwrap());
+    }
+
+    #[test]
+    fn open_database_at_historical_layer() {
+        let store = open_sync_memory_store();
+        let database = store.create("foodb").unwrap();
+        assert!(store.open_at("foodb", [1, 2, 3, 4, 5]).unwrap().is_none());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let child = builder.commit().unwrap();
+        database.set_head(&child).unwrap();
+
+        let snapshot = store.open_at("foodb", base.name()).unwrap().unwrap();
+        assert_eq!("foodb", snapshot.name());
+        assert_eq!(base.name(), snapshot.head_name());
+        assert!(!snapshot
+            .head()
+            .string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+
+        let builder = child.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let grandchild = builder.commit().unwrap();
+        database.set_head(&grandchild).unwrap();
+
+        let snapshot = store.open_at("foodb", child.name()).unwrap().unwrap();
+        assert_eq!(child.name(), snapshot.head_name());
+        assert_eq!(
+            grandchild.name(),
+            store
+                .open_at("foodb", grandchild.name())
+                .unwrap()
+                .unwrap()
+                .head_name()
+        );
+
+        let unrelated = store.create_base_layer().unwrap().commit().unwrap();
+        assert!(store.open_at("foodb", unrelated.name()).unwrap().is_none());
+        assert!(store.open_at("bardb", base.name()).unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_is_not_disturbed_by_concurrent_commit() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        let database = store.create("foodb").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        for i in 0..1000 {
+            builder
+                .add_value_triple(&format!("node{}", i), "value", &format!("{}", i))
+                .unwrap();
+        }
+        let base = builder.commit().unwrap();
+        database.set_head(&base).unwrap();
+
+        let (started_sender, started_receiver) = std::sync::mpsc::channel();
+        let (committed_sender, committed_receiver) = std::sync::mpsc::channel();
+        let head = database.head().unwrap().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut result = Vec::new();
+            for (count, triple) in head.triples().enumerate() {
+                if count == 100 {
+                    // let the writer move the head while we're halfway through
+                    started_sender.send(()).unwrap();
+                    committed_receiver.recv().unwrap();
+                }
+                result.push(head.id_triple_to_string(&triple).unwrap());
+            }
+
+            result
+        });
+
+        started_receiver.recv().unwrap();
+        let builder = base.open_write().unwrap();
+        for i in 0..500 {
+            builder
+                .remove_string_triple(&StringTriple::new_value(
+                    &format!("node{}", i),
+                    "value",
+                    &format!("{}", i),
+                ))
+                .unwrap();
+            builder
+                .add_value_triple(&format!("node{}", i), "value", "changed")
+                .unwrap();
+        }
+        let child = builder.commit().unwrap();
+        assert!(database.set_head(&child).unwrap());
+        committed_sender.send(()).unwrap();
+
+        let mut result = reader.join().unwrap();
+        result.sort();
+        let mut expected: Vec<_> = (0..1000)
+            .map(|i| StringTriple::new_value(&format!("node{}", i), "value", &format!("{}", i)))
+            .collect();
+        expected.sort();
+        assert_eq!(expected, result);
+
+        let head = database.head().unwrap().unwrap();
+        assert_eq!(child.name(), head.name());
+        assert!(head.string_triple_exists(&StringTriple::new_value("node1", "value", "changed")));
+    }
+
+    #[test]
+    fn store_builder_squashes_deep_heads() {
+        let dir = tempdir().unwrap();
+        let store = SyncStore::wrap(
+            StoreBuilder::new()
+                .directory(dir.path())
+                .cache_capacity(4)
+                .sync_on_commit(true)
+                .max_chain_depth(2)
+                .unwrap()
+                .build(),
+        );
+        let database = store.create("foodb").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        assert!(database.set_head(&base).unwrap());
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let child = builder.commit().unwrap();
+        assert!(database.set_head(&child).unwrap());
+        assert_eq!(
+            Some(child.name()),
+            database.head().unwrap().map(|h| h.name())
+        );
+
+        let builder = child.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        builder.set_metadata("author", "me").unwrap();
+        let grandchild = builder.commit().unwrap();
+        assert_eq!(Some(child.name()), grandchild.parent().map(|p| p.name()));
+        assert!(database.set_head(&grandchild).unwrap());
+
+        let reopened = open_sync_directory_store(dir.path());
+        let head = reopened
+            .open("foodb")
+            .unwrap()
+            .unwrap()
+            .head()
+            .unwrap()
+            .unwrap();
+        assert_ne!(grandchild.name(), head.name());
+        assert!(head.parent().is_none());
+        assert!(head.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(head.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+        assert_eq!(
+            Some(&"me".to_owned()),
+            head.metadata().unwrap().get("author")
+        );
+
+        // layers can keep being committed on top of the squashed head
+        let builder = head.open_write().unwrap();
+        builder.add_value_triple("horse", "says", "neigh").unwrap();
+        let next = builder.commit().unwrap();
+        assert!(database.set_head(&next).unwrap());
+    }
+
+    #[test]
+    fn store_builder_rejects_zero_chain_depth() {
+        let error = StoreBuilder::new().max_chain_depth(0).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn invalid_store_settings_are_rejected() {
+        for rate in &[0.0, 1.0, -0.5, 2.0, f64::NAN] {
+            let error = StoreBuilder::new().bloom_filter(*rate).err().unwrap();
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+            let error = open_sync_memory_store()
+                .with_bloom_filter(*rate)
+                .err()
+                .unwrap();
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        }
+
+        let policy = crate::store::ChangeStreamPolicy::DropNewest(0);
+        let error = StoreBuilder::new()
+            .change_stream_policy(policy)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        let error = open_memory_store()
+            .with_change_stream_policy(policy)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn directory_layer_chain_depth() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let child = builder.commit().unwrap();
+        let builder = child.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let grandchild = builder.commit().unwrap();
+
+        assert_eq!(3, grandchild.chain_depth());
+
+        let reopened = open_sync_directory_store(dir.path());
+        assert_eq!(1, reopened.layer_chain_depth(base.name()).unwrap());
+        assert_eq!(3, reopened.layer_chain_depth(grandchild.name()).unwrap());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            reopened
+                .layer_chain_depth([1, 2, 3, 4, 5])
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn import_ntriples_into_builder() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let input = "# animals\n\
+                     <cow> <says> \"moo\" .\n\
+                     \n\
+                     <cow> <likes> _:duck .\r\n\
+                     _:duck <says> \"quack\"@en .";
+        assert_eq!(3, builder.import_ntriples(input.as_bytes()).unwrap());
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let layer = builder.commit().unwrap();
+
+        assert!(layer.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "_:duck")));
+        assert!(layer.string_triple_exists(&StringTriple::new_lang_value(
+            "_:duck", "says", "quack", "en"
+        )));
+        assert_eq!(4, layer.triple_count());
+
+        let builder = layer.open_write().unwrap();
+        let error = builder
+            .import_ntriples(&b"<pig> <says> \"oink\" .\n<pig> <says> oink .\n"[..])
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().starts_with("line 2:"));
+
+        // what is exported can be imported again
+        let mut output = Vec::new();
+        layer.export_ntriples(&mut output).unwrap();
+        assert_eq!(
+            "_:duck <says> \"quack\"@en .\n\
+             <cow> <likes> _:duck .\n\
+             <cow> <says> \"moo\" .\n\
+             <pig> <says> \"oink\" .\n",
+            String::from_utf8(output.clone()).unwrap()
+        );
+        let mut async_output = Vec::new();
+        layer
+            .inner
+            .export_ntriples(&mut async_output)
+            .wait()
+            .unwrap();
+        assert_eq!(output, async_output);
+        let reimport = store.create_base_layer().unwrap();
+        assert_eq!(
+            4,
+            reimport
+                .import_ntriples(std::io::Cursor::new(output))
+                .unwrap()
+        );
+        let reimported = reimport.commit().unwrap();
+        let diff = LayerDiff::between(&layer, &reimported).unwrap();
+        assert!(diff.additions.is_empty() && diff.removals.is_empty());
+
+        builder.set_max_value_length(Some(3)).unwrap();
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            builder
+                .import_ntriples(&b"<duck> <says> \"quack\" .\n"[..])
+                .err()
+                .unwrap()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn delta_between_layer_and_ancestor() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let base = builder.commit().unwrap();
+
+        let builder = base.open_write().unwrap();
+        builder
+            .remove_string_triple(&StringTriple::new_value("cow", "says", "moo"))
+            .unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        builder.add_node_triple("duck", "likes", "pig").unwrap();
+        let child = builder.commit().unwrap();
+
+        let builder = child.open_write().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        builder
+            .remove_string_triple(&StringTriple::new_node("duck", "likes", "pig"))
+            .unwrap();
+        builder
+            .remove_string_triple(&StringTriple::new_value("pig", "says", "oink"))
+            .unwrap();
+        let grandchild = builder.commit().unwrap();
+
+        let delta = grandchild.delta_from(&base).unwrap();
+        assert_eq!(
+            vec![StringTriple::new_value("duck", "says", "quack")],
+            delta.additions
+        );
+        assert_eq!(
+            vec![StringTriple::new_value("pig", "says", "oink")],
+            delta.removals
+        );
+        assert_eq!(LayerDiff::between(&base, &grandchild).unwrap(), delta);
+
+        let delta = grandchild.delta_from(&child).unwrap();
+        assert_eq!(LayerDiff::between(&child, &grandchild).unwrap(), delta);
+        assert_eq!(2, delta.removals.len());
+
+        let delta = child.delta_from(&child).unwrap();
+        assert!(delta.additions.is_empty() && delta.removals.is_empty());
+
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            base.delta_from(&child).err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn list_all_databases() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        assert!(store.databases().unwrap().is_empty());
+
+        store.create("foo").unwrap();
+        store.create("bar").unwrap();
+        store.create("baz").unwrap();
+        assert!(store.delete("baz").unwrap());
+        // a label file that is still being written
+        std::fs::write(dir.path().join("partial.label"), b"1\n").unwrap();
+
+        assert_eq!(
+            vec!["bar".to_owned(), "foo".to_owned()],
+            store.databases().unwrap()
+        );
+
+        let store = open_sync_memory_store();
+        store.create("foo").unwrap();
+        assert_eq!(vec!["foo".to_owned()], store.databases().unwrap());
+    }
+
+    #[test]
+    fn namespaced_directory_databases() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        let people = store.create("tenant1/people").unwrap();
+        store.create("tenant1/places").unwrap();
+        store.create("tenant2/people").unwrap();
+        store.create("tenant1").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let layer = builder.commit().unwrap();
+        assert!(people.set_head(&layer).unwrap());
+
+        let reopened = open_sync_directory_store(dir.path());
+        assert_eq!(
+            vec!["tenant1/people".to_owned(), "tenant1/places".to_owned()],
+            reopened.databases_with_prefix("tenant1/").unwrap()
+        );
+        assert_eq!(4, reopened.databases_with_prefix("").unwrap().len());
+        let head = reopened
+            .open("tenant1/people")
+            .unwrap()
+            .unwrap()
+            .head()
+            .unwrap()
+            .unwrap();
+        assert_eq!(layer.name(), head.name());
+        assert!(reopened.open("tenant3/people").unwrap().is_none());
+    }
+
+    #[test]
+    fn append_only_builder_spills_to_temp_dir() {
+        let scratch = tempdir().unwrap();
+        let store = open_sync_memory_store().with_squash_temp_dir(scratch.path());
+
+        let empty = store.create_base_layer_append_only().commit().unwrap();
+        assert_eq!(0, empty.triples().count());
+
+        let builder = store.create_base_layer_append_only();
+        for i in 0..100 {
+            builder
+                .add_value_triple(&format!("{:03}", i), "value", &i.to_string())
+                .unwrap();
+        }
+        let layer = builder.commit().unwrap();
+        assert_eq!(100, layer.triples().count());
+        assert!(layer.string_triple_exists(&StringTriple::new_value("042", "value", "42")));
+        assert_eq!(0, std::fs::read_dir(scratch.path()).unwrap().count());
+    }
+
+    #[test]
+    fn append_only_builder_requires_subject_order() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer_append_only();
+        builder
+            .add_value_triple("2020-01-01", "temperature", "3")
+            .unwrap();
+        builder
+            .add_node_triple("2020-01-01", "station", "north")
+            .unwrap();
+        builder
+            .add_value_triple("2020-01-02", "temperature", "5")
+            .unwrap();
+        builder
+            .add_value_triple("2020-01-02", "temperature", "5")
+            .unwrap();
+        builder
+            .add_node_triple("2020-01-02", "station", "north")
+            .unwrap();
+
+        let error = builder
+            .add_value_triple("2020-01-01", "temperature", "4")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+
+        builder
+            .add_value_triple("2020-01-03", "temperature", "2")
+            .unwrap();
+        let layer = builder.commit().unwrap();
+        assert!(builder.commit().is_err());
+
+        let mut triples: Vec<_> = layer
+            .triples()
+            .map(|t| layer.id_triple_to_string(&t).unwrap())
+            .collect();
+        triples.sort();
+        assert_eq!(
+            vec![
+                StringTriple::new_node("2020-01-01", "station", "north"),
+                StringTriple::new_value("2020-01-01", "temperature", "3"),
+                StringTriple::new_node("2020-01-02", "station", "north"),
+                StringTriple::new_value("2020-01-02", "temperature", "5"),
+                StringTriple::new_value("2020-01-03", "temperature", "2"),
+            ],
+            triples
+        );
+        assert_eq!(
+            Some(StringTriple::new_value("2020-01-03", "temperature", "2")),
+            layer
+                .triples()
+                .last()
+                .and_then(|t| layer.id_triple_to_string(&t))
+        );
+    }
+
+    fn open_database_at_earlier_versions(store: SyncStore) {
+        let database = store.create("foo").unwrap();
+        assert!(database.head_at_version(0).unwrap().is_none());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let layer1 = builder.commit().unwrap();
+        assert!(database.set_head(&layer1).unwrap());
+
+        let builder = layer1.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let layer2 = builder.commit().unwrap();
+        assert!(database.set_head(&layer2).unwrap());
+
+        let builder = layer2.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let layer3 = builder.commit().unwrap();
+        let mut transaction = store.transaction();
+        transaction.set_head(&database, &layer3);
+        assert!(transaction.commit().unwrap());
+
+        let snapshot = database.head_at_version(1).unwrap().unwrap();
+        assert_eq!(layer1.name(), snapshot.head().name());
+        assert!(!snapshot
+            .head()
+            .string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+        let snapshot = database.head_at_version(2).unwrap().unwrap();
+        assert_eq!(layer2.name(), snapshot.head().name());
+        let snapshot = database.head_at_version(3).unwrap().unwrap();
+        assert_eq!(layer3.name(), snapshot.head().name());
+        assert!(database.head_at_version(4).unwrap().is_none());
+    }
+
+    #[test]
+    fn open_memory_database_at_earlier_versions() {
+        open_database_at_earlier_versions(open_sync_memory_store());
+    }
+
+    #[test]
+    fn open_directory_database_at_earlier_versions() {
+        let dir = tempdir().unwrap();
+        open_database_at_earlier_versions(open_sync_directory_store(dir.path()));
+
+        let store = open_sync_directory_store(dir.path());
+        let database = store.open("foo").unwrap().unwrap();
+        let first = database.head_at_version(1).unwrap().unwrap();
+        let third = database.head_at_version(3).unwrap().unwrap();
+        let grandparent = third.head().parent().unwrap().parent().unwrap();
+        assert_eq!(first.head().name(), grandparent.name());
+        assert!(database.head_at_version(0).unwrap().is_none());
+    }
+
+    fn compact_database_history(store: &SyncStore) {
+        let database = store.create("foo").unwrap();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let layer1 = builder.commit().unwrap();
+        assert!(database.set_head(&layer1).unwrap());
+        let builder = layer1.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let layer2 = builder.commit().unwrap();
+        assert!(database.set_head(&layer2).unwrap());
+
+        store.compact_labels().unwrap();
+
+        assert!(database.head_at_version(1).unwrap().is_none());
+        let snapshot = database.head_at_version(2).unwrap().unwrap();
+        assert_eq!(layer2.name(), snapshot.head().name());
+        assert_eq!(2, database.head_with_version().unwrap().1);
+
+        let builder = layer2.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let layer3 = builder.commit().unwrap();
+        assert!(database.set_head(&layer3).unwrap());
+        assert_eq!(3, database.head_with_version().unwrap().1);
+        let snapshot = database.head_at_version(2).unwrap().unwrap();
+        assert_eq!(layer2.name(), snapshot.head().name());
+        let snapshot = database.head_at_version(3).unwrap().unwrap();
+        assert_eq!(layer3.name(), snapshot.head().name());
+    }
+
+    #[test]
+    fn compact_memory_database_history() {
+        compact_database_history(&open_sync_memory_store());
+    }
+
+    #[test]
+    fn compact_directory_database_history() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        compact_database_history(&store);
+
+        let log = std::fs::read_to_string(dir.path().join("foo.versions")).unwrap();
+        assert_eq!(2, log.lines().count());
+        assert!(!dir.path().join("foo.versions.tmp").exists());
+
+        store.compact_labels().unwrap();
+        let log = std::fs::read_to_string(dir.path().join("foo.versions")).unwrap();
+        assert_eq!(1, log.lines().count());
+        assert!(log.starts_with("3 "));
+        assert_eq!(
+            vec!["foo".to_owned()],
+            store.databases_with_prefix("").unwrap()
+        );
+    }
+
+    #[test]
+    fn compact_squashes_chains_and_deletes_unreachable_layers() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let child = builder.commit().unwrap();
+        let builder = child.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let head = builder.commit().unwrap();
+        let farm = store.create("farm").unwrap();
+        assert!(farm.set_head(&head).unwrap());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cat", "says", "meow").unwrap();
+        let flat = builder.commit().unwrap();
+        let house = store.create("house").unwrap();
+        assert!(house.set_head(&flat).unwrap());
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("rock", "says", "nothing").unwrap();
+        let orphan = builder.commit().unwrap().name();
+
+        let triples: Vec<_> = head
+            .triples()
+            .map(|t| head.id_triple_to_string(&t).unwrap())
+            .collect();
+        let (base, child, head) = (base.name(), child.name(), head.name());
+
+        let report = store.compact().unwrap();
+        assert_eq!(1, report.chains_shortened);
+        assert_eq!(4, report.layers_deleted);
+        assert!(report.bytes_reclaimed > 0);
+
+        let new_head = farm.head().unwrap().unwrap();
+        assert_eq!(1, new_head.chain_depth());
+        let new_triples: Vec<_> = new_head
+            .triples()
+            .map(|t| new_head.id_triple_to_string(&t).unwrap())
+            .collect();
+        assert_eq!(triples, new_triples);
+        assert_eq!(flat.name(), house.head().unwrap().unwrap().name());
+
+        for layer in &[base, child, head, orphan] {
+            assert!(store.get_layer_from_id(*layer).unwrap().is_none());
+        }
+
+        assert_eq!(CompactReport::default(), store.compact().unwrap());
+    }
+
+    #[test]
+    fn delete_database_keeps_layers() {
+        let dir = tempdir().unwrap();
+        let store = open_sync_directory_store(dir.path());
+        let db = store.create("foo").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let layer = builder.commit().unwrap();
+        assert!(db.set_head(&layer).unwrap());
+
+        assert!(store.delete("foo").unwrap());
+        assert!(store.open("foo").unwrap().is_none());
+        assert!(!store.delete("foo").unwrap());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            db.set_head(&layer).err().unwrap().kind()
+        );
+
+        assert!(store.get_layer_from_id(layer.name()).unwrap().is_some());
+    }
+
+    #[test]
+    fn set_heads_moves_all_databases_or_none() {
+        let store = open_sync_memory_store();
+        let db1 = store.create("db1").unwrap();
+        let db2 = store.create("db2").unwrap();
+
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let other = builder.commit().unwrap();
+
+        assert!(store
+            .set_heads(&[("db1".to_owned(), &base), ("db2".to_owned(), &base)])
+            .unwrap());
+        assert_eq!(base.name(), db1.head().unwrap().unwrap().name());
+        assert_eq!(base.name(), db2.head().unwrap().unwrap().name());
+
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let child = builder.commit().unwrap();
+
+        // other is not a descendant of the head of db2, so db1 stays put too
+        assert!(!store
+            .set_heads(&[("db1".to_owned(), &child), ("db2".to_owned(), &other)])
+            .unwrap());
+        assert_eq!(base.name(), db1.head().unwrap().unwrap().name());
+        assert_eq!(base.name(), db2.head().unwrap().unwrap().name());
+
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            store
+                .set_heads(&[("db1".to_owned(), &child), ("db3".to_owned(), &child)])
+                .err()
+                .unwrap()
+                .kind()
+        );
+        assert_eq!(base.name(), db1.head().unwrap().unwrap().name());
+    }
+
+    #[test]
+    fn layers_keep_their_collation() {
+        let dir = tempdir().unwrap();
+        let name = {
+            let store = open_sync_directory_store(dir.path());
+            let builder = store.create_base_layer().unwrap();
+            builder
+                .set_collation(Arc::new(CaseInsensitiveCollation))
+                .unwrap();
+            for subject in &["bee", "Apple", "apple", "Bee", "banana"] {
+                builder.add_value_triple(subject, "is", "thing").unwrap();
+            }
+            let base = builder.commit().unwrap();
+
+            // the child layer sorts its own dictionaries the same way
+            let builder = base.open_write().unwrap();
+            builder.add_value_triple("Bear", "is", "thing").unwrap();
+            builder.add_value_triple("aardvark", "is", "thing").unwrap();
+            builder.commit().unwrap().name()
+        };
+
+        let store = open_sync_directory_store(dir.path());
+        let layer = store.get_layer_from_id(name).unwrap().unwrap();
+        assert_eq!("case_insensitive", layer.collation().name());
+        assert_eq!(
+            "case_insensitive",
+            layer.parent().unwrap().collation().name()
+        );
+
+        let subjects = |prefix| -> Vec<String> {
+            layer
+                .subjects_with_prefix(prefix, None)
+                .into_iter()
+                .map(|(_, s)| s)
+                .collect()
+        };
+        assert_eq!(
+            vec!["Apple", "aardvark", "apple", "Bear", "Bee", "banana", "bee"],
+            subjects("")
+        );
+        assert_eq!(vec!["Bear", "Bee"], subjects("B"));
+        assert_eq!(vec!["banana", "bee"], subjects("b"));
+        for subject in subjects("") {
+            assert!(layer.string_triple_exists(&StringTriple::new_value(&subject, "is", "thing")));
+        }
+        assert!(layer.subject_id("BEE").is_none());
+    }
+
+    #[test]
+    fn add_triple_if_condition_exists() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        let pig_is_animal = StringTriple::new_node("pig", "type", "animal");
+        assert!(!builder
+            .add_if_exists(
+                &StringTriple::new_value("pig", "alive", "yes"),
+                &pig_is_animal
+            )
+            .unwrap());
+        builder.add_string_triple(&pig_is_animal).unwrap();
+        builder.add_node_triple("rock", "type", "mineral").unwrap();
+        let base = builder.commit().unwrap();
+        assert_eq!(2, base.triples().count());
+
+        let builder = base.open_write().unwrap();
+        assert!(builder
+            .add_if_exists(
+                &StringTriple::new_value("pig", "alive", "yes"),
+                &pig_is_animal
+            )
+            .unwrap());
+        assert!(!builder
+            .add_if_exists(
+                &StringTriple::new_value("rock", "alive", "yes"),
+                &StringTriple::new_node("rock", "type", "animal"),
+            )
+            .unwrap());
+        let child = builder.commit().unwrap();
+
+        assert!(child.string_triple_exists(&StringTriple::new_value("pig", "alive", "yes")));
+        assert!(!child.string_triple_exists(&StringTriple::new_value("rock", "alive", "yes")));
+        assert_eq!(3, child.triples().count());
+    }
+
+    #[test]
+    fn verify_all_databases() {
+        let dir = tempdir().unwrap();
+        // layers are shared between stores on the same directory, so
+        // everything referring to the corrupted layer has to be dropped
+        // before reopening the store
+        let corrupt_name = {
+            let store = open_sync_directory_store(dir.path());
+            store.create("empty").unwrap();
+            let good = store.create("good").unwrap();
+            let bad = store.create("bad").unwrap();
+
+            let builder = store.create_base_layer().unwrap();
+            builder.add_value_triple("cow", "says", "moo").unwrap();
+            let layer = builder.commit().unwrap();
+            assert!(good.set_head(&layer).unwrap());
+
+            let builder = store.create_base_layer().unwrap();
+            builder.add_value_triple("pig", "says", "oink").unwrap();
+            let layer = builder.commit().unwrap();
+            assert!(bad.set_head(&layer).unwrap());
+
+            crate::storage::name_to_string(layer.name())
+        };
+
+        let path = dir
+            .path()
+            .join(&corrupt_name[0..3])
+            .join(&corrupt_name)
+            .join("node_dictionary_blocks.pfc");
+        let mut data = std::fs::read(&path).unwrap();
+        let pos = data.iter().position(|&b| b == b'p').unwrap();
+        data[pos] = 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let store = open_sync_directory_store(dir.path());
+        let results = store.verify_all().unwrap();
+        assert_eq!(3, results.len());
+        assert_eq!("bad", results[0].0);
+        assert!(results[0]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("not valid UTF-8"));
+        assert_eq!(("empty".to_owned(), Ok(())), results[1]);
+        assert_eq!(("good".to_owned(), Ok(())), results[2]);
+    }
+
+    #[test]
+    fn database_without_head_is_not_empty() {
+        let store = open_sync_memory_store();
+        let database = store.create("foodb").unwrap();
+        assert_eq!(None, database.head_name().unwrap());
+        assert!(!database.is_empty().unwrap());
+
+        let empty = store.create_base_layer().unwrap().commit().unwrap();
+        database.set_head(&empty).unwrap();
+        assert_eq!(Some(empty.name()), database.head_name().unwrap());
+        assert!(database.is_empty().unwrap());
+
+        let builder = empty.open_write().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let full = builder.commit().unwrap();
+        database.set_head(&full).unwrap();
+        assert!(!database.is_empty().unwrap());
+
+        let builder = full.open_write().unwrap();
+        builder
+            .remove_string_triple(&StringTriple::new_value("cow", "says", "moo"))
+            .unwrap();
+        let emptied = builder.commit().unwrap();
+        database.set_head(&emptied).unwrap();
+        assert!(database.is_empty().unwrap());
+    }
+
+    #[test]
+    fn add_node_and_value_triples() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_node_triple("cow", "likes", "duck").unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+
+        let layer = builder.commit().unwrap();
+
+        assert!(layer.string_triple_exists(&StringTriple::new_node("cow", "likes", "duck")));
+        assert!(layer.string_triple_exists(&StringTriple::new_value("duck", "says", "quack")));
+        assert!(!layer.string_triple_exists(&StringTriple::new_value("cow", "likes", "duck")));
+        assert!(!layer.string_triple_exists(&StringTriple::new_node("duck", "says", "quack")));
+    }
+
+    #[test]
+    fn remove_all_triples_of_a_subject() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_node_triple("cow", "likes", "duck").unwrap();
+        builder.add_node_triple("cow", "likes", "cow").unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        builder.add_node_triple("duck", "likes", "cow").unwrap();
+        builder.add_value_triple("duck", "says", "quack").unwrap();
+        let base = builder.commit().unwrap();
+
+        let builder = base.open_write().unwrap();
+        assert_eq!(3, builder.remove_subject("cow").unwrap());
+        assert_eq!(0, builder.remove_subject("horse").unwrap());
+        let child = builder.commit().unwrap();
+        assert!(!child.string_triple_exists(&StringTriple::new_value("cow", "says", "moo")));
+        assert!(child.string_triple_exists(&StringTriple::new_node("duck", "likes", "cow")));
+        assert_eq!(2, child.triples().count());
+
+        let builder = base.open_write().unwrap();
+        assert_eq!(4, builder.remove_subject_fully("cow").unwrap());
+        let child = builder.commit().unwrap();
+        assert!(!child.string_triple_exists(&StringTriple::new_node("duck", "likes", "cow")));
+        assert_eq!(1, child.triples().count());
+    }
+
+    #[test]
+    fn strings_over_the_length_limit_are_rejected() {
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "mooooooo").unwrap();
+
+        builder.set_max_value_length(Some(4)).unwrap();
+        builder.set_max_node_length(Some(5)).unwrap();
+        builder.set_max_predicate_length(Some(5)).unwrap();
+        builder.add_value_triple("duck", "says", "quak").unwrap();
+        let error = builder
+            .add_value_triple("cow", "says", "moooo")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("value"));
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            builder
+                .add_node_triple("cow", "likes", "chicken")
+                .unwrap_err()
+                .kind()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            builder
+                .add_node_triple("cow", "admires", "duck")
+                .unwrap_err()
+                .kind()
+        );
+
+        let layer = builder.commit().unwrap();
+        assert_eq!(2, layer.triples().count());
+    }
+
+    #[test]
+    fn commit_reports_progress() {
+        use crate::layer::CommitPhase;
+        use std::sync::Mutex;
+
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder.add_value_triple("cow", "says", "moo").unwrap();
+        let base = builder.commit().unwrap();
+
+        let builder = base.open_write().unwrap();
+        builder.add_value_triple("pig", "says", "oink").unwrap();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let child = builder
+            .commit_with_progress({
+                let reported = reported.clone();
+                move |progress| reported.lock().unwrap().push(progress)
+            })
+            .unwrap();
+        assert!(child.string_triple_exists(&StringTriple::new_value("pig", "says", "oink")));
+
+        let reported = reported.lock().unwrap();
+        let mut phases: Vec<_> = reported.iter().map(|p| p.phase).collect();
+        phases.dedup();
+        assert_eq!(
+            vec![
+                CommitPhase::WritingDictionaries,
+                CommitPhase::Sorting,
+                CommitPhase::WritingTriples,
+                CommitPhase::Finalizing,
+                CommitPhase::Done
+            ],
+            phases
+        );
+        assert!(reported.windows(2).all(|w| w[0].percent <= w[1].percent));
+        assert_eq!(100, reported.last().unwrap().percent);
+
+        // progress is counted within phases: pig and oink are new strings
+        let dictionaries: Vec<_> = reported
+            .iter()
+            .filter(|p| p.phase == CommitPhase::WritingDictionaries)
+            .map(|p| (p.done, p.total))
+            .collect();
+        assert_eq!(vec![(0, 2), (1, 2), (2, 2)], dictionaries);
+    }
+
+    #[test]
+    fn spilled_commit_reports_progress() {
+        use crate::layer::CommitPhase;
+        use std::sync::Mutex;
+
+        let dir = tempdir().unwrap();
+        let store = open_sync_memory_store();
+        let builder = store.create_base_layer().unwrap();
+        builder
+            .set_spill_threshold(2, dir.path().to_owned())
+            .unwrap();
+        for i in 0..5 {
+            builder
+                .add_value_triple(&format!("cow{}", i), "says", "moo")
+                .unwrap();
+        }
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let layer = builder
+            .commit_with_progress({
+                let reported = reported.clone();
+                move |progress| reported.lock().unwrap().push(progress)
+            })
+            .unwrap();
+        assert_eq!(5, layer.triples().count());
+
+        let reported = reported.lock().unwrap();
+        let mut phases: Vec<_> = reported.iter().map(|p| p.phase).collect();
+        phases.dedup();
+        assert_eq!(
+            vec![
+                CommitPhase::Sorting,
+                CommitPhase::WritingDictionaries,
+                CommitPhase::WritingTriples,
+                CommitPhase::Finalizing,
+                CommitPhase::Done
+            ],
+            phases
+        );
+        assert!(reported.windows(2).all(|w| w[0].percent <= w[1].percent));
+        assert!(
+            reported
+                .iter()
+                .filter(|p| p.phase == CommitPhase::WritingTriples)
+                .count()
+                > 2
+        );
+    }
+
     #[test]
     fn create_sync_layer_and_retrieve_it_by_id() {
         let store = open_sync_memory_store();
@@ -3,6 +3,7 @@ use super::util::*;
 use crate::storage::*;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::BytesMut;
+use futures::future;
 use futures::prelude::*;
 use tokio::codec::{Decoder, FramedRead};
 use tokio::prelude::*;
@@ -166,9 +167,10 @@ pub fn bitarray_stream_blocks<R: AsyncRead>(r: R) -> FramedRead<R, BitArrayBlock
 }
 
 fn bitarray_count_from_file<F: FileLoad>(f: F) -> impl Future<Item = u64, Error = std::io::Error> {
-    let offset = f.size() - 8;
-    tokio::io::read_exact(f.open_read_from(offset), vec![0; 8])
-        .map(|(_, buf)| BigEndian::read_u64(&buf))
+    future::result(file_footer_offset(&f)).and_then(move |offset| {
+        tokio::io::read_exact(f.open_read_from(offset), vec![0; 8])
+            .map(|(_, buf)| BigEndian::read_u64(&buf))
+    })
 }
 
 fn block_bits(block: u64) -> Vec<bool> {
@@ -50,6 +50,11 @@ impl<M: AsRef<[u8]> + Clone> BitIndex<M> {
         &self.array.bits()[bit_index..bit_index + 8]
     }
 
+    /// Returns the underlying bitarray.
+    pub fn bitarray(&self) -> &BitArray<M> {
+        &self.array
+    }
+
     /// Returns the length of the underlying bitarray.
     pub fn len(&self) -> usize {
         self.array.len()
@@ -0,0 +1,197 @@
+//! Logic for storing, loading and using bloom filters over triples.
+//!
+//! A bloom filter is a probabilistic set. It can tell with certainty
+//! that a triple was never inserted, but it may claim that a triple
+//! was inserted when it was not. How often that happens is the false
+//! positive rate, which is chosen when the filter is built.
+//!
+//! A lower false positive rate costs more space. The filter needs
+//! about `1.44 * log2(1/rate)` bits per triple, which comes down to
+//! roughly 9.6 bits per triple for a rate of 1%, and 14.4 bits per
+//! triple for a rate of 0.1%.
+//!
+//! The filter is stored as a bit array of a whole number of bytes,
+//! followed by the number of bits and the number of hash functions as
+//! big-endian u64s.
+use byteorder::{BigEndian, ByteOrder};
+use futures::prelude::*;
+use tokio::prelude::*;
+
+#[derive(Clone)]
+pub struct BloomFilter<M: AsRef<[u8]> + Clone> {
+    bits: M,
+    bit_count: u64,
+    hash_count: u64,
+}
+
+#[derive(Debug)]
+pub enum BloomFilterError {
+    InvalidCoding,
+}
+
+impl<M: AsRef<[u8]> + Clone> BloomFilter<M> {
+    pub fn parse(data: M) -> Result<BloomFilter<M>, BloomFilterError> {
+        let len = data.as_ref().len();
+        if len < 16 {
+            return Err(BloomFilterError::InvalidCoding);
+        }
+
+        let bit_count = BigEndian::read_u64(&data.as_ref()[len - 16..]);
+        let hash_count = BigEndian::read_u64(&data.as_ref()[len - 8..]);
+        if bit_count == 0
+            || hash_count == 0
+            || bit_count % 8 != 0
+            || bit_count as usize / 8 != len - 16
+        {
+            return Err(BloomFilterError::InvalidCoding);
+        }
+
+        Ok(BloomFilter {
+            bits: data,
+            bit_count,
+            hash_count,
+        })
+    }
+
+    /// Returns the size of the filter in bits.
+    pub fn bit_count(&self) -> u64 {
+        self.bit_count
+    }
+
+    /// Returns false if the given triple was definitely not inserted, and true if it may have been.
+    pub fn may_contain(&self, subject: u64, predicate: u64, object: u64) -> bool {
+        let bits = self.bits.as_ref();
+        bit_positions(subject, predicate, object, self.bit_count, self.hash_count)
+            .all(|pos| bits[(pos / 8) as usize] & (1 << (pos % 8)) != 0)
+    }
+}
+
+/// A builder for a bloom filter.
+///
+/// The filter is kept in memory until it is written out with `finalize`.
+pub struct BloomFilterBuilder {
+    bits: Vec<u8>,
+    bit_count: u64,
+    hash_count: u64,
+}
+
+/// Check that the given false positive rate is usable for a bloom filter.
+///
+/// Returns an `InvalidInput` error if it is not between 0 and 1.
+pub fn check_false_positive_rate(false_positive_rate: f64) -> std::io::Result<()> {
+    if false_positive_rate > 0.0 && false_positive_rate < 1.0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "bloom filter false positive rate should be between 0 and 1",
+        ))
+    }
+}
+
+impl BloomFilterBuilder {
+    /// Create a builder for a filter which is expected to hold the
+    /// given number of triples, sized so that it has the given false
+    /// positive rate.
+    ///
+    /// Panics if the false positive rate is not between 0 and 1. Use
+    /// `check_false_positive_rate` to check a rate beforehand.
+    pub fn new(expected_count: usize, false_positive_rate: f64) -> BloomFilterBuilder {
+        assert!(
+            check_false_positive_rate(false_positive_rate).is_ok(),
+            "bloom filter false positive rate should be between 0 and 1"
+        );
+
+        let n = expected_count.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        // round up to a whole number of bytes
+        let byte_count = (-n * false_positive_rate.ln() / (ln2 * ln2) / 8.0)
+            .ceil()
+            .max(1.0) as usize;
+        let bit_count = byte_count as u64 * 8;
+        let hash_count = (bit_count as f64 / n * ln2).round().max(1.0) as u64;
+
+        BloomFilterBuilder {
+            bits: vec![0; byte_count],
+            bit_count,
+            hash_count,
+        }
+    }
+
+    pub fn insert(&mut self, subject: u64, predicate: u64, object: u64) {
+        for pos in bit_positions(subject, predicate, object, self.bit_count, self.hash_count) {
+            self.bits[(pos / 8) as usize] |= 1 << (pos % 8);
+        }
+    }
+
+    pub fn finalize<W: AsyncWrite>(
+        self,
+        write: W,
+    ) -> impl Future<Item = W, Error = std::io::Error> {
+        let mut data = self.bits;
+        let mut buf = [0; 16];
+        BigEndian::write_u64(&mut buf[0..8], self.bit_count);
+        BigEndian::write_u64(&mut buf[8..16], self.hash_count);
+        data.extend_from_slice(&buf);
+
+        tokio::io::write_all(write, data).and_then(|(write, _)| tokio::io::flush(write))
+    }
+}
+
+/// Mix the bits of a u64 (the splitmix64 finalizer).
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Returns the positions of the bits that are set for the given triple.
+///
+/// This uses double hashing, deriving all positions from two hashes
+/// of the triple.
+fn bit_positions(
+    subject: u64,
+    predicate: u64,
+    object: u64,
+    bit_count: u64,
+    hash_count: u64,
+) -> impl Iterator<Item = u64> {
+    let h1 = mix(mix(mix(subject) ^ predicate) ^ object);
+    let h2 = mix(h1 ^ 0x9e3779b97f4a7c15) | 1;
+
+    (0..hash_count).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bit_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::*;
+    use crate::storage::*;
+
+    #[test]
+    fn build_and_query_bloom_filter() {
+        let store = MemoryBackedStore::new();
+        let mut builder = BloomFilterBuilder::new(1000, 0.01);
+        for i in 0..1000 {
+            builder.insert(i, i % 7, i * 3);
+        }
+        builder.finalize(store.open_write()).wait().unwrap();
+
+        let filter = BloomFilter::parse(store.map().wait().unwrap()).unwrap();
+
+        for i in 0..1000 {
+            assert!(filter.may_contain(i, i % 7, i * 3));
+        }
+
+        let false_positives = (1000..11000)
+            .filter(|&i| filter.may_contain(i, i % 7, i * 3))
+            .count();
+        assert!(false_positives < 300);
+    }
+
+    #[test]
+    fn parse_invalid_bloom_filter() {
+        assert!(BloomFilter::parse(vec![0u8; 8]).is_err());
+        assert!(BloomFilter::parse(vec![0u8; 16]).is_err());
+    }
+}
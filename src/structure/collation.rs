@@ -0,0 +1,178 @@
+//! Orderings for the strings in a dictionary.
+//!
+//! Dictionaries are sorted, which is what makes lookups by string
+//! and prefix searches fast. By default, strings are sorted by their
+//! bytes. A different `Collation` can be chosen when building a
+//! layer. Its name is stored alongside the layer, and looked up in a
+//! process-wide registry when the layer is loaded again, so that
+//! readers sort and search the dictionaries the same way.
+use lazy_static::lazy_static;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+/// An ordering of dictionary strings.
+///
+/// Prefix searches expect all strings that start with a given prefix
+/// to be sorted together, directly after the prefix itself. Any
+/// ordering that compares strings character by character, using some
+/// total order of characters, has this property.
+///
+/// The ordering of a layer is fixed when it is built, so a collation
+/// must never change how it orders strings once layers were built
+/// with it. A changed ordering needs a new name.
+pub trait Collation: Send + Sync {
+    /// The name under which this collation is stored and registered.
+    fn name(&self) -> &str;
+    /// Compare two strings.
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+}
+
+/// The default collation, which orders strings by their bytes.
+///
+/// Layers using this collation do not store a collation name.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytewiseCollation;
+
+impl Collation for BytewiseCollation {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// A collation that orders characters by their lowercase form.
+///
+/// Strings are compared character by character, and characters that
+/// only differ in case are ordered next to each other, with the
+/// uppercase character first. So `"apple"` sorts before `"Bee"`, which
+/// sorts before `"bee"`, whereas bytewise ordering puts `"Bee"` first.
+/// Other characters are ordered by the code point of their lowercase
+/// form. Strings remain distinct, so lookups are still case
+/// sensitive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaseInsensitiveCollation;
+
+impl Collation for CaseInsensitiveCollation {
+    fn name(&self) -> &str {
+        "case_insensitive"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let key = |c: char| (c.to_lowercase(), c);
+        for (x, y) in a.chars().zip(b.chars()) {
+            let ((x_lower, x), (y_lower, y)) = (key(x), key(y));
+            let ordering = x_lower.cmp(y_lower).then(x.cmp(&y));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        a.chars().count().cmp(&b.chars().count())
+    }
+}
+
+lazy_static! {
+    static ref COLLATIONS: RwLock<HashMap<String, Arc<dyn Collation>>> = {
+        let mut collations: HashMap<String, Arc<dyn Collation>> = HashMap::new();
+        for collation in [
+            Arc::new(BytewiseCollation) as Arc<dyn Collation>,
+            Arc::new(CaseInsensitiveCollation),
+        ]
+        .iter()
+        {
+            collations.insert(collation.name().to_owned(), collation.clone());
+        }
+
+        RwLock::new(collations)
+    };
+}
+
+/// Register a collation, so that layers built with it can be loaded.
+///
+/// The built-in collations are always registered. Registering a
+/// collation under a name that is already taken replaces the earlier
+/// one.
+pub fn register_collation(collation: Arc<dyn Collation>) -> io::Result<()> {
+    COLLATIONS
+        .write()
+        .map_err(|_| poisoned_registry_error())?
+        .insert(collation.name().to_owned(), collation);
+
+    Ok(())
+}
+
+/// Returns the registered collation with the given name, if any.
+pub fn collation_by_name(name: &str) -> io::Result<Option<Arc<dyn Collation>>> {
+    Ok(COLLATIONS
+        .read()
+        .map_err(|_| poisoned_registry_error())?
+        .get(name)
+        .cloned())
+}
+
+/// The error for a collation registry whose lock was poisoned by a panic while it was held.
+fn poisoned_registry_error() -> io::Error {
+    io::Error::other("collation registry lock was poisoned")
+}
+
+/// Returns true if the given collation is the default bytewise collation.
+pub fn is_bytewise(collation: &dyn Collation) -> bool {
+    collation.name() == BytewiseCollation.name()
+}
+
+/// Resolve the collation name stored with a layer.
+///
+/// A layer without a stored name uses bytewise ordering. A name that
+/// was never registered is an error, as the dictionaries of the layer
+/// cannot be searched without knowing their order.
+pub fn collation_from_stored_name(name: Option<&[u8]>) -> io::Result<Arc<dyn Collation>> {
+    let name = match name {
+        None | Some(b"") => return Ok(Arc::new(BytewiseCollation)),
+        Some(name) => String::from_utf8_lossy(name),
+    };
+
+    collation_by_name(&name)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("layer uses unknown collation {:?}", name),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_insensitive_ordering_keeps_prefixes_together() {
+        let mut strings = vec!["bee", "Apple", "apple", "Bee", "b", "apples", "Ápple", "B"];
+        strings.sort_by(|a, b| CaseInsensitiveCollation.compare(a, b));
+        assert_eq!(
+            vec!["Apple", "apple", "apples", "B", "Bee", "b", "bee", "Ápple"],
+            strings
+        );
+    }
+
+    #[test]
+    fn resolve_stored_collation_names() {
+        assert!(is_bytewise(&*collation_from_stored_name(None).unwrap()));
+        assert_eq!(
+            "case_insensitive",
+            collation_from_stored_name(Some(b"case_insensitive"))
+                .unwrap()
+                .name()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            collation_from_stored_name(Some(b"klingon"))
+                .err()
+                .unwrap()
+                .kind()
+        );
+    }
+}
@@ -4,6 +4,7 @@
 //! By using the minimal width necessary to store the largest value of
 //! the array, the byte representation of the array can be compressed
 //! significantly compared to using an array of u64.
+use super::util::file_footer_offset;
 use crate::storage::*;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::BytesMut;
@@ -97,6 +98,11 @@ impl<M: AsRef<[u8]> + Clone> LogArray<M> {
         self.len_bytes
     }
 
+    /// Returns the underlying data, including the trailing length and width.
+    pub fn data(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
     fn nums_for_index(&self, index: usize) -> (u64, u64) {
         let start_bit = self.width as usize * index;
         let start_byte = start_bit / 8;
@@ -305,12 +311,14 @@ impl<W: 'static + tokio::io::AsyncWrite + Send> LogArrayFileBuilder<W> {
                 Box::new(tokio::io::write_all(file, buf).map(|(file, _)| file))
             };
 
-        write_last_bits.and_then(move |file| {
-            let mut buf = vec![0u8; 8];
-            BigEndian::write_u32(&mut buf, count);
-            buf[4] = width;
-            tokio::io::write_all(file, buf).map(|(file, _)| file)
-        })
+        write_last_bits
+            .and_then(move |file| {
+                let mut buf = vec![0u8; 8];
+                BigEndian::write_u32(&mut buf, count);
+                buf[4] = width;
+                tokio::io::write_all(file, buf)
+            })
+            .and_then(|(file, _)| tokio::io::flush(file))
     }
 }
 
@@ -375,13 +383,15 @@ impl Decoder for LogArrayDecoder {
 pub fn logarray_file_get_length_and_width<F: FileLoad>(
     f: &F,
 ) -> impl Future<Item = (u32, u8), Error = std::io::Error> {
-    let end_offset = f.size() - 8;
-    // read the length and width
-    tokio::io::read_exact(f.open_read_from(end_offset), vec![0; 8]).map(move |(_, buf)| {
-        let len = BigEndian::read_u32(&buf);
-        let width = buf[4];
-
-        (len, width)
+    let f = f.clone();
+    future::result(file_footer_offset(&f)).and_then(move |end_offset| {
+        // read the length and width
+        tokio::io::read_exact(f.open_read_from(end_offset), vec![0; 8]).map(move |(_, buf)| {
+            let len = BigEndian::read_u32(&buf);
+            let width = buf[4];
+
+            (len, width)
+        })
     })
 }
 
@@ -5,6 +5,8 @@
 pub mod adjacencylist;
 pub mod bitarray;
 pub mod bitindex;
+pub mod bloomfilter;
+pub mod collation;
 pub mod logarray;
 pub mod pfc;
 mod util;
@@ -14,6 +16,10 @@ pub mod wavelettree;
 pub use adjacencylist::*;
 pub use bitarray::*;
 pub use bitindex::*;
+pub use bloomfilter::*;
+pub use collation::*;
 pub use logarray::*;
 pub use pfc::*;
 pub use wavelettree::*;
+
+pub(crate) use util::run_blocking;
@@ -2,10 +2,12 @@
 use byteorder::{BigEndian, ByteOrder};
 use futures::future;
 use futures::prelude::*;
-use std::cmp::{Ord, Ordering};
+use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt::Display;
+use std::sync::Arc;
 
+use super::collation::*;
 use super::logarray::*;
 use super::util::*;
 use super::vbyte::*;
@@ -14,6 +16,7 @@ use super::vbyte::*;
 pub enum PfcError {
     InvalidCoding,
     NotEnoughData,
+    InvalidUtf8,
 }
 
 impl Display for PfcError {
@@ -57,10 +60,9 @@ pub struct PfcBlockIterator<'a, M: AsRef<[u8]> + Clone> {
     string: Vec<u8>,
 }
 
-impl<'a, M: AsRef<[u8]> + Clone> Iterator for PfcBlockIterator<'a, M> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<String> {
+impl<'a, M: AsRef<[u8]> + Clone> PfcBlockIterator<'a, M> {
+    /// Decodes the next string in the block without checking that it is valid UTF-8.
+    fn next_bytes(&mut self) -> Option<&[u8]> {
         if self.pos == 0 {
             // we gotta read the initial prefix first (a nul-terminated string)
             self.string = self.block.head();
@@ -90,7 +92,16 @@ impl<'a, M: AsRef<[u8]> + Clone> Iterator for PfcBlockIterator<'a, M> {
             return None;
         }
 
-        Some(String::from_utf8(self.string.clone()).unwrap())
+        Some(&self.string)
+    }
+}
+
+impl<'a, M: AsRef<[u8]> + Clone> Iterator for PfcBlockIterator<'a, M> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.next_bytes()
+            .map(|bytes| String::from_utf8(bytes.to_vec()).expect("invalid utf-8 in dictionary"))
     }
 }
 
@@ -135,7 +146,7 @@ impl<M: AsRef<[u8]> + Clone> Iterator for OwnedPfcBlockIterator<M> {
             return None;
         }
 
-        Some(String::from_utf8(self.string.clone()).unwrap())
+        Some(String::from_utf8(self.string.clone()).expect("invalid utf-8 in dictionary"))
     }
 }
 
@@ -193,6 +204,25 @@ impl<M: AsRef<[u8]> + Clone> PfcBlock<M> {
         }
     }
 
+    /// Like `get`, but returns an error instead of panicking if the string is not valid UTF-8.
+    pub fn get_checked(&self, index: usize) -> Result<Option<String>, PfcError> {
+        if index >= self.n_strings {
+            return Ok(None);
+        }
+
+        let mut strings = self.strings();
+        for _ in 0..index {
+            strings.next_bytes();
+        }
+
+        match strings.next_bytes() {
+            None => Ok(None),
+            Some(bytes) => String::from_utf8(bytes.to_vec())
+                .map(Some)
+                .map_err(|_| PfcError::InvalidUtf8),
+        }
+    }
+
     pub fn len(&self) -> usize {
         let vbyte_len = VByte::required_len(self.encoded_strings.as_ref().len() as u64);
 
@@ -205,6 +235,7 @@ pub struct PfcDict<M: AsRef<[u8]> + Clone> {
     n_strings: u64,
     block_offsets: LogArray<M>,
     blocks: M,
+    collation: Arc<dyn Collation>,
 }
 
 pub struct PfcDictIterator<'a, M: AsRef<[u8]> + Clone> {
@@ -265,9 +296,24 @@ impl<M: AsRef<[u8]> + Clone> PfcDict<M> {
             n_strings: n_strings,
             block_offsets: block_offsets,
             blocks: blocks,
+            collation: Arc::new(BytewiseCollation),
         })
     }
 
+    /// Use the given collation to search this dictionary.
+    ///
+    /// This has to be the collation the dictionary was sorted with.
+    /// Dictionaries are parsed with bytewise ordering.
+    pub fn with_collation(mut self, collation: Arc<dyn Collation>) -> Self {
+        self.collation = collation;
+        self
+    }
+
+    /// The collation used to search this dictionary.
+    pub fn collation(&self) -> &Arc<dyn Collation> {
+        &self.collation
+    }
+
     pub fn len(&self) -> usize {
         self.n_strings as usize
     }
@@ -289,6 +335,23 @@ impl<M: AsRef<[u8]> + Clone> PfcDict<M> {
         }
     }
 
+    /// Like `get`, but returns an error instead of panicking if the string is not valid UTF-8.
+    pub fn get_checked(&self, ix: usize) -> Result<Option<String>, PfcError> {
+        if (ix as u64) < self.n_strings {
+            let block_index = ix / BLOCK_SIZE;
+            let block_offset = if block_index == 0 {
+                0
+            } else {
+                self.block_offsets.entry(block_index - 1)
+            };
+            let block = PfcBlock::parse(&self.blocks.as_ref()[block_offset as usize..])?;
+
+            block.get_checked(ix % BLOCK_SIZE)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn id(&self, s: &str) -> Option<u64> {
         // let's binary search
         let mut min = 0;
@@ -309,7 +372,7 @@ impl<M: AsRef<[u8]> + Clone> PfcDict<M> {
 
             let head = String::from_utf8(head_slice.to_vec()).unwrap();
 
-            match s.cmp(&head) {
+            match self.collation.compare(s, &head) {
                 Ordering::Less => {
                     if mid == 0 {
                         // we checked the first block and determined that the string should be in the previous block, if it exists.
@@ -357,6 +420,62 @@ impl<M: AsRef<[u8]> + Clone> PfcDict<M> {
             block: None,
         }
     }
+
+    /// Returns the head of the block with the given index.
+    fn block_head(&self, block_index: usize) -> &[u8] {
+        let block_offset = if block_index == 0 {
+            0
+        } else {
+            self.block_offsets.entry(block_index - 1) as usize
+        };
+        let block_slice = &self.blocks.as_ref()[block_offset..];
+        let head_end = block_slice.iter().position(|&b| b == 0).unwrap();
+
+        &block_slice[..head_end]
+    }
+
+    /// Returns the ids and strings of all entries starting with the given prefix, in order.
+    ///
+    /// Only the block heads are searched to find where matching
+    /// entries start, after which entries are decoded until one no
+    /// longer matches.
+    pub fn strings_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (u64, String)> + 'a {
+        let block_count = self.block_offsets.len() + 1;
+        let block_index = if self.n_strings == 0 {
+            // an exhausted iterator
+            block_count
+        } else {
+            // find the first block with a head after the prefix.
+            // matching strings start in the block before it.
+            let mut min = 0;
+            let mut max = block_count;
+            while min < max {
+                let mid = (min + max) / 2;
+                let head = String::from_utf8_lossy(self.block_head(mid));
+                if self.collation.compare(&head, prefix) != Ordering::Greater {
+                    min = mid + 1;
+                } else {
+                    max = mid;
+                }
+            }
+
+            min.saturating_sub(1)
+        };
+
+        let strings = PfcDictIterator {
+            dict: self,
+            block_index,
+            block: None,
+        };
+
+        (block_index as u64 * BLOCK_SIZE as u64..)
+            .zip(strings)
+            .skip_while(move |(_, s)| self.collation.compare(s, prefix) == Ordering::Less)
+            .take_while(move |(_, s)| s.starts_with(prefix))
+    }
 }
 
 pub struct PfcDictFileBuilder<W: tokio::io::AsyncWrite + Send> {
@@ -519,6 +638,84 @@ mod tests {
         assert_eq!(None, i.next());
     }
 
+    #[test]
+    fn find_strings_with_prefix() {
+        let contents: Vec<String> = (0..100).map(|i| format!("node{:03}", i)).collect();
+        let blocks = MemoryBackedStore::new();
+        let offsets = MemoryBackedStore::new();
+        let builder = PfcDictFileBuilder::new(blocks.open_write(), offsets.open_write());
+        builder
+            .add_all(contents.into_iter())
+            .and_then(|(_, b)| b.finalize())
+            .wait()
+            .unwrap();
+
+        let p =
+            PfcDict::parse(blocks.map().wait().unwrap(), offsets.map().wait().unwrap()).unwrap();
+
+        let found: Vec<_> = p.strings_with_prefix("node04").collect();
+        assert_eq!(10, found.len());
+        for (i, (id, s)) in found.into_iter().enumerate() {
+            assert_eq!(40 + i as u64, id);
+            assert_eq!(format!("node04{}", i), s);
+        }
+
+        assert_eq!(100, p.strings_with_prefix("").count());
+        assert_eq!(100, p.strings_with_prefix("node").count());
+        assert_eq!(
+            vec![(0, "node000".to_string())],
+            p.strings_with_prefix("node000").collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(99, "node099".to_string())],
+            p.strings_with_prefix("node099").collect::<Vec<_>>()
+        );
+        assert_eq!(0, p.strings_with_prefix("a").count());
+        assert_eq!(0, p.strings_with_prefix("node1").count());
+        assert_eq!(0, p.strings_with_prefix("z").count());
+    }
+
+    #[test]
+    fn search_dictionary_with_collation() {
+        // enough strings for several blocks, in case insensitive order
+        let mut contents: Vec<String> = (0..20)
+            .flat_map(|i| {
+                vec![
+                    format!("Node{:02}", i),
+                    format!("node{:02}", i),
+                    format!("NODE{:02}x", i),
+                ]
+            })
+            .collect();
+        contents.sort_by(|a, b| CaseInsensitiveCollation.compare(a, b));
+        let blocks = MemoryBackedStore::new();
+        let offsets = MemoryBackedStore::new();
+        let builder = PfcDictFileBuilder::new(blocks.open_write(), offsets.open_write());
+        builder
+            .add_all(contents.clone().into_iter())
+            .and_then(|(_, b)| b.finalize())
+            .wait()
+            .unwrap();
+
+        let p = PfcDict::parse(blocks.map().wait().unwrap(), offsets.map().wait().unwrap())
+            .unwrap()
+            .with_collation(Arc::new(CaseInsensitiveCollation));
+
+        for (i, s) in contents.iter().enumerate() {
+            assert_eq!(Some(i as u64), p.id(s));
+        }
+        assert_eq!(None, p.id("nOde01"));
+
+        let found: Vec<_> = p.strings_with_prefix("node1").map(|(_, s)| s).collect();
+        assert_eq!(
+            (10..20).map(|i| format!("node{}", i)).collect::<Vec<_>>(),
+            found
+        );
+        let found: Vec<_> = p.strings_with_prefix("NODE05").map(|(_, s)| s).collect();
+        assert_eq!(vec!["NODE05x".to_string()], found);
+        assert_eq!(60, p.strings_with_prefix("").count());
+    }
+
     #[test]
     fn can_create_pfc_dict_large() {
         let contents = vec![
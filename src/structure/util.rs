@@ -1,4 +1,5 @@
 use byteorder::{BigEndian, ByteOrder};
+use futures::future;
 use futures::prelude::*;
 
 pub fn find_common_prefix(b1: &[u8], b2: &[u8]) -> usize {
@@ -43,3 +44,34 @@ pub fn write_u64<W: tokio::io::AsyncWrite + Send>(
 
     tokio::io::write_all(w, v).map(|(w, _)| w)
 }
+
+/// Returns the offset of the 8-byte footer at the end of the given file.
+///
+/// Fails with `InvalidData` if the file is too short to have a footer.
+pub fn file_footer_offset<F: crate::storage::FileLoad>(f: &F) -> std::io::Result<usize> {
+    f.size()?.checked_sub(8).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "file is too short to contain a footer",
+        )
+    })
+}
+
+/// Run the given blocking operation without stalling the other tasks of the runtime.
+///
+/// On a tokio threadpool, the operation runs through
+/// `tokio_threadpool::blocking`, which hands the worker's other tasks
+/// to another thread first. Outside of a threadpool, for example when
+/// the future is driven with `wait()`, the operation simply runs inline.
+pub fn run_blocking<T, F: FnOnce() -> std::io::Result<T>>(
+    f: F,
+) -> impl Future<Item = T, Error = std::io::Error> {
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| (f.take().expect("blocking operation already ran"))()) {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => (f.take().expect("blocking operation already ran"))().map(Async::Ready),
+        }
+    })
+}
@@ -159,7 +159,8 @@ impl<M: AsRef<[u8]> + Clone> WaveletTree<M> {
         let mut alphabet_start = 0;
         let mut alphabet_end = 2_u64.pow(self.num_layers as u32) as u64;
 
-        if entry >= alphabet_end {
+        // an empty tree has no layers to look the entry up in
+        if entry >= alphabet_end || self.num_layers == 0 {
             return None;
         }
 